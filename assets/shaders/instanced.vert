@@ -0,0 +1,20 @@
+#version 450
+
+layout (location = 0) in vec3 vPosition;
+layout (location = 1) in vec3 vColor;
+
+layout (location = 2) in mat4 iTransform;
+layout (location = 6) in vec4 iTint;
+
+layout (location = 0) out vec3 outColor;
+
+layout (push_constant) uniform constants {
+	vec4 data;
+	mat4 render_matrix;
+} PushConstants;
+
+void main()
+{
+	gl_Position = PushConstants.render_matrix * iTransform * vec4(vPosition, 1.0);
+	outColor = vColor * iTint.rgb;
+}
\ No newline at end of file
@@ -0,0 +1,30 @@
+#version 450
+
+layout (location = 0) in vec2 inUv;
+layout (location = 1) in vec4 inColor;
+
+layout (location = 0) out vec4 outFragColor;
+
+layout (set = 0, binding = 0) uniform sampler2D image;
+
+layout (push_constant) uniform constants {
+	vec2 screenSize;
+	uint srgbTarget;
+} PushConstants;
+
+vec3 linearFromSrgb(vec3 srgb)
+{
+	vec3 lower = srgb / 12.92;
+	vec3 higher = pow((srgb + 0.055) / 1.055, vec3(2.4));
+	return mix(higher, lower, lessThan(srgb, vec3(0.04045)));
+}
+
+void main()
+{
+	// Vertex colours and textures are both sRGB encoded with premultiplied alpha.
+	vec4 color = inColor * texture(image, inUv);
+	if (PushConstants.srgbTarget != 0 && color.a > 0.0) {
+		color.rgb = linearFromSrgb(color.rgb / color.a) * color.a;
+	}
+	outFragColor = color;
+}
\ No newline at end of file
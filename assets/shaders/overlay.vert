@@ -0,0 +1,21 @@
+#version 450
+
+layout (location = 0) in vec2 vPosition;
+layout (location = 1) in vec2 vUv;
+layout (location = 2) in vec4 vColor;
+
+layout (location = 0) out vec2 outUv;
+layout (location = 1) out vec4 outColor;
+
+// Positions are in points from the top left corner of the screen.
+layout (push_constant) uniform constants {
+	vec2 screenSize;
+	uint srgbTarget;
+} PushConstants;
+
+void main()
+{
+	gl_Position = vec4(2.0 * vPosition / PushConstants.screenSize - 1.0, 0.0, 1.0);
+	outUv = vUv;
+	outColor = vColor;
+}
\ No newline at end of file
@@ -0,0 +1,10 @@
+#version 450
+
+layout (location = 0) out vec2 outUv;
+
+// One triangle past the corners of the screen, so every pixel is covered once.
+void main()
+{
+	outUv = vec2((gl_VertexIndex << 1) & 2, gl_VertexIndex & 2);
+	gl_Position = vec4(outUv * 2.0 - 1.0, 0.0, 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,35 @@
+#version 450
+
+// Keeps what is brighter than the threshold, easing in over the knee so bloom
+// doesn't switch on abruptly, while halving the resolution.
+
+layout (location = 0) in vec2 inUv;
+
+layout (location = 0) out vec4 outFragColor;
+
+layout (set = 0, binding = 0) uniform sampler2D source;
+
+// params: x threshold, y knee.
+layout (push_constant) uniform constants {
+	vec4 params;
+	vec2 texelSize;
+	uint flags;
+} PushConstants;
+
+void main()
+{
+	vec2 offset = PushConstants.texelSize * 0.5;
+	vec3 color = 0.25 * (
+		texture(source, inUv + vec2(-offset.x, -offset.y)).rgb +
+		texture(source, inUv + vec2(offset.x, -offset.y)).rgb +
+		texture(source, inUv + vec2(-offset.x, offset.y)).rgb +
+		texture(source, inUv + vec2(offset.x, offset.y)).rgb);
+
+	float threshold = PushConstants.params.x;
+	float knee = max(PushConstants.params.y, 1e-4);
+	float brightness = max(color.r, max(color.g, color.b));
+	float soft = clamp(brightness - threshold + knee, 0.0, 2.0 * knee);
+	soft = soft * soft / (4.0 * knee);
+	float contribution = max(soft, brightness - threshold) / max(brightness, 1e-4);
+	outFragColor = vec4(color * contribution, 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,31 @@
+#version 450
+
+// One direction of a 9 tap Gaussian blur, in 5 samples by letting bilinear
+// filtering weigh pairs of texels.
+
+layout (location = 0) in vec2 inUv;
+
+layout (location = 0) out vec4 outFragColor;
+
+layout (set = 0, binding = 0) uniform sampler2D source;
+
+// params: xy direction.
+layout (push_constant) uniform constants {
+	vec4 params;
+	vec2 texelSize;
+	uint flags;
+} PushConstants;
+
+const float offsets[3] = float[](0.0, 1.3846153846, 3.2307692308);
+const float weights[3] = float[](0.2270270270, 0.3162162162, 0.0702702703);
+
+void main()
+{
+	vec2 stride = PushConstants.params.xy * PushConstants.texelSize;
+	vec3 color = texture(source, inUv).rgb * weights[0];
+	for (int i = 1; i < 3; i++) {
+		color += texture(source, inUv + stride * offsets[i]).rgb * weights[i];
+		color += texture(source, inUv - stride * offsets[i]).rgb * weights[i];
+	}
+	outFragColor = vec4(color, 1.0);
+}
\ No newline at end of file
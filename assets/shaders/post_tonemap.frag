@@ -0,0 +1,44 @@
+#version 450
+
+// HDR to display: exposure and bloom, ACES tonemapping, gamma, then the colour
+// grading lookup table, which works on gamma encoded colour.
+
+layout (location = 0) in vec2 inUv;
+
+layout (location = 0) out vec4 outFragColor;
+
+layout (set = 0, binding = 0) uniform sampler2D scene;
+layout (set = 0, binding = 1) uniform sampler2D bloom;
+layout (set = 0, binding = 2) uniform sampler3D grade;
+
+// params: x exposure, y bloom intensity, z gamma, w grading strength.
+// flags: bit 0 when the target encodes to sRGB by itself.
+layout (push_constant) uniform constants {
+	vec4 params;
+	vec2 texelSize;
+	uint flags;
+} PushConstants;
+
+// Narkowicz's fit of the ACES filmic curve.
+vec3 aces(vec3 x)
+{
+	return clamp((x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14), 0.0, 1.0);
+}
+
+void main()
+{
+	vec3 hdr = texture(scene, inUv).rgb * PushConstants.params.x
+		+ texture(bloom, inUv).rgb * PushConstants.params.y;
+	float gamma = max(PushConstants.params.z, 1e-3);
+	vec3 display = pow(aces(hdr), vec3(1.0 / gamma));
+
+	// Sample texel centres, so the corners of the table map to 0 and 1.
+	float size = float(textureSize(grade, 0).x);
+	vec3 coordinate = display * ((size - 1.0) / size) + 0.5 / size;
+	vec3 graded = mix(display, texture(grade, coordinate).rgb, PushConstants.params.w);
+
+	if ((PushConstants.flags & 1u) != 0u) {
+		graded = pow(graded, vec3(gamma));
+	}
+	outFragColor = vec4(graded, 1.0);
+}
\ No newline at end of file
@@ -0,0 +1,482 @@
+use std::fmt::Write as _;
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context};
+use cgmath::{Vector3, Vector4};
+
+use crate::terrain::Terrain;
+
+const SCORCH_COLOR: Vector3<f32> = Vector3::new(0.08, 0.06, 0.05);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BrushKind {
+    Raise,
+    Lower,
+    Smooth,
+    Flatten { height: f32 },
+    Paint { color: Vector3<f32> },
+    Scorch,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TerrainEdit {
+    pub kind: BrushKind,
+    pub x: f32,
+    pub z: f32,
+    pub radius: f32,
+    pub strength: f32,
+}
+
+// Everything an edit overwrote, so that it can be put back.
+struct AppliedEdit {
+    edit: TerrainEdit,
+    min: (usize, usize),
+    max: (usize, usize),
+    heights: Vec<f32>,
+    tint: Vec<Vector4<f32>>,
+}
+
+impl TerrainEdit {
+    fn bounds(&self, terrain: &Terrain) -> ((usize, usize), (usize, usize)) {
+        let field = &terrain.heightfield;
+        let last = (field.size - 1) as f32;
+        let to_sample = |v: f32| (v / field.cell_size).clamp(0.0, last);
+        let min = (
+            to_sample(self.x - self.radius).floor() as usize,
+            to_sample(self.z - self.radius).floor() as usize,
+        );
+        let max = (
+            to_sample(self.x + self.radius).ceil() as usize,
+            to_sample(self.z + self.radius).ceil() as usize,
+        );
+        (min, max)
+    }
+
+    fn falloff(&self, wx: f32, wz: f32) -> f32 {
+        let dx = wx - self.x;
+        let dz = wz - self.z;
+        let d = (dx * dx + dz * dz).sqrt() / self.radius;
+        if d >= 1.0 {
+            0.0
+        } else {
+            let f = 1.0 - d * d;
+            f * f
+        }
+    }
+
+    fn apply(&self, terrain: &mut Terrain) -> AppliedEdit {
+        let (min, max) = self.bounds(terrain);
+        let field = &mut terrain.heightfield;
+
+        let mut heights = Vec::new();
+        let mut tint = Vec::new();
+        for z in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                let i = field.index(x, z);
+                heights.push(field.heights[i]);
+                tint.push(field.tint[i]);
+            }
+        }
+
+        // Smoothing reads from the snapshot so the result doesn't depend on the
+        // order samples are visited in.
+        // A zero or NaN radius makes `falloff` NaN, which would poison the field
+        // and every replay after it.
+        if !(self.radius > 0.0 && self.strength.is_finite()) {
+            return AppliedEdit {
+                edit: *self,
+                min,
+                max,
+                heights,
+                tint,
+            };
+        }
+        let width = max.0 - min.0 + 1;
+        let before = |x: usize, z: usize| heights[(z - min.1) * width + (x - min.0)];
+
+        for z in min.1..=max.1 {
+            for x in min.0..=max.0 {
+                let w = self.falloff(x as f32 * field.cell_size, z as f32 * field.cell_size)
+                    * self.strength;
+                if w <= 0.0 {
+                    continue;
+                }
+                let i = field.index(x, z);
+                match self.kind {
+                    BrushKind::Raise => field.heights[i] += w,
+                    BrushKind::Lower => field.heights[i] -= w,
+                    BrushKind::Flatten { height } => {
+                        field.heights[i] += (height - field.heights[i]) * w.min(1.0)
+                    }
+                    BrushKind::Smooth => {
+                        let clamp = |v: usize, d: isize, lo: usize, hi: usize| {
+                            (v as isize + d).clamp(lo as isize, hi as isize) as usize
+                        };
+                        let mut sum = 0.0;
+                        for dz in -1..=1 {
+                            for dx in -1..=1 {
+                                let nx = clamp(x, dx, min.0, max.0);
+                                let nz = clamp(z, dz, min.1, max.1);
+                                sum += before(nx, nz);
+                            }
+                        }
+                        field.heights[i] += (sum / 9.0 - field.heights[i]) * w.min(1.0);
+                    }
+                    BrushKind::Paint { color } => blend_tint(&mut field.tint[i], color, w),
+                    BrushKind::Scorch => blend_tint(&mut field.tint[i], SCORCH_COLOR, w),
+                }
+            }
+        }
+
+        terrain.mark_dirty(min, max);
+        AppliedEdit {
+            edit: *self,
+            min,
+            max,
+            heights,
+            tint,
+        }
+    }
+}
+
+impl AppliedEdit {
+    fn revert(self, terrain: &mut Terrain) {
+        let field = &mut terrain.heightfield;
+        let mut k = 0;
+        for z in self.min.1..=self.max.1 {
+            for x in self.min.0..=self.max.0 {
+                let i = field.index(x, z);
+                field.heights[i] = self.heights[k];
+                field.tint[i] = self.tint[k];
+                k += 1;
+            }
+        }
+        terrain.mark_dirty(self.min, self.max);
+    }
+}
+
+fn blend_tint(tint: &mut Vector4<f32>, color: Vector3<f32>, w: f32) {
+    let w = w.min(1.0);
+    let rgb = tint.truncate() * (1.0 - w) + color * w;
+    let alpha = tint.w + (1.0 - tint.w) * w;
+    *tint = rgb.extend(alpha);
+}
+
+// Edits are grouped into strokes (one press of a brush key), which is the unit of
+// undo and redo. The strokes still applied are what gets written to a save game.
+#[derive(Default)]
+pub struct EditHistory {
+    strokes: Vec<Vec<AppliedEdit>>,
+    undone: Vec<Vec<TerrainEdit>>,
+    in_stroke: bool,
+}
+
+impl EditHistory {
+    pub fn begin_stroke(&mut self) {
+        self.in_stroke = false;
+    }
+
+    pub fn apply(&mut self, terrain: &mut Terrain, edit: TerrainEdit) {
+        let applied = edit.apply(terrain);
+        if !self.in_stroke {
+            self.strokes.push(Vec::new());
+            self.in_stroke = true;
+        }
+        self.strokes.last_mut().unwrap().push(applied);
+        self.undone.clear();
+    }
+
+    pub fn undo(&mut self, terrain: &mut Terrain) -> bool {
+        self.in_stroke = false;
+        match self.strokes.pop() {
+            Some(stroke) => {
+                let edits = stroke.iter().map(|applied| applied.edit).collect();
+                for applied in stroke.into_iter().rev() {
+                    applied.revert(terrain);
+                }
+                self.undone.push(edits);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn redo(&mut self, terrain: &mut Terrain) -> bool {
+        self.in_stroke = false;
+        match self.undone.pop() {
+            Some(edits) => {
+                let stroke = edits.iter().map(|edit| edit.apply(terrain)).collect();
+                self.strokes.push(stroke);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn strokes(&self) -> Vec<Vec<TerrainEdit>> {
+        self.strokes
+            .iter()
+            .map(|stroke| stroke.iter().map(|applied| applied.edit).collect())
+            .collect()
+    }
+
+    // Resets the terrain and replays a recorded set of strokes on top of it.
+    pub fn replay(&mut self, terrain: &mut Terrain, strokes: Vec<Vec<TerrainEdit>>) {
+        terrain.regenerate();
+        self.strokes.clear();
+        self.undone.clear();
+        for stroke in strokes {
+            self.begin_stroke();
+            for edit in stroke {
+                self.apply(terrain, edit);
+            }
+        }
+        self.in_stroke = false;
+    }
+
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut out = String::new();
+        for stroke in self.strokes() {
+            out.push_str("stroke\n");
+            for edit in stroke {
+                writeln!(out, "{}", format_edit(&edit))?;
+            }
+        }
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, out).with_context(|| format!("writing {}", path.display()))
+    }
+
+    pub fn load(path: &Path) -> anyhow::Result<Vec<Vec<TerrainEdit>>> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let mut strokes: Vec<Vec<TerrainEdit>> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            if line == "stroke" {
+                strokes.push(Vec::new());
+                continue;
+            }
+            let edit = parse_edit(line)
+                .with_context(|| format!("{}:{}: bad edit", path.display(), number + 1))?;
+            match strokes.last_mut() {
+                Some(stroke) => stroke.push(edit),
+                None => strokes.push(vec![edit]),
+            }
+        }
+        Ok(strokes)
+    }
+}
+
+fn format_edit(edit: &TerrainEdit) -> String {
+    let head = format!("{} {} {} {}", edit.x, edit.z, edit.radius, edit.strength);
+    match edit.kind {
+        BrushKind::Raise => format!("raise {}", head),
+        BrushKind::Lower => format!("lower {}", head),
+        BrushKind::Smooth => format!("smooth {}", head),
+        BrushKind::Scorch => format!("scorch {}", head),
+        BrushKind::Flatten { height } => format!("flatten {} {}", head, height),
+        BrushKind::Paint { color } => {
+            format!("paint {} {} {} {}", head, color.x, color.y, color.z)
+        }
+    }
+}
+
+fn parse_edit(line: &str) -> anyhow::Result<TerrainEdit> {
+    let mut words = line.split_whitespace();
+    let name = words.next().ok_or_else(|| anyhow!("empty line"))?;
+    let numbers = words
+        .map(|w| w.parse::<f32>())
+        .collect::<Result<Vec<_>, _>>()?;
+    let expected = match name {
+        "raise" | "lower" | "smooth" | "scorch" => 4,
+        "flatten" => 5,
+        "paint" => 7,
+        _ => bail!("unknown brush '{}'", name),
+    };
+    if numbers.len() != expected {
+        bail!(
+            "'{}' takes {} numbers, got {}",
+            name,
+            expected,
+            numbers.len()
+        );
+    }
+    if let Some(number) = numbers.iter().find(|number| !number.is_finite()) {
+        bail!("'{}' is not a finite number", number);
+    }
+    if numbers[2] <= 0.0 {
+        bail!("radius must be positive, got {}", numbers[2]);
+    }
+    let kind = match name {
+        "raise" => BrushKind::Raise,
+        "lower" => BrushKind::Lower,
+        "smooth" => BrushKind::Smooth,
+        "scorch" => BrushKind::Scorch,
+        "flatten" => BrushKind::Flatten { height: numbers[4] },
+        _ => BrushKind::Paint {
+            color: Vector3::new(numbers[4], numbers[5], numbers[6]),
+        },
+    };
+    Ok(TerrainEdit {
+        kind,
+        x: numbers[0],
+        z: numbers[1],
+        radius: numbers[2],
+        strength: numbers[3],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::terrain::TerrainSettings;
+
+    fn terrain() -> Terrain {
+        Terrain::new(TerrainSettings {
+            chunks: 2,
+            ..Default::default()
+        })
+    }
+
+    fn edit(kind: BrushKind, x: f32) -> TerrainEdit {
+        TerrainEdit {
+            kind,
+            x,
+            z: 20.0,
+            radius: 6.0,
+            strength: 0.5,
+        }
+    }
+
+    fn strokes() -> Vec<Vec<TerrainEdit>> {
+        vec![
+            vec![
+                edit(BrushKind::Raise, 20.0),
+                edit(BrushKind::Raise, 22.0),
+                edit(BrushKind::Smooth, 21.0),
+            ],
+            vec![
+                edit(BrushKind::Flatten { height: 3.5 }, 30.0),
+                edit(
+                    BrushKind::Paint {
+                        color: Vector3::new(0.25, 0.5, 0.75),
+                    },
+                    30.0,
+                ),
+                edit(BrushKind::Scorch, 24.0),
+            ],
+        ]
+    }
+
+    fn apply_all(history: &mut EditHistory, terrain: &mut Terrain) {
+        for stroke in strokes() {
+            history.begin_stroke();
+            for edit in stroke {
+                history.apply(terrain, edit);
+            }
+        }
+    }
+
+    #[test]
+    fn undo_and_redo_round_trip() {
+        let mut terrain = terrain();
+        let (heights, tint) = (
+            terrain.heightfield.heights.clone(),
+            terrain.heightfield.tint.clone(),
+        );
+        let mut history = EditHistory::default();
+        apply_all(&mut history, &mut terrain);
+        let (edited_heights, edited_tint) = (
+            terrain.heightfield.heights.clone(),
+            terrain.heightfield.tint.clone(),
+        );
+        assert_ne!(edited_heights, heights);
+
+        assert!(history.undo(&mut terrain));
+        assert!(history.undo(&mut terrain));
+        assert!(!history.undo(&mut terrain));
+        assert_eq!(terrain.heightfield.heights, heights);
+        assert_eq!(terrain.heightfield.tint, tint);
+
+        assert!(history.redo(&mut terrain));
+        assert!(history.redo(&mut terrain));
+        assert!(!history.redo(&mut terrain));
+        assert_eq!(terrain.heightfield.heights, edited_heights);
+        assert_eq!(terrain.heightfield.tint, edited_tint);
+        assert_eq!(history.strokes(), strokes());
+    }
+
+    #[test]
+    fn a_new_edit_clears_redo() {
+        let mut terrain = terrain();
+        let mut history = EditHistory::default();
+        apply_all(&mut history, &mut terrain);
+        history.undo(&mut terrain);
+        history.begin_stroke();
+        history.apply(&mut terrain, edit(BrushKind::Lower, 25.0));
+        assert!(!history.redo(&mut terrain));
+    }
+
+    #[test]
+    fn save_load_and_replay() {
+        let mut terrain = terrain();
+        let mut history = EditHistory::default();
+        apply_all(&mut history, &mut terrain);
+
+        let path = std::env::temp_dir().join(format!("ecocide_edits_{}.txt", std::process::id()));
+        history.save(&path).unwrap();
+        let loaded = EditHistory::load(&path);
+        std::fs::remove_file(&path).unwrap();
+        let loaded = loaded.unwrap();
+        assert_eq!(loaded, strokes());
+
+        let mut replayed = self::terrain();
+        let mut replay_history = EditHistory::default();
+        replay_history.replay(&mut replayed, loaded);
+        assert_eq!(replayed.heightfield.heights, terrain.heightfield.heights);
+        assert_eq!(replayed.heightfield.tint, terrain.heightfield.tint);
+        assert_eq!(replay_history.strokes(), strokes());
+    }
+
+    #[test]
+    fn degenerate_edits_leave_the_terrain_alone() {
+        let mut terrain = terrain();
+        let heights = terrain.heightfield.heights.clone();
+        let cell = terrain.heightfield.cell_size;
+        let mut history = EditHistory::default();
+        history.begin_stroke();
+        for (radius, strength) in [(0.0, 0.5), (f32::NAN, 0.5), (4.0, f32::NAN)] {
+            // Centred on a sample, where a zero radius divides zero by zero.
+            let edit = TerrainEdit {
+                z: 10.0 * cell,
+                radius,
+                strength,
+                ..edit(BrushKind::Raise, 10.0 * cell)
+            };
+            history.apply(&mut terrain, edit);
+        }
+        assert_eq!(terrain.heightfield.heights, heights);
+    }
+
+    #[test]
+    fn bad_edits_are_rejected() {
+        assert!(parse_edit("raise 1 2 3").is_err());
+        assert!(parse_edit("melt 1 2 3 4").is_err());
+        assert!(parse_edit("flatten 1 2 3 x 5").is_err());
+        assert!(parse_edit("raise 1 2 0 4").is_err());
+        assert!(parse_edit("raise 1 2 -3 4").is_err());
+        assert!(parse_edit("raise 1 2 nan 4").is_err());
+        assert!(parse_edit("raise 1 2 3 NaN").is_err());
+        assert!(parse_edit("lower 1 2 3 inf").is_err());
+        assert!(parse_edit("paint 1 2 3 4 0.5 -inf 0.5").is_err());
+        assert_eq!(
+            parse_edit("flatten 1 2 3 4 5").unwrap().kind,
+            BrushKind::Flatten { height: 5.0 }
+        );
+    }
+}
@@ -0,0 +1,51 @@
+use cgmath::{Deg, InnerSpace, Matrix4, Point3, Rad, Vector3};
+
+pub struct Camera {
+    pub position: Vector3<f32>,
+    pub yaw: Rad<f32>,
+    pub pitch: Rad<f32>,
+    pub fov: Deg<f32>,
+    pub near: f32,
+    pub far: f32,
+}
+
+impl Camera {
+    pub fn new(position: Vector3<f32>) -> Self {
+        Camera {
+            position,
+            yaw: Rad(0f32),
+            pitch: Rad(-0.4f32),
+            fov: Deg(70f32),
+            near: 0.1f32,
+            far: 1000f32,
+        }
+    }
+
+    pub fn forward(&self) -> Vector3<f32> {
+        Vector3::new(
+            self.yaw.0.sin() * self.pitch.0.cos(),
+            self.pitch.0.sin(),
+            -self.yaw.0.cos() * self.pitch.0.cos(),
+        )
+        .normalize()
+    }
+
+    pub fn right(&self) -> Vector3<f32> {
+        self.forward().cross(Vector3::unit_y()).normalize()
+    }
+
+    pub fn view(&self) -> Matrix4<f32> {
+        let eye = Point3::new(self.position.x, self.position.y, self.position.z);
+        Matrix4::look_to_rh(eye, self.forward(), Vector3::unit_y())
+    }
+
+    pub fn projection(&self, aspect: f32) -> Matrix4<f32> {
+        let mut projection = cgmath::perspective(self.fov, aspect, self.near, self.far);
+        projection[1][1] *= -1f32;
+        projection
+    }
+
+    pub fn view_projection(&self, aspect: f32) -> Matrix4<f32> {
+        self.projection(aspect) * self.view()
+    }
+}
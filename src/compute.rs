@@ -0,0 +1,398 @@
+// Compute work: pipelines reading and writing storage buffers and images, descriptor
+// sets for them, dispatch and barrier helpers, and the queue the work runs on.
+
+use ash::{vk, Device};
+
+use crate::debug;
+use crate::error::EngineResult;
+use crate::pipeline::{build_compute_pipeline, shader_stage_create_info};
+
+// A stage and the accesses it makes, one side of a barrier.
+pub type Access = (vk::PipelineStageFlags, vk::AccessFlags);
+
+pub struct ComputePipeline {
+    pub set_layout: vk::DescriptorSetLayout,
+    pub layout: vk::PipelineLayout,
+    pub pipeline: vk::Pipeline,
+}
+
+impl ComputePipeline {
+    // `bindings` lists the descriptor type of each binding of set 0, in order.
+    pub fn new(
+        device: &Device,
+        name: &str,
+        shader: vk::ShaderModule,
+        bindings: &[vk::DescriptorType],
+        push_constant_size: u32,
+    ) -> EngineResult<Self> {
+        unsafe {
+            let bindings: Vec<vk::DescriptorSetLayoutBinding> = bindings
+                .iter()
+                .enumerate()
+                .map(|(binding, &ty)| {
+                    vk::DescriptorSetLayoutBinding::builder()
+                        .binding(binding as u32)
+                        .descriptor_type(ty)
+                        .descriptor_count(1)
+                        .stage_flags(vk::ShaderStageFlags::COMPUTE)
+                        .build()
+                })
+                .collect();
+            let set_layout = device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                None,
+            )?;
+
+            let push_constant_ranges = [vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::COMPUTE,
+                offset: 0,
+                size: push_constant_size,
+            }];
+            let set_layouts = [set_layout];
+            let mut layout_info = vk::PipelineLayoutCreateInfo::builder().set_layouts(&set_layouts);
+            if push_constant_size > 0 {
+                layout_info = layout_info.push_constant_ranges(&push_constant_ranges);
+            }
+            let layout = device.create_pipeline_layout(&layout_info, None)?;
+
+            let stage = shader_stage_create_info(vk::ShaderStageFlags::COMPUTE, shader).build();
+            let pipeline = build_compute_pipeline(device, stage, layout)?;
+            debug::set_name(device, set_layout, name);
+            debug::set_name(device, layout, name);
+            debug::set_name(device, pipeline, name);
+
+            Ok(ComputePipeline {
+                set_layout,
+                layout,
+                pipeline,
+            })
+        }
+    }
+
+    pub fn allocate_set(
+        &self,
+        device: &Device,
+        pool: vk::DescriptorPool,
+    ) -> EngineResult<vk::DescriptorSet> {
+        let set_layouts = [self.set_layout];
+        let sets = unsafe {
+            device.allocate_descriptor_sets(
+                &vk::DescriptorSetAllocateInfo::builder()
+                    .descriptor_pool(pool)
+                    .set_layouts(&set_layouts),
+            )
+        }?;
+        Ok(sets[0])
+    }
+
+    // Binds the pipeline and `set`, then dispatches `groups` workgroups.
+    pub unsafe fn dispatch(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        set: vk::DescriptorSet,
+        push_constants: &[u8],
+        groups: (u32, u32, u32),
+    ) {
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+        device.cmd_bind_descriptor_sets(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.layout,
+            0,
+            &[set],
+            &[],
+        );
+        if !push_constants.is_empty() {
+            device.cmd_push_constants(
+                command_buffer,
+                self.layout,
+                vk::ShaderStageFlags::COMPUTE,
+                0,
+                push_constants,
+            );
+        }
+        device.cmd_dispatch(command_buffer, groups.0, groups.1, groups.2);
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe {
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.layout, None);
+            device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+// Pool for compute descriptor sets. Sets can be freed back to it individually.
+pub fn create_descriptor_pool(device: &Device, max_sets: u32) -> EngineResult<vk::DescriptorPool> {
+    let pool_sizes = [
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_BUFFER,
+            descriptor_count: 8 * max_sets,
+        },
+        vk::DescriptorPoolSize {
+            ty: vk::DescriptorType::STORAGE_IMAGE,
+            descriptor_count: 4 * max_sets,
+        },
+    ];
+    let pool = unsafe {
+        device.create_descriptor_pool(
+            &vk::DescriptorPoolCreateInfo::builder()
+                .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+                .max_sets(max_sets)
+                .pool_sizes(&pool_sizes),
+            None,
+        )
+    }?;
+    Ok(pool)
+}
+
+pub fn write_storage_buffer(
+    device: &Device,
+    set: vk::DescriptorSet,
+    binding: u32,
+    buffer: vk::Buffer,
+) {
+    let buffer_info = [vk::DescriptorBufferInfo {
+        buffer,
+        offset: 0,
+        range: vk::WHOLE_SIZE,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(set)
+        .dst_binding(binding)
+        .descriptor_type(vk::DescriptorType::STORAGE_BUFFER)
+        .buffer_info(&buffer_info);
+    unsafe { device.update_descriptor_sets(&[write.build()], &[]) };
+}
+
+// Storage images are always accessed in the GENERAL layout.
+pub fn write_storage_image(
+    device: &Device,
+    set: vk::DescriptorSet,
+    binding: u32,
+    view: vk::ImageView,
+) {
+    let image_info = [vk::DescriptorImageInfo {
+        sampler: vk::Sampler::null(),
+        image_view: view,
+        image_layout: vk::ImageLayout::GENERAL,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(set)
+        .dst_binding(binding)
+        .descriptor_type(vk::DescriptorType::STORAGE_IMAGE)
+        .image_info(&image_info);
+    unsafe { device.update_descriptor_sets(&[write.build()], &[]) };
+}
+
+// Workgroups needed to cover `items` with groups of `group_size`.
+pub fn group_count(items: u32, group_size: u32) -> u32 {
+    items.div_ceil(group_size)
+}
+
+// Push constants are plain #[repr(C)] structs.
+pub fn as_bytes<T>(value: &T) -> &[u8] {
+    unsafe {
+        std::slice::from_raw_parts((value as *const T).cast::<u8>(), std::mem::size_of::<T>())
+    }
+}
+
+pub unsafe fn memory_barrier(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    src: Access,
+    dst: Access,
+) {
+    let barrier = vk::MemoryBarrier::builder()
+        .src_access_mask(src.1)
+        .dst_access_mask(dst.1);
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src.0,
+        dst.0,
+        vk::DependencyFlags::empty(),
+        &[barrier.build()],
+        &[],
+        &[],
+    );
+}
+
+pub unsafe fn buffer_barrier(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    buffer: vk::Buffer,
+    src: Access,
+    dst: Access,
+) {
+    let barrier = vk::BufferMemoryBarrier::builder()
+        .src_access_mask(src.1)
+        .dst_access_mask(dst.1)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .buffer(buffer)
+        .offset(0)
+        .size(vk::WHOLE_SIZE);
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src.0,
+        dst.0,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[barrier.build()],
+        &[],
+    );
+}
+
+// Moves a single mip, single layer colour image between layouts.
+pub unsafe fn image_barrier(
+    device: &Device,
+    command_buffer: vk::CommandBuffer,
+    image: vk::Image,
+    layouts: (vk::ImageLayout, vk::ImageLayout),
+    src: Access,
+    dst: Access,
+) {
+    let barrier = vk::ImageMemoryBarrier::builder()
+        .src_access_mask(src.1)
+        .dst_access_mask(dst.1)
+        .old_layout(layouts.0)
+        .new_layout(layouts.1)
+        .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+        .image(image)
+        .subresource_range(vk::ImageSubresourceRange {
+            aspect_mask: vk::ImageAspectFlags::COLOR,
+            base_mip_level: 0,
+            level_count: 1,
+            base_array_layer: 0,
+            layer_count: 1,
+        });
+    device.cmd_pipeline_barrier(
+        command_buffer,
+        src.0,
+        dst.0,
+        vk::DependencyFlags::empty(),
+        &[],
+        &[],
+        &[barrier.build()],
+    );
+}
+
+// Where standalone compute work (work that isn't part of a frame) is submitted. On
+// devices with a compute-only queue family that queue is used; otherwise it shares
+// the graphics queue. Nothing on the graphics side waits on this work, so users
+// that need the results block on `wait` and the work doesn't overlap the frame.
+//
+// Resources are created with exclusive sharing, so anything written here and then
+// used by graphics on a separate family needs a queue family ownership transfer.
+pub struct ComputeQueue {
+    pub family: u32,
+    pub queue: vk::Queue,
+    // True when `queue` is separate from the graphics queue.
+    pub dedicated: bool,
+    command_pool: vk::CommandPool,
+    command_buffer: vk::CommandBuffer,
+    fence: vk::Fence,
+    pending: bool,
+}
+
+impl ComputeQueue {
+    pub fn new(device: &Device, family: u32, dedicated: bool) -> EngineResult<Self> {
+        unsafe {
+            let queue = device.get_device_queue(family, 0);
+            let command_pool = device.create_command_pool(
+                &vk::CommandPoolCreateInfo::builder()
+                    .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
+                    .queue_family_index(family),
+                None,
+            )?;
+            let command_buffer = device.allocate_command_buffers(
+                &vk::CommandBufferAllocateInfo::builder()
+                    .command_buffer_count(1)
+                    .command_pool(command_pool)
+                    .level(vk::CommandBufferLevel::PRIMARY),
+            )?[0];
+            let fence = device.create_fence(&vk::FenceCreateInfo::default(), None)?;
+            debug::set_name(device, command_buffer, "Compute");
+            debug::set_name(device, fence, "Compute");
+            Ok(ComputeQueue {
+                family,
+                queue,
+                dedicated,
+                command_pool,
+                command_buffer,
+                fence,
+                pending: false,
+            })
+        }
+    }
+
+    // Records commands with `record` and submits them without waiting. Waits for
+    // the previous submission first, since the command buffer is reused.
+    pub fn submit<F: FnOnce(vk::CommandBuffer)>(
+        &mut self,
+        device: &Device,
+        record: F,
+    ) -> EngineResult<()> {
+        self.wait(device)?;
+        unsafe {
+            device
+                .reset_command_buffer(self.command_buffer, vk::CommandBufferResetFlags::empty())?;
+            device.begin_command_buffer(
+                self.command_buffer,
+                &vk::CommandBufferBeginInfo::builder()
+                    .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT),
+            )?;
+            record(self.command_buffer);
+            device.end_command_buffer(self.command_buffer)?;
+
+            let command_buffers = [self.command_buffer];
+            let submit_info = vk::SubmitInfo::builder().command_buffers(&command_buffers);
+            device.queue_submit(self.queue, &[submit_info.build()], self.fence)?;
+        }
+        self.pending = true;
+        Ok(())
+    }
+
+    // Blocks until the last submission has finished, if there is one.
+    pub fn wait(&mut self, device: &Device) -> EngineResult<()> {
+        if self.pending {
+            unsafe {
+                device.wait_for_fences(&[self.fence], true, u64::MAX)?;
+                device.reset_fences(&[self.fence])?;
+            }
+            self.pending = false;
+        }
+        Ok(())
+    }
+
+    // Waiting fails once the device is lost, and then there is nothing left to wait on.
+    pub fn destroy(&mut self, device: &Device) {
+        let _ = self.wait(device);
+        unsafe {
+            device.destroy_fence(self.fence, None);
+            device.destroy_command_pool(self.command_pool, None);
+        }
+    }
+}
+
+// A queue family with compute but no graphics, if the device has one.
+pub unsafe fn find_dedicated_compute_family(
+    instance: &ash::Instance,
+    pdevice: vk::PhysicalDevice,
+) -> Option<u32> {
+    instance
+        .get_physical_device_queue_family_properties(pdevice)
+        .iter()
+        .position(|family| {
+            family.queue_flags.contains(vk::QueueFlags::COMPUTE)
+                && !family.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+        })
+        .map(|index| index as u32)
+}
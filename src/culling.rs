@@ -0,0 +1,307 @@
+use std::fmt;
+
+use cgmath::{InnerSpace, Matrix, Matrix4, Vector3, Vector4};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Aabb {
+    pub min: Vector3<f32>,
+    pub max: Vector3<f32>,
+}
+
+impl Aabb {
+    // An empty set of points gives a degenerate box at the origin.
+    pub fn from_points<I: IntoIterator<Item = Vector3<f32>>>(points: I) -> Self {
+        let mut points = points.into_iter();
+        let first = match points.next() {
+            Some(point) => point,
+            None => {
+                let zero = Vector3::new(0.0, 0.0, 0.0);
+                return Aabb {
+                    min: zero,
+                    max: zero,
+                };
+            }
+        };
+        points.fold(
+            Aabb {
+                min: first,
+                max: first,
+            },
+            |aabb, p| Aabb {
+                min: Vector3::new(
+                    aabb.min.x.min(p.x),
+                    aabb.min.y.min(p.y),
+                    aabb.min.z.min(p.z),
+                ),
+                max: Vector3::new(
+                    aabb.max.x.max(p.x),
+                    aabb.max.y.max(p.y),
+                    aabb.max.z.max(p.z),
+                ),
+            },
+        )
+    }
+
+    pub fn center(&self) -> Vector3<f32> {
+        (self.min + self.max) * 0.5
+    }
+
+    pub fn half_extents(&self) -> Vector3<f32> {
+        (self.max - self.min) * 0.5
+    }
+
+    // The box enclosing this one once transformed. Looser than the transformed
+    // box itself under rotation, but never smaller.
+    pub fn transform(&self, m: &Matrix4<f32>) -> Aabb {
+        let center = (m * self.center().extend(1.0)).truncate();
+        let e = self.half_extents();
+        let extent =
+            |row: usize| m.x[row].abs() * e.x + m.y[row].abs() * e.y + m.z[row].abs() * e.z;
+        let half = Vector3::new(extent(0), extent(1), extent(2));
+        Aabb {
+            min: center - half,
+            max: center + half,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BoundingSphere {
+    pub center: Vector3<f32>,
+    pub radius: f32,
+}
+
+impl BoundingSphere {
+    // Centred on the points' bounding box, which is close enough to the minimal
+    // sphere for culling.
+    pub fn from_points(points: &[Vector3<f32>]) -> Self {
+        let center = Aabb::from_points(points.iter().copied()).center();
+        let radius = points
+            .iter()
+            .map(|p| (p - center).magnitude())
+            .fold(0.0, f32::max);
+        BoundingSphere { center, radius }
+    }
+
+    // Scales the radius by the transform's largest axis scale.
+    pub fn transform(&self, m: &Matrix4<f32>) -> BoundingSphere {
+        let scale =
+            m.x.truncate()
+                .magnitude()
+                .max(m.y.truncate().magnitude())
+                .max(m.z.truncate().magnitude());
+        BoundingSphere {
+            center: (m * self.center.extend(1.0)).truncate(),
+            radius: self.radius * scale,
+        }
+    }
+}
+
+// The six planes bounding what a view-projection matrix keeps after clipping. Each
+// plane is (normal, distance) with the normal facing inwards.
+#[derive(Clone, Copy, Debug)]
+pub struct Frustum {
+    pub planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    // Extracts the planes straight from the matrix rows, using Vulkan's clip space
+    // where depth runs from 0 to w.
+    pub fn from_matrix(view_projection: &Matrix4<f32>) -> Self {
+        let row = |i: usize| view_projection.row(i);
+        let normalize = |plane: Vector4<f32>| plane / plane.truncate().magnitude();
+        Frustum {
+            planes: [
+                normalize(row(3) + row(0)),
+                normalize(row(3) - row(0)),
+                normalize(row(3) + row(1)),
+                normalize(row(3) - row(1)),
+                normalize(row(2)),
+                normalize(row(3) - row(2)),
+            ],
+        }
+    }
+
+    pub fn contains_sphere(&self, sphere: &BoundingSphere) -> bool {
+        self.planes
+            .iter()
+            .all(|plane| plane.truncate().dot(sphere.center) + plane.w >= -sphere.radius)
+    }
+
+    // Only rejects boxes entirely behind one plane, so a few boxes near the corners
+    // are kept even though they are outside.
+    pub fn contains_aabb(&self, aabb: &Aabb) -> bool {
+        self.planes.iter().all(|plane| {
+            // The corner furthest along the plane's normal.
+            let pick = |normal: f32, min: f32, max: f32| if normal >= 0.0 { max } else { min };
+            let corner = Vector3::new(
+                pick(plane.x, aabb.min.x, aabb.max.x),
+                pick(plane.y, aabb.min.y, aabb.max.y),
+                pick(plane.z, aabb.min.z, aabb.max.z),
+            );
+            plane.truncate().dot(corner) + plane.w >= 0.0
+        })
+    }
+}
+
+// Objects drawn and skipped during one frame. Every instance counts as an object.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CullStats {
+    pub drawn: u32,
+    pub culled: u32,
+}
+
+impl CullStats {
+    pub fn record(&mut self, visible: bool) {
+        if visible {
+            self.drawn += 1;
+        } else {
+            self.culled += 1;
+        }
+    }
+}
+
+impl fmt::Display for CullStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "drawn {}, culled {}", self.drawn, self.culled)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Point3, Rad};
+
+    // A 90 degree square frustum from (0, 0, 10) looking at the origin, so at a
+    // depth d in front of the camera the sides are at x = ±d and y = ±d.
+    fn frustum() -> Frustum {
+        let projection = cgmath::perspective(Deg(90.0), 1.0, 1.0, 100.0);
+        let view = Matrix4::look_at_rh(
+            Point3::new(0.0, 0.0, 10.0),
+            Point3::new(0.0, 0.0, 0.0),
+            Vector3::unit_y(),
+        );
+        Frustum::from_matrix(&(projection * view))
+    }
+
+    fn sphere(x: f32, y: f32, z: f32, radius: f32) -> BoundingSphere {
+        BoundingSphere {
+            center: Vector3::new(x, y, z),
+            radius,
+        }
+    }
+
+    fn cube(x: f32, y: f32, z: f32, half: f32) -> Aabb {
+        let center = Vector3::new(x, y, z);
+        let half = Vector3::new(half, half, half);
+        Aabb {
+            min: center - half,
+            max: center + half,
+        }
+    }
+
+    // One sphere beyond each plane in turn: left, right, bottom, top, near, far.
+    const OUTSIDE: [(f32, f32, f32); 6] = [
+        (-20.0, 0.0, 0.0),
+        (20.0, 0.0, 0.0),
+        (0.0, -20.0, 0.0),
+        (0.0, 20.0, 0.0),
+        (0.0, 0.0, 9.5),
+        (0.0, 0.0, -150.0),
+    ];
+
+    #[test]
+    fn inside_is_kept() {
+        let frustum = frustum();
+        assert!(frustum.contains_sphere(&sphere(0.0, 0.0, 0.0, 1.0)));
+        assert!(frustum.contains_sphere(&sphere(5.0, -5.0, -20.0, 0.5)));
+        assert!(frustum.contains_aabb(&cube(0.0, 0.0, 0.0, 1.0)));
+        assert!(frustum.contains_aabb(&cube(-5.0, 5.0, -20.0, 0.5)));
+    }
+
+    #[test]
+    fn outside_each_plane_is_culled() {
+        let frustum = frustum();
+        for (x, y, z) in OUTSIDE {
+            let radius = if z == 9.5 { 0.1 } else { 1.0 };
+            assert!(
+                !frustum.contains_sphere(&sphere(x, y, z, radius)),
+                "{} {} {}",
+                x,
+                y,
+                z
+            );
+            assert!(
+                !frustum.contains_aabb(&cube(x, y, z, radius)),
+                "{} {} {}",
+                x,
+                y,
+                z
+            );
+        }
+    }
+
+    #[test]
+    fn straddling_a_plane_is_kept() {
+        let frustum = frustum();
+        // Centres just outside the left and far planes, but reaching back in.
+        for (x, z) in [(-10.5, 0.0), (0.0, -90.5)] {
+            assert!(frustum.contains_sphere(&sphere(x, 0.0, z, 1.0)));
+            assert!(frustum.contains_aabb(&cube(x, 0.0, z, 1.0)));
+            assert!(!frustum.contains_sphere(&sphere(x - 2.0, 0.0, z - 2.0, 0.1)));
+        }
+    }
+
+    #[test]
+    fn behind_the_camera_is_culled() {
+        let frustum = frustum();
+        assert!(!frustum.contains_sphere(&sphere(0.0, 0.0, 20.0, 1.0)));
+        assert!(!frustum.contains_aabb(&cube(0.0, 0.0, 20.0, 1.0)));
+        // Wider than the frustum there, which a projected test could get wrong.
+        assert!(!frustum.contains_aabb(&cube(0.0, 0.0, 30.0, 8.0)));
+    }
+
+    #[test]
+    fn transformed_bounds_enclose_the_transformed_corners() {
+        let aabb = Aabb {
+            min: Vector3::new(-1.0, -2.0, -0.5),
+            max: Vector3::new(1.0, 2.0, 0.5),
+        };
+        let m = Matrix4::from_translation(Vector3::new(3.0, -4.0, 5.0))
+            * Matrix4::from_axis_angle(Vector3::new(1.0, 2.0, 3.0).normalize(), Rad(0.7))
+            * Matrix4::from_nonuniform_scale(2.0, 0.5, 3.0);
+        let corners: Vec<Vector3<f32>> = (0..8)
+            .map(|i| {
+                let pick = |bit: u32, min: f32, max: f32| if i & bit == 0 { min } else { max };
+                let corner = Vector3::new(
+                    pick(1, aabb.min.x, aabb.max.x),
+                    pick(2, aabb.min.y, aabb.max.y),
+                    pick(4, aabb.min.z, aabb.max.z),
+                );
+                (m * corner.extend(1.0)).truncate()
+            })
+            .collect();
+
+        let bounds = aabb.transform(&m);
+        let tight = Aabb::from_points(corners.iter().copied());
+        for axis in 0..3 {
+            assert!(bounds.min[axis] <= tight.min[axis] + 1e-5);
+            assert!(bounds.max[axis] >= tight.max[axis] - 1e-5);
+        }
+
+        let sphere = BoundingSphere::from_points(&[aabb.min, aabb.max]).transform(&m);
+        for corner in corners {
+            assert!((corner - sphere.center).magnitude() <= sphere.radius + 1e-5);
+        }
+    }
+
+    #[test]
+    fn transform_is_exact_without_rotation() {
+        let aabb = cube(1.0, 2.0, 3.0, 1.0);
+        let m = Matrix4::from_translation(Vector3::new(-1.0, 0.0, 4.0))
+            * Matrix4::from_nonuniform_scale(2.0, 0.5, 3.0);
+        let bounds = aabb.transform(&m);
+        assert_eq!(bounds.min, Vector3::new(-1.0, 0.5, 10.0));
+        assert_eq!(bounds.max, Vector3::new(3.0, 1.5, 16.0));
+    }
+}
@@ -0,0 +1,221 @@
+// Validation layers, the debug messenger, and object names and labels for tools
+// like RenderDoc. Validation is optional: the layer only ships with the Vulkan SDK,
+// so by default it is used when installed and skipped otherwise. Messages go to the
+// `log` facade under the "vulkan" target.
+
+use std::borrow::Cow;
+use std::ffi::{CStr, CString};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+
+use ash::{extensions::ext::DebugUtils, vk, Device, Entry};
+
+pub const VALIDATION_ENV: &str = "ECOCIDE_VALIDATION";
+
+pub const VALIDATION_LAYER: &CStr = c"VK_LAYER_KHRONOS_validation";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ValidationMode {
+    Off,
+    IfAvailable,
+    // Fails to start without the layer.
+    Required,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ValidationSettings {
+    pub mode: ValidationMode,
+    // Messages below this are never delivered.
+    pub min_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    // Turns validation errors into panics, for tests and CI runs.
+    pub panic_on_error: bool,
+}
+
+impl Default for ValidationSettings {
+    fn default() -> Self {
+        ValidationSettings {
+            mode: if cfg!(debug_assertions) {
+                ValidationMode::IfAvailable
+            } else {
+                ValidationMode::Off
+            },
+            min_severity: vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+            panic_on_error: false,
+        }
+    }
+}
+
+impl ValidationSettings {
+    // `ECOCIDE_VALIDATION` is one of off, on, required or panic, where panic also
+    // requires the layer. Anything else keeps the defaults.
+    pub fn from_env() -> Self {
+        let defaults = ValidationSettings::default();
+        let mode = |mode| ValidationSettings { mode, ..defaults };
+        match std::env::var(VALIDATION_ENV).as_deref() {
+            Ok("off" | "0") => mode(ValidationMode::Off),
+            Ok("on" | "1") => mode(ValidationMode::IfAvailable),
+            Ok("required") => mode(ValidationMode::Required),
+            Ok("panic") => ValidationSettings {
+                panic_on_error: true,
+                ..mode(ValidationMode::Required)
+            },
+            _ => defaults,
+        }
+    }
+}
+
+pub fn layer_available(entry: &Entry, name: &CStr) -> bool {
+    entry
+        .enumerate_instance_layer_properties()
+        .unwrap_or_default()
+        .iter()
+        .any(|layer| unsafe { CStr::from_ptr(layer.layer_name.as_ptr()) } == name)
+}
+
+pub fn instance_extension_available(entry: &Entry, name: &CStr) -> bool {
+    entry
+        .enumerate_instance_extension_properties(None)
+        .unwrap_or_default()
+        .iter()
+        .any(|extension| unsafe { CStr::from_ptr(extension.extension_name.as_ptr()) } == name)
+}
+
+// Read by the callback, which has no other way to reach the settings.
+static PANIC_ON_ERROR: AtomicBool = AtomicBool::new(false);
+// The first validation error seen while PANIC_ON_ERROR is set. Unwinding can't
+// cross into the driver, so the callback only records it and
+// `check_validation` panics once the Vulkan call has returned.
+static VALIDATION_ERROR: Mutex<Option<String>> = Mutex::new(None);
+
+pub unsafe fn create_messenger(
+    debug_utils: &DebugUtils,
+    settings: &ValidationSettings,
+) -> ash::prelude::VkResult<vk::DebugUtilsMessengerEXT> {
+    PANIC_ON_ERROR.store(settings.panic_on_error, Ordering::Relaxed);
+    let severities = [
+        vk::DebugUtilsMessageSeverityFlagsEXT::VERBOSE,
+        vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING,
+        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR,
+    ];
+    let message_severity = severities
+        .into_iter()
+        .filter(|&severity| severity.as_raw() >= settings.min_severity.as_raw())
+        .fold(
+            vk::DebugUtilsMessageSeverityFlagsEXT::empty(),
+            |all, severity| all | severity,
+        );
+    let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
+        .message_severity(message_severity)
+        .message_type(
+            vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
+                | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
+                | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
+        )
+        .pfn_user_callback(Some(vulkan_debug_callback));
+    debug_utils.create_debug_utils_messenger(&debug_info, None)
+}
+
+unsafe extern "system" fn vulkan_debug_callback(
+    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
+    _user_data: *mut std::os::raw::c_void,
+) -> vk::Bool32 {
+    let callback_data = *p_callback_data;
+    let message_id_number = callback_data.message_id_number;
+
+    let message_id_name = if callback_data.p_message_id_name.is_null() {
+        Cow::from("")
+    } else {
+        CStr::from_ptr(callback_data.p_message_id_name).to_string_lossy()
+    };
+
+    let message = if callback_data.p_message.is_null() {
+        Cow::from("")
+    } else {
+        CStr::from_ptr(callback_data.p_message).to_string_lossy()
+    };
+
+    let level = match message_severity {
+        vk::DebugUtilsMessageSeverityFlagsEXT::ERROR => log::Level::Error,
+        vk::DebugUtilsMessageSeverityFlagsEXT::WARNING => log::Level::Warn,
+        vk::DebugUtilsMessageSeverityFlagsEXT::INFO => log::Level::Info,
+        _ => log::Level::Trace,
+    };
+    log::log!(
+        target: "vulkan",
+        level,
+        "{:?} [{} ({})] : {}",
+        message_type,
+        message_id_name,
+        message_id_number,
+        message,
+    );
+
+    if level == log::Level::Error
+        && message_type.contains(vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION)
+        && PANIC_ON_ERROR.load(Ordering::Relaxed)
+    {
+        if let Ok(mut error) = VALIDATION_ERROR.lock() {
+            error.get_or_insert_with(|| message.into_owned());
+        }
+    }
+
+    vk::FALSE
+}
+
+// Panics with the first validation error reported since the last check, when
+// validation errors are set to panic.
+pub fn check_validation() {
+    let error = VALIDATION_ERROR
+        .lock()
+        .ok()
+        .and_then(|mut error| error.take());
+    if let Some(message) = error {
+        panic!("Vulkan validation error: {}", message);
+    }
+}
+
+// The engine's loader, once it has one. Objects get created all over the place, so
+// naming goes through this instead of passing the loader down to every one of them.
+// Without VK_EXT_debug_utils names and labels are skipped.
+static NAMES: OnceLock<DebugUtils> = OnceLock::new();
+
+pub fn enable_names(debug_utils: &DebugUtils) {
+    let _ = NAMES.set(debug_utils.clone());
+}
+
+pub fn set_name<H: vk::Handle>(device: &Device, handle: H, name: &str) {
+    let (Some(debug_utils), Ok(name)) = (NAMES.get(), CString::new(name)) else {
+        return;
+    };
+    let name_info = vk::DebugUtilsObjectNameInfoEXT::builder()
+        .object_type(H::TYPE)
+        .object_handle(handle.as_raw())
+        .object_name(&name);
+    // A missing name is not worth failing over.
+    let _ = unsafe { debug_utils.debug_utils_set_object_name(device.handle(), &name_info) };
+}
+
+// Label colours, so RenderDoc's event browser is easier to scan.
+pub const COMPUTE_LABEL: [f32; 4] = [0.9, 0.6, 0.2, 1.0];
+pub const PASS_LABEL: [f32; 4] = [0.3, 0.6, 0.9, 1.0];
+pub const DRAW_LABEL: [f32; 4] = [0.5, 0.8, 0.5, 1.0];
+
+// Opens a labelled region of `command_buffer`, closed by `end_label`.
+pub unsafe fn begin_label(command_buffer: vk::CommandBuffer, name: &str, color: [f32; 4]) {
+    let (Some(debug_utils), Ok(name)) = (NAMES.get(), CString::new(name)) else {
+        return;
+    };
+    let label = vk::DebugUtilsLabelEXT::builder()
+        .label_name(&name)
+        .color(color);
+    debug_utils.cmd_begin_debug_utils_label(command_buffer, &label);
+}
+
+pub unsafe fn end_label(command_buffer: vk::CommandBuffer) {
+    if let Some(debug_utils) = NAMES.get() {
+        debug_utils.cmd_end_debug_utils_label(command_buffer);
+    }
+}
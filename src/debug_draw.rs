@@ -0,0 +1,309 @@
+// Immediate mode lines for seeing what systems are doing: bounds, paths, vectors,
+// labels. Anything can call these at any point in a frame; the engine collects the
+// lines when it records the frame and draws them over the scene, then they are
+// gone until drawn again. While turned off the calls cost next to nothing.
+
+use std::f32::consts::TAU;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, MutexGuard};
+
+use ash::{vk, Device};
+use cgmath::{InnerSpace, Vector3};
+use gpu_allocator::vulkan::*;
+
+use crate::culling::Aabb;
+use crate::error::EngineResult;
+use crate::mesh::{create_buffer, Vertex};
+
+// Segments in each of a sphere's three circles.
+const CIRCLE_SEGMENTS: usize = 24;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Default)]
+struct Batch {
+    // Pairs of vertices, one line each.
+    lines: Vec<Vertex>,
+    // Turned into lines facing the camera once it's known.
+    labels: Vec<Label>,
+}
+
+struct Label {
+    position: Vector3<f32>,
+    text: String,
+    height: f32,
+    color: Vector3<f32>,
+}
+
+fn batch() -> MutexGuard<'static, Batch> {
+    static BATCH: Mutex<Batch> = Mutex::new(Batch {
+        lines: Vec::new(),
+        labels: Vec::new(),
+    });
+    BATCH
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        let mut batch = batch();
+        batch.lines.clear();
+        batch.labels.clear();
+    }
+}
+
+pub fn line(from: Vector3<f32>, to: Vector3<f32>, color: Vector3<f32>) {
+    if enabled() {
+        push_line(&mut batch(), from, to, color);
+    }
+}
+
+// The edges of an axis aligned box.
+pub fn aabb(bounds: &Aabb, color: Vector3<f32>) {
+    if !enabled() {
+        return;
+    }
+    let (min, max) = (bounds.min, bounds.max);
+    let corner = |i: usize| {
+        Vector3::new(
+            if i & 1 == 0 { min.x } else { max.x },
+            if i & 2 == 0 { min.y } else { max.y },
+            if i & 4 == 0 { min.z } else { max.z },
+        )
+    };
+    let mut batch = batch();
+    // Corners whose index differs in a single bit share an edge.
+    for i in 0..8 {
+        for bit in [1, 2, 4] {
+            if i & bit == 0 {
+                push_line(&mut batch, corner(i), corner(i | bit), color);
+            }
+        }
+    }
+}
+
+// A circle around each axis.
+pub fn sphere(center: Vector3<f32>, radius: f32, color: Vector3<f32>) {
+    if !enabled() {
+        return;
+    }
+    let mut batch = batch();
+    for axis in 0..3 {
+        let point = |segment: usize| {
+            let angle = segment as f32 / CIRCLE_SEGMENTS as f32 * TAU;
+            let (sin, cos) = (angle.sin() * radius, angle.cos() * radius);
+            center
+                + match axis {
+                    0 => Vector3::new(0.0, cos, sin),
+                    1 => Vector3::new(cos, 0.0, sin),
+                    _ => Vector3::new(cos, sin, 0.0),
+                }
+        };
+        for segment in 0..CIRCLE_SEGMENTS {
+            push_line(&mut batch, point(segment), point(segment + 1), color);
+        }
+    }
+}
+
+// A line with a four pronged head at `to`, a fifth of the length long.
+pub fn arrow(from: Vector3<f32>, to: Vector3<f32>, color: Vector3<f32>) {
+    if !enabled() {
+        return;
+    }
+    let length = (to - from).magnitude();
+    if length <= f32::EPSILON {
+        return;
+    }
+    let direction = (to - from) / length;
+    let reference = if direction.y.abs() < 0.9 {
+        Vector3::unit_y()
+    } else {
+        Vector3::unit_x()
+    };
+    let side = direction.cross(reference).normalize();
+    let up = side.cross(direction);
+    let head = length * 0.2;
+    let back = to - direction * head;
+
+    let mut batch = batch();
+    push_line(&mut batch, from, to, color);
+    for prong in [side, -side, up, -up] {
+        push_line(&mut batch, to, back + prong * head * 0.5, color);
+    }
+}
+
+// Text centred above `position`, always facing the camera. `height` is in world
+// units. Letters are drawn in upper case.
+pub fn text3d(position: Vector3<f32>, text: &str, height: f32, color: Vector3<f32>) {
+    if enabled() {
+        batch().labels.push(Label {
+            position,
+            text: text.to_string(),
+            height,
+            color,
+        });
+    }
+}
+
+fn push_line(batch: &mut Batch, from: Vector3<f32>, to: Vector3<f32>, color: Vector3<f32>) {
+    batch.lines.push(Vertex {
+        position: from,
+        color,
+    });
+    batch.lines.push(Vertex {
+        position: to,
+        color,
+    });
+}
+
+// Everything drawn since the last call, as a line list, with labels laid out in
+// the plane spanned by the camera's `right` and `up`.
+pub fn take_lines(right: Vector3<f32>, up: Vector3<f32>) -> Vec<Vertex> {
+    let mut batch = batch();
+    let labels = std::mem::take(&mut batch.labels);
+    for label in labels {
+        // Glyphs are on a grid 4 wide and 6 high, with 2 between letters.
+        let unit = label.height / 6.0;
+        let width = label.text.chars().count() as f32 * 6.0 - 2.0;
+        let origin = label.position - right * (width * unit * 0.5);
+        for (i, c) in label.text.chars().enumerate() {
+            let offset = i as f32 * 6.0;
+            for stroke in glyph(c).split('/').filter(|stroke| !stroke.is_empty()) {
+                let points: Vec<Vector3<f32>> = stroke
+                    .as_bytes()
+                    .chunks(2)
+                    .map(|point| {
+                        let x = (point[0] - b'0') as f32 + offset;
+                        let y = (point[1] - b'0') as f32;
+                        origin + (right * x + up * y) * unit
+                    })
+                    .collect();
+                for pair in points.windows(2) {
+                    push_line(&mut batch, pair[0], pair[1], label.color);
+                }
+            }
+        }
+    }
+    std::mem::take(&mut batch.lines)
+}
+
+// Strokes for a character. Each stroke is a polyline of digit pairs, x then y on
+// a 4 by 6 grid with y up, and strokes are separated by slashes.
+fn glyph(c: char) -> &'static str {
+    match c.to_ascii_uppercase() {
+        'A' => "0004264440/0343",
+        'B' => "00063645443303/3342413000",
+        'C' => "46060040",
+        'D' => "00062644422000",
+        'E' => "46060040/0333",
+        'F' => "460600/0333",
+        'G' => "45460600404323",
+        'H' => "0006/4640/0343",
+        'I' => "0646/2620/0040",
+        'J' => "4641301001",
+        'K' => "0006/4602/1340",
+        'L' => "060040",
+        'M' => "0006234640",
+        'N' => "00064046",
+        'O' | '0' => "0006464000",
+        'P' => "0006464303",
+        'Q' => "0006464000/2240",
+        'R' => "0006464303/1340",
+        'S' | '5' => "460603434000",
+        'T' => "0646/2620",
+        'U' => "06004046",
+        'V' => "062046",
+        'W' => "0610233046",
+        'X' => "0046/0640",
+        'Y' => "062346/2320",
+        'Z' => "06464000",
+        '1' => "152620/1030",
+        '2' => "064643030040",
+        '3' => "06464000/0343",
+        '4' => "060343/4640",
+        '6' => "460600404303",
+        '7' => "064610",
+        '8' => "0006464000/0343",
+        '9' => "430306464000",
+        '-' => "0343",
+        '+' => "0343/2125",
+        '=' => "0242/0444",
+        '_' => "0040",
+        '.' => "2021",
+        ',' => "2110",
+        ':' => "2122/2425",
+        '/' => "0046",
+        '%' => "0046/0515/3141",
+        '(' => "36141230",
+        ')' => "16343210",
+        '<' => "460340",
+        '>' => "064300",
+        '!' => "2622/2021",
+        '\'' => "2624",
+        ' ' => "",
+        _ => "050646432322/2021",
+    }
+}
+
+// Host visible vertex buffer the lines are copied into each frame, grown as needed.
+pub struct LineBuffer {
+    pub buffer: vk::Buffer,
+    allocation: Option<Allocation>,
+    capacity: usize,
+    pub vertex_count: u32,
+}
+
+impl LineBuffer {
+    pub fn new(device: &Device, allocator: &mut Allocator, capacity: usize) -> EngineResult<Self> {
+        let capacity = capacity.max(2);
+        let (buffer, allocation) = create_buffer(
+            device,
+            allocator,
+            "Debug lines",
+            std::mem::size_of::<Vertex>() * capacity,
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+        )?;
+        Ok(LineBuffer {
+            buffer,
+            allocation: Some(allocation),
+            capacity,
+            vertex_count: 0,
+        })
+    }
+
+    // The GPU must be done with the previous contents.
+    pub fn write(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        vertices: &[Vertex],
+    ) -> EngineResult<()> {
+        if vertices.len() > self.capacity {
+            let grown = LineBuffer::new(device, allocator, vertices.len().next_power_of_two())?;
+            self.destroy(device, allocator);
+            *self = grown;
+        }
+        if let Some(allocation) = self.allocation.as_ref() {
+            unsafe {
+                let ptr = allocation.mapped_ptr().unwrap().cast::<Vertex>().as_ptr();
+                std::ptr::copy_nonoverlapping(vertices.as_ptr(), ptr, vertices.len());
+            }
+        }
+        self.vertex_count = vertices.len() as u32;
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe { device.destroy_buffer(self.buffer, None) };
+        if let Some(allocation) = self.allocation.take() {
+            allocator.free(allocation).unwrap();
+        }
+        self.vertex_count = 0;
+    }
+}
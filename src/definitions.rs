@@ -0,0 +1,600 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use serde::Deserialize;
+
+use crate::ecosystem::{Prey, Sensitivity, Species, Trophic};
+
+pub const SPECIES_DIR: &str = "assets/species";
+pub const BIOMES_DIR: &str = "assets/biomes";
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpeciesDef {
+    pub name: String,
+    pub trophic: Trophic,
+    #[serde(default)]
+    pub birth_rate: f64,
+    pub death_rate: f64,
+    pub carrying_capacity: f64,
+    #[serde(default)]
+    pub conversion: f64,
+    #[serde(default)]
+    pub migration: f64,
+    #[serde(default)]
+    pub diet: Vec<PreyDef>,
+    #[serde(default)]
+    pub sensitivity: Sensitivity,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PreyDef {
+    pub prey: String,
+    pub attack_rate: f64,
+    pub handling_time: f64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BiomeDef {
+    pub name: String,
+    // Range of normalized terrain height (0 at the lowest point, 1 at the terrain's
+    // height scale) this biome covers.
+    pub min_height: f32,
+    pub max_height: f32,
+    // Multiplies the carrying capacity of the listed species. Species not listed
+    // keep their own capacity.
+    #[serde(default)]
+    pub capacity: BTreeMap<String, f64>,
+}
+
+#[derive(Debug)]
+pub struct DefinitionError {
+    pub file: PathBuf,
+    pub field: String,
+    pub reason: String,
+}
+
+impl fmt::Display for DefinitionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {}: {}",
+            self.file.display(),
+            self.field,
+            self.reason
+        )
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Definitions {
+    pub species: Vec<(PathBuf, SpeciesDef)>,
+    pub biomes: Vec<(PathBuf, BiomeDef)>,
+}
+
+impl Definitions {
+    pub fn load() -> Result<Self, Vec<DefinitionError>> {
+        Self::load_from(Path::new(SPECIES_DIR), Path::new(BIOMES_DIR))
+    }
+
+    pub fn load_from(species_dir: &Path, biomes_dir: &Path) -> Result<Self, Vec<DefinitionError>> {
+        let mut errors = Vec::new();
+        let species = load_dir::<SpeciesDef>(species_dir, &mut errors);
+        let biomes = load_dir::<BiomeDef>(biomes_dir, &mut errors);
+        let definitions = Definitions { species, biomes };
+        definitions.validate(&mut errors);
+        if errors.is_empty() {
+            Ok(definitions)
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate(&self, errors: &mut Vec<DefinitionError>) {
+        let mut error = |file: &Path, field: &str, reason: String| {
+            errors.push(DefinitionError {
+                file: file.to_path_buf(),
+                field: field.to_string(),
+                reason,
+            })
+        };
+
+        if self.species.is_empty() {
+            error(
+                Path::new(SPECIES_DIR),
+                "-",
+                "no species defined".to_string(),
+            );
+        }
+
+        let mut names = HashSet::new();
+        for (file, def) in self.species.iter() {
+            if def.name.is_empty() {
+                error(file, "name", "must not be empty".to_string());
+            } else if !names.insert(def.name.as_str()) {
+                error(file, "name", format!("'{}' is defined twice", def.name));
+            }
+
+            let rates = [
+                ("birth_rate", def.birth_rate),
+                ("death_rate", def.death_rate),
+                ("carrying_capacity", def.carrying_capacity),
+                ("conversion", def.conversion),
+                ("migration", def.migration),
+                ("sensitivity.soil", def.sensitivity.soil),
+                ("sensitivity.water", def.sensitivity.water),
+                ("sensitivity.air", def.sensitivity.air),
+                ("sensitivity.temperature", def.sensitivity.temperature),
+            ];
+            for (field, value) in rates {
+                if !value.is_finite() || value < 0.0 {
+                    error(
+                        file,
+                        field,
+                        format!("must be a non-negative number, got {}", value),
+                    );
+                }
+            }
+            if def.conversion > 1.0 {
+                error(file, "conversion", "must be at most 1".to_string());
+            }
+            if def.migration > 1.0 {
+                error(file, "migration", "must be at most 1".to_string());
+            }
+
+            match def.trophic {
+                Trophic::Producer => {
+                    if !def.diet.is_empty() {
+                        error(file, "diet", "producers don't eat anything".to_string());
+                    }
+                    if def.birth_rate <= 0.0 {
+                        error(
+                            file,
+                            "birth_rate",
+                            "producers need a growth rate".to_string(),
+                        );
+                    }
+                }
+                Trophic::Herbivore | Trophic::Predator => {
+                    if def.diet.is_empty() {
+                        error(file, "diet", "consumers need something to eat".to_string());
+                    }
+                }
+            }
+        }
+
+        for (file, def) in self.species.iter() {
+            for (i, prey) in def.diet.iter().enumerate() {
+                let field = format!("diet[{}].prey", i);
+                if prey.prey == def.name {
+                    error(file, &field, "a species can't eat itself".to_string());
+                } else if !names.contains(prey.prey.as_str()) {
+                    error(file, &field, format!("unknown species '{}'", prey.prey));
+                }
+                for (name, value) in [
+                    ("attack_rate", prey.attack_rate),
+                    ("handling_time", prey.handling_time),
+                ] {
+                    if !value.is_finite() || value < 0.0 {
+                        error(
+                            file,
+                            &format!("diet[{}].{}", i, name),
+                            format!("must be a non-negative number, got {}", value),
+                        );
+                    }
+                }
+            }
+        }
+
+        let mut biome_names = HashSet::new();
+        for (file, def) in self.biomes.iter() {
+            if def.name.is_empty() {
+                error(file, "name", "must not be empty".to_string());
+            } else if !biome_names.insert(def.name.as_str()) {
+                error(file, "name", format!("'{}' is defined twice", def.name));
+            }
+            if !(0.0..=1.0).contains(&def.min_height) {
+                error(file, "min_height", "must be between 0 and 1".to_string());
+            }
+            if !(0.0..=1.0).contains(&def.max_height) {
+                error(file, "max_height", "must be between 0 and 1".to_string());
+            }
+            if def.min_height >= def.max_height {
+                error(file, "max_height", "must be above min_height".to_string());
+            }
+            for (species, scale) in def.capacity.iter() {
+                let field = format!("capacity[\"{}\"]", species);
+                if !names.contains(species.as_str()) {
+                    error(file, &field, format!("unknown species '{}'", species));
+                }
+                if !scale.is_finite() || *scale < 0.0 {
+                    error(
+                        file,
+                        &field,
+                        format!("must be a non-negative number, got {}", scale),
+                    );
+                }
+            }
+        }
+    }
+
+    // Species with diets resolved to ids, in a stable order (sorted by file name).
+    pub fn species(&self) -> Vec<Species> {
+        let id = |name: &str| {
+            self.species
+                .iter()
+                .position(|(_, def)| def.name == name)
+                .unwrap()
+        };
+        self.species
+            .iter()
+            .map(|(_, def)| Species {
+                name: def.name.clone(),
+                trophic: def.trophic,
+                birth_rate: def.birth_rate,
+                death_rate: def.death_rate,
+                carrying_capacity: def.carrying_capacity,
+                conversion: def.conversion,
+                migration: def.migration,
+                diet: def
+                    .diet
+                    .iter()
+                    .map(|prey| Prey {
+                        species: id(&prey.prey),
+                        attack_rate: prey.attack_rate,
+                        handling_time: prey.handling_time,
+                    })
+                    .collect(),
+                sensitivity: def.sensitivity,
+            })
+            .collect()
+    }
+
+    // The biome covering a normalized terrain height. Falls back to the closest
+    // range if the biomes leave a gap.
+    pub fn biome_for_height(&self, height: f32) -> Option<&BiomeDef> {
+        self.biomes.iter().map(|(_, biome)| biome).min_by(|a, b| {
+            let distance = |biome: &BiomeDef| {
+                (biome.min_height - height)
+                    .max(height - biome.max_height)
+                    .max(0.0)
+            };
+            distance(a).partial_cmp(&distance(b)).unwrap()
+        })
+    }
+}
+
+fn load_dir<T: serde::de::DeserializeOwned>(
+    dir: &Path,
+    errors: &mut Vec<DefinitionError>,
+) -> Vec<(PathBuf, T)> {
+    let files = match definition_files(dir) {
+        Ok(files) => files,
+        Err(e) => {
+            errors.push(DefinitionError {
+                file: dir.to_path_buf(),
+                field: "-".to_string(),
+                reason: e.to_string(),
+            });
+            return Vec::new();
+        }
+    };
+
+    let mut loaded = Vec::new();
+    for file in files {
+        let text = match std::fs::read_to_string(&file) {
+            Ok(text) => text,
+            Err(e) => {
+                errors.push(DefinitionError {
+                    file,
+                    field: "-".to_string(),
+                    reason: e.to_string(),
+                });
+                continue;
+            }
+        };
+        match ron::from_str::<T>(&text) {
+            Ok(def) => loaded.push((file, def)),
+            Err(e) => errors.push(DefinitionError {
+                file,
+                field: ron_error_field(&e.code),
+                reason: format!("line {}:{}: {}", e.position.line, e.position.col, e.code),
+            }),
+        }
+    }
+    loaded
+}
+
+// The key a RON error is about, when it names one.
+fn ron_error_field(error: &ron::Error) -> String {
+    match error {
+        ron::Error::NoSuchStructField { found, .. } => found.clone(),
+        ron::Error::MissingStructField { field, .. }
+        | ron::Error::DuplicateStructField { field, .. } => field.to_string(),
+        _ => "-".to_string(),
+    }
+}
+
+fn definition_files(dir: &Path) -> std::io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.extension().is_some_and(|ext| ext == "ron") {
+            files.push(path);
+        }
+    }
+    files.sort();
+    Ok(files)
+}
+
+// Polls the definition directories for added, removed or modified files.
+pub struct DefinitionWatcher {
+    dirs: Vec<PathBuf>,
+    stamps: Vec<(PathBuf, Option<SystemTime>)>,
+}
+
+impl DefinitionWatcher {
+    pub fn new() -> Self {
+        let dirs = vec![PathBuf::from(SPECIES_DIR), PathBuf::from(BIOMES_DIR)];
+        let stamps = Self::scan(&dirs);
+        DefinitionWatcher { dirs, stamps }
+    }
+
+    fn scan(dirs: &[PathBuf]) -> Vec<(PathBuf, Option<SystemTime>)> {
+        dirs.iter()
+            .flat_map(|dir| definition_files(dir).unwrap_or_default())
+            .map(|file| {
+                let modified = std::fs::metadata(&file).and_then(|m| m.modified()).ok();
+                (file, modified)
+            })
+            .collect()
+    }
+
+    pub fn changed(&mut self) -> bool {
+        let stamps = Self::scan(&self.dirs);
+        if stamps != self.stamps {
+            self.stamps = stamps;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GRASS: &str = r#"(
+        name: "grass",
+        trophic: Producer,
+        birth_rate: 0.8,
+        death_rate: 0.0,
+        carrying_capacity: 2000.0,
+    )"#;
+    const RABBIT: &str = r#"(
+        name: "rabbit",
+        trophic: Herbivore,
+        death_rate: 0.1,
+        carrying_capacity: 400.0,
+        conversion: 0.15,
+        diet: [(prey: "grass", attack_rate: 0.0015, handling_time: 0.5)],
+    )"#;
+    const MEADOW: &str = r#"(
+        name: "meadow",
+        min_height: 0.0,
+        max_height: 1.0,
+        capacity: {"grass": 1.2},
+    )"#;
+
+    // Writes the files into fresh species and biome directories, loads them and
+    // cleans up again.
+    fn load(
+        name: &str,
+        species: &[(&str, &str)],
+        biomes: &[(&str, &str)],
+    ) -> Result<Definitions, Vec<DefinitionError>> {
+        let root = std::env::temp_dir().join(format!(
+            "ecocide_definitions_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let (species_dir, biomes_dir) = (root.join("species"), root.join("biomes"));
+        for (dir, files) in [(&species_dir, species), (&biomes_dir, biomes)] {
+            std::fs::create_dir_all(dir).unwrap();
+            for (file, text) in files {
+                std::fs::write(dir.join(file), text).unwrap();
+            }
+        }
+        let definitions = Definitions::load_from(&species_dir, &biomes_dir);
+        std::fs::remove_dir_all(&root).unwrap();
+        definitions
+    }
+
+    fn errors(
+        name: &str,
+        species: &[(&str, &str)],
+        biomes: &[(&str, &str)],
+    ) -> Vec<DefinitionError> {
+        load(name, species, biomes).expect_err("the definitions should be rejected")
+    }
+
+    fn assert_reported(errors: &[DefinitionError], file: &str, field: &str, reason: &str) {
+        assert!(
+            errors.iter().any(|error| error.file.ends_with(file)
+                && error.field == field
+                && error.reason == reason),
+            "no '{}: {}: {}' in {:?}",
+            file,
+            field,
+            reason,
+            errors
+        );
+    }
+
+    #[test]
+    fn valid_definitions_load() {
+        let definitions = load(
+            "valid",
+            &[("grass.ron", GRASS), ("rabbit.ron", RABBIT)],
+            &[("meadow.ron", MEADOW)],
+        )
+        .unwrap();
+        let species = definitions.species();
+        assert_eq!(species[1].diet[0].species, 0);
+    }
+
+    #[test]
+    fn unknown_prey_is_reported() {
+        let fox = RABBIT
+            .replace("\"rabbit\"", "\"fox\"")
+            .replace("\"grass\"", "\"hare\"");
+        let errors = errors(
+            "unknown_prey",
+            &[("grass.ron", GRASS), ("fox.ron", &fox)],
+            &[("meadow.ron", MEADOW)],
+        );
+        assert_eq!(errors.len(), 1);
+        assert_reported(
+            &errors,
+            "species/fox.ron",
+            "diet[0].prey",
+            "unknown species 'hare'",
+        );
+    }
+
+    #[test]
+    fn a_species_eating_itself_is_reported() {
+        let cannibal = RABBIT.replace("prey: \"grass\"", "prey: \"rabbit\"");
+        let errors = errors(
+            "cannibal",
+            &[("grass.ron", GRASS), ("rabbit.ron", &cannibal)],
+            &[("meadow.ron", MEADOW)],
+        );
+        assert_reported(
+            &errors,
+            "species/rabbit.ron",
+            "diet[0].prey",
+            "a species can't eat itself",
+        );
+    }
+
+    #[test]
+    fn duplicate_names_are_reported() {
+        let errors = errors(
+            "duplicates",
+            &[("grass.ron", GRASS), ("meadow_grass.ron", GRASS)],
+            &[("meadow.ron", MEADOW), ("pasture.ron", MEADOW)],
+        );
+        assert_reported(
+            &errors,
+            "species/meadow_grass.ron",
+            "name",
+            "'grass' is defined twice",
+        );
+        assert_reported(
+            &errors,
+            "biomes/pasture.ron",
+            "name",
+            "'meadow' is defined twice",
+        );
+    }
+
+    #[test]
+    fn negative_and_non_finite_rates_are_reported() {
+        let rabbit = RABBIT
+            .replace("death_rate: 0.1", "death_rate: -0.1")
+            .replace("carrying_capacity: 400.0", "carrying_capacity: inf")
+            .replace("handling_time: 0.5", "handling_time: NaN");
+        let errors = errors(
+            "rates",
+            &[("grass.ron", GRASS), ("rabbit.ron", &rabbit)],
+            &[("meadow.ron", MEADOW)],
+        );
+        assert_eq!(errors.len(), 3);
+        assert_reported(
+            &errors,
+            "species/rabbit.ron",
+            "death_rate",
+            "must be a non-negative number, got -0.1",
+        );
+        assert_reported(
+            &errors,
+            "species/rabbit.ron",
+            "carrying_capacity",
+            "must be a non-negative number, got inf",
+        );
+        assert_reported(
+            &errors,
+            "species/rabbit.ron",
+            "diet[0].handling_time",
+            "must be a non-negative number, got NaN",
+        );
+    }
+
+    #[test]
+    fn conversion_and_migration_above_one_are_reported() {
+        let rabbit = RABBIT.replace("conversion: 0.15", "conversion: 1.5, migration: 2.0");
+        let errors = errors(
+            "fractions",
+            &[("grass.ron", GRASS), ("rabbit.ron", &rabbit)],
+            &[("meadow.ron", MEADOW)],
+        );
+        assert_eq!(errors.len(), 2);
+        assert_reported(
+            &errors,
+            "species/rabbit.ron",
+            "conversion",
+            "must be at most 1",
+        );
+        assert_reported(
+            &errors,
+            "species/rabbit.ron",
+            "migration",
+            "must be at most 1",
+        );
+    }
+
+    #[test]
+    fn unknown_species_in_a_biome_is_reported() {
+        let meadow = MEADOW.replace("{\"grass\": 1.2}", "{\"grass\": 1.2, \"moss\": 0.5}");
+        let errors = errors(
+            "biome_capacity",
+            &[("grass.ron", GRASS)],
+            &[("meadow.ron", &meadow)],
+        );
+        assert_eq!(errors.len(), 1);
+        assert_reported(
+            &errors,
+            "biomes/meadow.ron",
+            "capacity[\"moss\"]",
+            "unknown species 'moss'",
+        );
+    }
+
+    #[test]
+    fn parse_errors_name_the_key_and_give_the_position_in_the_reason() {
+        let grass = GRASS.replace("death_rate", "dearth_rate");
+        let errors = errors(
+            "parse",
+            &[("grass.ron", &grass), ("rabbit.ron", "(name: \"rabbit\",")],
+            &[("meadow.ron", MEADOW)],
+        );
+        let grass = errors
+            .iter()
+            .find(|error| error.file.ends_with("species/grass.ron"))
+            .unwrap();
+        assert_eq!(grass.field, "dearth_rate");
+        assert!(grass.reason.starts_with("line 5:"), "{}", grass.reason);
+        let rabbit = errors
+            .iter()
+            .find(|error| error.file.ends_with("species/rabbit.ron"))
+            .unwrap();
+        assert_eq!(rabbit.field, "-");
+        assert!(rabbit.reason.starts_with("line 1:"), "{}", rabbit.reason);
+    }
+}
@@ -0,0 +1,331 @@
+// The developer UI's windows: engine stats, an inspector for species and pollution
+// sources, population graphs and tuning values. F1 shows and hides it.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use egui::{Color32, ComboBox, DragValue, Grid, Sense, Shape, Slider, Stroke};
+
+use crate::debug_draw;
+use crate::engine::VkEngine;
+use crate::environment::{EnvironmentSettings, Layer, LayerParams, LAYERS};
+use crate::post::PostSettings;
+use crate::profiler;
+use crate::simulation::Simulation;
+use crate::{FRAME_LIMITS, MSAA_LEVELS, PRESENT_MODES};
+
+// Population samples kept for the graphs, one every `SAMPLE_INTERVAL`.
+const HISTORY: usize = 300;
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+pub const GRAPH_COLORS: [Color32; 6] = [
+    Color32::from_rgb(120, 200, 90),
+    Color32::from_rgb(230, 180, 60),
+    Color32::from_rgb(220, 80, 70),
+    Color32::from_rgb(90, 160, 230),
+    Color32::from_rgb(190, 110, 220),
+    Color32::from_rgb(200, 200, 200),
+];
+
+#[derive(Default)]
+pub struct DevUi {
+    // Total population of each species, oldest first.
+    history: VecDeque<Vec<f64>>,
+    last_sample: Option<Instant>,
+    species: usize,
+}
+
+impl DevUi {
+    // Samples the populations every so often, shown or not, so the graphs have a
+    // past when opened.
+    pub fn record(&mut self, simulation: &Simulation) {
+        let now = Instant::now();
+        if self
+            .last_sample
+            .is_some_and(|last| now - last < SAMPLE_INTERVAL)
+        {
+            return;
+        }
+        self.last_sample = Some(now);
+        let eco = &simulation.ecosystem;
+        let totals = (0..eco.species.len())
+            .map(|id| eco.total_population(id))
+            .collect();
+        if self.history.len() == HISTORY {
+            self.history.pop_front();
+        }
+        self.history.push_back(totals);
+    }
+
+    pub fn show(
+        &mut self,
+        ctx: &egui::Context,
+        engine: &mut VkEngine,
+        simulation: &mut Simulation,
+        diffuse_on_gpu: &mut bool,
+    ) {
+        egui::Window::new("Engine").show(ctx, |ui| {
+            engine_stats(ui, engine, diffuse_on_gpu);
+        });
+        egui::Window::new("Inspector")
+            .default_open(false)
+            .show(ctx, |ui| self.inspector(ui, simulation));
+        egui::Window::new("Populations")
+            .default_open(false)
+            .show(ctx, |ui| self.populations(ui, simulation));
+        egui::Window::new("Tuning")
+            .default_open(false)
+            .show(ctx, |ui| tuning(ui, engine, simulation));
+    }
+
+    fn inspector(&mut self, ui: &mut egui::Ui, simulation: &mut Simulation) {
+        let eco = &mut simulation.ecosystem;
+        if eco.species.is_empty() {
+            ui.label("No species loaded");
+            return;
+        }
+        self.species = self.species.min(eco.species.len() - 1);
+        ComboBox::from_label("Species")
+            .selected_text(eco.species[self.species].name.as_str())
+            .show_ui(ui, |ui| {
+                for (id, species) in eco.species.iter().enumerate() {
+                    ui.selectable_value(&mut self.species, id, species.name.as_str());
+                }
+            });
+        let id = self.species;
+        let species = &mut eco.species[id];
+        Grid::new("species").num_columns(2).show(ui, |ui| {
+            ui.label("Trophic level");
+            ui.label(format!("{:?}", species.trophic));
+            ui.end_row();
+            ui.label("Birth rate");
+            ui.add(
+                DragValue::new(&mut species.birth_rate)
+                    .speed(0.001)
+                    .clamp_range(0.0..=f64::INFINITY),
+            );
+            ui.end_row();
+            ui.label("Death rate");
+            ui.add(
+                DragValue::new(&mut species.death_rate)
+                    .speed(0.001)
+                    .clamp_range(0.0..=f64::INFINITY),
+            );
+            ui.end_row();
+            ui.label("Carrying capacity");
+            ui.add(
+                DragValue::new(&mut species.carrying_capacity)
+                    .speed(1.0)
+                    .clamp_range(0.0..=f64::INFINITY),
+            );
+            ui.end_row();
+            ui.label("Migration");
+            ui.add(
+                DragValue::new(&mut species.migration)
+                    .speed(0.001)
+                    .clamp_range(0.0..=f64::INFINITY),
+            );
+            ui.end_row();
+        });
+        ui.label("Edits last until the definitions are reloaded.");
+
+        ui.separator();
+        ui.label("Population by region");
+        Grid::new("regions").show(ui, |ui| {
+            for (index, region) in eco.regions.iter().enumerate() {
+                ui.label(format!("{:.0}", region.populations[id]));
+                if (index + 1) % crate::simulation::REGIONS_X == 0 {
+                    ui.end_row();
+                }
+            }
+        });
+
+        ui.separator();
+        let sources = &mut simulation.environment.sources;
+        ui.label(format!("{} pollution sources", sources.len()));
+        sources.retain_mut(|source| {
+            let mut keep = true;
+            ui.horizontal(|ui| {
+                ui.label(format!("({:.0}, {:.0})", source.x, source.z));
+                ui.add(Slider::new(&mut source.emission.air, 0.0..=400.0).text("air"));
+                if let Some(remaining) = source.remaining {
+                    ui.label(format!("{:.1} s left", remaining));
+                }
+                keep = !ui.button("Remove").clicked();
+            });
+            keep
+        });
+    }
+
+    fn populations(&mut self, ui: &mut egui::Ui, simulation: &Simulation) {
+        let species = &simulation.ecosystem.species;
+        let max = self
+            .history
+            .iter()
+            .flatten()
+            .copied()
+            .fold(1.0f64, f64::max);
+        let (response, painter) = ui.allocate_painter(
+            egui::vec2(ui.available_width().max(240.0), 140.0),
+            Sense::hover(),
+        );
+        let rect = response.rect;
+        painter.rect_stroke(rect, 0.0, Stroke::new(1.0, Color32::DARK_GRAY));
+        for id in 0..species.len() {
+            let points: Vec<egui::Pos2> = self
+                .history
+                .iter()
+                .enumerate()
+                .filter_map(|(i, totals)| {
+                    let total = *totals.get(id)?;
+                    let x = rect.left() + rect.width() * i as f32 / (HISTORY - 1) as f32;
+                    let y = rect.bottom() - rect.height() * (total / max) as f32;
+                    Some(egui::pos2(x, y))
+                })
+                .collect();
+            let color = GRAPH_COLORS[id % GRAPH_COLORS.len()];
+            painter.add(Shape::line(points, Stroke::new(1.5, color)));
+        }
+        ui.label(format!(
+            "Last {} s, peak {:.0}",
+            (HISTORY as u32 * SAMPLE_INTERVAL).as_secs(),
+            max
+        ));
+        for (id, s) in species.iter().enumerate() {
+            let color = GRAPH_COLORS[id % GRAPH_COLORS.len()];
+            ui.colored_label(
+                color,
+                format!(
+                    "{} {:.0}",
+                    s.name,
+                    simulation.ecosystem.total_population(id)
+                ),
+            );
+        }
+    }
+}
+
+fn engine_stats(ui: &mut egui::Ui, engine: &mut VkEngine, diffuse_on_gpu: &mut bool) {
+    ui.label(format!("Frame {:.2} ms", profiler::frame_time_ms()));
+    for timer in engine.gpu_timer.iter().chain(engine.compute_timer.iter()) {
+        for (name, ms) in timer.results.iter() {
+            ui.label(format!("  {} {:.2} ms", name, ms));
+        }
+    }
+    ui.label(format!(
+        "Device {}",
+        engine.devices[engine.device_index].name
+    ));
+    ui.label(format!("Instances {}", engine.cull_stats));
+
+    ui.separator();
+    let mut graphics = engine.graphics;
+    ui.checkbox(&mut graphics.vsync, "Vsync");
+    ui.checkbox(&mut graphics.prefer_srgb, "sRGB output");
+    ComboBox::from_label("Present mode")
+        .selected_text(format!("{:?}", engine.present_mode))
+        .show_ui(ui, |ui| {
+            for mode in PRESENT_MODES {
+                let text = mode.map_or("From vsync".to_string(), |mode| format!("{:?}", mode));
+                ui.selectable_value(&mut graphics.present_mode, mode, text);
+            }
+        });
+    ComboBox::from_label("Frame limit")
+        .selected_text(frame_limit_text(graphics.frame_limit))
+        .show_ui(ui, |ui| {
+            for limit in FRAME_LIMITS {
+                ui.selectable_value(&mut graphics.frame_limit, limit, frame_limit_text(limit));
+            }
+        });
+    ComboBox::from_label("MSAA")
+        .selected_text(msaa_text(graphics.msaa))
+        .show_ui(ui, |ui| {
+            for samples in MSAA_LEVELS {
+                ui.selectable_value(&mut graphics.msaa, samples, msaa_text(samples));
+            }
+        });
+    if engine.samples.as_raw() != graphics.msaa {
+        ui.label(format!("Using {}", msaa_text(engine.samples.as_raw())));
+    }
+    if graphics != engine.graphics {
+        engine.set_graphics(graphics);
+    }
+
+    let mut debug_lines = debug_draw::enabled();
+    if ui.checkbox(&mut debug_lines, "Debug lines").changed() {
+        debug_draw::set_enabled(debug_lines);
+    }
+    ui.checkbox(diffuse_on_gpu, "Diffusion on the GPU");
+}
+
+fn msaa_text(samples: u32) -> String {
+    if samples > 1 {
+        format!("{}x", samples)
+    } else {
+        "Off".to_string()
+    }
+}
+
+fn frame_limit_text(limit: Option<u32>) -> String {
+    limit.map_or("None".to_string(), |limit| format!("{} fps", limit))
+}
+
+fn tuning(ui: &mut egui::Ui, engine: &mut VkEngine, simulation: &mut Simulation) {
+    ui.checkbox(&mut simulation.paused, "Pause simulation");
+    ui.add(Slider::new(&mut engine.camera.fov.0, 30.0..=110.0).text("Field of view"));
+    ui.collapsing("Post processing", |ui| {
+        post_settings(ui, &mut engine.post.settings)
+    });
+    let settings = &mut simulation.environment.settings;
+    Grid::new("layers").num_columns(4).show(ui, |ui| {
+        ui.label("Layer");
+        ui.label("Diffusion");
+        ui.label("Decay");
+        ui.label("Rest");
+        ui.end_row();
+        for layer in LAYERS {
+            let params = layer_params(settings, layer);
+            ui.label(format!("{:?}", layer));
+            ui.add(
+                DragValue::new(&mut params.diffusion)
+                    .speed(0.01)
+                    .clamp_range(0.0..=f32::INFINITY),
+            );
+            ui.add(
+                DragValue::new(&mut params.decay)
+                    .speed(0.001)
+                    .clamp_range(0.0..=f32::INFINITY),
+            );
+            ui.add(DragValue::new(&mut params.rest).speed(0.1));
+            ui.end_row();
+        }
+    });
+}
+
+fn post_settings(ui: &mut egui::Ui, settings: &mut PostSettings) {
+    ui.add(
+        Slider::new(&mut settings.exposure, 0.05..=8.0)
+            .logarithmic(true)
+            .text("Exposure"),
+    );
+    ui.add(Slider::new(&mut settings.gamma, 1.6..=2.8).text("Gamma"));
+    ui.checkbox(&mut settings.bloom, "Bloom");
+    ui.add_enabled_ui(settings.bloom, |ui| {
+        ui.add(Slider::new(&mut settings.bloom_threshold, 0.0..=4.0).text("Threshold"));
+        ui.add(Slider::new(&mut settings.bloom_knee, 0.0..=1.0).text("Knee"));
+        ui.add(Slider::new(&mut settings.bloom_intensity, 0.0..=2.0).text("Intensity"));
+    });
+    let grade = &mut settings.grade;
+    ui.add(Slider::new(&mut grade.strength, 0.0..=1.0).text("Grading"));
+    ui.add(Slider::new(&mut grade.contrast, 0.5..=1.5).text("Contrast"));
+    ui.add(Slider::new(&mut grade.saturation, 0.0..=2.0).text("Saturation"));
+    ui.add(Slider::new(&mut grade.temperature, -1.0..=1.0).text("Temperature"));
+}
+
+fn layer_params(settings: &mut EnvironmentSettings, layer: Layer) -> &mut LayerParams {
+    match layer {
+        Layer::Soil => &mut settings.soil,
+        Layer::Water => &mut settings.water,
+        Layer::Air => &mut settings.air,
+        Layer::Temperature => &mut settings.temperature,
+    }
+}
@@ -0,0 +1,373 @@
+// Picks the physical device to render with. Every device is scored, devices that
+// can't run the engine at all are kept in the list with the reasons why, and the
+// choice can be overridden by name or index, which is how a software rasterizer
+// such as lavapipe gets picked for testing.
+
+use std::ffi::CStr;
+use std::fmt;
+
+use anyhow::{anyhow, bail};
+use ash::extensions::khr::{Surface, Swapchain};
+use ash::{vk, Instance};
+
+use crate::error::EngineResult;
+
+// Names the device to use, overriding the scoring.
+pub const DEVICE_ENV: &str = "ECOCIDE_DEVICE";
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum DeviceChoice {
+    // The best scoring usable device.
+    #[default]
+    Auto,
+    // Position in `enumerate_physical_devices`.
+    Index(usize),
+    // Case insensitive part of the device name.
+    Name(String),
+}
+
+impl DeviceChoice {
+    // Reads `ECOCIDE_DEVICE`, which holds either an index or part of a name.
+    pub fn from_env() -> Self {
+        match std::env::var(DEVICE_ENV) {
+            Ok(value) if !value.trim().is_empty() => match value.trim().parse() {
+                Ok(index) => DeviceChoice::Index(index),
+                Err(_) => DeviceChoice::Name(value.trim().to_string()),
+            },
+            _ => DeviceChoice::Auto,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct DeviceCandidate {
+    pub index: usize,
+    pub pdevice: vk::PhysicalDevice,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    // Total size of the device local heaps.
+    pub memory: u64,
+    // A queue family that can draw and present to the surface.
+    pub queue_family: Option<u32>,
+    // What the engine needs that the device doesn't have. Empty when usable.
+    pub missing: Vec<String>,
+}
+
+impl DeviceCandidate {
+    pub fn usable(&self) -> bool {
+        self.missing.is_empty()
+    }
+
+    // Higher is better. Only compares usable devices: the type decides, then memory.
+    pub fn score(&self) -> u64 {
+        let rank = match self.device_type {
+            vk::PhysicalDeviceType::DISCRETE_GPU => 4,
+            vk::PhysicalDeviceType::INTEGRATED_GPU => 3,
+            vk::PhysicalDeviceType::VIRTUAL_GPU => 2,
+            vk::PhysicalDeviceType::CPU => 1,
+            _ => 0,
+        };
+        (rank << 48) + (self.memory >> 20)
+    }
+}
+
+impl fmt::Display for DeviceCandidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}: {} ({:?}, {} MiB)",
+            self.index,
+            self.name,
+            self.device_type,
+            self.memory >> 20
+        )?;
+        if !self.usable() {
+            write!(f, ", missing {}", self.missing.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+// Every physical device, in the order the driver lists them.
+pub unsafe fn candidates(
+    instance: &Instance,
+    surface_loader: &Surface,
+    surface: vk::SurfaceKHR,
+) -> EngineResult<Vec<DeviceCandidate>> {
+    let candidates = instance
+        .enumerate_physical_devices()?
+        .into_iter()
+        .enumerate()
+        .map(|(index, pdevice)| {
+            let properties = instance.get_physical_device_properties(pdevice);
+            let name = CStr::from_ptr(properties.device_name.as_ptr())
+                .to_string_lossy()
+                .into_owned();
+            let memory_properties = instance.get_physical_device_memory_properties(pdevice);
+            let memory = memory_properties.memory_heaps
+                [..memory_properties.memory_heap_count as usize]
+                .iter()
+                .filter(|heap| heap.flags.contains(vk::MemoryHeapFlags::DEVICE_LOCAL))
+                .map(|heap| heap.size)
+                .sum();
+
+            let queue_family = instance
+                .get_physical_device_queue_family_properties(pdevice)
+                .iter()
+                .enumerate()
+                .map(|(family, info)| (family as u32, info))
+                .find(|&(family, info)| {
+                    info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
+                        && surface_loader
+                            .get_physical_device_surface_support(pdevice, family, surface)
+                            .unwrap_or(false)
+                })
+                .map(|(family, _)| family);
+
+            let mut missing = Vec::new();
+            if queue_family.is_none() {
+                missing.push("a graphics queue that can present".to_string());
+            }
+            let extensions = instance
+                .enumerate_device_extension_properties(pdevice)
+                .unwrap_or_default();
+            let has_swapchain = extensions.iter().any(|extension| {
+                CStr::from_ptr(extension.extension_name.as_ptr()) == Swapchain::name()
+            });
+            if !has_swapchain {
+                missing.push(Swapchain::name().to_string_lossy().into_owned());
+            }
+            if instance
+                .get_physical_device_features(pdevice)
+                .shader_clip_distance
+                != vk::TRUE
+            {
+                missing.push("shaderClipDistance".to_string());
+            }
+
+            DeviceCandidate {
+                index,
+                pdevice,
+                name,
+                device_type: properties.device_type,
+                memory,
+                queue_family,
+                missing,
+            }
+        })
+        .collect();
+    Ok(candidates)
+}
+
+pub fn select<'a>(
+    candidates: &'a [DeviceCandidate],
+    choice: &DeviceChoice,
+) -> anyhow::Result<&'a DeviceCandidate> {
+    let chosen = match choice {
+        DeviceChoice::Auto => {
+            return candidates
+                .iter()
+                .filter(|candidate| candidate.usable())
+                .max_by_key(|candidate| candidate.score())
+                .ok_or_else(|| anyhow!("None of the {} devices is usable", candidates.len()));
+        }
+        DeviceChoice::Index(index) => candidates
+            .get(*index)
+            .ok_or_else(|| anyhow!("There is no device {}", index))?,
+        DeviceChoice::Name(name) => {
+            let name = name.to_lowercase();
+            candidates
+                .iter()
+                .find(|candidate| candidate.name.to_lowercase().contains(&name))
+                .ok_or_else(|| anyhow!("No device is called anything like {:?}", name))?
+        }
+    };
+    if !chosen.usable() {
+        bail!("Device {} is not usable", chosen);
+    }
+    Ok(chosen)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GIB: u64 = 1 << 30;
+
+    fn candidate(
+        index: usize,
+        name: &str,
+        device_type: vk::PhysicalDeviceType,
+        memory: u64,
+        missing: &[&str],
+    ) -> DeviceCandidate {
+        DeviceCandidate {
+            index,
+            pdevice: vk::PhysicalDevice::null(),
+            name: name.to_string(),
+            device_type,
+            memory,
+            queue_family: Some(0),
+            missing: missing.iter().map(|reason| reason.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn the_type_beats_any_amount_of_memory() {
+        let discrete = candidate(0, "GeForce", vk::PhysicalDeviceType::DISCRETE_GPU, GIB, &[]);
+        let integrated = candidate(
+            1,
+            "Iris",
+            vk::PhysicalDeviceType::INTEGRATED_GPU,
+            1024 * GIB,
+            &[],
+        );
+        let cpu = candidate(2, "llvmpipe", vk::PhysicalDeviceType::CPU, 65536 * GIB, &[]);
+        assert!(discrete.score() > integrated.score());
+        assert!(integrated.score() > cpu.score());
+
+        let candidates = [cpu, integrated, discrete];
+        assert_eq!(
+            select(&candidates, &DeviceChoice::Auto).unwrap().name,
+            "GeForce"
+        );
+    }
+
+    #[test]
+    fn memory_breaks_ties_within_a_type() {
+        let small = candidate(
+            0,
+            "Small",
+            vk::PhysicalDeviceType::DISCRETE_GPU,
+            4 * GIB,
+            &[],
+        );
+        let large = candidate(
+            1,
+            "Large",
+            vk::PhysicalDeviceType::DISCRETE_GPU,
+            8 * GIB,
+            &[],
+        );
+        assert!(large.score() > small.score());
+
+        let candidates = [small, large];
+        assert_eq!(
+            select(&candidates, &DeviceChoice::Auto).unwrap().name,
+            "Large"
+        );
+    }
+
+    #[test]
+    fn auto_skips_unusable_devices() {
+        let candidates = [
+            candidate(
+                0,
+                "GeForce",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                8 * GIB,
+                &["shaderClipDistance"],
+            ),
+            candidate(1, "llvmpipe", vk::PhysicalDeviceType::CPU, GIB, &[]),
+        ];
+        assert_eq!(
+            select(&candidates, &DeviceChoice::Auto).unwrap().name,
+            "llvmpipe"
+        );
+    }
+
+    #[test]
+    fn auto_errors_when_nothing_is_usable() {
+        let candidates = [
+            candidate(
+                0,
+                "GeForce",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                GIB,
+                &["VK_KHR_swapchain"],
+            ),
+            candidate(
+                1,
+                "llvmpipe",
+                vk::PhysicalDeviceType::CPU,
+                GIB,
+                &["shaderClipDistance"],
+            ),
+        ];
+        let error = select(&candidates, &DeviceChoice::Auto).unwrap_err();
+        assert!(
+            error.to_string().contains("None of the 2 devices"),
+            "{}",
+            error
+        );
+        assert!(select(&[], &DeviceChoice::Auto).is_err());
+    }
+
+    #[test]
+    fn an_index_out_of_range_errors() {
+        let candidates = [candidate(
+            0,
+            "GeForce",
+            vk::PhysicalDeviceType::DISCRETE_GPU,
+            GIB,
+            &[],
+        )];
+        assert_eq!(
+            select(&candidates, &DeviceChoice::Index(0)).unwrap().name,
+            "GeForce"
+        );
+        let error = select(&candidates, &DeviceChoice::Index(1)).unwrap_err();
+        assert!(error.to_string().contains("no device 1"), "{}", error);
+    }
+
+    #[test]
+    fn names_match_case_insensitively() {
+        let candidates = [
+            candidate(
+                0,
+                "NVIDIA GeForce RTX 3070",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                8 * GIB,
+                &[],
+            ),
+            candidate(
+                1,
+                "llvmpipe (LLVM 15.0.7, 256 bits)",
+                vk::PhysicalDeviceType::CPU,
+                GIB,
+                &[],
+            ),
+        ];
+        let chosen = select(&candidates, &DeviceChoice::Name("LLVMPIPE".to_string())).unwrap();
+        assert_eq!(chosen.index, 1);
+        let chosen = select(&candidates, &DeviceChoice::Name("geforce".to_string())).unwrap();
+        assert_eq!(chosen.index, 0);
+        assert!(select(&candidates, &DeviceChoice::Name("radeon".to_string())).is_err());
+    }
+
+    #[test]
+    fn a_chosen_unusable_device_is_refused_with_what_it_lacks() {
+        let candidates = [
+            candidate(0, "GeForce", vk::PhysicalDeviceType::DISCRETE_GPU, GIB, &[]),
+            candidate(
+                1,
+                "llvmpipe",
+                vk::PhysicalDeviceType::CPU,
+                GIB,
+                &["a graphics queue that can present", "shaderClipDistance"],
+            ),
+        ];
+        for choice in [
+            DeviceChoice::Index(1),
+            DeviceChoice::Name("llvm".to_string()),
+        ] {
+            let error = select(&candidates, &choice).unwrap_err().to_string();
+            assert!(error.contains("llvmpipe"), "{}", error);
+            assert!(
+                error.contains("missing a graphics queue that can present, shaderClipDistance"),
+                "{}",
+                error
+            );
+        }
+    }
+}
@@ -0,0 +1,448 @@
+// Populations are continuous quantities and every update is plain f64 arithmetic in
+// a fixed order, so a given starting state always produces the same history.
+
+use serde::Deserialize;
+
+pub type SpeciesId = usize;
+
+// Populations that fall below this are considered locally extinct.
+pub const EXTINCTION_THRESHOLD: f64 = 0.01;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+pub enum Trophic {
+    Producer,
+    Herbivore,
+    Predator,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Prey {
+    pub species: SpeciesId,
+    // Rate at which one consumer finds and eats one unit of this prey.
+    pub attack_rate: f64,
+    // Time spent eating one unit of prey, which saturates consumption when food is plentiful.
+    pub handling_time: f64,
+}
+
+// How strongly each kind of environmental damage stresses a species. Stress raises
+// the death rate and lowers the birth rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Sensitivity {
+    pub soil: f64,
+    pub water: f64,
+    pub air: f64,
+    // Per degree away from the resting temperature.
+    pub temperature: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Species {
+    pub name: String,
+    pub trophic: Trophic,
+    // Producers grow logistically at this rate; consumers only reproduce by eating.
+    pub birth_rate: f64,
+    pub death_rate: f64,
+    // Per region, before the region's own capacity scale is applied.
+    pub carrying_capacity: f64,
+    // Fraction of eaten prey turned into new individuals.
+    pub conversion: f64,
+    // Fraction of the population that wanders off to neighbouring regions per second.
+    pub migration: f64,
+    pub diet: Vec<Prey>,
+    pub sensitivity: Sensitivity,
+}
+
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub populations: Vec<f64>,
+    // Per species multiplier on carrying capacity, set by the region's biome.
+    pub capacity_scale: Vec<f64>,
+    pub neighbours: Vec<usize>,
+}
+
+// Multipliers applied on top of a species' own rates in one region, e.g. from pollution.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateModifier {
+    pub birth: f64,
+    pub death: f64,
+}
+
+impl Default for RateModifier {
+    fn default() -> Self {
+        RateModifier {
+            birth: 1.0,
+            death: 1.0,
+        }
+    }
+}
+
+impl RateModifier {
+    pub fn from_stress(stress: f64) -> Self {
+        let stress = stress.max(0.0);
+        RateModifier {
+            birth: 1.0 / (1.0 + stress),
+            death: 1.0 + stress,
+        }
+    }
+}
+
+pub struct Ecosystem {
+    pub species: Vec<Species>,
+    pub regions: Vec<Region>,
+    // Indexed [region][species].
+    pub modifiers: Vec<Vec<RateModifier>>,
+    pub ticks: u64,
+}
+
+impl Ecosystem {
+    pub fn new(species: Vec<Species>, regions: Vec<Region>) -> Self {
+        let modifiers = regions
+            .iter()
+            .map(|_| vec![RateModifier::default(); species.len()])
+            .collect();
+        Ecosystem {
+            species,
+            regions,
+            modifiers,
+            ticks: 0,
+        }
+    }
+
+    // Lays regions out in a `width` x `height` grid, each connected to its four
+    // neighbours and starting with `initial` populations.
+    pub fn grid_regions(width: usize, height: usize, initial: &[f64]) -> Vec<Region> {
+        let mut regions = Vec::with_capacity(width * height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut neighbours = Vec::new();
+                if x > 0 {
+                    neighbours.push(y * width + x - 1);
+                }
+                if x + 1 < width {
+                    neighbours.push(y * width + x + 1);
+                }
+                if y > 0 {
+                    neighbours.push((y - 1) * width + x);
+                }
+                if y + 1 < height {
+                    neighbours.push((y + 1) * width + x);
+                }
+                regions.push(Region {
+                    populations: initial.to_vec(),
+                    capacity_scale: vec![1.0; initial.len()],
+                    neighbours,
+                });
+            }
+        }
+        regions
+    }
+
+    pub fn total_population(&self, species: SpeciesId) -> f64 {
+        self.regions.iter().map(|r| r.populations[species]).sum()
+    }
+
+    // Swaps in a new set of species, e.g. after the definitions were edited. Existing
+    // populations carry over by name; species that are new start out empty.
+    pub fn replace_species(&mut self, species: Vec<Species>) {
+        let old_ids: Vec<Option<SpeciesId>> = species
+            .iter()
+            .map(|new| self.species.iter().position(|old| old.name == new.name))
+            .collect();
+        for region in self.regions.iter_mut() {
+            let remap = |values: &[f64], missing: f64| -> Vec<f64> {
+                old_ids
+                    .iter()
+                    .map(|id| id.map_or(missing, |id| values[id]))
+                    .collect()
+            };
+            region.populations = remap(&region.populations, 0.0);
+            region.capacity_scale = remap(&region.capacity_scale, 1.0);
+        }
+        for modifiers in self.modifiers.iter_mut() {
+            *modifiers = old_ids
+                .iter()
+                .map(|id| id.map_or(RateModifier::default(), |id| modifiers[id]))
+                .collect();
+        }
+        self.species = species;
+    }
+
+    pub fn step(&mut self, dt: f64) {
+        let deltas: Vec<Vec<f64>> = (0..self.regions.len())
+            .map(|region| self.local_change(region, dt))
+            .collect();
+        for (region, delta) in self.regions.iter_mut().zip(deltas) {
+            for (population, change) in region.populations.iter_mut().zip(delta) {
+                *population += change;
+            }
+        }
+
+        self.migrate(dt);
+
+        for region in self.regions.iter_mut() {
+            for population in region.populations.iter_mut() {
+                if *population < EXTINCTION_THRESHOLD {
+                    *population = 0.0;
+                }
+            }
+        }
+        self.ticks += 1;
+    }
+
+    // Births, deaths and predation inside a single region over `dt`.
+    fn local_change(&self, region_index: usize, dt: f64) -> Vec<f64> {
+        let region = &self.regions[region_index];
+        let modifiers = &self.modifiers[region_index];
+        let n = &region.populations;
+        let mut delta = vec![0.0; n.len()];
+
+        for (id, species) in self.species.iter().enumerate() {
+            if n[id] <= 0.0 {
+                continue;
+            }
+            let capacity = species.carrying_capacity * region.capacity_scale[id];
+            let crowding = if capacity > 0.0 {
+                (1.0 - n[id] / capacity).max(0.0)
+            } else {
+                0.0
+            };
+            let modifier = modifiers[id];
+
+            // Holling type II: the time spent handling food caps how much gets eaten.
+            let handling: f64 = species
+                .diet
+                .iter()
+                .map(|prey| prey.attack_rate * prey.handling_time * n[prey.species])
+                .sum();
+            let mut eaten_total = 0.0;
+            for prey in species.diet.iter() {
+                let eaten = prey.attack_rate * n[prey.species] / (1.0 + handling) * n[id] * dt;
+                // Never eat more than is there.
+                let eaten = eaten.min(n[prey.species] + delta[prey.species]).max(0.0);
+                delta[prey.species] -= eaten;
+                eaten_total += eaten;
+            }
+
+            let births = match species.trophic {
+                Trophic::Producer => species.birth_rate * n[id] * crowding * dt,
+                Trophic::Herbivore | Trophic::Predator => {
+                    species.conversion * eaten_total * crowding
+                }
+            };
+            let deaths = species.death_rate * modifier.death * n[id] * dt;
+            delta[id] += births * modifier.birth - deaths;
+        }
+
+        for (change, population) in delta.iter_mut().zip(n.iter()) {
+            *change = change.max(-population);
+        }
+        delta
+    }
+
+    fn migrate(&mut self, dt: f64) {
+        let mut flow = vec![vec![0.0; self.species.len()]; self.regions.len()];
+        for (index, region) in self.regions.iter().enumerate() {
+            if region.neighbours.is_empty() {
+                continue;
+            }
+            for (id, species) in self.species.iter().enumerate() {
+                let leaving = (species.migration * dt).min(1.0) * region.populations[id];
+                let share = leaving / region.neighbours.len() as f64;
+                flow[index][id] -= leaving;
+                for &neighbour in region.neighbours.iter() {
+                    flow[neighbour][id] += share;
+                }
+            }
+        }
+        for (region, flow) in self.regions.iter_mut().zip(flow) {
+            for (population, change) in region.populations.iter_mut().zip(flow) {
+                *population = (*population + change).max(0.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::noise::splitmix64;
+
+    fn species(name: &str, trophic: Trophic, diet: Vec<Prey>) -> Species {
+        Species {
+            name: name.to_string(),
+            trophic,
+            birth_rate: 0.0,
+            death_rate: 0.0,
+            carrying_capacity: 1000.0,
+            conversion: 0.0,
+            migration: 0.0,
+            diet,
+            sensitivity: Sensitivity::default(),
+        }
+    }
+
+    fn prey(species: SpeciesId, attack_rate: f64, handling_time: f64) -> Prey {
+        Prey {
+            species,
+            attack_rate,
+            handling_time,
+        }
+    }
+
+    // Grass, rabbits eating grass and foxes eating rabbits, over a grid of regions
+    // whose starting populations come from `seed`.
+    fn food_chain(seed: u64) -> Ecosystem {
+        let species = vec![
+            Species {
+                birth_rate: 0.8,
+                death_rate: 0.05,
+                migration: 0.01,
+                ..species("grass", Trophic::Producer, vec![])
+            },
+            Species {
+                death_rate: 0.2,
+                carrying_capacity: 200.0,
+                conversion: 0.3,
+                migration: 0.05,
+                ..species("rabbit", Trophic::Herbivore, vec![prey(0, 0.01, 0.1)])
+            },
+            Species {
+                death_rate: 0.1,
+                carrying_capacity: 50.0,
+                conversion: 0.2,
+                migration: 0.1,
+                ..species("fox", Trophic::Predator, vec![prey(1, 0.02, 0.5)])
+            },
+        ];
+        let mut regions = Ecosystem::grid_regions(3, 3, &[0.0; 3]);
+        let mut state = seed;
+        for region in regions.iter_mut() {
+            for (population, scale) in region.populations.iter_mut().zip([1000.0, 200.0, 50.0]) {
+                *population = (splitmix64(&mut state) % 1000) as f64 / 1000.0 * scale;
+            }
+        }
+        Ecosystem::new(species, regions)
+    }
+
+    fn populations(ecosystem: &Ecosystem) -> Vec<u64> {
+        ecosystem
+            .regions
+            .iter()
+            .flat_map(|region| region.populations.iter().map(|p| p.to_bits()))
+            .collect()
+    }
+
+    #[test]
+    fn same_seed_and_ticks_give_identical_results() {
+        let mut a = food_chain(0xEC0);
+        let mut b = food_chain(0xEC0);
+        for _ in 0..500 {
+            a.step(0.1);
+            b.step(0.1);
+        }
+        assert_eq!(a.ticks, 500);
+        assert_eq!(populations(&a), populations(&b));
+
+        let mut c = food_chain(0xEC1);
+        for _ in 0..500 {
+            c.step(0.1);
+        }
+        assert_ne!(populations(&a), populations(&c));
+    }
+
+    #[test]
+    fn producers_grow_logistically_to_capacity() {
+        let grass = Species {
+            birth_rate: 0.5,
+            ..species("grass", Trophic::Producer, vec![])
+        };
+        let mut ecosystem = Ecosystem::new(vec![grass], Ecosystem::grid_regions(1, 1, &[10.0]));
+        let mut last = 10.0;
+        for _ in 0..2000 {
+            ecosystem.step(0.1);
+            let population = ecosystem.total_population(0);
+            assert!(population >= last && population <= 1000.0);
+            last = population;
+        }
+        assert!((last - 1000.0).abs() < 1.0, "ended at {}", last);
+
+        // Fastest at half capacity, as logistic growth is.
+        let growth = |start: f64| {
+            let grass = Species {
+                birth_rate: 0.5,
+                ..species("grass", Trophic::Producer, vec![])
+            };
+            let mut ecosystem =
+                Ecosystem::new(vec![grass], Ecosystem::grid_regions(1, 1, &[start]));
+            ecosystem.step(0.1);
+            ecosystem.total_population(0) - start
+        };
+        assert!(growth(500.0) > growth(100.0));
+        assert!(growth(500.0) > growth(900.0));
+    }
+
+    #[test]
+    fn predation_follows_holling_type_two() {
+        let (attack_rate, handling_time, predators, dt) = (0.05, 0.5, 10.0, 0.1);
+        let eaten = |prey_count: f64| {
+            let species = vec![
+                species("prey", Trophic::Producer, vec![]),
+                species(
+                    "predator",
+                    Trophic::Predator,
+                    vec![prey(0, attack_rate, handling_time)],
+                ),
+            ];
+            let mut ecosystem = Ecosystem::new(
+                species,
+                Ecosystem::grid_regions(1, 1, &[prey_count, predators]),
+            );
+            ecosystem.step(dt);
+            prey_count - ecosystem.total_population(0)
+        };
+        for prey_count in [10.0, 100.0, 1000.0, 10000.0] {
+            let expected = attack_rate * prey_count
+                / (1.0 + attack_rate * handling_time * prey_count)
+                * predators
+                * dt;
+            assert!((eaten(prey_count) - expected).abs() < 1e-9);
+        }
+        // Saturates at one prey per handling time per predator.
+        let limit = predators * dt / handling_time;
+        assert!(eaten(1e7) < limit && eaten(1e7) > limit * 0.99);
+    }
+
+    #[test]
+    fn populations_below_the_threshold_go_extinct() {
+        let dying = Species {
+            death_rate: 0.5,
+            ..species("dying", Trophic::Producer, vec![])
+        };
+        let stable = species("stable", Trophic::Producer, vec![]);
+        let mut ecosystem = Ecosystem::new(
+            vec![dying, stable],
+            Ecosystem::grid_regions(1, 1, &[1.0, EXTINCTION_THRESHOLD * 0.5]),
+        );
+        ecosystem.step(0.1);
+        assert_eq!(ecosystem.total_population(1), 0.0);
+        assert!(ecosystem.total_population(0) > 0.0);
+        for _ in 0..200 {
+            ecosystem.step(0.1);
+            let population = ecosystem.total_population(0);
+            assert!(population == 0.0 || population >= EXTINCTION_THRESHOLD);
+        }
+        assert_eq!(ecosystem.total_population(0), 0.0);
+    }
+
+    #[test]
+    fn stress_lowers_births_and_raises_deaths() {
+        assert_eq!(RateModifier::from_stress(0.0), RateModifier::default());
+        assert_eq!(RateModifier::from_stress(-1.0), RateModifier::default());
+        let modifier = RateModifier::from_stress(1.0);
+        assert_eq!(modifier.birth, 0.5);
+        assert_eq!(modifier.death, 2.0);
+        let worse = RateModifier::from_stress(3.0);
+        assert!(worse.birth < modifier.birth && worse.death > modifier.death);
+    }
+}
@@ -1,8 +1,11 @@
 use cgmath::{Matrix4, Quaternion, Vector3, Vector4};
 use gpu_allocator::vulkan::*;
+use std::collections::HashMap;
 use std::ffi::CStr;
+use std::mem::size_of;
 use std::os::raw::c_char;
-use std::{borrow::Cow, mem::size_of};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
 
 use ash::{
     extensions::{
@@ -12,19 +15,199 @@ use ash::{
     vk, Device, Entry, Instance,
 };
 
+use winit::dpi::PhysicalSize;
 use winit::window::Window;
 
-use crate::mesh::{monkey_mesh, MeshBuffer};
-use crate::pipeline::{build_pipeline, shader_stage_create_info, PushConstant};
+use crate::camera::Camera;
+use crate::compute::{
+    create_descriptor_pool, find_dedicated_compute_family, ComputePipeline, ComputeQueue,
+};
+use crate::culling::{CullStats, Frustum};
+use crate::debug::{self, ValidationMode, ValidationSettings};
+use crate::debug_draw::{self, LineBuffer};
+use crate::device::{self, DeviceCandidate, DeviceChoice};
+use crate::error::{EngineError, EngineResult};
+use crate::gpu_culling::{cull_pipeline, CullTarget};
+use crate::gpu_diffusion::{diffusion_pipeline, GpuDiffuser, GpuDiffusion, DIFFUSE_SHADER};
+use crate::mesh::{
+    indexed_monkey_mesh, monkey_mesh, tree_mesh, upload_mesh, InstanceBuffer, InstanceData,
+    MeshBuffer,
+};
+use crate::overlay::{OverlayFrame, OverlayRenderer};
+use crate::pipeline::{
+    build_line_pipeline, build_pipeline, shader_stage_create_info, PipelineTarget, PushConstant,
+};
+use crate::post::{self, PostChain, HDR_FORMAT};
+use crate::profiler::{self, GpuTimer};
+use crate::render_graph::{
+    ImageDesc, ImportedImage, Load, PassLayout, RenderGraph, RenderGraphCache,
+};
+use crate::terrain::{ChunkCoord, Terrain, TerrainSettings};
+
+pub const DEPTH_FORMAT: vk::Format = vk::Format::D32_SFLOAT;
+// Set to anything to draw with render pass objects even where dynamic rendering is
+// available.
+pub const RENDER_PASSES_ENV: &str = "ECOCIDE_RENDER_PASSES";
+const SKY_COLOR: [f32; 4] = [0.55, 0.7, 0.85, 1.0];
+
+// What the main pass draws this frame, decided before the graph is built.
+struct SceneFrame {
+    view_projection: Matrix4<f32>,
+    // The terrain chunks inside the frustum.
+    chunks: Vec<ChunkCoord>,
+    // The monkey's model matrix, when it is visible.
+    monkey: Option<Matrix4<f32>>,
+}
+
+// A mesh drawn any number of times with a single draw call. Instances outside the
+// view are culled every frame: on the GPU when the device supports indirect count
+// draws, otherwise on the CPU before uploading the survivors.
+pub struct InstanceBatch {
+    pub mesh: MeshBuffer,
+    pub instances: InstanceBuffer,
+    pub gpu_cull: Option<CullTarget>,
+    all: Vec<InstanceData>,
+    visible: Vec<InstanceData>,
+    changed: bool,
+}
+
+impl InstanceBatch {
+    fn new(
+        device: &Device,
+        allocator: &mut Allocator,
+        mesh: MeshBuffer,
+        cull_pipeline: Option<(&ComputePipeline, vk::DescriptorPool)>,
+    ) -> EngineResult<Self> {
+        let gpu_cull = match cull_pipeline {
+            Some((pipeline, pool)) => Some(CullTarget::new(device, allocator, pipeline, pool)?),
+            None => None,
+        };
+        Ok(InstanceBatch {
+            mesh,
+            instances: InstanceBuffer::new(device, allocator, 1024)?,
+            gpu_cull,
+            all: Vec::new(),
+            visible: Vec::new(),
+            changed: false,
+        })
+    }
+
+    pub fn set(&mut self, instances: Vec<InstanceData>) {
+        self.all = instances;
+        self.changed = true;
+    }
+
+    // Gets the buffers ready for this frame. Must only run once the GPU has
+    // finished the previous frame.
+    fn prepare(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        frustum: &Frustum,
+        stats: &mut CullStats,
+    ) -> EngineResult<()> {
+        match self.gpu_cull.as_mut() {
+            Some(target) => {
+                // The GPU's count is from the previous frame, read before it's reset.
+                let drawn = target.drawn().min(self.instances.count);
+                stats.drawn += drawn;
+                stats.culled += self.instances.count - drawn;
+                if self.changed {
+                    self.instances.write(device, allocator, &self.all)?;
+                    self.changed = false;
+                }
+                target.reserve(device, allocator, &self.instances)?;
+            }
+            None => {
+                let sphere = self.mesh.sphere;
+                self.visible.clear();
+                for instance in self.all.iter() {
+                    let visible = frustum.contains_sphere(&sphere.transform(&instance.transform));
+                    stats.record(visible);
+                    if visible {
+                        self.visible.push(*instance);
+                    }
+                }
+                self.instances.write(device, allocator, &self.visible)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        self.mesh.destroy(device, allocator);
+        self.instances.destroy(device, allocator);
+        if let Some(target) = self.gpu_cull.as_mut() {
+            target.destroy(device, allocator);
+        }
+    }
+}
+
+pub struct EngineSettings {
+    // Use a compute-only queue family for standalone compute work when the device
+    // has one. The work is still waited on, see `ComputeQueue`.
+    pub async_compute: bool,
+    // Draw with dynamic rendering and synchronization2 barriers when the device
+    // has them, rather than render pass and framebuffer objects.
+    pub dynamic_rendering: bool,
+    pub graphics: GraphicsSettings,
+    pub device: DeviceChoice,
+    pub validation: ValidationSettings,
+}
+
+impl Default for EngineSettings {
+    fn default() -> Self {
+        EngineSettings {
+            async_compute: true,
+            dynamic_rendering: true,
+            graphics: GraphicsSettings::default(),
+            device: DeviceChoice::Auto,
+            validation: ValidationSettings::default(),
+        }
+    }
+}
+
+// Everything here can change while running, see `VkEngine::set_graphics`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GraphicsSettings {
+    // Without vsync frames are presented as soon as they are done and may tear.
+    pub vsync: bool,
+    // Tried before anything `vsync` asks for, when the surface supports it.
+    pub present_mode: Option<vk::PresentModeKHR>,
+    // Most frames drawn per second, on top of whatever the present mode allows.
+    pub frame_limit: Option<u32>,
+    // Have the swapchain encode to sRGB, so shaders can output linear colour.
+    pub prefer_srgb: bool,
+    // Samples per pixel for the scene, 1 for none. Lowered to what the device
+    // can do.
+    pub msaa: u32,
+}
+
+impl Default for GraphicsSettings {
+    fn default() -> Self {
+        GraphicsSettings {
+            vsync: true,
+            present_mode: None,
+            frame_limit: None,
+            prefer_srgb: true,
+            msaa: 4,
+        }
+    }
+}
 
 pub struct VkEngine {
     pub entry: Entry,
     pub instance: Instance,
-    pub debug_utils_loader: DebugUtils,
+    // Only when the driver has VK_EXT_debug_utils.
+    pub debug_utils_loader: Option<DebugUtils>,
+    // Null without validation.
     pub debug_callback: vk::DebugUtilsMessengerEXT,
 
     pub device: Device,
     pub pdevice: vk::PhysicalDevice,
+    // Every device found, usable or not, and the one in use.
+    pub devices: Vec<DeviceCandidate>,
+    pub device_index: usize,
     pub queue_family_index: u32,
     pub present_queue: vk::Queue,
 
@@ -35,14 +218,31 @@ pub struct VkEngine {
     pub surface: vk::SurfaceKHR,
     pub surface_format: vk::SurfaceFormatKHR,
     pub surface_resolution: vk::Extent2D,
+    pub present_mode: vk::PresentModeKHR,
+    pub graphics: GraphicsSettings,
+    // When the last frame started, for the frame limit.
+    last_frame: Instant,
+    // The window's inner size in physical pixels, zero while minimized.
+    pub window_extent: vk::Extent2D,
+    pub scale_factor: f64,
+    // Set when the window changed size and the swapchain has to follow.
+    pub swapchain_dirty: bool,
 
     pub swapchain_loader: Swapchain,
     pub swapchain: vk::SwapchainKHR,
     pub present_images: Vec<vk::Image>,
     pub present_image_views: Vec<vk::ImageView>,
 
-    pub framebuffers: Vec<vk::Framebuffer>,
-    pub render_pass: vk::RenderPass,
+    // In effect for the scene pass, after clamping `graphics.msaa`.
+    pub samples: vk::SampleCountFlags,
+    // Every sample count both colour and depth attachments support.
+    supported_samples: vk::SampleCountFlags,
+
+    // Images, render passes and framebuffers the frames' render graphs use.
+    pub graph_cache: RenderGraphCache,
+    // Where to write the next frame's render graph, for graphviz.
+    pub graph_dump: Option<PathBuf>,
+    pub post: PostChain,
 
     pub present_semaphore: vk::Semaphore,
     pub render_semaphore: vk::Semaphore,
@@ -50,32 +250,70 @@ pub struct VkEngine {
 
     pub pipeline_layout: vk::PipelineLayout,
     pub pipeline: vk::Pipeline,
+    pub instanced_pipeline: vk::Pipeline,
+    pub line_pipeline: vk::Pipeline,
+    // Only when the device can draw indirect with a GPU written count.
+    pub cull_pipeline: Option<ComputePipeline>,
+    pub diffusion_pipeline: ComputePipeline,
+    // Created the first time diffusion runs on the GPU.
+    pub gpu_diffusion: Option<GpuDiffusion>,
+    pub descriptor_pool: vk::DescriptorPool,
+    pub compute: ComputeQueue,
+    // Only when the graphics queue can write timestamps.
+    pub gpu_timer: Option<GpuTimer>,
+    // Times the standalone compute work, when the compute queue can write timestamps.
+    pub compute_timer: Option<GpuTimer>,
 
     pub compiler: shaderc::Compiler,
     pub allocator: Option<Allocator>,
     pub meshes: MeshBuffer,
 
+    pub camera: Camera,
+    pub terrain: Terrain,
+    pub terrain_meshes: HashMap<ChunkCoord, MeshBuffer>,
+    pub vegetation: InstanceBatch,
+    pub fauna: InstanceBatch,
+    // What `debug_draw` collected for the frame being drawn.
+    pub debug_lines: LineBuffer,
+    pub overlay: OverlayRenderer,
+    // Handed over by `set_overlay`, applied by the next frame drawn.
+    overlay_frame: Option<OverlayFrame>,
+    // Counted while recording the last frame.
+    pub cull_stats: CullStats,
+
     pub frame_count: u32,
 }
 
 impl VkEngine {
-    pub fn new(window: &Window) -> Self {
+    pub fn new(window: &Window, settings: EngineSettings) -> EngineResult<Self> {
         unsafe {
             let entry = Entry::linked();
             let app_name = CStr::from_bytes_with_nul_unchecked(b"Ecocide\0");
-            let layer_names = [CStr::from_bytes_with_nul_unchecked(
-                b"VK_LAYER_KHRONOS_validation\0",
-            )];
+            let validation = &settings.validation;
+            let mut layer_names = Vec::new();
+            if validation.mode != ValidationMode::Off {
+                if debug::layer_available(&entry, debug::VALIDATION_LAYER) {
+                    layer_names.push(debug::VALIDATION_LAYER);
+                } else if validation.mode == ValidationMode::Required {
+                    return Err(EngineError::MissingLayer(
+                        debug::VALIDATION_LAYER.to_string_lossy().into_owned(),
+                    ));
+                } else {
+                    log::warn!("Validation layer not installed, running without it");
+                }
+            }
             let layers_names_raw: Vec<*const c_char> = layer_names
                 .iter()
                 .map(|raw_name| raw_name.as_ptr())
                 .collect();
 
-            let mut extension_names = ash_window::enumerate_required_extensions(window)
-                .unwrap()
-                .to_vec();
+            let mut extension_names = ash_window::enumerate_required_extensions(window)?.to_vec();
 
-            extension_names.push(DebugUtils::name().as_ptr());
+            let debug_utils_available =
+                debug::instance_extension_available(&entry, DebugUtils::name());
+            if debug_utils_available {
+                extension_names.push(DebugUtils::name().as_ptr());
+            }
             // extension_names.push(CStr::from_bytes_with_nul_unchecked(b"VK_KHR_device_group\0").as_ptr());
 
             let appinfo = vk::ApplicationInfo::builder()
@@ -91,61 +329,35 @@ impl VkEngine {
                 .enabled_extension_names(&extension_names)
                 .flags(vk::InstanceCreateFlags::default());
 
-            let instance: Instance = entry
-                .create_instance(&create_info, None)
-                .expect("Instance creation error");
+            let instance: Instance = entry.create_instance(&create_info, None)?;
 
-            let debug_info = vk::DebugUtilsMessengerCreateInfoEXT::builder()
-                .message_severity(
-                    vk::DebugUtilsMessageSeverityFlagsEXT::ERROR
-                        | vk::DebugUtilsMessageSeverityFlagsEXT::WARNING
-                        | vk::DebugUtilsMessageSeverityFlagsEXT::INFO,
-                )
-                .message_type(
-                    vk::DebugUtilsMessageTypeFlagsEXT::GENERAL
-                        | vk::DebugUtilsMessageTypeFlagsEXT::VALIDATION
-                        | vk::DebugUtilsMessageTypeFlagsEXT::PERFORMANCE,
-                )
-                .pfn_user_callback(Some(vulkan_debug_callback));
-
-            let debug_utils_loader = DebugUtils::new(&entry, &instance);
-            let debug_callback = debug_utils_loader
-                .create_debug_utils_messenger(&debug_info, None)
-                .unwrap();
-
-            let surface = ash_window::create_surface(&entry, &instance, &window, None).unwrap();
+            let debug_utils_loader =
+                debug_utils_available.then(|| DebugUtils::new(&entry, &instance));
+            let debug_callback = match debug_utils_loader.as_ref() {
+                Some(debug_utils) if validation.mode != ValidationMode::Off => {
+                    debug::create_messenger(debug_utils, validation)?
+                }
+                _ => vk::DebugUtilsMessengerEXT::null(),
+            };
+            if let Some(debug_utils) = debug_utils_loader.as_ref() {
+                debug::enable_names(debug_utils);
+            }
 
-            let pdevices = instance
-                .enumerate_physical_devices()
-                .expect("Physical Device Error");
+            let surface = ash_window::create_surface(&entry, &instance, &window, None)?;
 
             let surface_loader = Surface::new(&entry, &instance);
-            let (pdevice, queue_family_index) = pdevices
-                .iter()
-                .find_map(|pdevice| {
-                    instance
-                        .get_physical_device_queue_family_properties(*pdevice)
-                        .iter()
-                        .enumerate()
-                        .find_map(|(index, info)| {
-                            let supports_graphic_and_surface =
-                                info.queue_flags.contains(vk::QueueFlags::GRAPHICS)
-                                    && surface_loader
-                                        .get_physical_device_surface_support(
-                                            *pdevice,
-                                            index as u32,
-                                            surface,
-                                        )
-                                        .unwrap();
-                            if supports_graphic_and_surface {
-                                Some((*pdevice, index))
-                            } else {
-                                None
-                            }
-                        })
+            let devices = device::candidates(&instance, &surface_loader, surface)?;
+            // A device asked for by name or index that can't be used falls back to
+            // the best one there is.
+            let chosen = device::select(&devices, &settings.device)
+                .or_else(|e| {
+                    log::warn!("{:#}, picking a device automatically", e);
+                    device::select(&devices, &DeviceChoice::Auto)
                 })
-                .expect("Couldn't find suitable device.");
-            let queue_family_index = queue_family_index as u32;
+                .map_err(|e| EngineError::NoSuitableDevice(format!("{:#}", e)))?;
+            let (pdevice, device_index) = (chosen.pdevice, chosen.index);
+            // Usable devices always have one.
+            let queue_family_index = chosen.queue_family.unwrap();
             let device_extension_names_raw = [Swapchain::name().as_ptr()];
             let features = vk::PhysicalDeviceFeatures {
                 shader_clip_distance: 1,
@@ -153,24 +365,60 @@ impl VkEngine {
             };
             let priorities = [1.0];
 
-            let queue_info = vk::DeviceQueueCreateInfo::builder()
+            let compute_family = if settings.async_compute {
+                find_dedicated_compute_family(&instance, pdevice)
+            } else {
+                None
+            };
+            let mut queue_infos = vec![vk::DeviceQueueCreateInfo::builder()
                 .queue_family_index(queue_family_index)
-                .queue_priorities(&priorities);
+                .queue_priorities(&priorities)
+                .build()];
+            if let Some(family) = compute_family {
+                queue_infos.push(
+                    vk::DeviceQueueCreateInfo::builder()
+                        .queue_family_index(family)
+                        .queue_priorities(&priorities)
+                        .build(),
+                );
+            }
 
-            let device_create_info = vk::DeviceCreateInfo::builder()
-                .queue_create_infos(std::slice::from_ref(&queue_info))
+            let gpu_culling = supports_gpu_culling(&instance, pdevice, queue_family_index);
+            let mut vulkan12_features =
+                vk::PhysicalDeviceVulkan12Features::builder().draw_indirect_count(true);
+            let dynamic_rendering =
+                settings.dynamic_rendering && supports_dynamic_rendering(&instance, pdevice);
+            let mut vulkan13_features = vk::PhysicalDeviceVulkan13Features::builder()
+                .dynamic_rendering(true)
+                .synchronization2(true);
+            let mut device_create_info = vk::DeviceCreateInfo::builder()
+                .queue_create_infos(&queue_infos)
                 .enabled_extension_names(&device_extension_names_raw)
                 .enabled_features(&features);
+            if gpu_culling {
+                device_create_info = device_create_info.push_next(&mut vulkan12_features);
+            }
+            if dynamic_rendering {
+                device_create_info = device_create_info.push_next(&mut vulkan13_features);
+            }
+            log::info!(
+                "Drawing with {}",
+                if dynamic_rendering {
+                    "dynamic rendering"
+                } else {
+                    "render pass objects"
+                }
+            );
 
-            let device: Device = instance
-                .create_device(pdevice, &device_create_info, None)
-                .unwrap();
+            let device: Device = instance.create_device(pdevice, &device_create_info, None)?;
             let swapchain_loader = Swapchain::new(&instance, &device);
             let present_queue = device.get_device_queue(queue_family_index as u32, 0);
+            let window_extent = physical_extent(window.inner_size());
             let (
                 swapchain,
                 surface_resolution,
                 surface_format,
+                present_mode,
                 present_images,
                 present_image_views,
             ) = create_swapchain(
@@ -179,48 +427,64 @@ impl VkEngine {
                 &surface_loader,
                 &surface,
                 &swapchain_loader,
+                window_extent,
+                &settings.graphics,
                 None,
-            );
+            )?;
 
             let pool_create_info = vk::CommandPoolCreateInfo::builder()
                 .flags(vk::CommandPoolCreateFlags::RESET_COMMAND_BUFFER)
                 .queue_family_index(queue_family_index);
 
-            let command_pool = device.create_command_pool(&pool_create_info, None).unwrap();
+            let command_pool = device.create_command_pool(&pool_create_info, None)?;
 
             let command_buffer_allocate_info = vk::CommandBufferAllocateInfo::builder()
                 .command_buffer_count(1)
                 .command_pool(command_pool)
                 .level(vk::CommandBufferLevel::PRIMARY);
 
-            let command_buffer = device
-                .allocate_command_buffers(&command_buffer_allocate_info)
-                .unwrap()[0];
+            let command_buffer = device.allocate_command_buffers(&command_buffer_allocate_info)?[0];
+            debug::set_name(&device, command_pool, "Frame");
+            debug::set_name(&device, command_buffer, "Frame");
 
-            let render_pass = create_render_pass(&device, surface_format);
-            let framebuffers = create_framebuffers(
-                &device,
-                &present_image_views,
-                surface_resolution,
-                render_pass,
-            );
+            let mut allocator = Allocator::new(&AllocatorCreateDesc {
+                instance: instance.clone(),
+                device: device.clone(),
+                physical_device: pdevice,
+                debug_settings: Default::default(),
+                buffer_device_address: false,
+            })?;
 
-            let semaphore_create_info = vk::SemaphoreCreateInfo::default();
+            let limits = instance.get_physical_device_properties(pdevice).limits;
+            let supported_samples =
+                limits.framebuffer_color_sample_counts & limits.framebuffer_depth_sample_counts;
+            let samples = sample_count(settings.graphics.msaa, supported_samples);
 
-            let present_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let render_semaphore = device
-                .create_semaphore(&semaphore_create_info, None)
-                .unwrap();
-            let render_fence = device
-                .create_fence(
-                    &vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED),
-                    None,
-                )
-                .unwrap();
+            let semaphore_create_info = vk::SemaphoreCreateInfo::default();
 
-            let compiler = shaderc::Compiler::new().unwrap();
+            let present_semaphore = device.create_semaphore(&semaphore_create_info, None)?;
+            let render_semaphore = device.create_semaphore(&semaphore_create_info, None)?;
+            let render_fence = device.create_fence(
+                &vk::FenceCreateInfo::builder().flags(vk::FenceCreateFlags::SIGNALED),
+                None,
+            )?;
+            debug::set_name(&device, present_semaphore, "Present");
+            debug::set_name(&device, render_semaphore, "Render");
+            debug::set_name(&device, render_fence, "Render");
+
+            let compiler = shaderc::Compiler::new().ok_or_else(|| EngineError::Shader {
+                file: "-".to_string(),
+                reason: "could not create a compiler".to_string(),
+            })?;
+
+            let mut graph_cache = RenderGraphCache::new(dynamic_rendering);
+            let post = PostChain::new(
+                &device,
+                &mut allocator,
+                &compiler,
+                &mut graph_cache,
+                surface_format.format,
+            )?;
 
             let push_constant_ranges = [vk::PushConstantRange {
                 stage_flags: vk::ShaderStageFlags::VERTEX,
@@ -232,45 +496,76 @@ impl VkEngine {
                 .flags(vk::PipelineLayoutCreateFlags::empty())
                 .set_layouts(&[])
                 .push_constant_ranges(&push_constant_ranges);
-            let pipeline_layout = device
-                .create_pipeline_layout(&pipeline_layout_info, None)
-                .unwrap();
+            let pipeline_layout = device.create_pipeline_layout(&pipeline_layout_info, None)?;
+            debug::set_name(&device, pipeline_layout, "Scene");
+            let scene_target =
+                graph_cache.pipeline_target(&device, &scene_layout(samples), "Scene")?;
+            let (pipeline, instanced_pipeline, line_pipeline) =
+                build_scene_pipelines(&device, &compiler, &scene_target, pipeline_layout, samples)?;
             let shaders = vec![
                 compile_shader(
                     &device,
                     &compiler,
-                    "assets/shaders/triangle.frag",
-                    shaderc::ShaderKind::Fragment,
-                ),
+                    "assets/shaders/cull.comp",
+                    shaderc::ShaderKind::Compute,
+                )?,
                 compile_shader(
                     &device,
                     &compiler,
-                    "assets/shaders/triangle.vert",
-                    shaderc::ShaderKind::Vertex,
-                ),
+                    DIFFUSE_SHADER,
+                    shaderc::ShaderKind::Compute,
+                )?,
             ];
-            let shader_info = vec![
-                shader_stage_create_info(vk::ShaderStageFlags::FRAGMENT, shaders[0]).build(),
-                shader_stage_create_info(vk::ShaderStageFlags::VERTEX, shaders[1]).build(),
-            ];
-
-            let pipeline = build_pipeline(&device, render_pass, &shader_info, pipeline_layout);
+            let cull_pipeline = if gpu_culling {
+                Some(cull_pipeline(&device, shaders[0])?)
+            } else {
+                None
+            };
+            let diffusion_pipeline = diffusion_pipeline(&device, shaders[1])?;
+            let descriptor_pool = create_descriptor_pool(&device, 16)?;
+            debug::set_name(&device, descriptor_pool, "Compute");
+            let compute = ComputeQueue::new(
+                &device,
+                compute_family.unwrap_or(queue_family_index),
+                compute_family.is_some(),
+            )?;
+            let gpu_timer = GpuTimer::new(&instance, pdevice, &device, queue_family_index);
+            let compute_timer = GpuTimer::new(&instance, pdevice, &device, compute.family);
 
             for shader in shaders {
                 device.destroy_shader_module(shader, None)
             }
 
-            let mut allocator = Allocator::new(&AllocatorCreateDesc {
-                instance: instance.clone(),
-                device: device.clone(),
-                physical_device: pdevice,
-                debug_settings: Default::default(),
-                buffer_device_address: false,
-            })
-            .unwrap();
-
-            let meshes = monkey_mesh(&device, &mut allocator);
-            VkEngine {
+            let meshes = monkey_mesh(&device, &mut allocator)?;
+            let cull_with = cull_pipeline
+                .as_ref()
+                .map(|pipeline| (pipeline, descriptor_pool));
+            let tree = tree_mesh(&device, &mut allocator)?;
+            let vegetation = InstanceBatch::new(&device, &mut allocator, tree, cull_with)?;
+            let animal = indexed_monkey_mesh(&device, &mut allocator)?;
+            let fauna = InstanceBatch::new(&device, &mut allocator, animal, cull_with)?;
+            let debug_lines = LineBuffer::new(&device, &mut allocator, 4096)?;
+            let overlay_target = graph_cache.pipeline_target(
+                &device,
+                &overlay_layout(surface_format.format),
+                "Overlay",
+            )?;
+            let overlay = OverlayRenderer::new(
+                &device,
+                &compiler,
+                &overlay_target,
+                vk::SampleCountFlags::TYPE_1,
+            )?;
+
+            let terrain = Terrain::new(TerrainSettings::default());
+            let center = terrain.heightfield.extent() * 0.5;
+            let camera = Camera::new(Vector3::new(
+                center,
+                terrain.heightfield.sample(center, center + 24f32) + 16f32,
+                center + 24f32,
+            ));
+            debug::check_validation();
+            Ok(VkEngine {
                 entry,
                 instance,
                 debug_utils_loader,
@@ -278,6 +573,8 @@ impl VkEngine {
                 device,
                 queue_family_index,
                 pdevice,
+                devices,
+                device_index,
                 surface_loader,
                 present_queue,
                 command_pool,
@@ -285,168 +582,583 @@ impl VkEngine {
                 surface,
                 surface_format,
                 surface_resolution,
+                present_mode,
+                graphics: settings.graphics,
+                last_frame: Instant::now(),
+                window_extent,
+                scale_factor: window.scale_factor(),
+                swapchain_dirty: false,
                 swapchain,
                 swapchain_loader,
                 present_images,
                 present_image_views,
-                framebuffers,
-                render_pass,
+                samples,
+                supported_samples,
+                graph_cache,
+                graph_dump: None,
+                post,
                 render_fence,
                 render_semaphore,
                 present_semaphore,
                 pipeline_layout,
                 pipeline,
+                instanced_pipeline,
+                line_pipeline,
+                cull_pipeline,
+                diffusion_pipeline,
+                gpu_diffusion: None,
+                descriptor_pool,
+                compute,
+                gpu_timer,
+                compute_timer,
                 compiler,
                 allocator: Some(allocator),
                 meshes,
+                camera,
+                terrain,
+                terrain_meshes: HashMap::new(),
+                vegetation,
+                fauna,
+                debug_lines,
+                overlay,
+                overlay_frame: None,
+                cull_stats: CullStats::default(),
                 frame_count: 0,
+            })
+        }
+    }
+
+    // Runs the environment's diffusion on the compute queue, for grids of the given
+    // size.
+    pub fn gpu_diffuser(&mut self, width: usize, height: usize) -> EngineResult<GpuDiffuser<'_>> {
+        let allocator = self.allocator.as_mut().unwrap();
+        if let Some(diffusion) = self.gpu_diffusion.as_mut() {
+            if (diffusion.width, diffusion.height) != (width, height) {
+                self.compute.wait(&self.device)?;
+                diffusion.destroy(&self.device, allocator);
+                self.gpu_diffusion = None;
             }
         }
+        if self.gpu_diffusion.is_none() {
+            self.gpu_diffusion = Some(GpuDiffusion::new(
+                &self.device,
+                allocator,
+                &self.diffusion_pipeline,
+                self.descriptor_pool,
+                width,
+                height,
+            )?);
+        }
+        let diffusion = self.gpu_diffusion.as_mut().unwrap();
+        Ok(GpuDiffuser {
+            device: &self.device,
+            queue: &mut self.compute,
+            diffusion,
+            timer: self.compute_timer.as_mut(),
+            error: None,
+        })
     }
 
-    pub unsafe fn recreate_swapchain(&mut self) {
-        self.device.device_wait_idle().unwrap();
+    // Called with the window's new inner size whenever it changes, including when
+    // the scale factor does.
+    pub fn resize(&mut self, size: PhysicalSize<u32>) {
+        let extent = physical_extent(size);
+        if extent != self.window_extent {
+            self.window_extent = extent;
+            self.swapchain_dirty = true;
+        }
+    }
 
-        let (swapchain, surface_resolution, surface_format, present_images, present_image_views) =
-            create_swapchain(
-                &self.device,
-                &self.pdevice,
-                &self.surface_loader,
-                &self.surface,
-                &self.swapchain_loader,
-                Some(self.swapchain),
-            );
+    fn minimized(&self) -> bool {
+        self.window_extent.width == 0 || self.window_extent.height == 0
+    }
 
-        for framebuffer in std::mem::take(&mut self.framebuffers) {
-            self.device.destroy_framebuffer(framebuffer, None);
+    // Leaves the swapchain alone and returns false while there is nothing to
+    // present to, as when the window is minimized.
+    pub unsafe fn recreate_swapchain(&mut self) -> EngineResult<bool> {
+        let capabilities = self
+            .surface_loader
+            .get_physical_device_surface_capabilities(self.pdevice, self.surface)?;
+        let extent = surface_extent(&capabilities, self.window_extent);
+        if extent.width == 0 || extent.height == 0 {
+            return Ok(false);
         }
+        self.device.device_wait_idle()?;
+
+        let (
+            swapchain,
+            surface_resolution,
+            surface_format,
+            present_mode,
+            present_images,
+            present_image_views,
+        ) = create_swapchain(
+            &self.device,
+            &self.pdevice,
+            &self.surface_loader,
+            &self.surface,
+            &self.swapchain_loader,
+            self.window_extent,
+            &self.graphics,
+            Some(self.swapchain),
+        )?;
+
+        self.graph_cache.release_framebuffers(&self.device);
 
         for image_view in std::mem::take(&mut self.present_image_views) {
             self.device.destroy_image_view(image_view, None);
         }
 
-        self.device.destroy_render_pass(self.render_pass, None);
-
         self.swapchain_loader
             .destroy_swapchain(self.swapchain, None);
 
         self.swapchain = swapchain;
         self.surface_resolution = surface_resolution;
+        let format_changed = surface_format.format != self.surface_format.format;
         self.surface_format = surface_format;
+        self.present_mode = present_mode;
         self.present_images = present_images;
         self.present_image_views = present_image_views;
-        self.render_pass = create_render_pass(&self.device, self.surface_format);
-        self.framebuffers = create_framebuffers(
+
+        let samples = sample_count(self.graphics.msaa, self.supported_samples);
+        let samples_changed = samples != self.samples;
+        self.samples = samples;
+        self.post.set_output_format(
             &self.device,
-            &self.present_image_views,
-            self.surface_resolution,
-            self.render_pass,
-        )
+            &self.compiler,
+            &mut self.graph_cache,
+            self.surface_format.format,
+        )?;
+        if samples_changed {
+            self.device.destroy_pipeline(self.pipeline, None);
+            self.device.destroy_pipeline(self.instanced_pipeline, None);
+            self.device.destroy_pipeline(self.line_pipeline, None);
+            let target = self.graph_cache.pipeline_target(
+                &self.device,
+                &scene_layout(self.samples),
+                "Scene",
+            )?;
+            let (pipeline, instanced_pipeline, line_pipeline) = build_scene_pipelines(
+                &self.device,
+                &self.compiler,
+                &target,
+                self.pipeline_layout,
+                self.samples,
+            )?;
+            self.pipeline = pipeline;
+            self.instanced_pipeline = instanced_pipeline;
+            self.line_pipeline = line_pipeline;
+        }
+        if format_changed {
+            let target = self.graph_cache.pipeline_target(
+                &self.device,
+                &overlay_layout(self.surface_format.format),
+                "Overlay",
+            )?;
+            self.overlay.rebuild_pipeline(
+                &self.device,
+                &self.compiler,
+                &target,
+                vk::SampleCountFlags::TYPE_1,
+            )?;
+        }
+        self.swapchain_dirty = false;
+        Ok(true)
+    }
+
+    // What to draw over the scene from the next frame on. Texture changes in it are
+    // never skipped: a frame that isn't drawn keeps them for the one that is.
+    pub fn set_overlay(&mut self, mut frame: OverlayFrame) {
+        if let Some(mut skipped) = self.overlay_frame.take() {
+            skipped.textures.append(frame.textures);
+            frame.textures = skipped.textures;
+        }
+        self.overlay_frame = Some(frame);
+    }
+
+    // Takes effect from the next frame, recreating the swapchain if needed.
+    pub fn set_graphics(&mut self, graphics: GraphicsSettings) {
+        let swapchain_changed = graphics.vsync != self.graphics.vsync
+            || graphics.present_mode != self.graphics.present_mode
+            || graphics.prefer_srgb != self.graphics.prefer_srgb
+            || graphics.msaa != self.graphics.msaa;
+        self.graphics = graphics;
+        self.swapchain_dirty |= swapchain_changed;
+    }
+
+    // Sleeps off whatever is left of the frame limit's frame time.
+    fn limit_frame_rate(&mut self) {
+        if let Some(limit) = self.graphics.frame_limit.filter(|&limit| limit > 0) {
+            let frame_time = Duration::from_secs_f64(1.0 / limit as f64);
+            let elapsed = self.last_frame.elapsed();
+            if elapsed < frame_time {
+                std::thread::sleep(frame_time - elapsed);
+            }
+        }
+        self.last_frame = Instant::now();
+    }
+
+    // Builds and uploads the terrain chunks the camera needs, and frees the ones it
+    // has moved away from. Must only run once the previous frame's fence has signalled.
+    fn stream_terrain(&mut self) -> EngineResult<()> {
+        let update = self.terrain.stream(self.camera.position);
+        let allocator = self.allocator.as_mut().unwrap();
+        for coord in update.unload {
+            if let Some(mut mesh) = self.terrain_meshes.remove(&coord) {
+                mesh.destroy(&self.device, allocator);
+            }
+        }
+        for chunk in update.load {
+            let mesh = upload_mesh(&self.device, allocator, "Terrain chunk", chunk.vertices)?;
+            if let Some(mut old) = self.terrain_meshes.insert(chunk.coord, mesh) {
+                old.destroy(&self.device, allocator);
+            }
+        }
+        Ok(())
+    }
+
+    unsafe fn draw_mesh(&self, mesh: &MeshBuffer, render_matrix: Matrix4<f32>) {
+        let buffers = [mesh.buffer];
+        let offsets = [0];
+        self.device
+            .cmd_bind_vertex_buffers(self.command_buffer, 0, &buffers, &offsets);
+        self.push_constants(render_matrix);
+        self.device
+            .cmd_draw(self.command_buffer, mesh.vertex_count, 1, 0, 0);
+    }
+
+    // Expects the instanced pipeline to be bound.
+    unsafe fn draw_instanced(&self, batch: &InstanceBatch, view_projection: Matrix4<f32>) {
+        if batch.instances.count == 0 {
+            return;
+        }
+        if let Some(target) = batch.gpu_cull.as_ref() {
+            self.push_constants(view_projection);
+            target.draw(&self.device, self.command_buffer, &batch.mesh);
+            return;
+        }
+        let buffers = [batch.mesh.buffer, batch.instances.buffer];
+        let offsets = [0, 0];
+        self.device
+            .cmd_bind_vertex_buffers(self.command_buffer, 0, &buffers, &offsets);
+        self.push_constants(view_projection);
+        self.device.cmd_draw(
+            self.command_buffer,
+            batch.mesh.vertex_count,
+            batch.instances.count,
+            0,
+            0,
+        );
+    }
+
+    // The compute half of the frame: culls the instanced batches into their
+    // indirect draws.
+    unsafe fn record_cull(&self, frustum: &Frustum) {
+        let Some(pipeline) = self.cull_pipeline.as_ref() else {
+            return;
+        };
+        for batch in [&self.vegetation, &self.fauna] {
+            if let (Some(target), count @ 1..) = (&batch.gpu_cull, batch.instances.count) {
+                target.record(
+                    &self.device,
+                    self.command_buffer,
+                    pipeline,
+                    &batch.mesh,
+                    count,
+                    frustum,
+                );
+            }
+        }
     }
 
-    pub fn draw(&mut self) {
+    // Everything the main pass draws, inside the render pass the graph began.
+    unsafe fn record_scene(&self, frame: &SceneFrame) {
+        let view_projection = frame.view_projection;
+        self.device.cmd_bind_pipeline(
+            self.command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.pipeline,
+        );
+
+        debug::begin_label(self.command_buffer, "Terrain", debug::DRAW_LABEL);
+        for mesh in frame
+            .chunks
+            .iter()
+            .filter_map(|coord| self.terrain_meshes.get(coord))
+        {
+            // Chunk vertices are already in world space.
+            self.draw_mesh(mesh, view_projection);
+        }
+        debug::end_label(self.command_buffer);
+
+        if let Some(model) = frame.monkey {
+            debug::begin_label(self.command_buffer, "Monkey", debug::DRAW_LABEL);
+            self.draw_mesh(&self.meshes, view_projection * model);
+            debug::end_label(self.command_buffer);
+        }
+
+        self.device.cmd_bind_pipeline(
+            self.command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.instanced_pipeline,
+        );
+        debug::begin_label(self.command_buffer, "Vegetation", debug::DRAW_LABEL);
+        self.draw_instanced(&self.vegetation, view_projection);
+        debug::end_label(self.command_buffer);
+        debug::begin_label(self.command_buffer, "Fauna", debug::DRAW_LABEL);
+        self.draw_instanced(&self.fauna, view_projection);
+        debug::end_label(self.command_buffer);
+
+        // Last, depth tested against the finished scene.
+        if self.debug_lines.vertex_count > 0 {
+            debug::begin_label(self.command_buffer, "Debug lines", debug::DRAW_LABEL);
+            self.device.cmd_bind_pipeline(
+                self.command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.line_pipeline,
+            );
+            self.push_constants(view_projection);
+            self.device.cmd_bind_vertex_buffers(
+                self.command_buffer,
+                0,
+                &[self.debug_lines.buffer],
+                &[0],
+            );
+            self.device
+                .cmd_draw(self.command_buffer, self.debug_lines.vertex_count, 1, 0, 0);
+            debug::end_label(self.command_buffer);
+        }
+    }
+
+    unsafe fn push_constants(&self, render_matrix: Matrix4<f32>) {
+        let push_constant = std::mem::transmute::<PushConstant, [u8; 80]>(PushConstant {
+            data: Vector4::<f32>::new(0f32, 0f32, 0f32, 0f32),
+            render_matrix,
+        });
+        self.device.cmd_push_constants(
+            self.command_buffer,
+            self.pipeline_layout,
+            vk::ShaderStageFlags::VERTEX,
+            0,
+            &push_constant,
+        );
+    }
+
+    // Frames that can't be drawn right now, such as while minimized, are skipped
+    // without an error.
+    pub fn draw(&mut self) -> EngineResult<()> {
+        if self.minimized() {
+            return Ok(());
+        }
+        self.limit_frame_rate();
         unsafe {
+            if self.swapchain_dirty && !self.recreate_swapchain()? {
+                return Ok(());
+            }
             let swapchain_index = match self.swapchain_loader.acquire_next_image(
                 self.swapchain,
                 1000000000,
                 self.present_semaphore,
                 vk::Fence::null(),
             ) {
-                Ok((_, true)) | Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
-                    self.recreate_swapchain();
-                    return;
+                Err(vk::Result::ERROR_OUT_OF_DATE_KHR) => {
+                    self.swapchain_dirty = true;
+                    return Ok(());
                 }
-                Ok((index, _)) => index,
-                Err(_) => panic!("Error getting next swapchain image"),
+                // A suboptimal acquire still signals `present_semaphore`, so the image
+                // has to be drawn and presented before the swapchain is recreated.
+                Ok((index, suboptimal)) => {
+                    self.swapchain_dirty |= suboptimal;
+                    index
+                }
+                Err(e) => return Err(e.into()),
             };
 
             self.device
-                .wait_for_fences(&[self.render_fence], true, 1000000000)
-                .unwrap();
-            self.device.reset_fences(&[self.render_fence]).unwrap();
+                .wait_for_fences(&[self.render_fence], true, 1000000000)?;
+            self.device.reset_fences(&[self.render_fence])?;
+
+            {
+                let _scope = profiler::scope("Stream terrain");
+                self.stream_terrain()?;
+            }
+
+            let _scope = profiler::scope("Record frame");
+            let aspect =
+                self.surface_resolution.width as f32 / self.surface_resolution.height as f32;
+            let view_projection = self.camera.view_projection(aspect);
+            let frustum = Frustum::from_matrix(&view_projection);
+            let mut stats = CullStats::default();
+            let allocator = self.allocator.as_mut().unwrap();
+            self.vegetation
+                .prepare(&self.device, allocator, &frustum, &mut stats)?;
+            self.fauna
+                .prepare(&self.device, allocator, &frustum, &mut stats)?;
+            let right = self.camera.right();
+            let lines = debug_draw::take_lines(right, right.cross(self.camera.forward()));
+            self.debug_lines.write(&self.device, allocator, &lines)?;
+
+            // Chunk vertices are already in world space.
+            let chunks = self
+                .terrain_meshes
+                .iter()
+                .filter(|(_, mesh)| {
+                    let visible = frustum.contains_aabb(&mesh.bounds);
+                    stats.record(visible);
+                    visible
+                })
+                .map(|(&coord, _)| coord)
+                .collect();
+            let center = self.terrain.heightfield.extent() * 0.5;
+            let qrot: Quaternion<f32> = cgmath::Rotation3::from_axis_angle(
+                Vector3::new(0f32, 1f32, 0f32),
+                cgmath::Rad(0.03f32 * self.frame_count as f32),
+            );
+            let model = Matrix4::from_translation(Vector3::new(
+                center,
+                self.terrain.heightfield.sample(center, center) + 4f32,
+                center,
+            )) * Matrix4::from(qrot);
+            let visible = frustum.contains_aabb(&self.meshes.bounds.transform(&model));
+            stats.record(visible);
+            self.cull_stats = stats;
+            let scene_frame = SceneFrame {
+                view_projection,
+                chunks,
+                monkey: visible.then_some(model),
+            };
 
             self.device
-                .reset_command_buffer(self.command_buffer, vk::CommandBufferResetFlags::empty())
-                .unwrap();
+                .reset_command_buffer(self.command_buffer, vk::CommandBufferResetFlags::empty())?;
             let command_begin_info = vk::CommandBufferBeginInfo::builder()
                 .flags(vk::CommandBufferUsageFlags::ONE_TIME_SUBMIT);
             self.device
-                .begin_command_buffer(self.command_buffer, &command_begin_info)
-                .unwrap();
-
-            let render_pass_begin_info = vk::RenderPassBeginInfo::builder()
-                .render_pass(self.render_pass)
-                .render_area(vk::Rect2D {
-                    offset: vk::Offset2D { x: 0, y: 0 },
-                    extent: self.surface_resolution,
-                })
-                .framebuffer(self.framebuffers[swapchain_index as usize])
-                .clear_values(&[vk::ClearValue {
-                    color: vk::ClearColorValue {
-                        float32: [0f32, 0f32, 0f32, 1f32],
-                    },
-                }]);
-            self.device.cmd_begin_render_pass(
+                .begin_command_buffer(self.command_buffer, &command_begin_info)?;
+            if let Some(timer) = self.gpu_timer.as_mut() {
+                timer.begin_frame(&self.device, self.command_buffer);
+            }
+            if let Some(frame) = self.overlay_frame.take() {
+                self.overlay.prepare(
+                    &self.device,
+                    self.allocator.as_mut().unwrap(),
+                    self.command_buffer,
+                    frame,
+                    self.surface_resolution,
+                )?;
+            }
+            self.post.prepare(
+                &self.device,
+                self.allocator.as_mut().unwrap(),
                 self.command_buffer,
-                &render_pass_begin_info,
-                vk::SubpassContents::INLINE,
+            )?;
+
+            let extent = self.surface_resolution;
+            let mut graph: RenderGraph<VkEngine> = RenderGraph::default();
+            let swapchain = graph.import_image(
+                "Swapchain",
+                ImportedImage {
+                    image: self.present_images[swapchain_index as usize],
+                    view: self.present_image_views[swapchain_index as usize],
+                    desc: ImageDesc {
+                        format: self.surface_format.format,
+                        extent,
+                        samples: vk::SampleCountFlags::TYPE_1,
+                    },
+                    layout: vk::ImageLayout::UNDEFINED,
+                    // Where the submit waits for the image to be acquired.
+                    access: (
+                        vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                        vk::AccessFlags::empty(),
+                    ),
+                    final_layout: Some(vk::ImageLayout::PRESENT_SRC_KHR),
+                },
             );
-            self.device.cmd_bind_pipeline(
-                self.command_buffer,
-                vk::PipelineBindPoint::GRAPHICS,
-                self.pipeline,
+            let scene = graph.create_image("HDR scene", PostChain::scene_desc(extent));
+            let depth = graph.create_image(
+                "Depth",
+                ImageDesc {
+                    format: DEPTH_FORMAT,
+                    extent,
+                    samples: self.samples,
+                },
             );
-            let scissor = vk::Rect2D {
-                offset: vk::Offset2D { x: 0, y: 0 },
-                extent: self.surface_resolution,
-            };
-            let viewport = vk::Viewport {
-                x: 0f32,
-                y: 0f32,
-                width: self.surface_resolution.width as f32,
-                height: self.surface_resolution.height as f32,
-                min_depth: 0.0f32,
-                max_depth: 1.0f32,
+            let multisampled = self.samples != vk::SampleCountFlags::TYPE_1;
+            let color = if multisampled {
+                graph.create_image(
+                    "Multisampled colour",
+                    ImageDesc {
+                        format: HDR_FORMAT,
+                        extent,
+                        samples: self.samples,
+                    },
+                )
+            } else {
+                scene
             };
-            let scissors = [scissor];
-            let viewports = [viewport];
-            self.device
-                .cmd_set_viewport(self.command_buffer, 0, &viewports);
-            self.device
-                .cmd_set_scissor(self.command_buffer, 0, &scissors);
-
-            let buffers = [self.meshes.buffer];
-            let offsets = [0];
-			self.device
-                .cmd_bind_vertex_buffers(self.command_buffer, 0, &buffers, &offsets);
-
-            let qrot: Quaternion<f32> = cgmath::Rotation3::from_axis_angle(
-                Vector3::new(0f32, 1f32, 0f32),
-                cgmath::Rad(0.03f32 * self.frame_count as f32),
-            );
-            let model: Matrix4<f32> = qrot.into();
-            let view = cgmath::Matrix4::from_translation(Vector3::new(0f32, 0f32, -2f32));
-            let mut projection =
-                cgmath::perspective(cgmath::Deg(90f32), 800f32 / 600f32, 0.1f32, 200f32);
-			projection[1][1] *= -1f32;
-            let push_constant = std::mem::transmute::<PushConstant, [u8; 80]>(PushConstant {
-                data: Vector4::<f32>::new(0f32, 0f32, 0f32, 0f32),
-                render_matrix: projection * view * model,
-            });
-
-            self.device.cmd_push_constants(
-                self.command_buffer,
-                self.pipeline_layout,
-                vk::ShaderStageFlags::VERTEX,
-                0,
-                &push_constant,
+            // The CPU reads the counts back once the frame's fence has signalled.
+            let culled = graph.import_buffer(
+                "Culled instances",
+                Some((vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ)),
             );
 
-            self.device
-                .cmd_draw(self.command_buffer, self.meshes.vertex_count, 1, 0, 0);
-
-            self.device.cmd_end_render_pass(self.command_buffer);
-            self.device.end_command_buffer(self.command_buffer).unwrap();
+            if self.cull_pipeline.is_some() {
+                graph
+                    .pass("Cull")
+                    .write_buffer(
+                        culled,
+                        (
+                            vk::PipelineStageFlags::TRANSFER
+                                | vk::PipelineStageFlags::COMPUTE_SHADER,
+                            vk::AccessFlags::TRANSFER_WRITE | vk::AccessFlags::SHADER_WRITE,
+                        ),
+                    )
+                    .record(move |engine, _| engine.record_cull(&frustum));
+            }
+            let mut main_pass = graph.pass("Main pass").color(color, Load::Clear(SKY_COLOR));
+            if multisampled {
+                main_pass = main_pass.resolve(scene);
+            }
+            main_pass
+                .depth(depth, Load::Clear([1.0, 0.0, 0.0, 0.0]))
+                .read_buffer(
+                    culled,
+                    (
+                        vk::PipelineStageFlags::DRAW_INDIRECT
+                            | vk::PipelineStageFlags::VERTEX_INPUT,
+                        vk::AccessFlags::INDIRECT_COMMAND_READ
+                            | vk::AccessFlags::VERTEX_ATTRIBUTE_READ,
+                    ),
+                )
+                .record(move |engine, _| engine.record_scene(&scene_frame));
+            self.post.add_passes(&mut graph, scene, swapchain);
+            let srgb_target = post::encodes_srgb(self.surface_format.format);
+            graph
+                .pass("Overlay")
+                .color(swapchain, Load::Keep)
+                .record(move |engine, context| {
+                    engine.overlay.record(
+                        context.device,
+                        context.command_buffer,
+                        context.extent,
+                        srgb_target,
+                    )
+                });
+
+            let graph = graph.compile(
+                &self.device,
+                self.allocator.as_mut().unwrap(),
+                &mut self.graph_cache,
+            )?;
+            if let Some(path) = self.graph_dump.take() {
+                match std::fs::write(&path, graph.dot()) {
+                    Ok(()) => log::info!("Wrote {}", path.display()),
+                    Err(e) => log::warn!("Could not write {}: {}", path.display(), e),
+                }
+            }
+            let mut timer = self.gpu_timer.take();
+            graph.execute(self, &self.device, self.command_buffer, timer.as_mut());
+            self.gpu_timer = timer;
+            self.device.end_command_buffer(self.command_buffer)?;
 
             let submit_info = vk::SubmitInfo::builder()
                 .wait_dst_stage_mask(&[vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT])
@@ -456,8 +1168,7 @@ impl VkEngine {
                 .build();
 
             self.device
-                .queue_submit(self.present_queue, &[submit_info], self.render_fence)
-                .expect("Queue Submit Failure");
+                .queue_submit(self.present_queue, &[submit_info], self.render_fence)?;
 
             let swapchains = &[self.swapchain];
             let wait_semaphores = &[self.render_semaphore];
@@ -472,40 +1183,62 @@ impl VkEngine {
                 .queue_present(self.present_queue, &present_info)
             {
                 Err(vk::Result::ERROR_OUT_OF_DATE_KHR) | Ok(true) => {
-                    self.recreate_swapchain();
+                    self.swapchain_dirty = true;
                 }
-                Err(_) => panic!("Issue Presenting"),
+                Err(e) => return Err(e.into()),
                 _ => {}
             };
         }
+        debug::check_validation();
         self.frame_count = self.frame_count + 1 % 60;
+        Ok(())
     }
 }
 
 impl Drop for VkEngine {
     fn drop(&mut self) {
         unsafe {
-            self.device.device_wait_idle().unwrap();
-            self.device.destroy_buffer(self.meshes.buffer, None);
+            // Fails once the device is lost, and then there is nothing left to wait on.
+            let _ = self.device.device_wait_idle();
 
-            let meshes = std::mem::take(&mut self.meshes.meshes);
-            if let (Some(ms), Some(mut alloc)) = (meshes, std::mem::take(&mut self.allocator)) {
-                for mesh in ms.into_iter() {
-                    alloc.free(mesh.allocation).unwrap();
+            if let Some(allocator) = self.allocator.as_mut() {
+                self.meshes.destroy(&self.device, allocator);
+                for (_, mut mesh) in self.terrain_meshes.drain() {
+                    mesh.destroy(&self.device, allocator);
+                }
+                self.vegetation.destroy(&self.device, allocator);
+                self.fauna.destroy(&self.device, allocator);
+                self.debug_lines.destroy(&self.device, allocator);
+                self.overlay.destroy(&self.device, allocator);
+                self.post.destroy(&self.device, allocator);
+                self.graph_cache.destroy(&self.device, allocator);
+                if let Some(mut diffusion) = self.gpu_diffusion.take() {
+                    diffusion.destroy(&self.device, allocator);
                 }
             }
             drop(std::mem::take(&mut self.allocator));
             self.device.destroy_command_pool(self.command_pool, None);
             self.device.destroy_pipeline(self.pipeline, None);
+            self.device.destroy_pipeline(self.instanced_pipeline, None);
+            self.device.destroy_pipeline(self.line_pipeline, None);
+            if let Some(pipeline) = self.cull_pipeline.take() {
+                pipeline.destroy(&self.device);
+            }
+            self.diffusion_pipeline.destroy(&self.device);
+            self.device
+                .destroy_descriptor_pool(self.descriptor_pool, None);
+            self.compute.destroy(&self.device);
+            for timer in [self.gpu_timer.take(), self.compute_timer.take()]
+                .into_iter()
+                .flatten()
+            {
+                timer.destroy(&self.device);
+            }
             self.device
                 .destroy_pipeline_layout(self.pipeline_layout, None);
             for &image_view in self.present_image_views.iter() {
                 self.device.destroy_image_view(image_view, None);
             }
-            for &framebuffer in self.framebuffers.iter() {
-                self.device.destroy_framebuffer(framebuffer, None);
-            }
-            self.device.destroy_render_pass(self.render_pass, None);
             self.device.destroy_semaphore(self.present_semaphore, None);
             self.device.destroy_semaphore(self.render_semaphore, None);
             self.device.destroy_fence(self.render_fence, None);
@@ -514,66 +1247,43 @@ impl Drop for VkEngine {
                 .destroy_swapchain(self.swapchain, None);
             self.device.destroy_device(None);
             self.surface_loader.destroy_surface(self.surface, None);
-            self.debug_utils_loader
-                .destroy_debug_utils_messenger(self.debug_callback, None);
+            if let Some(debug_utils) = self.debug_utils_loader.as_ref() {
+                if self.debug_callback != vk::DebugUtilsMessengerEXT::null() {
+                    debug_utils.destroy_debug_utils_messenger(self.debug_callback, None);
+                }
+            }
             self.instance.destroy_instance(None);
         }
     }
 }
 
-unsafe extern "system" fn vulkan_debug_callback(
-    message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
-    message_type: vk::DebugUtilsMessageTypeFlagsEXT,
-    p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _user_data: *mut std::os::raw::c_void,
-) -> vk::Bool32 {
-    let callback_data = *p_callback_data;
-    let message_id_number: i32 = callback_data.message_id_number as i32;
-
-    let message_id_name = if callback_data.p_message_id_name.is_null() {
-        Cow::from("")
-    } else {
-        CStr::from_ptr(callback_data.p_message_id_name).to_string_lossy()
-    };
-
-    let message = if callback_data.p_message.is_null() {
-        Cow::from("")
-    } else {
-        CStr::from_ptr(callback_data.p_message).to_string_lossy()
-    };
-
-    println!(
-        "{:?}:\n{:?} [{} ({})] : {}\n",
-        message_severity,
-        message_type,
-        message_id_name,
-        &message_id_number.to_string(),
-        message,
-    );
-
-    vk::FALSE
-}
+// A swapchain with its extent, format, present mode, images and image views.
+type SwapchainParts = (
+    vk::SwapchainKHR,
+    vk::Extent2D,
+    vk::SurfaceFormatKHR,
+    vk::PresentModeKHR,
+    Vec<vk::Image>,
+    Vec<vk::ImageView>,
+);
 
+#[allow(clippy::too_many_arguments)]
 unsafe fn create_swapchain(
     device: &Device,
     pdevice: &vk::PhysicalDevice,
     surface_loader: &Surface,
     surface: &vk::SurfaceKHR,
     swapchain_loader: &Swapchain,
+    window_extent: vk::Extent2D,
+    graphics: &GraphicsSettings,
     old_swapchain: Option<vk::SwapchainKHR>,
-) -> (
-    vk::SwapchainKHR,
-    vk::Extent2D,
-    vk::SurfaceFormatKHR,
-    Vec<vk::Image>,
-    Vec<vk::ImageView>,
-) {
-    let surface_format = surface_loader
-        .get_physical_device_surface_formats(*pdevice, *surface)
-        .unwrap()[0];
-    let surface_capabilities = surface_loader
-        .get_physical_device_surface_capabilities(*pdevice, *surface)
-        .unwrap();
+) -> EngineResult<SwapchainParts> {
+    let surface_format = choose_surface_format(
+        &surface_loader.get_physical_device_surface_formats(*pdevice, *surface)?,
+        graphics.prefer_srgb,
+    );
+    let surface_capabilities =
+        surface_loader.get_physical_device_surface_capabilities(*pdevice, *surface)?;
     let mut desired_image_count = surface_capabilities.min_image_count + 1;
     if surface_capabilities.max_image_count > 0
         && desired_image_count > surface_capabilities.max_image_count
@@ -581,13 +1291,7 @@ unsafe fn create_swapchain(
         desired_image_count = surface_capabilities.max_image_count;
     }
 
-    let surface_resolution = match surface_capabilities.current_extent.width {
-        std::u32::MAX => vk::Extent2D {
-            width: 800,
-            height: 600,
-        },
-        _ => surface_capabilities.current_extent,
-    };
+    let surface_resolution = surface_extent(&surface_capabilities, window_extent);
     let pre_transform = if surface_capabilities
         .supported_transforms
         .contains(vk::SurfaceTransformFlagsKHR::IDENTITY)
@@ -596,14 +1300,9 @@ unsafe fn create_swapchain(
     } else {
         surface_capabilities.current_transform
     };
-    let present_modes = surface_loader
-        .get_physical_device_surface_present_modes(*pdevice, *surface)
-        .unwrap();
-    let present_mode = present_modes
-        .iter()
-        .cloned()
-        .find(|&mode| mode == vk::PresentModeKHR::MAILBOX)
-        .unwrap_or(vk::PresentModeKHR::FIFO);
+    let present_modes =
+        surface_loader.get_physical_device_surface_present_modes(*pdevice, *surface)?;
+    let present_mode = choose_present_mode(&present_modes, graphics);
 
     let swapchain_create_info = vk::SwapchainCreateInfoKHR::builder()
         .surface(*surface)
@@ -625,11 +1324,10 @@ unsafe fn create_swapchain(
         swapchain_create_info
     };
 
-    let swapchain = swapchain_loader
-        .create_swapchain(&swapchain_create_info, None)
-        .unwrap();
+    let swapchain = swapchain_loader.create_swapchain(&swapchain_create_info, None)?;
+    debug::set_name(device, swapchain, "Swapchain");
 
-    let present_images = swapchain_loader.get_swapchain_images(swapchain).unwrap();
+    let present_images = swapchain_loader.get_swapchain_images(swapchain)?;
     let present_image_views = present_images
         .iter()
         .map(|&image| {
@@ -650,87 +1348,235 @@ unsafe fn create_swapchain(
                     layer_count: 1,
                 })
                 .image(image);
-            device.create_image_view(&create_view_info, None).unwrap()
+            debug::set_name(device, image, "Swapchain image");
+            let view = device.create_image_view(&create_view_info, None)?;
+            debug::set_name(device, view, "Swapchain image");
+            Ok(view)
         })
-        .collect();
-    (
+        .collect::<EngineResult<_>>()?;
+    Ok((
         swapchain,
         surface_resolution,
         surface_format,
+        present_mode,
         present_images,
         present_image_views,
-    )
+    ))
 }
 
-unsafe fn create_framebuffers(
-    device: &Device,
-    present_image_views: &Vec<vk::ImageView>,
-    surface_resolution: vk::Extent2D,
-    render_pass: vk::RenderPass,
-) -> Vec<vk::Framebuffer> {
-    present_image_views
+// The 8 bit RGBA formats every desktop driver offers, in the requested encoding if
+// the surface has it, else whatever it lists first.
+fn choose_surface_format(formats: &[vk::SurfaceFormatKHR], srgb: bool) -> vk::SurfaceFormatKHR {
+    let wanted: &[vk::Format] = if srgb {
+        &[vk::Format::B8G8R8A8_SRGB, vk::Format::R8G8B8A8_SRGB]
+    } else {
+        &[vk::Format::B8G8R8A8_UNORM, vk::Format::R8G8B8A8_UNORM]
+    };
+    wanted
         .iter()
-        .map(|&image_view| {
-            device
-                .create_framebuffer(
-                    &vk::FramebufferCreateInfo::builder()
-                        .render_pass(render_pass)
-                        .attachments(&[image_view])
-                        .width(surface_resolution.width)
-                        .height(surface_resolution.height)
-                        .layers(1),
-                    None,
-                )
-                .unwrap()
+        .find_map(|&format| {
+            formats.iter().copied().find(|candidate| {
+                candidate.format == format
+                    && candidate.color_space == vk::ColorSpaceKHR::SRGB_NONLINEAR
+            })
         })
-        .collect()
+        .unwrap_or(formats[0])
 }
 
-unsafe fn create_render_pass(
-    device: &Device,
-    surface_format: vk::SurfaceFormatKHR,
-) -> vk::RenderPass {
-    let color_attachment = [vk::AttachmentDescription {
-        format: surface_format.format,
+// FIFO is the only mode every surface supports, so it is the last resort either way.
+fn choose_present_mode(
+    available: &[vk::PresentModeKHR],
+    graphics: &GraphicsSettings,
+) -> vk::PresentModeKHR {
+    let fallbacks: &[vk::PresentModeKHR] = if graphics.vsync {
+        &[vk::PresentModeKHR::FIFO]
+    } else {
+        &[
+            vk::PresentModeKHR::IMMEDIATE,
+            vk::PresentModeKHR::MAILBOX,
+            vk::PresentModeKHR::FIFO_RELAXED,
+        ]
+    };
+    graphics
+        .present_mode
+        .iter()
+        .chain(fallbacks)
+        .copied()
+        .find(|mode| available.contains(mode))
+        .unwrap_or(vk::PresentModeKHR::FIFO)
+}
+
+// Surfaces that leave the size up to the swapchain report an extent of u32::MAX;
+// those get the window's size, within what the surface allows.
+fn surface_extent(
+    capabilities: &vk::SurfaceCapabilitiesKHR,
+    window_extent: vk::Extent2D,
+) -> vk::Extent2D {
+    if capabilities.current_extent.width != u32::MAX {
+        return capabilities.current_extent;
+    }
+    let (min, max) = (capabilities.min_image_extent, capabilities.max_image_extent);
+    vk::Extent2D {
+        width: window_extent.width.clamp(min.width, max.width),
+        height: window_extent.height.clamp(min.height, max.height),
+    }
+}
+
+fn physical_extent(size: PhysicalSize<u32>) -> vk::Extent2D {
+    vk::Extent2D {
+        width: size.width,
+        height: size.height,
+    }
+}
+
+// What the main pass draws into. With MSAA the multisampled colour is resolved
+// into the scene target as the pass ends.
+fn scene_layout(samples: vk::SampleCountFlags) -> PassLayout {
+    PassLayout {
+        colors: vec![HDR_FORMAT],
+        depth: Some(DEPTH_FORMAT),
+        samples,
+        resolve: samples != vk::SampleCountFlags::TYPE_1,
+    }
+}
+
+// The overlay draws straight into the swapchain image, over the post chain.
+fn overlay_layout(format: vk::Format) -> PassLayout {
+    PassLayout {
+        colors: vec![format],
+        depth: None,
         samples: vk::SampleCountFlags::TYPE_1,
-        load_op: vk::AttachmentLoadOp::CLEAR,
-        store_op: vk::AttachmentStoreOp::STORE,
-        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
-        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
-        initial_layout: vk::ImageLayout::UNDEFINED,
-        final_layout: vk::ImageLayout::PRESENT_SRC_KHR,
-        ..Default::default()
-    }];
-    let color_attachment_ref = [vk::AttachmentReference {
-        attachment: 0,
-        layout: vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
-    }];
-
-    let subpass = [vk::SubpassDescription {
-        pipeline_bind_point: vk::PipelineBindPoint::GRAPHICS,
-        color_attachment_count: 1,
-        p_color_attachments: color_attachment_ref.as_ptr(),
-        ..Default::default()
-    }];
-
-    let renderpass = vk::RenderPassCreateInfo::builder()
-        .attachments(&color_attachment)
-        .subpasses(&subpass);
-
-    device.create_render_pass(&renderpass, None).unwrap()
+        resolve: false,
+    }
 }
 
-fn compile_shader(
+// The most samples up to `requested` that the device supports, at least one.
+fn sample_count(requested: u32, supported: vk::SampleCountFlags) -> vk::SampleCountFlags {
+    [
+        vk::SampleCountFlags::TYPE_64,
+        vk::SampleCountFlags::TYPE_32,
+        vk::SampleCountFlags::TYPE_16,
+        vk::SampleCountFlags::TYPE_8,
+        vk::SampleCountFlags::TYPE_4,
+        vk::SampleCountFlags::TYPE_2,
+    ]
+    .into_iter()
+    .find(|&count| count.as_raw() <= requested && supported.contains(count))
+    .unwrap_or(vk::SampleCountFlags::TYPE_1)
+}
+
+// Culling on the GPU needs a compute capable queue and `drawIndirectCount`, which
+// is core since Vulkan 1.2.
+unsafe fn supports_gpu_culling(
+    instance: &Instance,
+    pdevice: vk::PhysicalDevice,
+    queue_family_index: u32,
+) -> bool {
+    let properties = instance.get_physical_device_properties(pdevice);
+    if vk::api_version_major(properties.api_version) == 1
+        && vk::api_version_minor(properties.api_version) < 2
+    {
+        return false;
+    }
+    let compute = instance.get_physical_device_queue_family_properties(pdevice)
+        [queue_family_index as usize]
+        .queue_flags
+        .contains(vk::QueueFlags::COMPUTE);
+
+    let mut vulkan12_features = vk::PhysicalDeviceVulkan12Features::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut vulkan12_features);
+    instance.get_physical_device_features2(pdevice, &mut features);
+    compute && vulkan12_features.draw_indirect_count == vk::TRUE
+}
+
+// Dynamic rendering and synchronization2 are core since Vulkan 1.3, though still
+// optional features there.
+unsafe fn supports_dynamic_rendering(instance: &Instance, pdevice: vk::PhysicalDevice) -> bool {
+    let properties = instance.get_physical_device_properties(pdevice);
+    if vk::api_version_major(properties.api_version) == 1
+        && vk::api_version_minor(properties.api_version) < 3
+    {
+        return false;
+    }
+    let mut vulkan13_features = vk::PhysicalDeviceVulkan13Features::default();
+    let mut features = vk::PhysicalDeviceFeatures2::builder().push_next(&mut vulkan13_features);
+    instance.get_physical_device_features2(pdevice, &mut features);
+    vulkan13_features.dynamic_rendering == vk::TRUE
+        && vulkan13_features.synchronization2 == vk::TRUE
+}
+
+// The graphics pipelines: plain, instanced, and lines for debug drawing. They have
+// to be rebuilt whenever the scene target changes format or sample count.
+unsafe fn build_scene_pipelines(
+    device: &Device,
+    compiler: &shaderc::Compiler,
+    target: &PipelineTarget,
+    layout: vk::PipelineLayout,
+    samples: vk::SampleCountFlags,
+) -> EngineResult<(vk::Pipeline, vk::Pipeline, vk::Pipeline)> {
+    let shaders = [
+        compile_shader(
+            device,
+            compiler,
+            "assets/shaders/triangle.frag",
+            shaderc::ShaderKind::Fragment,
+        )?,
+        compile_shader(
+            device,
+            compiler,
+            "assets/shaders/triangle.vert",
+            shaderc::ShaderKind::Vertex,
+        )?,
+        compile_shader(
+            device,
+            compiler,
+            "assets/shaders/instanced.vert",
+            shaderc::ShaderKind::Vertex,
+        )?,
+    ];
+    let shader_info = vec![
+        shader_stage_create_info(vk::ShaderStageFlags::FRAGMENT, shaders[0]).build(),
+        shader_stage_create_info(vk::ShaderStageFlags::VERTEX, shaders[1]).build(),
+    ];
+    let instanced_shader_info = vec![
+        shader_stage_create_info(vk::ShaderStageFlags::FRAGMENT, shaders[0]).build(),
+        shader_stage_create_info(vk::ShaderStageFlags::VERTEX, shaders[2]).build(),
+    ];
+
+    let pipeline = build_pipeline(device, target, &shader_info, layout, false, samples)?;
+    let instanced_pipeline = build_pipeline(
+        device,
+        target,
+        &instanced_shader_info,
+        layout,
+        true,
+        samples,
+    )?;
+    let line_pipeline = build_line_pipeline(device, target, &shader_info, layout, samples)?;
+    for shader in shaders {
+        device.destroy_shader_module(shader, None)
+    }
+    debug::set_name(device, pipeline, "Scene");
+    debug::set_name(device, instanced_pipeline, "Scene instanced");
+    debug::set_name(device, line_pipeline, "Debug lines");
+    Ok((pipeline, instanced_pipeline, line_pipeline))
+}
+
+pub fn compile_shader(
     device: &Device,
     compiler: &shaderc::Compiler,
     file: &str,
     kind: shaderc::ShaderKind,
-) -> vk::ShaderModule {
-    let source = std::fs::read_to_string(file).unwrap();
+) -> EngineResult<vk::ShaderModule> {
+    let shader_error = |reason: String| EngineError::Shader {
+        file: file.to_string(),
+        reason,
+    };
+    let source = std::fs::read_to_string(file).map_err(|e| shader_error(e.to_string()))?;
     let artifact = compiler
         .compile_into_spirv(source.as_str(), kind, file, "main", None)
-        .unwrap();
+        .map_err(|e| shader_error(e.to_string()))?;
 
     let create_info = vk::ShaderModuleCreateInfo::builder().code(artifact.as_binary());
-    unsafe { device.create_shader_module(&create_info, None).unwrap() }
+    unsafe { Ok(device.create_shader_module(&create_info, None)?) }
 }
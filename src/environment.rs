@@ -0,0 +1,414 @@
+// Grid of environmental conditions over the map. Each layer spreads to its
+// neighbours and fades back towards its resting value over time. Values are f32 so
+// that a GPU implementation can reproduce them.
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layer {
+    Soil,
+    Water,
+    Air,
+    Temperature,
+}
+
+pub const LAYERS: [Layer; 4] = [Layer::Soil, Layer::Water, Layer::Air, Layer::Temperature];
+
+#[derive(Clone, Copy, Debug)]
+pub struct LayerParams {
+    // Diffusion coefficient in world units squared per second.
+    pub diffusion: f32,
+    // Fraction of the difference from `rest` removed per second.
+    pub decay: f32,
+    pub rest: f32,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct EnvironmentSettings {
+    pub width: usize,
+    pub height: usize,
+    pub cell_size: f32,
+    pub soil: LayerParams,
+    pub water: LayerParams,
+    pub air: LayerParams,
+    pub temperature: LayerParams,
+}
+
+impl EnvironmentSettings {
+    pub fn layer(&self, layer: Layer) -> LayerParams {
+        match layer {
+            Layer::Soil => self.soil,
+            Layer::Water => self.water,
+            Layer::Air => self.air,
+            Layer::Temperature => self.temperature,
+        }
+    }
+}
+
+impl Default for EnvironmentSettings {
+    fn default() -> Self {
+        EnvironmentSettings {
+            width: 64,
+            height: 64,
+            cell_size: 8.0,
+            soil: LayerParams {
+                diffusion: 0.5,
+                decay: 0.002,
+                rest: 0.0,
+            },
+            water: LayerParams {
+                diffusion: 20.0,
+                decay: 0.01,
+                rest: 0.0,
+            },
+            air: LayerParams {
+                diffusion: 80.0,
+                decay: 0.05,
+                rest: 0.0,
+            },
+            temperature: LayerParams {
+                diffusion: 40.0,
+                decay: 0.1,
+                rest: 15.0,
+            },
+        }
+    }
+}
+
+// Amount added per second to each layer by a source, spread over its radius.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Emission {
+    pub soil: f32,
+    pub water: f32,
+    pub air: f32,
+    pub heat: f32,
+}
+
+impl Emission {
+    pub fn layer(&self, layer: Layer) -> f32 {
+        match layer {
+            Layer::Soil => self.soil,
+            Layer::Water => self.water,
+            Layer::Air => self.air,
+            Layer::Temperature => self.heat,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PollutionSource {
+    pub x: f32,
+    pub z: f32,
+    pub radius: f32,
+    pub emission: Emission,
+    // Seconds left before a one-off source (e.g. a fire) burns out. Buildings have none.
+    pub remaining: Option<f32>,
+}
+
+pub struct Environment {
+    pub settings: EnvironmentSettings,
+    pub soil: Vec<f32>,
+    pub water: Vec<f32>,
+    pub air: Vec<f32>,
+    pub temperature: Vec<f32>,
+    pub sources: Vec<PollutionSource>,
+}
+
+impl Environment {
+    pub fn new(settings: EnvironmentSettings) -> Self {
+        let cells = settings.width * settings.height;
+        Environment {
+            soil: vec![settings.soil.rest; cells],
+            water: vec![settings.water.rest; cells],
+            air: vec![settings.air.rest; cells],
+            temperature: vec![settings.temperature.rest; cells],
+            sources: Vec::new(),
+            settings,
+        }
+    }
+
+    pub fn values(&self, layer: Layer) -> &[f32] {
+        match layer {
+            Layer::Soil => &self.soil,
+            Layer::Water => &self.water,
+            Layer::Air => &self.air,
+            Layer::Temperature => &self.temperature,
+        }
+    }
+
+    pub fn values_mut(&mut self, layer: Layer) -> &mut Vec<f32> {
+        match layer {
+            Layer::Soil => &mut self.soil,
+            Layer::Water => &mut self.water,
+            Layer::Air => &mut self.air,
+            Layer::Temperature => &mut self.temperature,
+        }
+    }
+
+    pub fn add_source(&mut self, source: PollutionSource) {
+        self.sources.push(source);
+    }
+
+    pub fn step(&mut self, dt: f32, diffuser: &mut dyn Diffuser) {
+        self.emit(dt);
+        let settings = self.settings;
+        for layer in LAYERS {
+            let diffusion = settings.layer(layer).diffusion;
+            diffuser.diffuse(self.values_mut(layer), &settings, diffusion, dt);
+            self.decay(layer, dt);
+        }
+    }
+
+    fn emit(&mut self, dt: f32) {
+        let settings = self.settings;
+        let sources = std::mem::take(&mut self.sources);
+        for source in sources.iter() {
+            // Emission is spread evenly over the cells inside the radius.
+            let cells = cells_in_radius(&settings, source.x, source.z, source.radius);
+            if cells.is_empty() {
+                continue;
+            }
+            let duration = source.remaining.map_or(dt, |remaining| remaining.min(dt));
+            for layer in LAYERS {
+                let amount = source.emission.layer(layer) * duration / cells.len() as f32;
+                if amount == 0.0 {
+                    continue;
+                }
+                let values = self.values_mut(layer);
+                for &cell in cells.iter() {
+                    values[cell] += amount;
+                }
+            }
+        }
+        self.sources = sources
+            .into_iter()
+            .filter_map(|mut source| match source.remaining {
+                Some(remaining) if remaining <= dt => None,
+                Some(remaining) => {
+                    source.remaining = Some(remaining - dt);
+                    Some(source)
+                }
+                None => Some(source),
+            })
+            .collect();
+    }
+
+    fn decay(&mut self, layer: Layer, dt: f32) {
+        let params = self.settings.layer(layer);
+        let keep = (1.0 - params.decay * dt).max(0.0);
+        for value in self.values_mut(layer).iter_mut() {
+            *value = params.rest + (*value - params.rest) * keep;
+        }
+    }
+
+    // Mean of a layer over a rectangle of cells, `max` exclusive.
+    pub fn mean(&self, layer: Layer, min: (usize, usize), max: (usize, usize)) -> f32 {
+        let values = self.values(layer);
+        let mut sum = 0.0;
+        for z in min.1..max.1 {
+            for x in min.0..max.0 {
+                sum += values[z * self.settings.width + x];
+            }
+        }
+        sum / ((max.0 - min.0) * (max.1 - min.1)).max(1) as f32
+    }
+}
+
+fn cells_in_radius(settings: &EnvironmentSettings, x: f32, z: f32, radius: f32) -> Vec<usize> {
+    let size = settings.cell_size;
+    let to_cell = |v: f32, limit: usize| (v / size).floor().clamp(0.0, limit as f32 - 1.0) as usize;
+    let (x0, x1) = (
+        to_cell(x - radius, settings.width),
+        to_cell(x + radius, settings.width),
+    );
+    let (z0, z1) = (
+        to_cell(z - radius, settings.height),
+        to_cell(z + radius, settings.height),
+    );
+
+    let mut cells = Vec::new();
+    for cz in z0..=z1 {
+        for cx in x0..=x1 {
+            let dx = (cx as f32 + 0.5) * size - x;
+            let dz = (cz as f32 + 0.5) * size - z;
+            if dx * dx + dz * dz <= radius * radius {
+                cells.push(cz * settings.width + cx);
+            }
+        }
+    }
+    // Small sources still land somewhere.
+    if cells.is_empty() && x >= 0.0 && z >= 0.0 {
+        cells.push(to_cell(z, settings.height) * settings.width + to_cell(x, settings.width));
+    }
+    cells
+}
+
+// Spreads one layer across the grid over `dt`. Implementations must match
+// `CpuDiffuser`, the reference, to within rounding.
+pub trait Diffuser {
+    fn diffuse(
+        &mut self,
+        values: &mut [f32],
+        settings: &EnvironmentSettings,
+        diffusion: f32,
+        dt: f32,
+    );
+}
+
+#[derive(Default)]
+pub struct CpuDiffuser {
+    scratch: Vec<f32>,
+}
+
+impl Diffuser for CpuDiffuser {
+    fn diffuse(
+        &mut self,
+        values: &mut [f32],
+        settings: &EnvironmentSettings,
+        diffusion: f32,
+        dt: f32,
+    ) {
+        self.scratch.resize(values.len(), 0.0);
+        diffuse(values, &mut self.scratch, settings, diffusion, dt);
+    }
+}
+
+// Large steps are split up so that each sub-step stays within the explicit
+// scheme's stability limit. Returns the number of sub-steps and the rate of each.
+pub fn diffusion_substeps(settings: &EnvironmentSettings, diffusion: f32, dt: f32) -> (u32, f32) {
+    if diffusion <= 0.0 {
+        return (0, 0.0);
+    }
+    let rate = diffusion * dt / (settings.cell_size * settings.cell_size);
+    let substeps = (rate / 0.2).ceil().max(1.0) as u32;
+    (substeps, rate / substeps as f32)
+}
+
+// Explicit five point diffusion with closed borders.
+pub fn diffuse(
+    values: &mut [f32],
+    scratch: &mut [f32],
+    settings: &EnvironmentSettings,
+    diffusion: f32,
+    dt: f32,
+) {
+    let (w, h) = (settings.width, settings.height);
+    let (substeps, rate) = diffusion_substeps(settings, diffusion, dt);
+
+    for _ in 0..substeps {
+        for z in 0..h {
+            for x in 0..w {
+                let i = z * w + x;
+                let c = values[i];
+                let left = if x > 0 { values[i - 1] } else { c };
+                let right = if x + 1 < w { values[i + 1] } else { c };
+                let up = if z > 0 { values[i - w] } else { c };
+                let down = if z + 1 < h { values[i + w] } else { c };
+                scratch[i] = c + rate * (left + right + up + down - 4.0 * c);
+            }
+        }
+        values.copy_from_slice(scratch);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const STILL: LayerParams = LayerParams {
+        diffusion: 0.0,
+        decay: 0.0,
+        rest: 0.0,
+    };
+
+    fn settings(layer: LayerParams) -> EnvironmentSettings {
+        EnvironmentSettings {
+            width: 16,
+            height: 12,
+            cell_size: 4.0,
+            soil: layer,
+            water: layer,
+            air: layer,
+            temperature: layer,
+        }
+    }
+
+    fn total(values: &[f32]) -> f64 {
+        values.iter().map(|&v| v as f64).sum()
+    }
+
+    #[test]
+    fn diffusion_conserves_mass_without_decay() {
+        let settings = settings(STILL);
+        let mut values = vec![0.0; settings.width * settings.height];
+        values[5 * settings.width + 3] = 100.0;
+        values[settings.width - 1] = 40.0;
+        let mut diffuser = CpuDiffuser::default();
+        for _ in 0..50 {
+            // Large enough to need several sub-steps.
+            diffuser.diffuse(&mut values, &settings, 20.0, 1.0);
+        }
+        assert!((total(&values) - 140.0).abs() < 1e-3);
+        assert!(values.iter().all(|&v| v >= 0.0));
+        assert!(values[5 * settings.width + 3] < 100.0);
+        assert!(values[6 * settings.width + 3] > 0.0);
+    }
+
+    #[test]
+    fn layers_decay_towards_rest() {
+        let settings = settings(LayerParams {
+            decay: 0.1,
+            rest: 15.0,
+            ..STILL
+        });
+        let mut environment = Environment::new(settings);
+        environment.temperature[0] = 35.0;
+        environment.step(2.0, &mut CpuDiffuser::default());
+        assert!((environment.temperature[0] - (15.0 + 20.0 * 0.8)).abs() < 1e-5);
+        assert_eq!(environment.temperature[1], 15.0);
+
+        // A step longer than the decay time can't overshoot the resting value.
+        environment.step(20.0, &mut CpuDiffuser::default());
+        assert_eq!(environment.temperature[0], 15.0);
+    }
+
+    #[test]
+    fn sources_emit_their_rate_over_the_radius() {
+        let mut environment = Environment::new(settings(STILL));
+        let emission = Emission {
+            air: 10.0,
+            soil: 2.0,
+            ..Default::default()
+        };
+        environment.add_source(PollutionSource {
+            x: 30.0,
+            z: 20.0,
+            radius: 9.0,
+            emission,
+            remaining: None,
+        });
+        // A fire that burns out halfway through the step.
+        environment.add_source(PollutionSource {
+            x: 10.0,
+            z: 10.0,
+            radius: 1.0,
+            emission: Emission {
+                heat: 4.0,
+                ..Default::default()
+            },
+            remaining: Some(0.5),
+        });
+        let mut diffuser = CpuDiffuser::default();
+        environment.step(1.0, &mut diffuser);
+        assert!((total(&environment.air) - 10.0).abs() < 1e-4);
+        assert!((total(&environment.soil) - 2.0).abs() < 1e-4);
+        assert!((total(&environment.temperature) - 2.0).abs() < 1e-4);
+        assert_eq!(total(&environment.water), 0.0);
+        assert_eq!(environment.sources.len(), 1);
+        // Spread evenly over more than one cell.
+        let touched = environment.air.iter().filter(|&&v| v > 0.0).count();
+        assert!(touched > 1);
+
+        environment.step(1.0, &mut diffuser);
+        assert!((total(&environment.air) - 20.0).abs() < 1e-4);
+        assert!((total(&environment.temperature) - 2.0).abs() < 1e-4);
+    }
+}
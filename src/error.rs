@@ -0,0 +1,81 @@
+use std::fmt;
+
+use ash::vk;
+use gpu_allocator::AllocationError;
+
+// What can go wrong bringing up the renderer or drawing a frame. The variants the
+// app can react to get their own case; everything else keeps the Vulkan result.
+#[derive(Debug)]
+pub enum EngineError {
+    MissingLayer(String),
+    MissingExtension(String),
+    NoSuitableDevice(String),
+    // The window's surface went away; a new one is needed to keep drawing.
+    SurfaceLost,
+    // The driver gave up on the device, usually after a crash or hang.
+    DeviceLost,
+    OutOfMemory,
+    Shader { file: String, reason: String },
+    Asset { file: String, reason: String },
+    Allocation(AllocationError),
+    Vulkan(vk::Result),
+}
+
+pub type EngineResult<T> = Result<T, EngineError>;
+
+impl From<vk::Result> for EngineError {
+    fn from(result: vk::Result) -> Self {
+        match result {
+            vk::Result::ERROR_SURFACE_LOST_KHR => EngineError::SurfaceLost,
+            vk::Result::ERROR_DEVICE_LOST => EngineError::DeviceLost,
+            vk::Result::ERROR_OUT_OF_HOST_MEMORY | vk::Result::ERROR_OUT_OF_DEVICE_MEMORY => {
+                EngineError::OutOfMemory
+            }
+            vk::Result::ERROR_LAYER_NOT_PRESENT => {
+                EngineError::MissingLayer("a requested layer".to_string())
+            }
+            vk::Result::ERROR_EXTENSION_NOT_PRESENT => {
+                EngineError::MissingExtension("a requested extension".to_string())
+            }
+            result => EngineError::Vulkan(result),
+        }
+    }
+}
+
+impl From<AllocationError> for EngineError {
+    fn from(error: AllocationError) -> Self {
+        match error {
+            AllocationError::OutOfMemory => EngineError::OutOfMemory,
+            error => EngineError::Allocation(error),
+        }
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EngineError::MissingLayer(layer) => {
+                write!(f, "Vulkan layer {} is not installed", layer)
+            }
+            EngineError::MissingExtension(extension) => {
+                write!(f, "Vulkan extension {} is not supported", extension)
+            }
+            EngineError::NoSuitableDevice(reason) => {
+                write!(f, "No suitable graphics device: {}", reason)
+            }
+            EngineError::SurfaceLost => write!(f, "The window surface was lost"),
+            EngineError::DeviceLost => write!(f, "The graphics device was lost"),
+            EngineError::OutOfMemory => write!(f, "Out of memory"),
+            EngineError::Shader { file, reason } => {
+                write!(f, "Could not build shader {}: {}", file, reason)
+            }
+            EngineError::Asset { file, reason } => {
+                write!(f, "Could not load {}: {}", file, reason)
+            }
+            EngineError::Allocation(error) => write!(f, "Allocation failed: {}", error),
+            EngineError::Vulkan(result) => write!(f, "Vulkan error {}", result),
+        }
+    }
+}
+
+impl std::error::Error for EngineError {}
@@ -0,0 +1,214 @@
+// Frustum culling of instance batches on the GPU. A compute pass tests every
+// instance against the frustum, compacts the survivors into a second buffer and
+// counts them into an indirect draw, so the CPU never looks at single instances.
+
+use ash::{vk, Device};
+use cgmath::Vector4;
+use gpu_allocator::vulkan::*;
+use memoffset::offset_of;
+
+use crate::compute::{
+    as_bytes, buffer_barrier, group_count, write_storage_buffer, ComputePipeline,
+};
+use crate::culling::Frustum;
+use crate::error::EngineResult;
+use crate::mesh::{create_buffer, InstanceBuffer, MeshBuffer};
+
+// Must match local_size_x in cull.comp.
+pub const CULL_GROUP_SIZE: u32 = 64;
+
+#[repr(C)]
+pub struct CullConstants {
+    pub planes: [Vector4<f32>; 6],
+    // Bounding sphere of the mesh in its own space, radius in w.
+    pub sphere: Vector4<f32>,
+    pub count: u32,
+}
+
+// The indirect command followed by how many of them to draw, so one buffer serves
+// as both arguments of `cmd_draw_indexed_indirect_count`.
+#[derive(Clone, Copy, Default)]
+#[repr(C)]
+pub struct IndirectDraw {
+    pub command: vk::DrawIndexedIndirectCommand,
+    pub draw_count: u32,
+}
+
+// Descriptor types of cull.comp's bindings: source instances, visible instances
+// and the indirect draw.
+pub const CULL_BINDINGS: [vk::DescriptorType; 3] = [vk::DescriptorType::STORAGE_BUFFER; 3];
+
+pub fn cull_pipeline(device: &Device, shader: vk::ShaderModule) -> EngineResult<ComputePipeline> {
+    ComputePipeline::new(
+        device,
+        "Cull",
+        shader,
+        &CULL_BINDINGS,
+        std::mem::size_of::<CullConstants>() as u32,
+    )
+}
+
+// Where one batch's surviving instances and draw command end up.
+pub struct CullTarget {
+    pub visible: InstanceBuffer,
+    pub draw: vk::Buffer,
+    pub draw_allocation: Option<Allocation>,
+    pub descriptor_set: vk::DescriptorSet,
+    // The source buffer the descriptor set currently points at.
+    bound_source: vk::Buffer,
+}
+
+impl CullTarget {
+    pub fn new(
+        device: &Device,
+        allocator: &mut Allocator,
+        pipeline: &ComputePipeline,
+        descriptor_pool: vk::DescriptorPool,
+    ) -> EngineResult<Self> {
+        let (draw, draw_allocation) = create_buffer(
+            device,
+            allocator,
+            "Indirect draw",
+            std::mem::size_of::<IndirectDraw>(),
+            vk::BufferUsageFlags::INDIRECT_BUFFER
+                | vk::BufferUsageFlags::STORAGE_BUFFER
+                | vk::BufferUsageFlags::TRANSFER_DST,
+        )?;
+        let ptr = draw_allocation
+            .mapped_ptr()
+            .ok_or(vk::Result::ERROR_MEMORY_MAP_FAILED)?
+            .cast::<IndirectDraw>();
+        unsafe { ptr.as_ptr().write(IndirectDraw::default()) };
+
+        let descriptor_set = pipeline.allocate_set(device, descriptor_pool)?;
+
+        Ok(CullTarget {
+            visible: InstanceBuffer::new(device, allocator, 1)?,
+            draw,
+            draw_allocation: Some(draw_allocation),
+            descriptor_set,
+            bound_source: vk::Buffer::null(),
+        })
+    }
+
+    // Makes room for everything in `source` and points the descriptors at the
+    // current buffers. Only call while the GPU is not using them.
+    pub fn reserve(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        source: &InstanceBuffer,
+    ) -> EngineResult<()> {
+        let grow = self.visible.capacity < source.capacity;
+        if grow {
+            let grown = InstanceBuffer::new(device, allocator, source.capacity)?;
+            self.visible.destroy(device, allocator);
+            self.visible = grown;
+        }
+        if grow || self.bound_source != source.buffer {
+            for (binding, buffer) in [source.buffer, self.visible.buffer, self.draw]
+                .into_iter()
+                .enumerate()
+            {
+                write_storage_buffer(device, self.descriptor_set, binding as u32, buffer);
+            }
+            self.bound_source = source.buffer;
+        }
+        Ok(())
+    }
+
+    // Instances that survived the last completed cull.
+    pub fn drawn(&self) -> u32 {
+        self.draw_allocation
+            .as_ref()
+            .and_then(|allocation| allocation.mapped_ptr())
+            .map_or(0, |ptr| unsafe {
+                ptr.cast::<IndirectDraw>()
+                    .as_ptr()
+                    .read()
+                    .command
+                    .instance_count
+            })
+    }
+
+    // Records the cull of `count` instances of `mesh`. Must be recorded outside a
+    // render pass; the caller syncs its writes with the draw and the count readback.
+    pub unsafe fn record(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        pipeline: &ComputePipeline,
+        mesh: &MeshBuffer,
+        count: u32,
+        frustum: &Frustum,
+    ) {
+        let reset = IndirectDraw {
+            command: vk::DrawIndexedIndirectCommand {
+                index_count: mesh.index_count,
+                ..Default::default()
+            },
+            draw_count: 0,
+        };
+        device.cmd_update_buffer(command_buffer, self.draw, 0, as_bytes(&reset));
+        buffer_barrier(
+            device,
+            command_buffer,
+            self.draw,
+            (
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_WRITE,
+            ),
+            (
+                vk::PipelineStageFlags::COMPUTE_SHADER,
+                vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+            ),
+        );
+
+        let constants = CullConstants {
+            planes: frustum.planes,
+            sphere: mesh.sphere.center.extend(mesh.sphere.radius),
+            count,
+        };
+        pipeline.dispatch(
+            device,
+            command_buffer,
+            self.descriptor_set,
+            as_bytes(&constants),
+            (group_count(count, CULL_GROUP_SIZE), 1, 1),
+        );
+    }
+
+    // Draws whatever the last recorded cull let through. Expects the instanced
+    // pipeline to be bound.
+    pub unsafe fn draw(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        mesh: &MeshBuffer,
+    ) {
+        device.cmd_bind_vertex_buffers(
+            command_buffer,
+            0,
+            &[mesh.buffer, self.visible.buffer],
+            &[0, 0],
+        );
+        device.cmd_bind_index_buffer(command_buffer, mesh.index_buffer, 0, vk::IndexType::UINT32);
+        device.cmd_draw_indexed_indirect_count(
+            command_buffer,
+            self.draw,
+            0,
+            self.draw,
+            offset_of!(IndirectDraw, draw_count) as u64,
+            1,
+            std::mem::size_of::<vk::DrawIndexedIndirectCommand>() as u32,
+        );
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        self.visible.destroy(device, allocator);
+        unsafe { device.destroy_buffer(self.draw, None) };
+        if let Some(allocation) = self.draw_allocation.take() {
+            allocator.free(allocation).unwrap();
+        }
+    }
+}
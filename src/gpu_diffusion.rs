@@ -0,0 +1,541 @@
+// Environment diffusion as a compute shader. The grid is uploaded into one of two
+// storage images, ping-ponged between them for every sub-step and read back, so the
+// CPU side of the environment model doesn't change.
+
+use anyhow::Context;
+use ash::{vk, Device, Entry};
+use gpu_allocator::vulkan::*;
+
+use crate::compute::{
+    as_bytes, buffer_barrier, group_count, image_barrier, memory_barrier, write_storage_image,
+    ComputePipeline, ComputeQueue,
+};
+use crate::debug;
+use crate::engine::compile_shader;
+use crate::environment::{diffusion_substeps, CpuDiffuser, Diffuser, EnvironmentSettings, LAYERS};
+use crate::error::{EngineError, EngineResult};
+use crate::mesh::create_buffer;
+use crate::noise::splitmix64;
+use crate::profiler::{self, GpuTimer};
+use crate::simulation::TICK;
+
+pub const DIFFUSE_SHADER: &str = "assets/shaders/diffuse.comp";
+// Must match local_size_x and local_size_y in diffuse.comp.
+const GROUP_SIZE: u32 = 8;
+// Largest difference allowed between the two implementations, relative to the
+// values' magnitude.
+pub const TOLERANCE: f32 = 1e-4;
+
+pub fn diffusion_pipeline(
+    device: &Device,
+    shader: vk::ShaderModule,
+) -> EngineResult<ComputePipeline> {
+    ComputePipeline::new(
+        device,
+        "Diffusion",
+        shader,
+        &[vk::DescriptorType::STORAGE_IMAGE; 2],
+        std::mem::size_of::<f32>() as u32,
+    )
+}
+
+struct StorageImage {
+    image: vk::Image,
+    view: vk::ImageView,
+    allocation: Option<Allocation>,
+}
+
+pub struct GpuDiffusion {
+    pub width: usize,
+    pub height: usize,
+    images: [StorageImage; 2],
+    // Reads images[0] and writes images[1], and the other way round.
+    sets: [vk::DescriptorSet; 2],
+    staging: vk::Buffer,
+    staging_allocation: Option<Allocation>,
+    pipeline: vk::Pipeline,
+    layout: vk::PipelineLayout,
+    descriptor_pool: vk::DescriptorPool,
+}
+
+impl GpuDiffusion {
+    pub fn new(
+        device: &Device,
+        allocator: &mut Allocator,
+        pipeline: &ComputePipeline,
+        descriptor_pool: vk::DescriptorPool,
+        width: usize,
+        height: usize,
+    ) -> EngineResult<Self> {
+        let images = [
+            create_storage_image(device, allocator, width, height)?,
+            create_storage_image(device, allocator, width, height)?,
+        ];
+        let mut sets = [vk::DescriptorSet::null(); 2];
+        for (set, (source, target)) in sets.iter_mut().zip([(0, 1), (1, 0)]) {
+            *set = pipeline.allocate_set(device, descriptor_pool)?;
+            write_storage_image(device, *set, 0, images[source].view);
+            write_storage_image(device, *set, 1, images[target].view);
+        }
+        let (staging, staging_allocation) = create_buffer(
+            device,
+            allocator,
+            "Diffusion staging",
+            std::mem::size_of::<f32>() * width * height,
+            vk::BufferUsageFlags::TRANSFER_SRC | vk::BufferUsageFlags::TRANSFER_DST,
+        )?;
+        Ok(GpuDiffusion {
+            width,
+            height,
+            images,
+            sets,
+            staging,
+            staging_allocation: Some(staging_allocation),
+            pipeline: pipeline.pipeline,
+            layout: pipeline.layout,
+            descriptor_pool,
+        })
+    }
+
+    // Runs the whole step on `queue` and waits for the result. Since every
+    // submission is waited on, `timer` turns its sections over per submission
+    // rather than per frame.
+    #[allow(clippy::too_many_arguments)]
+    pub fn diffuse(
+        &mut self,
+        device: &Device,
+        queue: &mut ComputeQueue,
+        mut timer: Option<&mut GpuTimer>,
+        values: &mut [f32],
+        settings: &EnvironmentSettings,
+        diffusion: f32,
+        dt: f32,
+    ) -> EngineResult<()> {
+        assert_eq!(values.len(), self.width * self.height);
+        let (substeps, rate) = diffusion_substeps(settings, diffusion, dt);
+        if substeps == 0 {
+            return Ok(());
+        }
+        // The wait is part of the scope, so it covers the GPU time as well.
+        let _scope = profiler::scope("GPU diffusion");
+        let staging = self.staging_allocation.as_ref().unwrap();
+        let mapped = staging.mapped_ptr().unwrap().cast::<f32>().as_ptr();
+        unsafe { std::ptr::copy_nonoverlapping(values.as_ptr(), mapped, values.len()) };
+
+        queue.submit(device, |command_buffer| unsafe {
+            debug::begin_label(command_buffer, "Diffusion", debug::COMPUTE_LABEL);
+            if let Some(timer) = timer.as_deref_mut() {
+                timer.begin_frame(device, command_buffer);
+            }
+            self.record(device, command_buffer, timer, substeps, rate);
+            debug::end_label(command_buffer);
+        })?;
+        queue.wait(device)?;
+
+        unsafe { std::ptr::copy_nonoverlapping(mapped, values.as_mut_ptr(), values.len()) };
+        Ok(())
+    }
+
+    unsafe fn record(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        mut timer: Option<&mut GpuTimer>,
+        substeps: u32,
+        rate: f32,
+    ) {
+        let extent = vk::Extent3D {
+            width: self.width as u32,
+            height: self.height as u32,
+            depth: 1,
+        };
+        let copy = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(extent)
+            .build();
+        let none = (
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            vk::AccessFlags::empty(),
+        );
+        let transfer_write = (
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+        let shader_read_write = (
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        );
+
+        // Upload into the first image; the second only ever gets written first.
+        image_barrier(
+            device,
+            command_buffer,
+            self.images[0].image,
+            (
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            ),
+            none,
+            transfer_write,
+        );
+        device.cmd_copy_buffer_to_image(
+            command_buffer,
+            self.staging,
+            self.images[0].image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[copy],
+        );
+        image_barrier(
+            device,
+            command_buffer,
+            self.images[0].image,
+            (
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::GENERAL,
+            ),
+            transfer_write,
+            shader_read_write,
+        );
+        image_barrier(
+            device,
+            command_buffer,
+            self.images[1].image,
+            (vk::ImageLayout::UNDEFINED, vk::ImageLayout::GENERAL),
+            none,
+            shader_read_write,
+        );
+
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::COMPUTE,
+            self.pipeline,
+        );
+        device.cmd_push_constants(
+            command_buffer,
+            self.layout,
+            vk::ShaderStageFlags::COMPUTE,
+            0,
+            as_bytes(&rate),
+        );
+        let groups_x = group_count(self.width as u32, GROUP_SIZE);
+        let groups_y = group_count(self.height as u32, GROUP_SIZE);
+        let token = timer
+            .as_deref_mut()
+            .and_then(|timer| timer.begin(device, command_buffer, "Diffusion"));
+        for step in 0..substeps as usize {
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::COMPUTE,
+                self.layout,
+                0,
+                &[self.sets[step % 2]],
+                &[],
+            );
+            device.cmd_dispatch(command_buffer, groups_x, groups_y, 1);
+            memory_barrier(device, command_buffer, shader_read_write, shader_read_write);
+        }
+        if let Some(timer) = timer {
+            timer.end(device, command_buffer, token);
+        }
+
+        let result = &self.images[substeps as usize % 2];
+        image_barrier(
+            device,
+            command_buffer,
+            result.image,
+            (
+                vk::ImageLayout::GENERAL,
+                vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            ),
+            shader_read_write,
+            (
+                vk::PipelineStageFlags::TRANSFER,
+                vk::AccessFlags::TRANSFER_READ,
+            ),
+        );
+        device.cmd_copy_image_to_buffer(
+            command_buffer,
+            result.image,
+            vk::ImageLayout::TRANSFER_SRC_OPTIMAL,
+            self.staging,
+            &[copy],
+        );
+        buffer_barrier(
+            device,
+            command_buffer,
+            self.staging,
+            transfer_write,
+            (vk::PipelineStageFlags::HOST, vk::AccessFlags::HOST_READ),
+        );
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe {
+            device
+                .free_descriptor_sets(self.descriptor_pool, &self.sets)
+                .unwrap();
+            for image in self.images.iter_mut() {
+                device.destroy_image_view(image.view, None);
+                device.destroy_image(image.image, None);
+                if let Some(allocation) = image.allocation.take() {
+                    allocator.free(allocation).unwrap();
+                }
+            }
+            device.destroy_buffer(self.staging, None);
+        }
+        if let Some(allocation) = self.staging_allocation.take() {
+            allocator.free(allocation).unwrap();
+        }
+    }
+}
+
+// `GpuDiffusion` together with what it needs to run, for handing to the simulation.
+// `Diffuser` can't fail, so the first error is kept in `error` and the remaining
+// steps are skipped.
+pub struct GpuDiffuser<'a> {
+    pub device: &'a Device,
+    pub queue: &'a mut ComputeQueue,
+    pub diffusion: &'a mut GpuDiffusion,
+    // Only when the compute queue can write timestamps.
+    pub timer: Option<&'a mut GpuTimer>,
+    pub error: Option<EngineError>,
+}
+
+impl Diffuser for GpuDiffuser<'_> {
+    fn diffuse(
+        &mut self,
+        values: &mut [f32],
+        settings: &EnvironmentSettings,
+        diffusion: f32,
+        dt: f32,
+    ) {
+        if self.error.is_some() {
+            return;
+        }
+        if let Err(error) = self.diffusion.diffuse(
+            self.device,
+            self.queue,
+            self.timer.as_deref_mut(),
+            values,
+            settings,
+            diffusion,
+            dt,
+        ) {
+            self.error = Some(error);
+        }
+    }
+}
+
+fn create_storage_image(
+    device: &Device,
+    allocator: &mut Allocator,
+    width: usize,
+    height: usize,
+) -> EngineResult<StorageImage> {
+    unsafe {
+        let image_info = vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(vk::Format::R32_SFLOAT)
+            .extent(vk::Extent3D {
+                width: width as u32,
+                height: height as u32,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(
+                vk::ImageUsageFlags::STORAGE
+                    | vk::ImageUsageFlags::TRANSFER_SRC
+                    | vk::ImageUsageFlags::TRANSFER_DST,
+            )
+            .sharing_mode(vk::SharingMode::EXCLUSIVE);
+        let image = device.create_image(&image_info, None)?;
+
+        let requirements = device.get_image_memory_requirements(image);
+        let allocation = allocator.allocate(&AllocationCreateDesc {
+            name: "Diffusion grid",
+            requirements,
+            location: gpu_allocator::MemoryLocation::GpuOnly,
+            linear: false,
+        })?;
+        device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+
+        let view_info = vk::ImageViewCreateInfo::builder()
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(vk::Format::R32_SFLOAT)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image(image);
+        let view = device.create_image_view(&view_info, None)?;
+        debug::set_name(device, image, "Diffusion grid");
+        debug::set_name(device, view, "Diffusion grid");
+        Ok(StorageImage {
+            image,
+            view,
+            allocation: Some(allocation),
+        })
+    }
+}
+
+// Runs both implementations side by side on a headless device and reports the
+// largest relative difference. Needs no window, so it also runs on a software
+// driver such as lavapipe:
+//
+//     VK_ICD_FILENAMES=/usr/share/vulkan/icd.d/lvp_icd.x86_64.json ecocide --check-diffusion
+pub fn check_against_cpu() -> anyhow::Result<f32> {
+    // Enough steps for every layer to spread well beyond its starting cells.
+    const STEPS: usize = 50;
+
+    unsafe {
+        let entry = Entry::linked();
+        let app_info = vk::ApplicationInfo::builder().api_version(vk::make_api_version(0, 1, 1, 0));
+        let instance = entry
+            .create_instance(
+                &vk::InstanceCreateInfo::builder().application_info(&app_info),
+                None,
+            )
+            .context("Could not create a Vulkan instance")?;
+
+        let (pdevice, family) = instance
+            .enumerate_physical_devices()?
+            .into_iter()
+            .find_map(|pdevice| {
+                instance
+                    .get_physical_device_queue_family_properties(pdevice)
+                    .iter()
+                    .position(|family| family.queue_flags.contains(vk::QueueFlags::COMPUTE))
+                    .map(|family| (pdevice, family as u32))
+            })
+            .context("No Vulkan device with a compute queue")?;
+        let name = std::ffi::CStr::from_ptr(
+            instance
+                .get_physical_device_properties(pdevice)
+                .device_name
+                .as_ptr(),
+        );
+        let priorities = [1.0];
+        let queue_info = vk::DeviceQueueCreateInfo::builder()
+            .queue_family_index(family)
+            .queue_priorities(&priorities);
+        let device = instance.create_device(
+            pdevice,
+            &vk::DeviceCreateInfo::builder().queue_create_infos(std::slice::from_ref(&queue_info)),
+            None,
+        )?;
+        let mut allocator = Allocator::new(&AllocatorCreateDesc {
+            instance: instance.clone(),
+            device: device.clone(),
+            physical_device: pdevice,
+            debug_settings: Default::default(),
+            buffer_device_address: false,
+        })?;
+
+        let compiler = shaderc::Compiler::new().context("Could not create a shader compiler")?;
+        let shader = compile_shader(
+            &device,
+            &compiler,
+            DIFFUSE_SHADER,
+            shaderc::ShaderKind::Compute,
+        )?;
+        let pipeline = diffusion_pipeline(&device, shader)?;
+        device.destroy_shader_module(shader, None);
+        let descriptor_pool = crate::compute::create_descriptor_pool(&device, 2)?;
+        let mut queue = ComputeQueue::new(&device, family, false)?;
+        log::info!(
+            "Checking diffusion on {}, queue family {}",
+            name.to_string_lossy(),
+            queue.family
+        );
+
+        let settings = EnvironmentSettings::default();
+        let mut gpu = GpuDiffusion::new(
+            &device,
+            &mut allocator,
+            &pipeline,
+            descriptor_pool,
+            settings.width,
+            settings.height,
+        )?;
+        let mut gpu_diffuser = GpuDiffuser {
+            device: &device,
+            queue: &mut queue,
+            diffusion: &mut gpu,
+            timer: None,
+            error: None,
+        };
+        let mut cpu = CpuDiffuser::default();
+        let mut seed = 0x5eed;
+        let mut worst = 0f32;
+        for layer in LAYERS {
+            let diffusion = settings.layer(layer).diffusion;
+            // Sparse spikes, like pollution sources, over a small random background.
+            let initial: Vec<f32> = (0..settings.width * settings.height)
+                .map(|_| {
+                    let roll = splitmix64(&mut seed);
+                    let background = (roll >> 40) as f32 / (1u64 << 24) as f32;
+                    if roll.is_multiple_of(50) {
+                        100.0 + background * 100.0
+                    } else {
+                        background
+                    }
+                })
+                .collect();
+            let mut reference = initial.clone();
+            let mut values = initial;
+            for _ in 0..STEPS {
+                cpu.diffuse(&mut reference, &settings, diffusion, TICK as f32);
+                gpu_diffuser.diffuse(&mut values, &settings, diffusion, TICK as f32);
+            }
+            if let Some(error) = gpu_diffuser.error.take() {
+                return Err(error.into());
+            }
+            let error = reference
+                .iter()
+                .zip(values.iter())
+                .map(|(a, b)| (a - b).abs() / a.abs().max(1.0))
+                .fold(0.0, f32::max);
+            log::info!("{:?}: largest relative difference {:e}", layer, error);
+            worst = worst.max(error);
+        }
+
+        gpu.destroy(&device, &mut allocator);
+        queue.destroy(&device);
+        device.destroy_descriptor_pool(descriptor_pool, None);
+        pipeline.destroy(&device);
+        drop(allocator);
+        device.destroy_device(None);
+        instance.destroy_instance(None);
+        Ok(worst)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Run with `cargo test -- --ignored`, pointing VK_ICD_FILENAMES at lavapipe on
+    // machines without a GPU.
+    #[test]
+    #[ignore = "needs a Vulkan device"]
+    fn gpu_matches_the_cpu_reference() {
+        let error = check_against_cpu().unwrap();
+        assert!(
+            error <= TOLERANCE,
+            "largest relative difference {:e} is over {:e}",
+            error,
+            TOLERANCE
+        );
+    }
+}
@@ -0,0 +1,250 @@
+// The in-game HUD: frame and simulation status, a bar per species and a minimap of
+// the regions, drawn over the scene whether or not the developer UI is open.
+
+use std::path::Path;
+
+use egui::{pos2, vec2, Color32, ColorImage, Pos2, Rect, TextureOptions};
+use winit::dpi::PhysicalSize;
+
+use crate::dev_ui::GRAPH_COLORS;
+use crate::engine::VkEngine;
+use crate::overlay::OverlayFrame;
+use crate::simulation::{Simulation, REGIONS_X, REGIONS_Z};
+use crate::sprites::{Sprite, SpriteBatch};
+use crate::text::{TextBatch, TextStyle, FONT_FILE};
+
+// Points between the HUD and the window's edges.
+const MARGIN: f32 = 8.0;
+// Room between a panel's edge and what's in it.
+const PADDING: f32 = 6.0;
+const MINIMAP_SIZE: f32 = 128.0;
+const BAR_SIZE: egui::Vec2 = egui::Vec2::new(120.0, 8.0);
+// The procedural atlas: a panel to nine-slice and a round marker.
+const PANEL_SIZE: usize = 24;
+const PANEL_BORDER: f32 = 8.0;
+const DOT_SIZE: usize = 16;
+
+// Layers, back to front.
+const PANEL_LAYER: i32 = 0;
+const CONTENT_LAYER: i32 = 1;
+const MARKER_LAYER: i32 = 2;
+
+pub struct Hud {
+    text: TextBatch,
+    sprites: SpriteBatch,
+    panel: Sprite,
+    dot: Sprite,
+}
+
+impl Hud {
+    pub fn new() -> anyhow::Result<Self> {
+        let text = TextBatch::load(Path::new(FONT_FILE))?;
+        let mut sprites = SpriteBatch::new();
+        let atlas = sprites.add_atlas(hud_atlas(), TextureOptions::LINEAR);
+        Ok(Hud {
+            text,
+            sprites,
+            panel: atlas.sprite([0, 0], [PANEL_SIZE, PANEL_SIZE]),
+            dot: atlas.sprite([PANEL_SIZE, 0], [DOT_SIZE, DOT_SIZE]),
+        })
+    }
+
+    pub fn draw(&mut self, engine: &VkEngine, simulation: &Simulation, size: PhysicalSize<u32>) {
+        let screen = vec2(size.width as f32, size.height as f32) / engine.scale_factor as f32;
+        let status_bottom = self.status(engine, simulation, screen.x);
+        self.key_hint(screen.y);
+        self.species_bars(simulation, pos2(MARGIN, status_bottom + MARGIN));
+        self.minimap(
+            engine,
+            simulation,
+            Rect::from_min_size(
+                (screen - vec2(MARGIN + MINIMAP_SIZE, MARGIN + MINIMAP_SIZE)).to_pos2(),
+                vec2(MINIMAP_SIZE, MINIMAP_SIZE),
+            ),
+        );
+    }
+
+    // Sprites go under the text, and both under the developer UI.
+    pub fn finish(&mut self, frame: &mut OverlayFrame) {
+        self.text.finish(frame);
+        self.sprites.finish(frame);
+    }
+
+    // Status text in the top left corner, wrapped to the window. Returns where its
+    // panel ends.
+    fn status(&mut self, engine: &VkEngine, simulation: &Simulation, width: f32) -> f32 {
+        let status = format!("{}\n{}", crate::frame_stats(engine), simulation.summary());
+        let style = TextStyle {
+            size: 14.0,
+            wrap_width: Some((width - 2.0 * (MARGIN + PADDING)).max(MARGIN)),
+            ..Default::default()
+        };
+        let inset = vec2(MARGIN + PADDING, MARGIN + PADDING);
+        let rect = self.text.draw(inset.to_pos2(), &status, style);
+        let panel = rect.expand(PADDING);
+        self.sprites.nine_slice(
+            &self.panel,
+            PANEL_BORDER,
+            panel,
+            Color32::WHITE,
+            PANEL_LAYER,
+        );
+        panel.bottom()
+    }
+
+    // The overlay keys in the bottom left corner, clear of the minimap.
+    fn key_hint(&mut self, height: f32) {
+        let hint = "F1 developer UI  F4 debug lines";
+        let style = TextStyle {
+            size: 12.0,
+            color: Color32::from_gray(190),
+            ..Default::default()
+        };
+        let size = self.text.measure(hint, &style);
+        self.text
+            .draw(pos2(MARGIN, height - MARGIN - size.y), hint, style);
+    }
+
+    // Each species' population against what its regions can carry.
+    fn species_bars(&mut self, simulation: &Simulation, top_left: Pos2) {
+        let eco = &simulation.ecosystem;
+        if eco.species.is_empty() {
+            return;
+        }
+        let style = TextStyle {
+            size: 12.0,
+            ..Default::default()
+        };
+        let row_height = (style.size * 1.4).max(BAR_SIZE.y);
+        let label_width = eco
+            .species
+            .iter()
+            .map(|species| self.text.measure(&species.name, &style).x)
+            .fold(0.0, f32::max);
+        let inner = vec2(
+            label_width + PADDING + BAR_SIZE.x,
+            row_height * eco.species.len() as f32,
+        );
+        let panel = Rect::from_min_size(top_left, inner + vec2(PADDING, PADDING) * 2.0);
+        self.sprites.nine_slice(
+            &self.panel,
+            PANEL_BORDER,
+            panel,
+            Color32::WHITE,
+            PANEL_LAYER,
+        );
+
+        for (id, species) in eco.species.iter().enumerate() {
+            let row = top_left + vec2(PADDING, PADDING + row_height * id as f32);
+            self.text.draw(row, &species.name, style);
+            let capacity: f64 = eco
+                .regions
+                .iter()
+                .map(|region| species.carrying_capacity * region.capacity_scale[id])
+                .sum();
+            let fraction = if capacity > 0.0 {
+                (eco.total_population(id) / capacity) as f32
+            } else {
+                0.0
+            };
+            let bar = Rect::from_min_size(
+                row + vec2(label_width + PADDING, (row_height - BAR_SIZE.y) * 0.5),
+                BAR_SIZE,
+            );
+            self.sprites.bar(
+                bar,
+                fraction,
+                GRAPH_COLORS[id % GRAPH_COLORS.len()],
+                Color32::from_black_alpha(140),
+                CONTENT_LAYER,
+            );
+        }
+    }
+
+    // The regions shaded by how full they are, pollution sources in red and the
+    // camera in white, with x to the right and z down.
+    fn minimap(&mut self, engine: &VkEngine, simulation: &Simulation, rect: Rect) {
+        self.sprites
+            .nine_slice(&self.panel, PANEL_BORDER, rect, Color32::WHITE, PANEL_LAYER);
+        let map = rect.shrink(PADDING);
+        let eco = &simulation.ecosystem;
+        let cell = vec2(
+            map.width() / REGIONS_X as f32,
+            map.height() / REGIONS_Z as f32,
+        );
+        for (index, region) in eco.regions.iter().enumerate() {
+            let (rx, rz) = (index % REGIONS_X, index / REGIONS_X);
+            let occupancy = eco
+                .species
+                .iter()
+                .enumerate()
+                .map(|(id, species)| {
+                    let capacity = species.carrying_capacity * region.capacity_scale[id];
+                    if capacity > 0.0 {
+                        (region.populations[id] / capacity).min(1.0)
+                    } else {
+                        0.0
+                    }
+                })
+                .sum::<f64>()
+                / eco.species.len().max(1) as f64;
+            let green = (40.0 + 180.0 * occupancy) as u8;
+            let min = map.min + vec2(rx as f32 * cell.x, rz as f32 * cell.y);
+            self.sprites.rect(
+                Rect::from_min_size(min, cell).shrink(0.5),
+                Color32::from_rgb(20, green, 30),
+                CONTENT_LAYER,
+            );
+        }
+
+        let extent = engine.terrain.heightfield.extent();
+        let to_map =
+            |x: f32, z: f32| map.min + vec2(x / extent * map.width(), z / extent * map.height());
+        for source in simulation.environment.sources.iter() {
+            self.marker(
+                to_map(source.x, source.z),
+                8.0,
+                Color32::from_rgb(230, 60, 50),
+            );
+        }
+        let camera = engine.camera.position;
+        let position = to_map(camera.x, camera.z).clamp(map.min, map.max);
+        self.marker(position, 10.0, Color32::WHITE);
+    }
+
+    fn marker(&mut self, center: Pos2, size: f32, color: Color32) {
+        let rect = Rect::from_center_size(center, vec2(size, size));
+        self.sprites.sprite(&self.dot, rect, color, MARKER_LAYER);
+    }
+}
+
+// A rounded panel with a light edge, then a soft white dot, side by side.
+fn hud_atlas() -> ColorImage {
+    let width = PANEL_SIZE + DOT_SIZE;
+    let height = PANEL_SIZE.max(DOT_SIZE);
+    let mut image = ColorImage::new([width, height], Color32::TRANSPARENT);
+    let fill = Color32::from_rgba_unmultiplied(16, 20, 24, 180);
+    let edge = Color32::from_rgba_unmultiplied(200, 210, 220, 200);
+    let half = PANEL_SIZE as f32 * 0.5;
+    let radius = 6.0;
+    for y in 0..PANEL_SIZE {
+        for x in 0..PANEL_SIZE {
+            // Signed distance to the rounded rectangle's outline.
+            let p = vec2((x as f32 + 0.5 - half).abs(), (y as f32 + 0.5 - half).abs());
+            let q = p - vec2(half - radius, half - radius);
+            let distance = q.max(egui::Vec2::ZERO).length() + q.x.max(q.y).min(0.0) - radius;
+            let coverage = (0.5 - distance).clamp(0.0, 1.0);
+            let color = if distance > -1.5 { edge } else { fill };
+            image[(x, y)] = color.gamma_multiply(coverage);
+        }
+    }
+    let radius = DOT_SIZE as f32 * 0.5;
+    for y in 0..DOT_SIZE {
+        for x in 0..DOT_SIZE {
+            let offset = vec2(x as f32 + 0.5 - radius, y as f32 + 0.5 - radius);
+            let coverage = (radius - 0.5 - offset.length()).clamp(0.0, 1.0);
+            image[(PANEL_SIZE + x, y)] = Color32::WHITE.gamma_multiply(coverage);
+        }
+    }
+    image
+}
@@ -0,0 +1,34 @@
+// A minimal logger for the `log` facade that writes to stderr. `ECOCIDE_LOG` sets
+// the most verbose level shown: error, warn, info, debug or trace.
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+pub const LOG_ENV: &str = "ECOCIDE_LOG";
+
+struct StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+pub fn init() {
+    let level = std::env::var(LOG_ENV)
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    if log::set_logger(&LOGGER).is_ok() {
+        log::set_max_level(level);
+    }
+}
@@ -1,8 +1,51 @@
+mod brush;
+mod camera;
+mod compute;
+mod culling;
+mod debug;
+mod debug_draw;
+mod definitions;
+mod dev_ui;
+mod device;
+mod ecosystem;
 mod engine;
+mod environment;
+mod error;
+mod gpu_culling;
+mod gpu_diffusion;
+mod hud;
+mod logging;
 mod mesh;
+mod noise;
+mod overlay;
 mod pipeline;
+mod post;
+mod profiler;
+mod render_graph;
+mod scatter;
+mod simulation;
+mod sprites;
+mod terrain;
+mod text;
+mod ui;
 
-use engine::VkEngine;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use ash::vk;
+use brush::{BrushKind, EditHistory, TerrainEdit};
+use cgmath::Vector3;
+use debug::ValidationSettings;
+use definitions::{DefinitionError, DefinitionWatcher, Definitions};
+use dev_ui::DevUi;
+use device::DeviceChoice;
+use engine::{EngineSettings, GraphicsSettings, VkEngine, RENDER_PASSES_ENV};
+use environment::{CpuDiffuser, Emission, EnvironmentSettings, PollutionSource};
+use hud::Hud;
+use simulation::{Simulation, REGIONS_X, REGIONS_Z};
+use terrain::Terrain;
+use ui::Ui;
 
 use winit::{
     event::{ElementState, Event, KeyboardInput, VirtualKeyCode, WindowEvent},
@@ -10,17 +53,99 @@ use winit::{
 };
 use winit::{event_loop::EventLoop, window::WindowBuilder};
 
+const CAMERA_SPEED: f32 = 24.0;
+const CAMERA_TURN_SPEED: f32 = 1.5;
+const BRUSH_RADIUS: f32 = 6.0;
+const BRUSH_RATE: f32 = 6.0;
+const BRUSH_REACH: f32 = 400.0;
+const EDITS_FILE: &str = "saves/terrain_edits.txt";
+const TRACE_FILE: &str = "saves/trace.json";
+const GRAPH_FILE: &str = "saves/render_graph.dot";
+const FACTORY_EMISSION: Emission = Emission {
+    soil: 5.0,
+    water: 20.0,
+    air: 40.0,
+    heat: 10.0,
+};
+// What M and L cycle through.
+const PRESENT_MODES: [Option<vk::PresentModeKHR>; 4] = [
+    None,
+    Some(vk::PresentModeKHR::MAILBOX),
+    Some(vk::PresentModeKHR::FIFO_RELAXED),
+    Some(vk::PresentModeKHR::IMMEDIATE),
+];
+const FRAME_LIMITS: [Option<u32>; 4] = [None, Some(30), Some(60), Some(144)];
+// What N cycles through, in samples per pixel.
+const MSAA_LEVELS: [u32; 4] = [1, 2, 4, 8];
+const FIRE_EMISSION: Emission = Emission {
+    soil: 20.0,
+    water: 0.0,
+    air: 200.0,
+    heat: 400.0,
+};
+
 fn main() {
+    logging::init();
+    if std::env::args().any(|arg| arg == "--check-diffusion") {
+        std::process::exit(check_diffusion());
+    }
     let event_loop = EventLoop::new();
     let window = WindowBuilder::new()
         .with_title("Ecocide")
         .with_inner_size(winit::dpi::LogicalSize::new(800.0f64, 600.0f64))
         .build(&event_loop)
         .unwrap();
-    let mut engine = VkEngine::new(&window);
+    let settings = EngineSettings {
+        device: DeviceChoice::from_env(),
+        validation: ValidationSettings::from_env(),
+        dynamic_rendering: std::env::var_os(RENDER_PASSES_ENV).is_none(),
+        ..Default::default()
+    };
+    let mut engine = VkEngine::new(&window, settings).unwrap_or_else(|e| {
+        log::error!("Could not start the renderer: {}", e);
+        std::process::exit(1);
+    });
+    for candidate in engine.devices.iter() {
+        let marker = if candidate.index == engine.device_index {
+            "*"
+        } else {
+            " "
+        };
+        log::info!("{} {}", marker, candidate);
+    }
+    let mut held_keys = HashSet::new();
+    let mut last_frame = Instant::now();
+    let mut edits = EditHistory::default();
+    let mut flatten_height = 0f32;
+    let definitions = Definitions::load().unwrap_or_else(|errors| {
+        report_definition_errors(&errors);
+        std::process::exit(1);
+    });
+    let environment = EnvironmentSettings {
+        cell_size: engine.terrain.heightfield.extent() / 64f32,
+        ..Default::default()
+    };
+    let mut simulation = Simulation::new(definitions, region_heights(&engine.terrain), environment);
+    let mut watcher = DefinitionWatcher::new();
+    let mut last_summary = Instant::now();
+    let start = Instant::now();
+    let mut vegetation_revision = None;
+    let mut cpu_diffuser = CpuDiffuser::default();
+    let mut diffuse_on_gpu = false;
+    let mut ui = Ui::new(window.scale_factor());
+    let mut dev_ui = DevUi::default();
+    let mut hud = Hud::new().unwrap_or_else(|e| {
+        log::error!("Could not load the HUD font: {:#}", e);
+        std::process::exit(1);
+    });
 
     event_loop.run(move |event, _, control_flow| {
         *control_flow = ControlFlow::Poll;
+        if let Event::WindowEvent { event, .. } = &event {
+            if ui.on_event(event) {
+                return;
+            }
+        }
         match event {
             Event::WindowEvent {
                 event:
@@ -36,8 +161,418 @@ fn main() {
                     },
                 ..
             } => *control_flow = ControlFlow::Exit,
-            Event::MainEventsCleared => engine.draw(),
+            Event::WindowEvent {
+                event: WindowEvent::Resized(size),
+                ..
+            } => engine.resize(size),
+            Event::WindowEvent {
+                event:
+                    WindowEvent::ScaleFactorChanged {
+                        scale_factor,
+                        new_inner_size,
+                    },
+                ..
+            } => {
+                engine.scale_factor = scale_factor;
+                engine.resize(*new_inner_size);
+            }
+            Event::WindowEvent {
+                event:
+                    WindowEvent::KeyboardInput {
+                        input:
+                            KeyboardInput {
+                                state,
+                                virtual_keycode: Some(key),
+                                ..
+                            },
+                        ..
+                    },
+                ..
+            } => match state {
+                ElementState::Pressed => {
+                    if held_keys.insert(key) {
+                        key_pressed(
+                            &mut engine,
+                            &mut simulation,
+                            &mut edits,
+                            &mut flatten_height,
+                            &mut diffuse_on_gpu,
+                            &mut ui,
+                            key,
+                        );
+                    }
+                }
+                ElementState::Released => {
+                    held_keys.remove(&key);
+                }
+            },
+            Event::MainEventsCleared => {
+                let now = Instant::now();
+                let dt = (now - last_frame).as_secs_f32();
+                last_frame = now;
+                profiler::end_frame();
+
+                let simulation_scope = profiler::scope("Simulation");
+                if diffuse_on_gpu {
+                    let grid = &simulation.environment.settings;
+                    let error = match engine.gpu_diffuser(grid.width, grid.height) {
+                        Ok(mut diffuser) => {
+                            simulation.advance(dt as f64, &mut diffuser);
+                            diffuser.error
+                        }
+                        Err(e) => Some(e),
+                    };
+                    if let Some(e) = error {
+                        log::error!("GPU diffusion failed, switching to the CPU: {}", e);
+                        diffuse_on_gpu = false;
+                    }
+                } else {
+                    simulation.advance(dt as f64, &mut cpu_diffuser);
+                }
+                drop(simulation_scope);
+                let mut replant = vegetation_revision != Some(engine.terrain.revision());
+                if now - last_summary > Duration::from_secs(1) {
+                    window.set_title(&format!(
+                        "Ecocide - {} - {} - {} - diffusion on {} - {:?}{}",
+                        frame_stats(&engine),
+                        simulation.summary(),
+                        engine.cull_stats,
+                        match (diffuse_on_gpu, engine.compute.dedicated) {
+                            (false, _) => "CPU",
+                            (true, false) => "GPU",
+                            (true, true) => "GPU (compute queue)",
+                        },
+                        engine.present_mode,
+                        engine
+                            .graphics
+                            .frame_limit
+                            .map_or(String::new(), |limit| format!(" capped at {} fps", limit))
+                    ));
+                    last_summary = now;
+
+                    if watcher.changed() {
+                        match Definitions::load() {
+                            Ok(definitions) => {
+                                simulation.reload(definitions);
+                                log::info!("Reloaded species and biome definitions");
+                            }
+                            Err(errors) => report_definition_errors(&errors),
+                        }
+                    }
+                    replant = true;
+                }
+                let scatter_scope = profiler::scope("Scatter");
+                if replant {
+                    let trees = scatter::vegetation(&engine.terrain, &simulation.ecosystem);
+                    engine.vegetation.set(trees);
+                    vegetation_revision = Some(engine.terrain.revision());
+                }
+                let time = (now - start).as_secs_f32();
+                let animals = scatter::fauna(&engine.terrain, &simulation.ecosystem, time);
+                engine.fauna.set(animals);
+                drop(scatter_scope);
+
+                move_camera(&mut engine, &held_keys, dt);
+                paint_terrain(
+                    &mut engine,
+                    &mut simulation,
+                    &mut edits,
+                    &held_keys,
+                    flatten_height,
+                    dt,
+                );
+                if debug_draw::enabled() {
+                    draw_debug_overlay(&engine, &simulation);
+                }
+                dev_ui.record(&simulation);
+                let mut overlay = ui.run(window.inner_size(), engine.scale_factor, |ctx| {
+                    dev_ui.show(ctx, &mut engine, &mut simulation, &mut diffuse_on_gpu)
+                });
+                hud.draw(&engine, &simulation, window.inner_size());
+                hud.finish(&mut overlay);
+                engine.set_overlay(overlay);
+                if let Err(e) = engine.draw() {
+                    log::error!("Rendering stopped: {}", e);
+                    *control_flow = ControlFlow::Exit;
+                }
+            }
             _ => (),
         }
     });
 }
+
+fn move_camera(engine: &mut VkEngine, held_keys: &HashSet<VirtualKeyCode>, dt: f32) {
+    let camera = &mut engine.camera;
+    let forward = camera.forward();
+    let right = camera.right();
+    let step = CAMERA_SPEED * dt;
+    let turn = CAMERA_TURN_SPEED * dt;
+
+    for key in held_keys.iter() {
+        match key {
+            VirtualKeyCode::W => camera.position += forward * step,
+            VirtualKeyCode::S => camera.position -= forward * step,
+            VirtualKeyCode::D => camera.position += right * step,
+            VirtualKeyCode::A => camera.position -= right * step,
+            VirtualKeyCode::E => camera.position.y += step,
+            VirtualKeyCode::Q => camera.position.y -= step,
+            VirtualKeyCode::Left => camera.yaw.0 -= turn,
+            VirtualKeyCode::Right => camera.yaw.0 += turn,
+            VirtualKeyCode::Up => camera.pitch.0 = (camera.pitch.0 + turn).min(1.5),
+            VirtualKeyCode::Down => camera.pitch.0 = (camera.pitch.0 - turn).max(-1.5),
+            _ => {}
+        }
+    }
+}
+
+fn brush_target(engine: &VkEngine) -> Option<Vector3<f32>> {
+    engine
+        .terrain
+        .raycast(engine.camera.position, engine.camera.forward(), BRUSH_REACH)
+}
+
+fn brush_for_key(key: VirtualKeyCode, flatten_height: f32) -> Option<BrushKind> {
+    match key {
+        VirtualKeyCode::R => Some(BrushKind::Raise),
+        VirtualKeyCode::F => Some(BrushKind::Lower),
+        VirtualKeyCode::T => Some(BrushKind::Smooth),
+        VirtualKeyCode::G => Some(BrushKind::Flatten {
+            height: flatten_height,
+        }),
+        VirtualKeyCode::P => Some(BrushKind::Paint {
+            color: Vector3::new(0.55, 0.42, 0.2),
+        }),
+        VirtualKeyCode::B => Some(BrushKind::Scorch),
+        _ => None,
+    }
+}
+
+fn key_pressed(
+    engine: &mut VkEngine,
+    simulation: &mut Simulation,
+    edits: &mut EditHistory,
+    flatten_height: &mut f32,
+    diffuse_on_gpu: &mut bool,
+    ui: &mut Ui,
+    key: VirtualKeyCode,
+) {
+    match key {
+        VirtualKeyCode::F1 => {
+            ui.visible = !ui.visible;
+        }
+        VirtualKeyCode::Z => {
+            edits.undo(&mut engine.terrain);
+        }
+        VirtualKeyCode::Y => {
+            edits.redo(&mut engine.terrain);
+        }
+        VirtualKeyCode::F2 => {
+            *diffuse_on_gpu = !*diffuse_on_gpu;
+        }
+        VirtualKeyCode::V => {
+            let graphics = engine.graphics;
+            engine.set_graphics(GraphicsSettings {
+                vsync: !graphics.vsync,
+                ..graphics
+            });
+        }
+        VirtualKeyCode::M => {
+            let graphics = engine.graphics;
+            engine.set_graphics(GraphicsSettings {
+                present_mode: next_in(&PRESENT_MODES, graphics.present_mode),
+                ..graphics
+            });
+        }
+        VirtualKeyCode::L => {
+            let graphics = engine.graphics;
+            engine.set_graphics(GraphicsSettings {
+                frame_limit: next_in(&FRAME_LIMITS, graphics.frame_limit),
+                ..graphics
+            });
+        }
+        VirtualKeyCode::F3 => {
+            let graphics = engine.graphics;
+            engine.set_graphics(GraphicsSettings {
+                prefer_srgb: !graphics.prefer_srgb,
+                ..graphics
+            });
+        }
+        VirtualKeyCode::N => {
+            let graphics = engine.graphics;
+            engine.set_graphics(GraphicsSettings {
+                msaa: next_in(&MSAA_LEVELS, graphics.msaa),
+                ..graphics
+            });
+        }
+        VirtualKeyCode::F4 => {
+            debug_draw::set_enabled(!debug_draw::enabled());
+        }
+        VirtualKeyCode::F5 => {
+            if let Err(e) = edits.save(Path::new(EDITS_FILE)) {
+                log::error!("Could not save terrain edits: {:#}", e);
+            }
+        }
+        VirtualKeyCode::F12 => match profiler::write_chrome_trace(Path::new(TRACE_FILE)) {
+            Ok(()) => log::info!("Wrote {}", TRACE_FILE),
+            Err(e) => log::warn!("Could not write the trace: {:#}", e),
+        },
+        VirtualKeyCode::F11 => {
+            engine.graph_dump = Some(PathBuf::from(GRAPH_FILE));
+        }
+        VirtualKeyCode::F9 => match EditHistory::load(Path::new(EDITS_FILE)) {
+            Ok(strokes) => edits.replay(&mut engine.terrain, strokes),
+            Err(e) => log::warn!("Could not load terrain edits: {:#}", e),
+        },
+        VirtualKeyCode::K => {
+            if let Some(hit) = brush_target(engine) {
+                simulation.environment.add_source(PollutionSource {
+                    x: hit.x,
+                    z: hit.z,
+                    radius: 12f32,
+                    emission: FACTORY_EMISSION,
+                    remaining: None,
+                });
+            }
+        }
+        _ if brush_for_key(key, 0f32).is_some() => {
+            if let Some(hit) = brush_target(engine) {
+                *flatten_height = hit.y;
+            }
+            edits.begin_stroke();
+        }
+        _ => {}
+    }
+}
+
+fn paint_terrain(
+    engine: &mut VkEngine,
+    simulation: &mut Simulation,
+    edits: &mut EditHistory,
+    held_keys: &HashSet<VirtualKeyCode>,
+    flatten_height: f32,
+    dt: f32,
+) {
+    let hit = match brush_target(engine) {
+        Some(hit) => hit,
+        None => return,
+    };
+    for key in held_keys.iter() {
+        if let Some(kind) = brush_for_key(*key, flatten_height) {
+            let edit = TerrainEdit {
+                kind,
+                x: hit.x,
+                z: hit.z,
+                radius: BRUSH_RADIUS,
+                strength: BRUSH_RATE * dt,
+            };
+            edits.apply(&mut engine.terrain, edit);
+
+            if kind == BrushKind::Scorch {
+                simulation.environment.add_source(PollutionSource {
+                    x: hit.x,
+                    z: hit.z,
+                    radius: BRUSH_RADIUS,
+                    emission: FIRE_EMISSION,
+                    remaining: Some(dt),
+                });
+            }
+        }
+    }
+}
+
+// The loaded terrain chunks, where the brush would land and what the pollution
+// sources are putting out.
+fn draw_debug_overlay(engine: &VkEngine, simulation: &Simulation) {
+    for mesh in engine.terrain_meshes.values() {
+        debug_draw::aabb(&mesh.bounds, Vector3::new(0.4, 0.4, 0.4));
+    }
+    if let Some(hit) = brush_target(engine) {
+        let color = Vector3::new(1.0, 1.0, 0.2);
+        debug_draw::sphere(hit, BRUSH_RADIUS, color);
+        debug_draw::line(hit, hit + Vector3::new(0.0, BRUSH_RADIUS, 0.0), color);
+    }
+    for source in simulation.environment.sources.iter() {
+        let ground = Vector3::new(
+            source.x,
+            engine.terrain.heightfield.sample(source.x, source.z),
+            source.z,
+        );
+        let color = Vector3::new(1.0, 0.3, 0.2);
+        let plume = ground + Vector3::new(0.0, source.emission.air * 0.1, 0.0);
+        debug_draw::sphere(ground, source.radius, color);
+        debug_draw::arrow(ground, plume, color);
+        debug_draw::text3d(
+            plume + Vector3::new(0.0, 1.0, 0.0),
+            &format!("air {:.0}", source.emission.air),
+            1.5,
+            color,
+        );
+    }
+}
+
+// The entry after `current`, wrapping around.
+fn next_in<T: Copy + PartialEq>(options: &[T], current: T) -> T {
+    let index = options.iter().position(|&option| option == current);
+    options[index.map_or(0, |index| (index + 1) % options.len())]
+}
+
+// Average frame time and the GPU's share of it, per timed pass.
+fn frame_stats(engine: &VkEngine) -> String {
+    let mut stats = format!("{:.1} ms", profiler::frame_time_ms());
+    for timer in engine.gpu_timer.iter().chain(engine.compute_timer.iter()) {
+        for (name, ms) in timer.results.iter() {
+            stats += &format!(", {} {:.2} ms", name, ms);
+        }
+    }
+    stats
+}
+
+fn report_definition_errors(errors: &[DefinitionError]) {
+    log::error!("Invalid species/biome definitions:");
+    for error in errors {
+        log::error!("  {}", error);
+    }
+}
+
+// Compares the compute shader diffusion against the CPU reference and returns the
+// process exit code.
+fn check_diffusion() -> i32 {
+    match gpu_diffusion::check_against_cpu() {
+        Ok(error) if error <= gpu_diffusion::TOLERANCE => 0,
+        Ok(error) => {
+            log::error!(
+                "GPU diffusion differs from the CPU reference by {:e}, more than {:e}",
+                error,
+                gpu_diffusion::TOLERANCE
+            );
+            1
+        }
+        Err(e) => {
+            log::error!("Could not run the diffusion check: {:#}", e);
+            2
+        }
+    }
+}
+
+// Mean normalized height of each simulation region, laid out the same way as the
+// ecosystem's region grid.
+fn region_heights(terrain: &Terrain) -> Vec<f32> {
+    let field = &terrain.heightfield;
+    let cells = field.size - 1;
+    let mut heights = Vec::with_capacity(REGIONS_X * REGIONS_Z);
+    for rz in 0..REGIONS_Z {
+        for rx in 0..REGIONS_X {
+            let (x0, x1) = (rx * cells / REGIONS_X, (rx + 1) * cells / REGIONS_X);
+            let (z0, z1) = (rz * cells / REGIONS_Z, (rz + 1) * cells / REGIONS_Z);
+            let mut sum = 0f32;
+            for z in z0..z1 {
+                for x in x0..x1 {
+                    sum += field.height(x, z);
+                }
+            }
+            let mean = sum / ((x1 - x0) * (z1 - z0)) as f32;
+            heights.push(mean / terrain.settings.height_scale);
+        }
+    }
+    heights
+}
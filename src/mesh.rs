@@ -1,9 +1,13 @@
 use ash::{vk, Device};
-use cgmath::Vector3;
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4};
 use gpu_allocator::vulkan::*;
 use tobj::GPU_LOAD_OPTIONS;
 
-#[derive(Clone)]
+use crate::culling::{Aabb, BoundingSphere};
+use crate::debug;
+use crate::error::{EngineError, EngineResult};
+
+#[derive(Clone, Copy)]
 #[repr(C)]
 pub struct Vertex {
     pub position: cgmath::Vector3<f32>,
@@ -18,60 +22,271 @@ pub struct Mesh {
 pub struct MeshBuffer {
     pub buffer: vk::Buffer,
     pub vertex_count: u32,
+    // In the space the vertices are given in.
+    pub bounds: Aabb,
+    pub sphere: BoundingSphere,
     pub meshes: Option<Vec<Mesh>>,
+    // Null unless the mesh was uploaded with indices.
+    pub index_buffer: vk::Buffer,
+    pub index_count: u32,
+    pub index_allocation: Option<Allocation>,
+}
+
+// Per-instance attributes, read from a second vertex binding that advances once
+// per instance instead of once per vertex.
+#[derive(Clone, Copy)]
+#[repr(C)]
+pub struct InstanceData {
+    pub transform: Matrix4<f32>,
+    // Multiplies the mesh's vertex colours.
+    pub tint: Vector4<f32>,
+}
+
+pub struct InstanceBuffer {
+    pub buffer: vk::Buffer,
+    pub allocation: Option<Allocation>,
+    pub capacity: usize,
+    pub count: u32,
+}
+
+const MONKEY_FILE: &str = "assets/monkey_flat.obj";
+
+pub fn monkey_mesh(device: &Device, allocator: &mut Allocator) -> EngineResult<MeshBuffer> {
+    let (vertices, indices) = load_monkey()?;
+    let vertices = indices.iter().map(|&i| vertices[i as usize]).collect();
+    upload_mesh(device, allocator, "Monkey", vertices)
 }
 
-pub fn monkey_mesh(device: &Device, allocator: &mut Allocator) -> MeshBuffer {
-    let (models, _) =
-        tobj::load_obj("assets/monkey_flat.obj", &GPU_LOAD_OPTIONS).expect("Could not load monkey");
+pub fn indexed_monkey_mesh(device: &Device, allocator: &mut Allocator) -> EngineResult<MeshBuffer> {
+    let (vertices, indices) = load_monkey()?;
+    upload_indexed_mesh(device, allocator, "Monkey", vertices, &indices)
+}
 
-    let mut vertices: Vec<Vertex> = Vec::new();
-    let mesh = &models[0].mesh;
+fn load_monkey() -> EngineResult<(Vec<Vertex>, Vec<u32>)> {
+    let asset_error = |reason: String| EngineError::Asset {
+        file: MONKEY_FILE.to_string(),
+        reason,
+    };
+    let (models, _) = tobj::load_obj(MONKEY_FILE, &GPU_LOAD_OPTIONS)
+        .map_err(|error| asset_error(error.to_string()))?;
+    let mesh = &models
+        .first()
+        .ok_or_else(|| asset_error("no models".to_string()))?
+        .mesh;
+    if mesh.normals.len() != mesh.positions.len() {
+        return Err(asset_error("every vertex needs a normal".to_string()));
+    }
     let positions = &mesh.positions;
     let normals = &mesh.normals;
-
-    for idx in mesh.indices.iter() {
-        let i = *idx as usize;
-        vertices.push(Vertex {
+    let vertices = (0..positions.len() / 3)
+        .map(|i| Vertex {
             position: Vector3::new(positions[i * 3], positions[i * 3 + 1], positions[i * 3 + 2]),
             color: Vector3::new(normals[i * 3], normals[i * 3 + 1], normals[i * 3 + 2]),
-        });
-    }
-
-    let buffer_info = vk::BufferCreateInfo::builder()
-        .size(10000 * 12)
-        .usage(vk::BufferUsageFlags::VERTEX_BUFFER);
-    let buffer = unsafe { device.create_buffer(&buffer_info, None) }.unwrap();
-    let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
-    let allocation = allocator
-        .allocate(&AllocationCreateDesc {
-            name: "Triangle",
-            requirements,
-            location: gpu_allocator::MemoryLocation::CpuToGpu,
-            linear: true,
         })
-        .unwrap();
-    unsafe {
-        device
-            .bind_buffer_memory(buffer, allocation.memory(), allocation.offset())
-            .unwrap()
-    };
+        .collect();
+    Ok((vertices, mesh.indices.clone()))
+}
+
+pub fn upload_mesh(
+    device: &Device,
+    allocator: &mut Allocator,
+    name: &str,
+    vertices: Vec<Vertex>,
+) -> EngineResult<MeshBuffer> {
+    let (buffer, allocation) = create_buffer(
+        device,
+        allocator,
+        name,
+        std::mem::size_of::<Vertex>() * vertices.len().max(1),
+        vk::BufferUsageFlags::VERTEX_BUFFER,
+    )?;
     unsafe {
         let ptr = allocation.mapped_ptr().unwrap().cast::<Vertex>().as_ptr();
-        std::ptr::copy_nonoverlapping(
-            vertices.as_ptr(),
-            ptr,
-            std::mem::size_of::<Vertex>() * vertices.len(),
-        );
+        std::ptr::copy_nonoverlapping(vertices.as_ptr(), ptr, vertices.len());
     };
 
-    MeshBuffer {
+    let positions: Vec<Vector3<f32>> = vertices.iter().map(|v| v.position).collect();
+    Ok(MeshBuffer {
         buffer,
         vertex_count: (vertices.len()) as u32,
+        bounds: Aabb::from_points(positions.iter().copied()),
+        sphere: BoundingSphere::from_points(&positions),
         meshes: Some(vec![Mesh {
             vertices,
             allocation,
         }]),
+        index_buffer: vk::Buffer::null(),
+        index_count: 0,
+        index_allocation: None,
+    })
+}
+
+pub fn upload_indexed_mesh(
+    device: &Device,
+    allocator: &mut Allocator,
+    name: &str,
+    vertices: Vec<Vertex>,
+    indices: &[u32],
+) -> EngineResult<MeshBuffer> {
+    let mut mesh = upload_mesh(device, allocator, name, vertices)?;
+    let (buffer, allocation) = create_buffer(
+        device,
+        allocator,
+        name,
+        std::mem::size_of::<u32>() * indices.len().max(1),
+        vk::BufferUsageFlags::INDEX_BUFFER,
+    )?;
+    unsafe {
+        let ptr = allocation.mapped_ptr().unwrap().cast::<u32>().as_ptr();
+        std::ptr::copy_nonoverlapping(indices.as_ptr(), ptr, indices.len());
+    };
+    mesh.index_buffer = buffer;
+    mesh.index_count = indices.len() as u32;
+    mesh.index_allocation = Some(allocation);
+    Ok(mesh)
+}
+
+// Host visible buffer, mapped for the lifetime of its allocation.
+pub fn create_buffer(
+    device: &Device,
+    allocator: &mut Allocator,
+    name: &str,
+    size: usize,
+    usage: vk::BufferUsageFlags,
+) -> EngineResult<(vk::Buffer, Allocation)> {
+    let buffer_info = vk::BufferCreateInfo::builder()
+        .size(size as u64)
+        .usage(usage);
+    let buffer = unsafe { device.create_buffer(&buffer_info, None) }?;
+    let requirements = unsafe { device.get_buffer_memory_requirements(buffer) };
+    let allocation = allocator.allocate(&AllocationCreateDesc {
+        name,
+        requirements,
+        location: gpu_allocator::MemoryLocation::CpuToGpu,
+        linear: true,
+    })?;
+    unsafe { device.bind_buffer_memory(buffer, allocation.memory(), allocation.offset()) }?;
+    debug::set_name(device, buffer, name);
+    Ok((buffer, allocation))
+}
+
+impl MeshBuffer {
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe { device.destroy_buffer(self.buffer, None) };
+        if let Some(meshes) = self.meshes.take() {
+            for mesh in meshes.into_iter() {
+                allocator.free(mesh.allocation).unwrap();
+            }
+        }
+        if let Some(allocation) = self.index_allocation.take() {
+            unsafe { device.destroy_buffer(self.index_buffer, None) };
+            allocator.free(allocation).unwrap();
+        }
+    }
+}
+
+impl InstanceBuffer {
+    pub fn new(device: &Device, allocator: &mut Allocator, capacity: usize) -> EngineResult<Self> {
+        let capacity = capacity.max(1);
+        let (buffer, allocation) = create_buffer(
+            device,
+            allocator,
+            "Instances",
+            std::mem::size_of::<InstanceData>() * capacity,
+            vk::BufferUsageFlags::VERTEX_BUFFER | vk::BufferUsageFlags::STORAGE_BUFFER,
+        )?;
+        Ok(InstanceBuffer {
+            buffer,
+            allocation: Some(allocation),
+            capacity,
+            count: 0,
+        })
+    }
+
+    // Replaces the contents, growing the buffer if they don't fit. The GPU must be
+    // done with the previous contents.
+    pub fn write(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        instances: &[InstanceData],
+    ) -> EngineResult<()> {
+        if instances.len() > self.capacity {
+            let grown =
+                InstanceBuffer::new(device, allocator, instances.len().next_power_of_two())?;
+            self.destroy(device, allocator);
+            *self = grown;
+        }
+        if let Some(allocation) = self.allocation.as_ref() {
+            unsafe {
+                let ptr = allocation
+                    .mapped_ptr()
+                    .unwrap()
+                    .cast::<InstanceData>()
+                    .as_ptr();
+                std::ptr::copy_nonoverlapping(instances.as_ptr(), ptr, instances.len());
+            }
+        }
+        self.count = instances.len() as u32;
+        Ok(())
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe { device.destroy_buffer(self.buffer, None) };
+        if let Some(allocation) = self.allocation.take() {
+            allocator.free(allocation).unwrap();
+        }
+        self.count = 0;
+    }
+}
+
+// A low-poly conifer with its base at the origin, about four units tall.
+pub fn tree_mesh(device: &Device, allocator: &mut Allocator) -> EngineResult<MeshBuffer> {
+    let bark = Vector3::new(0.40, 0.27, 0.15);
+    let leaves = Vector3::new(0.16, 0.40, 0.18);
+    let mut vertices = Vec::new();
+    push_cone(&mut vertices, 0.15, 0.15, 0.0, 1.2, 6, bark);
+    push_cone(&mut vertices, 1.1, 0.0, 0.8, 2.8, 8, leaves);
+    push_cone(&mut vertices, 0.8, 0.0, 2.0, 3.8, 8, leaves);
+    // Flat shading leaves no vertices to share, but indirect draws need indices.
+    let indices: Vec<u32> = (0..vertices.len() as u32).collect();
+    upload_indexed_mesh(device, allocator, "Tree", vertices, &indices)
+}
+
+// Sides of a (possibly truncated) cone around the y axis, flat shaded.
+fn push_cone(
+    vertices: &mut Vec<Vertex>,
+    bottom_radius: f32,
+    top_radius: f32,
+    bottom: f32,
+    top: f32,
+    sides: u32,
+    color: Vector3<f32>,
+) {
+    let ring = |radius: f32, y: f32, i: u32| {
+        let angle = std::f32::consts::TAU * i as f32 / sides as f32;
+        Vector3::new(radius * angle.cos(), y, radius * angle.sin())
+    };
+    for i in 0..sides {
+        let (a, b) = (
+            ring(bottom_radius, bottom, i),
+            ring(bottom_radius, bottom, i + 1),
+        );
+        let (c, d) = (ring(top_radius, top, i), ring(top_radius, top, i + 1));
+        let mut normal = (b - a).cross(c - a).normalize();
+        if normal.dot(a + b) < 0.0 {
+            normal = -normal;
+        }
+        let light = Vector3::new(0.4f32, 0.8, 0.3).normalize();
+        let shaded = color * (0.45 + 0.55 * normal.dot(light).max(0.0));
+        let vertex = |position| Vertex {
+            position,
+            color: shaded,
+        };
+        vertices.extend_from_slice(&[vertex(a), vertex(c), vertex(b)]);
+        if top_radius > 0.0 {
+            vertices.extend_from_slice(&[vertex(b), vertex(c), vertex(d)]);
+        }
     }
 }
 
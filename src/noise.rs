@@ -0,0 +1,143 @@
+pub struct Noise {
+    perm: [u8; 512],
+}
+
+impl Noise {
+    pub fn new(seed: u64) -> Self {
+        let mut table: [u8; 256] = [0; 256];
+        for (i, v) in table.iter_mut().enumerate() {
+            *v = i as u8;
+        }
+
+        let mut state = seed;
+        for i in (1..256).rev() {
+            let j = (splitmix64(&mut state) % (i as u64 + 1)) as usize;
+            table.swap(i, j);
+        }
+
+        let mut perm = [0; 512];
+        for (i, v) in perm.iter_mut().enumerate() {
+            *v = table[i & 255];
+        }
+        Noise { perm }
+    }
+
+    // Classic 2D Perlin noise, roughly in [-1, 1].
+    pub fn perlin(&self, x: f32, y: f32) -> f32 {
+        let xi = x.floor() as i32 & 255;
+        let yi = y.floor() as i32 & 255;
+        let xf = x - x.floor();
+        let yf = y - y.floor();
+
+        let u = fade(xf);
+        let v = fade(yf);
+
+        let p = &self.perm;
+        let aa = p[p[xi as usize] as usize + yi as usize];
+        let ab = p[p[xi as usize] as usize + yi as usize + 1];
+        let ba = p[p[xi as usize + 1] as usize + yi as usize];
+        let bb = p[p[xi as usize + 1] as usize + yi as usize + 1];
+
+        let x1 = lerp(grad(aa, xf, yf), grad(ba, xf - 1.0, yf), u);
+        let x2 = lerp(grad(ab, xf, yf - 1.0), grad(bb, xf - 1.0, yf - 1.0), u);
+        lerp(x1, x2, v)
+    }
+
+    // Fractal sum of `octaves` layers of perlin noise, normalized back to [-1, 1].
+    pub fn fbm(&self, x: f32, y: f32, octaves: u32, lacunarity: f32, gain: f32) -> f32 {
+        let mut sum = 0.0;
+        let mut amplitude = 1.0;
+        let mut frequency = 1.0;
+        let mut norm = 0.0;
+        for _ in 0..octaves {
+            sum += amplitude * self.perlin(x * frequency, y * frequency);
+            norm += amplitude;
+            amplitude *= gain;
+            frequency *= lacunarity;
+        }
+        sum / norm
+    }
+}
+
+pub fn splitmix64(state: &mut u64) -> u64 {
+    *state = state.wrapping_add(0x9e3779b97f4a7c15);
+    let mut z = *state;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+fn fade(t: f32) -> f32 {
+    t * t * t * (t * (t * 6.0 - 15.0) + 10.0)
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + t * (b - a)
+}
+
+fn grad(hash: u8, x: f32, y: f32) -> f32 {
+    match hash & 7 {
+        0 => x + y,
+        1 => -x + y,
+        2 => x - y,
+        3 => -x - y,
+        4 => x,
+        5 => -x,
+        6 => y,
+        _ => -y,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn same_seed_gives_same_noise() {
+        let a = Noise::new(42);
+        let b = Noise::new(42);
+        for i in 0..64 {
+            let (x, y) = (i as f32 * 0.37, i as f32 * 1.13);
+            assert_eq!(a.perlin(x, y).to_bits(), b.perlin(x, y).to_bits());
+            assert_eq!(
+                a.fbm(x, y, 5, 2.0, 0.5).to_bits(),
+                b.fbm(x, y, 5, 2.0, 0.5).to_bits()
+            );
+        }
+    }
+
+    #[test]
+    fn different_seeds_differ() {
+        let a = Noise::new(1);
+        let b = Noise::new(2);
+        let differs = (0..64).any(|i| {
+            let (x, y) = (i as f32 * 0.37 + 0.1, i as f32 * 1.13 + 0.2);
+            a.perlin(x, y) != b.perlin(x, y)
+        });
+        assert!(differs);
+    }
+
+    #[test]
+    fn perlin_is_zero_on_the_lattice_and_bounded() {
+        let noise = Noise::new(7);
+        for y in -4..4 {
+            for x in -4..4 {
+                assert_eq!(noise.perlin(x as f32, y as f32), 0.0);
+            }
+        }
+        for i in 0..1000 {
+            let (x, y) = (i as f32 * 0.173, i as f32 * 0.291);
+            assert!(noise.fbm(x, y, 6, 2.0, 0.5).abs() <= 1.0);
+        }
+    }
+
+    #[test]
+    fn splitmix_is_a_fixed_sequence() {
+        let mut a = 5;
+        let mut b = 5;
+        let first: Vec<u64> = (0..4).map(|_| splitmix64(&mut a)).collect();
+        let second: Vec<u64> = (0..4).map(|_| splitmix64(&mut b)).collect();
+        assert_eq!(first, second);
+        assert_ne!(first[0], first[1]);
+    }
+}
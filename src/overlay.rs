@@ -0,0 +1,638 @@
+// Draws 2D meshes over the scene, for the developer UI and anything else laid out
+// in screen space. It takes egui's output as it comes: triangles in points, each
+// mesh with a texture and a clip rectangle, plus the textures to create, update
+// and free before drawing.
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use ash::{vk, Device};
+use egui::epaint::{ClippedPrimitive, ImageData, ImageDelta, Primitive, TextureId, Vertex};
+use egui::{TextureFilter, TexturesDelta};
+use gpu_allocator::vulkan::*;
+
+use crate::compute::{as_bytes, image_barrier};
+use crate::debug;
+use crate::engine::compile_shader;
+use crate::error::EngineResult;
+use crate::mesh::create_buffer;
+use crate::pipeline::{build_overlay_pipeline, shader_stage_create_info, PipelineTarget};
+
+// Textures alive at once. egui itself needs one for its fonts.
+const MAX_TEXTURES: u32 = 64;
+
+// One frame's worth of overlay, handed to the engine before it draws.
+#[derive(Default)]
+pub struct OverlayFrame {
+    pub primitives: Vec<ClippedPrimitive>,
+    pub textures: TexturesDelta,
+    // Physical pixels per point.
+    pub pixels_per_point: f32,
+}
+
+// A texture id of our own, for meshes built outside egui. egui only hands out
+// managed ones, so the two never collide.
+pub fn user_texture_id() -> TextureId {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    TextureId::User(NEXT.fetch_add(1, Ordering::Relaxed))
+}
+
+#[repr(C)]
+struct OverlayPush {
+    // In points.
+    screen_size: [f32; 2],
+    // Non-zero when the colour attachment encodes to sRGB by itself.
+    srgb_target: u32,
+}
+
+struct Texture {
+    image: vk::Image,
+    view: vk::ImageView,
+    allocation: Allocation,
+    set: vk::DescriptorSet,
+}
+
+struct Draw {
+    scissor: vk::Rect2D,
+    set: vk::DescriptorSet,
+    first_index: u32,
+    index_count: u32,
+    vertex_offset: i32,
+}
+
+// A host visible buffer that grows to fit.
+struct HostBuffer {
+    buffer: vk::Buffer,
+    allocation: Allocation,
+    capacity: usize,
+}
+
+pub struct OverlayRenderer {
+    set_layout: vk::DescriptorSetLayout,
+    layout: vk::PipelineLayout,
+    pipeline: vk::Pipeline,
+    descriptor_pool: vk::DescriptorPool,
+    linear_sampler: vk::Sampler,
+    nearest_sampler: vk::Sampler,
+    textures: HashMap<TextureId, Texture>,
+    vertices: Option<HostBuffer>,
+    indices: Option<HostBuffer>,
+    draws: Vec<Draw>,
+    screen_size: [f32; 2],
+    // Freed by egui after the last frame was recorded, so only let go of once
+    // that frame is done.
+    pending_free: Vec<TextureId>,
+    // Staging buffers and replaced textures the last frame used.
+    retired_buffers: Vec<(vk::Buffer, Allocation)>,
+    retired_textures: Vec<Texture>,
+}
+
+impl OverlayRenderer {
+    pub fn new(
+        device: &Device,
+        compiler: &shaderc::Compiler,
+        target: &PipelineTarget,
+        samples: vk::SampleCountFlags,
+    ) -> EngineResult<Self> {
+        unsafe {
+            let bindings = [vk::DescriptorSetLayoutBinding::builder()
+                .binding(0)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .descriptor_count(1)
+                .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                .build()];
+            let set_layout = device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                None,
+            )?;
+            let push_constant_ranges = [vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+                offset: 0,
+                size: std::mem::size_of::<OverlayPush>() as u32,
+            }];
+            let set_layouts = [set_layout];
+            let layout = device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::builder()
+                    .set_layouts(&set_layouts)
+                    .push_constant_ranges(&push_constant_ranges),
+                None,
+            )?;
+            let pool_sizes = [vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: MAX_TEXTURES,
+            }];
+            let descriptor_pool = device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::builder()
+                    .flags(vk::DescriptorPoolCreateFlags::FREE_DESCRIPTOR_SET)
+                    .max_sets(MAX_TEXTURES)
+                    .pool_sizes(&pool_sizes),
+                None,
+            )?;
+            let sampler = |filter: vk::Filter| {
+                device.create_sampler(
+                    &vk::SamplerCreateInfo::builder()
+                        .mag_filter(filter)
+                        .min_filter(filter)
+                        .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                        .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                        .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                        .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                        .max_lod(vk::LOD_CLAMP_NONE),
+                    None,
+                )
+            };
+            let linear_sampler = sampler(vk::Filter::LINEAR)?;
+            let nearest_sampler = sampler(vk::Filter::NEAREST)?;
+            debug::set_name(device, set_layout, "Overlay");
+            debug::set_name(device, layout, "Overlay");
+            debug::set_name(device, descriptor_pool, "Overlay");
+
+            let mut renderer = OverlayRenderer {
+                set_layout,
+                layout,
+                pipeline: vk::Pipeline::null(),
+                descriptor_pool,
+                linear_sampler,
+                nearest_sampler,
+                textures: HashMap::new(),
+                vertices: None,
+                indices: None,
+                draws: Vec::new(),
+                screen_size: [1.0, 1.0],
+                pending_free: Vec::new(),
+                retired_buffers: Vec::new(),
+                retired_textures: Vec::new(),
+            };
+            renderer.rebuild_pipeline(device, compiler, target, samples)?;
+            Ok(renderer)
+        }
+    }
+
+    // Needed whenever the target changes format or sample count.
+    pub fn rebuild_pipeline(
+        &mut self,
+        device: &Device,
+        compiler: &shaderc::Compiler,
+        target: &PipelineTarget,
+        samples: vk::SampleCountFlags,
+    ) -> EngineResult<()> {
+        let shaders = [
+            compile_shader(
+                device,
+                compiler,
+                "assets/shaders/overlay.vert",
+                shaderc::ShaderKind::Vertex,
+            )?,
+            compile_shader(
+                device,
+                compiler,
+                "assets/shaders/overlay.frag",
+                shaderc::ShaderKind::Fragment,
+            )?,
+        ];
+        unsafe {
+            let shader_info = [
+                shader_stage_create_info(vk::ShaderStageFlags::VERTEX, shaders[0]).build(),
+                shader_stage_create_info(vk::ShaderStageFlags::FRAGMENT, shaders[1]).build(),
+            ];
+            let pipeline =
+                build_overlay_pipeline(device, target, &shader_info, self.layout, samples)?;
+            if self.pipeline != vk::Pipeline::null() {
+                device.destroy_pipeline(self.pipeline, None);
+            }
+            self.pipeline = pipeline;
+            for shader in shaders {
+                device.destroy_shader_module(shader, None);
+            }
+        }
+        debug::set_name(device, self.pipeline, "Overlay");
+        Ok(())
+    }
+
+    // Applies the frame's texture changes, recording uploads into `command_buffer`
+    // ahead of the render pass, and fills the vertex and index buffers. Must only
+    // run once the GPU has finished the previous frame.
+    pub unsafe fn prepare(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        command_buffer: vk::CommandBuffer,
+        frame: OverlayFrame,
+        extent: vk::Extent2D,
+    ) -> EngineResult<()> {
+        for (buffer, allocation) in self.retired_buffers.drain(..) {
+            device.destroy_buffer(buffer, None);
+            allocator.free(allocation)?;
+        }
+        for texture in self.retired_textures.drain(..) {
+            destroy_texture(device, allocator, self.descriptor_pool, texture)?;
+        }
+        for id in std::mem::take(&mut self.pending_free) {
+            if let Some(texture) = self.textures.remove(&id) {
+                destroy_texture(device, allocator, self.descriptor_pool, texture)?;
+            }
+        }
+
+        for (id, delta) in frame.textures.set.iter() {
+            self.set_texture(device, allocator, command_buffer, *id, delta)?;
+        }
+        self.pending_free = frame.textures.free;
+
+        let pixels_per_point = frame.pixels_per_point.max(f32::EPSILON);
+        self.screen_size = [
+            extent.width as f32 / pixels_per_point,
+            extent.height as f32 / pixels_per_point,
+        ];
+        self.draws.clear();
+        let mut vertices: Vec<Vertex> = Vec::new();
+        let mut indices: Vec<u32> = Vec::new();
+        for primitive in frame.primitives {
+            let mesh = match primitive.primitive {
+                Primitive::Mesh(mesh) => mesh,
+                // Nothing here paints through callbacks.
+                Primitive::Callback(_) => continue,
+            };
+            let texture = match self.textures.get(&mesh.texture_id) {
+                Some(texture) => texture,
+                None => continue,
+            };
+            let clip = primitive.clip_rect;
+            let (x0, y0) = (
+                (clip.min.x * pixels_per_point).round().max(0.0) as u32,
+                (clip.min.y * pixels_per_point).round().max(0.0) as u32,
+            );
+            let (x1, y1) = (
+                ((clip.max.x * pixels_per_point).round().max(0.0) as u32).min(extent.width),
+                ((clip.max.y * pixels_per_point).round().max(0.0) as u32).min(extent.height),
+            );
+            if x1 <= x0 || y1 <= y0 || mesh.indices.is_empty() {
+                continue;
+            }
+            self.draws.push(Draw {
+                scissor: vk::Rect2D {
+                    offset: vk::Offset2D {
+                        x: x0 as i32,
+                        y: y0 as i32,
+                    },
+                    extent: vk::Extent2D {
+                        width: x1 - x0,
+                        height: y1 - y0,
+                    },
+                },
+                set: texture.set,
+                first_index: indices.len() as u32,
+                index_count: mesh.indices.len() as u32,
+                vertex_offset: vertices.len() as i32,
+            });
+            vertices.extend_from_slice(&mesh.vertices);
+            indices.extend_from_slice(&mesh.indices);
+        }
+        write_host_buffer(
+            device,
+            allocator,
+            &mut self.vertices,
+            "Overlay vertices",
+            vk::BufferUsageFlags::VERTEX_BUFFER,
+            &vertices,
+        )?;
+        write_host_buffer(
+            device,
+            allocator,
+            &mut self.indices,
+            "Overlay indices",
+            vk::BufferUsageFlags::INDEX_BUFFER,
+            &indices,
+        )?;
+        Ok(())
+    }
+
+    unsafe fn set_texture(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        command_buffer: vk::CommandBuffer,
+        id: TextureId,
+        delta: &ImageDelta,
+    ) -> EngineResult<()> {
+        let (size, pixels): ([usize; 2], Vec<egui::Color32>) = match &delta.image {
+            ImageData::Color(image) => (image.size, image.pixels.clone()),
+            ImageData::Font(image) => (image.size, image.srgba_pixels(None).collect()),
+        };
+        if size[0] == 0 || size[1] == 0 {
+            return Ok(());
+        }
+        // A delta without a position replaces the whole texture, possibly resized.
+        let old_layout = if delta.pos.is_none() {
+            let sampler = match delta.options.magnification {
+                TextureFilter::Nearest => self.nearest_sampler,
+                TextureFilter::Linear => self.linear_sampler,
+            };
+            let texture = create_texture(
+                device,
+                allocator,
+                self.descriptor_pool,
+                self.set_layout,
+                sampler,
+                size,
+            )?;
+            if let Some(old) = self.textures.insert(id, texture) {
+                self.retired_textures.push(old);
+            }
+            vk::ImageLayout::UNDEFINED
+        } else {
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        };
+        let image = match self.textures.get(&id) {
+            Some(texture) => texture.image,
+            None => return Ok(()),
+        };
+
+        let (staging, allocation) = create_buffer(
+            device,
+            allocator,
+            "Overlay upload",
+            pixels.len() * 4,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+        )?;
+        let mapped = allocation.mapped_ptr().unwrap().cast::<egui::Color32>();
+        std::ptr::copy_nonoverlapping(pixels.as_ptr(), mapped.as_ptr(), pixels.len());
+
+        let [x, y] = delta.pos.unwrap_or([0, 0]);
+        let copy = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_offset(vk::Offset3D {
+                x: x as i32,
+                y: y as i32,
+                z: 0,
+            })
+            .image_extent(vk::Extent3D {
+                width: size[0] as u32,
+                height: size[1] as u32,
+                depth: 1,
+            })
+            .build();
+        let shader_read = (
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+        let transfer_write = (
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+        image_barrier(
+            device,
+            command_buffer,
+            image,
+            (old_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            shader_read,
+            transfer_write,
+        );
+        device.cmd_copy_buffer_to_image(
+            command_buffer,
+            staging,
+            image,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[copy],
+        );
+        image_barrier(
+            device,
+            command_buffer,
+            image,
+            (
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ),
+            transfer_write,
+            shader_read,
+        );
+        self.retired_buffers.push((staging, allocation));
+        Ok(())
+    }
+
+    // Records the draws inside the render pass, over whatever is there already.
+    pub unsafe fn record(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        extent: vk::Extent2D,
+        srgb_target: bool,
+    ) {
+        let (Some(vertices), Some(indices)) = (self.vertices.as_ref(), self.indices.as_ref())
+        else {
+            return;
+        };
+        if self.draws.is_empty() {
+            return;
+        }
+        device.cmd_bind_pipeline(
+            command_buffer,
+            vk::PipelineBindPoint::GRAPHICS,
+            self.pipeline,
+        );
+        device.cmd_set_viewport(
+            command_buffer,
+            0,
+            &[vk::Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: extent.width as f32,
+                height: extent.height as f32,
+                min_depth: 0.0,
+                max_depth: 1.0,
+            }],
+        );
+        let push = OverlayPush {
+            screen_size: self.screen_size,
+            srgb_target: srgb_target as u32,
+        };
+        device.cmd_push_constants(
+            command_buffer,
+            self.layout,
+            vk::ShaderStageFlags::VERTEX | vk::ShaderStageFlags::FRAGMENT,
+            0,
+            as_bytes(&push),
+        );
+        device.cmd_bind_vertex_buffers(command_buffer, 0, &[vertices.buffer], &[0]);
+        device.cmd_bind_index_buffer(command_buffer, indices.buffer, 0, vk::IndexType::UINT32);
+        for draw in self.draws.iter() {
+            device.cmd_set_scissor(command_buffer, 0, &[draw.scissor]);
+            device.cmd_bind_descriptor_sets(
+                command_buffer,
+                vk::PipelineBindPoint::GRAPHICS,
+                self.layout,
+                0,
+                &[draw.set],
+                &[],
+            );
+            device.cmd_draw_indexed(
+                command_buffer,
+                draw.index_count,
+                1,
+                draw.first_index,
+                draw.vertex_offset,
+                0,
+            );
+        }
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe {
+            for (buffer, allocation) in self.retired_buffers.drain(..) {
+                device.destroy_buffer(buffer, None);
+                allocator.free(allocation).unwrap();
+            }
+            let textures = self.retired_textures.drain(..);
+            for texture in textures.chain(self.textures.drain().map(|(_, texture)| texture)) {
+                destroy_texture(device, allocator, self.descriptor_pool, texture).unwrap();
+            }
+            for buffer in [self.vertices.take(), self.indices.take()]
+                .into_iter()
+                .flatten()
+            {
+                device.destroy_buffer(buffer.buffer, None);
+                allocator.free(buffer.allocation).unwrap();
+            }
+            device.destroy_pipeline(self.pipeline, None);
+            device.destroy_pipeline_layout(self.layout, None);
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_descriptor_set_layout(self.set_layout, None);
+            device.destroy_sampler(self.linear_sampler, None);
+            device.destroy_sampler(self.nearest_sampler, None);
+        }
+    }
+}
+
+unsafe fn create_texture(
+    device: &Device,
+    allocator: &mut Allocator,
+    descriptor_pool: vk::DescriptorPool,
+    set_layout: vk::DescriptorSetLayout,
+    sampler: vk::Sampler,
+    size: [usize; 2],
+) -> EngineResult<Texture> {
+    // egui's colours are sRGB encoded, and blending them as they are is what it
+    // expects, so the texture isn't decoded when sampled.
+    let format = vk::Format::R8G8B8A8_UNORM;
+    let image_info = vk::ImageCreateInfo::builder()
+        .image_type(vk::ImageType::TYPE_2D)
+        .format(format)
+        .extent(vk::Extent3D {
+            width: size[0] as u32,
+            height: size[1] as u32,
+            depth: 1,
+        })
+        .mip_levels(1)
+        .array_layers(1)
+        .samples(vk::SampleCountFlags::TYPE_1)
+        .tiling(vk::ImageTiling::OPTIMAL)
+        .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+        .sharing_mode(vk::SharingMode::EXCLUSIVE);
+    let image = device.create_image(&image_info, None)?;
+    let requirements = device.get_image_memory_requirements(image);
+    let allocation = allocator.allocate(&AllocationCreateDesc {
+        name: "Overlay texture",
+        requirements,
+        location: gpu_allocator::MemoryLocation::GpuOnly,
+        linear: false,
+    })?;
+    device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+    let view = device.create_image_view(
+        &vk::ImageViewCreateInfo::builder()
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image(image),
+        None,
+    )?;
+    debug::set_name(device, image, "Overlay texture");
+    debug::set_name(device, view, "Overlay texture");
+
+    let set_layouts = [set_layout];
+    let set = device.allocate_descriptor_sets(
+        &vk::DescriptorSetAllocateInfo::builder()
+            .descriptor_pool(descriptor_pool)
+            .set_layouts(&set_layouts),
+    )?[0];
+    let image_info = [vk::DescriptorImageInfo {
+        sampler,
+        image_view: view,
+        image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+    }];
+    let write = vk::WriteDescriptorSet::builder()
+        .dst_set(set)
+        .dst_binding(0)
+        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+        .image_info(&image_info);
+    device.update_descriptor_sets(&[write.build()], &[]);
+    Ok(Texture {
+        image,
+        view,
+        allocation,
+        set,
+    })
+}
+
+unsafe fn destroy_texture(
+    device: &Device,
+    allocator: &mut Allocator,
+    descriptor_pool: vk::DescriptorPool,
+    texture: Texture,
+) -> EngineResult<()> {
+    device.free_descriptor_sets(descriptor_pool, &[texture.set])?;
+    device.destroy_image_view(texture.view, None);
+    device.destroy_image(texture.image, None);
+    allocator.free(texture.allocation)?;
+    Ok(())
+}
+
+// Replaces the contents of `slot`, growing it when they don't fit. The GPU must be
+// done with the previous contents.
+fn write_host_buffer<T: Copy>(
+    device: &Device,
+    allocator: &mut Allocator,
+    slot: &mut Option<HostBuffer>,
+    name: &str,
+    usage: vk::BufferUsageFlags,
+    data: &[T],
+) -> EngineResult<()> {
+    let fits = slot
+        .as_ref()
+        .is_some_and(|buffer| buffer.capacity >= data.len());
+    if !fits {
+        if let Some(old) = slot.take() {
+            unsafe { device.destroy_buffer(old.buffer, None) };
+            allocator.free(old.allocation)?;
+        }
+        let capacity = data.len().max(1024).next_power_of_two();
+        let (buffer, allocation) = create_buffer(
+            device,
+            allocator,
+            name,
+            std::mem::size_of::<T>() * capacity,
+            usage,
+        )?;
+        *slot = Some(HostBuffer {
+            buffer,
+            allocation,
+            capacity,
+        });
+    }
+    if let Some(buffer) = slot.as_ref() {
+        unsafe {
+            let ptr = buffer.allocation.mapped_ptr().unwrap().cast::<T>().as_ptr();
+            std::ptr::copy_nonoverlapping(data.as_ptr(), ptr, data.len());
+        }
+    }
+    Ok(())
+}
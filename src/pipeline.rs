@@ -4,13 +4,46 @@ use ash::{vk, Device};
 use cgmath::{Matrix4, Vector4};
 use memoffset::offset_of;
 
-use crate::mesh::Vertex;
+use crate::error::EngineResult;
+use crate::mesh::{InstanceData, Vertex};
 
 pub struct PushConstant {
     pub data: Vector4<f32>,
     pub render_matrix: Matrix4<f32>,
 }
 
+// What a graphics pipeline draws into: a compatible render pass, or with dynamic
+// rendering just the formats of the attachments.
+#[derive(Clone, Debug)]
+pub enum PipelineTarget {
+    RenderPass(vk::RenderPass),
+    Formats {
+        colors: Vec<vk::Format>,
+        depth: Option<vk::Format>,
+    },
+}
+
+impl PipelineTarget {
+    fn render_pass(&self) -> vk::RenderPass {
+        match self {
+            PipelineTarget::RenderPass(render_pass) => *render_pass,
+            PipelineTarget::Formats { .. } => vk::RenderPass::null(),
+        }
+    }
+
+    // Chained into the pipeline's create info in place of the render pass.
+    fn rendering_info(&self) -> Option<vk::PipelineRenderingCreateInfoBuilder<'_>> {
+        match self {
+            PipelineTarget::RenderPass(_) => None,
+            PipelineTarget::Formats { colors, depth } => Some(
+                vk::PipelineRenderingCreateInfo::builder()
+                    .color_attachment_formats(colors)
+                    .depth_attachment_format(depth.unwrap_or(vk::Format::UNDEFINED)),
+            ),
+        }
+    }
+}
+
 pub unsafe fn shader_stage_create_info<'a>(
     stage: vk::ShaderStageFlags,
     shader_module: vk::ShaderModule,
@@ -21,7 +54,12 @@ pub unsafe fn shader_stage_create_info<'a>(
         .name(CStr::from_bytes_with_nul_unchecked(b"main\0"))
 }
 
-unsafe fn vertex_input_state_create_info<'a>() -> (
+// Binding 0 holds the mesh's vertices. Instanced pipelines add binding 1, stepped
+// per instance, with the transform in locations 2-5 (one per column) and the tint
+// in location 6.
+unsafe fn vertex_input_state_create_info(
+    instanced: bool,
+) -> (
     Vec<vk::VertexInputAttributeDescription>,
     Vec<vk::VertexInputBindingDescription>,
 ) {
@@ -33,17 +71,43 @@ unsafe fn vertex_input_state_create_info<'a>() -> (
     let position_attr = vk::VertexInputAttributeDescription::builder()
         .binding(0)
         .location(0)
-        .format(vk::Format::R32G32B32A32_SFLOAT)
+        .format(vk::Format::R32G32B32_SFLOAT)
         .offset(offset_of!(Vertex, position) as u32);
 
     let color_attr = vk::VertexInputAttributeDescription::builder()
         .binding(0)
         .location(1)
-        .format(vk::Format::R32G32B32A32_SFLOAT)
+        .format(vk::Format::R32G32B32_SFLOAT)
         .offset(offset_of!(Vertex, color) as u32);
 
-    let attributes = vec![color_attr.build(), position_attr.build()];
-    let bindings = vec![main_binding.build()];
+    let mut attributes = vec![color_attr.build(), position_attr.build()];
+    let mut bindings = vec![main_binding.build()];
+
+    if instanced {
+        let instance_binding = vk::VertexInputBindingDescription::builder()
+            .binding(1)
+            .stride(std::mem::size_of::<InstanceData>() as u32)
+            .input_rate(vk::VertexInputRate::INSTANCE);
+
+        let transform = offset_of!(InstanceData, transform) as u32;
+        let column = std::mem::size_of::<Vector4<f32>>() as u32;
+        for i in 0..4 {
+            let column_attr = vk::VertexInputAttributeDescription::builder()
+                .binding(1)
+                .location(2 + i)
+                .format(vk::Format::R32G32B32A32_SFLOAT)
+                .offset(transform + i * column);
+            attributes.push(column_attr.build());
+        }
+
+        let tint_attr = vk::VertexInputAttributeDescription::builder()
+            .binding(1)
+            .location(6)
+            .format(vk::Format::R32G32B32A32_SFLOAT)
+            .offset(offset_of!(InstanceData, tint) as u32);
+        attributes.push(tint_attr.build());
+        bindings.push(instance_binding.build());
+    }
     (attributes, bindings)
 }
 
@@ -71,67 +135,283 @@ unsafe fn rasterization_state_create_info<'a>(
         .depth_bias_slope_factor(0.0f32)
 }
 
-unsafe fn multisampling_state_create_info<'a>() -> vk::PipelineMultisampleStateCreateInfoBuilder<'a>
-{
+unsafe fn multisampling_state_create_info<'a>(
+    samples: vk::SampleCountFlags,
+) -> vk::PipelineMultisampleStateCreateInfoBuilder<'a> {
     vk::PipelineMultisampleStateCreateInfo::builder()
         .sample_shading_enable(false)
-        .rasterization_samples(vk::SampleCountFlags::TYPE_1)
+        .rasterization_samples(samples)
         .min_sample_shading(1.0f32)
         .sample_mask(&[])
         .alpha_to_coverage_enable(false)
         .alpha_to_one_enable(false)
 }
 
+unsafe fn depth_stencil_state_create_info<'a>(
+    depth_test: bool,
+    depth_write: bool,
+) -> vk::PipelineDepthStencilStateCreateInfoBuilder<'a> {
+    vk::PipelineDepthStencilStateCreateInfo::builder()
+        .depth_test_enable(depth_test)
+        .depth_write_enable(depth_write)
+        .depth_compare_op(if depth_test {
+            vk::CompareOp::LESS_OR_EQUAL
+        } else {
+            vk::CompareOp::ALWAYS
+        })
+        .depth_bounds_test_enable(false)
+        .stencil_test_enable(false)
+        .min_depth_bounds(0.0f32)
+        .max_depth_bounds(1.0f32)
+}
+
 unsafe fn color_blend_attachment_state<'a>() -> vk::PipelineColorBlendAttachmentStateBuilder<'a> {
     vk::PipelineColorBlendAttachmentState::builder()
         .color_write_mask(vk::ColorComponentFlags::RGBA)
         .blend_enable(false)
 }
 
+// Blends premultiplied colour over what's already in the target.
+unsafe fn premultiplied_blend_attachment_state<'a>(
+) -> vk::PipelineColorBlendAttachmentStateBuilder<'a> {
+    vk::PipelineColorBlendAttachmentState::builder()
+        .color_write_mask(vk::ColorComponentFlags::RGBA)
+        .blend_enable(true)
+        .src_color_blend_factor(vk::BlendFactor::ONE)
+        .dst_color_blend_factor(vk::BlendFactor::ONE_MINUS_SRC_ALPHA)
+        .color_blend_op(vk::BlendOp::ADD)
+        .src_alpha_blend_factor(vk::BlendFactor::ONE_MINUS_DST_ALPHA)
+        .dst_alpha_blend_factor(vk::BlendFactor::ONE)
+        .alpha_blend_op(vk::BlendOp::ADD)
+}
+
+// egui's vertex layout: position in points, texture coordinates and an 8 bit
+// premultiplied colour.
+fn overlay_vertex_input_state() -> (
+    [vk::VertexInputAttributeDescription; 3],
+    [vk::VertexInputBindingDescription; 1],
+) {
+    let bindings = [vk::VertexInputBindingDescription::builder()
+        .binding(0)
+        .stride(std::mem::size_of::<egui::epaint::Vertex>() as u32)
+        .input_rate(vk::VertexInputRate::VERTEX)
+        .build()];
+    let attribute = |location: u32, format: vk::Format, offset: usize| {
+        vk::VertexInputAttributeDescription::builder()
+            .binding(0)
+            .location(location)
+            .format(format)
+            .offset(offset as u32)
+            .build()
+    };
+    let attributes = [
+        attribute(
+            0,
+            vk::Format::R32G32_SFLOAT,
+            offset_of!(egui::epaint::Vertex, pos),
+        ),
+        attribute(
+            1,
+            vk::Format::R32G32_SFLOAT,
+            offset_of!(egui::epaint::Vertex, uv),
+        ),
+        attribute(
+            2,
+            vk::Format::R8G8B8A8_UNORM,
+            offset_of!(egui::epaint::Vertex, color),
+        ),
+    ];
+    (attributes, bindings)
+}
+
+// What sets the graphics pipelines apart. Everything else, such as the dynamic
+// viewport and scissor, is shared by all of them.
+struct GraphicsPipelineDesc<'a> {
+    shaders: &'a [vk::PipelineShaderStageCreateInfo],
+    attributes: &'a [vk::VertexInputAttributeDescription],
+    bindings: &'a [vk::VertexInputBindingDescription],
+    topology: vk::PrimitiveTopology,
+    samples: vk::SampleCountFlags,
+    blend: vk::PipelineColorBlendAttachmentState,
+    depth_test: bool,
+    depth_write: bool,
+}
+
 pub fn build_pipeline(
     device: &Device,
-    render_pass: vk::RenderPass,
+    target: &PipelineTarget,
     shaders: &Vec<vk::PipelineShaderStageCreateInfo>,
     layout: vk::PipelineLayout,
-) -> vk::Pipeline {
+    instanced: bool,
+    samples: vk::SampleCountFlags,
+) -> EngineResult<vk::Pipeline> {
+    build_scene_pipeline(
+        device,
+        target,
+        shaders,
+        layout,
+        instanced,
+        vk::PrimitiveTopology::TRIANGLE_LIST,
+        samples,
+    )
+}
+
+// Line lists over the scene: depth tested so they sit in it, but not written.
+pub fn build_line_pipeline(
+    device: &Device,
+    target: &PipelineTarget,
+    shaders: &[vk::PipelineShaderStageCreateInfo],
+    layout: vk::PipelineLayout,
+    samples: vk::SampleCountFlags,
+) -> EngineResult<vk::Pipeline> {
+    build_scene_pipeline(
+        device,
+        target,
+        shaders,
+        layout,
+        false,
+        vk::PrimitiveTopology::LINE_LIST,
+        samples,
+    )
+}
+
+fn build_scene_pipeline(
+    device: &Device,
+    target: &PipelineTarget,
+    shaders: &[vk::PipelineShaderStageCreateInfo],
+    layout: vk::PipelineLayout,
+    instanced: bool,
+    topology: vk::PrimitiveTopology,
+    samples: vk::SampleCountFlags,
+) -> EngineResult<vk::Pipeline> {
+    unsafe {
+        let (attributes, bindings) = vertex_input_state_create_info(instanced);
+        let desc = GraphicsPipelineDesc {
+            shaders,
+            attributes: &attributes,
+            bindings: &bindings,
+            topology,
+            samples,
+            blend: color_blend_attachment_state().build(),
+            depth_test: true,
+            depth_write: topology != vk::PrimitiveTopology::LINE_LIST,
+        };
+        build_graphics_pipeline(device, target, layout, &desc)
+    }
+}
+
+// 2D triangles over the scene, from egui's vertex layout. Blended, no depth.
+pub fn build_overlay_pipeline(
+    device: &Device,
+    target: &PipelineTarget,
+    shaders: &[vk::PipelineShaderStageCreateInfo],
+    layout: vk::PipelineLayout,
+    samples: vk::SampleCountFlags,
+) -> EngineResult<vk::Pipeline> {
+    unsafe {
+        let (attributes, bindings) = overlay_vertex_input_state();
+        let desc = GraphicsPipelineDesc {
+            shaders,
+            attributes: &attributes,
+            bindings: &bindings,
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            samples,
+            blend: premultiplied_blend_attachment_state().build(),
+            depth_test: false,
+            depth_write: false,
+        };
+        build_graphics_pipeline(device, target, layout, &desc)
+    }
+}
+
+// A triangle covering the target, generated in the vertex shader from the vertex
+// index, for post processing passes. No vertex input, depth or blending.
+pub fn build_fullscreen_pipeline(
+    device: &Device,
+    target: &PipelineTarget,
+    shaders: &[vk::PipelineShaderStageCreateInfo],
+    layout: vk::PipelineLayout,
+) -> EngineResult<vk::Pipeline> {
+    unsafe {
+        let desc = GraphicsPipelineDesc {
+            shaders,
+            attributes: &[],
+            bindings: &[],
+            topology: vk::PrimitiveTopology::TRIANGLE_LIST,
+            samples: vk::SampleCountFlags::TYPE_1,
+            blend: color_blend_attachment_state().build(),
+            depth_test: false,
+            depth_write: false,
+        };
+        build_graphics_pipeline(device, target, layout, &desc)
+    }
+}
+
+fn build_graphics_pipeline(
+    device: &Device,
+    target: &PipelineTarget,
+    layout: vk::PipelineLayout,
+    desc: &GraphicsPipelineDesc,
+) -> EngineResult<vk::Pipeline> {
     unsafe {
         let viewport_state = vk::PipelineViewportStateCreateInfo::builder()
             .viewport_count(1)
             .scissor_count(1);
 
-        let color_attachment = color_blend_attachment_state();
-        let attachments = [color_attachment.build()];
+        let attachments = [desc.blend];
         let color_blending = vk::PipelineColorBlendStateCreateInfo::builder()
             .logic_op(vk::LogicOp::COPY)
             .attachments(&attachments);
 
-        let (attrs, bindings) = vertex_input_state_create_info();
         let vertex_input = vk::PipelineVertexInputStateCreateInfo::builder()
-            .vertex_attribute_descriptions(&attrs)
-            .vertex_binding_descriptions(&bindings);
+            .vertex_attribute_descriptions(desc.attributes)
+            .vertex_binding_descriptions(desc.bindings);
 
-        let input_assembly = input_assembly_create_info(vk::PrimitiveTopology::TRIANGLE_LIST);
+        let input_assembly = input_assembly_create_info(desc.topology);
         let rasterization = rasterization_state_create_info(vk::PolygonMode::FILL);
-        let multisampling = multisampling_state_create_info();
+        let multisampling = multisampling_state_create_info(desc.samples);
+        let depth_stencil = depth_stencil_state_create_info(desc.depth_test, desc.depth_write);
 
         let dyn_states = [vk::DynamicState::VIEWPORT, vk::DynamicState::SCISSOR];
         let dyn_state = vk::PipelineDynamicStateCreateInfo::builder().dynamic_states(&dyn_states);
-        let pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
-            .stages(shaders)
+        let mut rendering_info = target.rendering_info();
+        let mut pipeline_info = vk::GraphicsPipelineCreateInfo::builder()
+            .stages(desc.shaders)
             .vertex_input_state(&vertex_input)
             .input_assembly_state(&input_assembly)
             .viewport_state(&viewport_state)
             .color_blend_state(&color_blending)
             .rasterization_state(&rasterization)
             .multisample_state(&multisampling)
+            .depth_stencil_state(&depth_stencil)
             .layout(layout)
             .dynamic_state(&dyn_state)
-            .render_pass(render_pass)
+            .render_pass(target.render_pass())
             .base_pipeline_handle(vk::Pipeline::null());
+        if let Some(rendering_info) = rendering_info.as_mut() {
+            pipeline_info = pipeline_info.push_next(rendering_info);
+        }
 
         let pipelines = &[pipeline_info.build()];
-        device
+        let pipelines = device
             .create_graphics_pipelines(vk::PipelineCache::null(), pipelines, None)
-            .unwrap()[0]
+            .map_err(|(_, result)| result)?;
+        Ok(pipelines[0])
+    }
+}
+
+pub fn build_compute_pipeline(
+    device: &Device,
+    shader: vk::PipelineShaderStageCreateInfo,
+    layout: vk::PipelineLayout,
+) -> EngineResult<vk::Pipeline> {
+    let pipeline_info = vk::ComputePipelineCreateInfo::builder()
+        .stage(shader)
+        .layout(layout)
+        .base_pipeline_handle(vk::Pipeline::null());
+    let pipelines = unsafe {
+        device.create_compute_pipelines(vk::PipelineCache::null(), &[pipeline_info.build()], None)
     }
+    .map_err(|(_, result)| result)?;
+    Ok(pipelines[0])
 }
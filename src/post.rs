@@ -0,0 +1,677 @@
+// Everything between the scene and the screen. The scene is drawn in linear HDR
+// into a floating point target, then a chain of fullscreen passes turns it into
+// what the swapchain shows: bloom from the brightest parts, exposure, ACES
+// tonemapping, gamma and a colour grading lookup table. The passes go into the
+// frame's render graph, which also provides the intermediate targets; the last one
+// writes the graph's output, usually the swapchain image.
+
+use ash::{vk, Device};
+use gpu_allocator::vulkan::*;
+
+use crate::compute::{as_bytes, image_barrier};
+use crate::debug;
+use crate::engine::compile_shader;
+use crate::error::EngineResult;
+use crate::mesh::create_buffer;
+use crate::pipeline::{build_fullscreen_pipeline, shader_stage_create_info, PipelineTarget};
+use crate::render_graph::{
+    ImageDesc, ImageId, ImportedImage, Load, PassLayout, RenderGraph, RenderGraphCache, NO_ACCESS,
+};
+
+pub const HDR_FORMAT: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+const LUT_FORMAT: vk::Format = vk::Format::R8G8B8A8_UNORM;
+// Entries along each side of the grading table.
+const LUT_SIZE: u32 = 32;
+const VERTEX_SHADER: &str = "assets/shaders/post.vert";
+// Descriptor sets in the pool, one per pass.
+const MAX_PASSES: u32 = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PostSettings {
+    // Multiplies the scene before tonemapping.
+    pub exposure: f32,
+    pub bloom: bool,
+    // Brightness above which pixels bloom, and how far below it they start to.
+    pub bloom_threshold: f32,
+    pub bloom_knee: f32,
+    pub bloom_intensity: f32,
+    pub grade: ColorGrade,
+    // What the picture is encoded for. With an sRGB swapchain it's decoded again
+    // after grading and the hardware does the rest.
+    pub gamma: f32,
+}
+
+impl Default for PostSettings {
+    fn default() -> Self {
+        PostSettings {
+            exposure: 1.0,
+            bloom: true,
+            bloom_threshold: 1.0,
+            bloom_knee: 0.5,
+            bloom_intensity: 0.6,
+            grade: ColorGrade::default(),
+            gamma: 2.2,
+        }
+    }
+}
+
+// Baked into the grading table, which applies to gamma encoded colour.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorGrade {
+    // How much of the table to use, 0 for none.
+    pub strength: f32,
+    pub contrast: f32,
+    pub saturation: f32,
+    // Positive is warmer, negative cooler.
+    pub temperature: f32,
+}
+
+impl Default for ColorGrade {
+    fn default() -> Self {
+        ColorGrade {
+            strength: 1.0,
+            contrast: 1.05,
+            saturation: 1.1,
+            temperature: 0.0,
+        }
+    }
+}
+
+impl ColorGrade {
+    // The table as RGBA8, red varying fastest, then green, then blue.
+    fn bake(&self) -> Vec<[u8; 4]> {
+        let size = LUT_SIZE as usize;
+        let mut table = Vec::with_capacity(size * size * size);
+        for b in 0..size {
+            for g in 0..size {
+                for r in 0..size {
+                    let scale = 1.0 / (size - 1) as f32;
+                    let mut color = [r as f32 * scale, g as f32 * scale, b as f32 * scale];
+                    for channel in color.iter_mut() {
+                        *channel = (*channel - 0.5) * self.contrast + 0.5;
+                    }
+                    let luma = 0.2126 * color[0] + 0.7152 * color[1] + 0.0722 * color[2];
+                    for channel in color.iter_mut() {
+                        *channel = luma + (*channel - luma) * self.saturation;
+                    }
+                    color[0] *= 1.0 + 0.1 * self.temperature;
+                    color[2] *= 1.0 - 0.1 * self.temperature;
+                    let byte = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+                    table.push([byte(color[0]), byte(color[1]), byte(color[2]), 255]);
+                }
+            }
+        }
+        table
+    }
+}
+
+// The chain's own images, which the render graph provides every frame. The scene
+// is drawn into the first and the others hold intermediate results.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Target {
+    Scene,
+    BloomA,
+    BloomB,
+}
+
+impl Target {
+    // What the scene's size is divided by.
+    fn divisor(self) -> u32 {
+        match self {
+            Target::Scene => 1,
+            Target::BloomA | Target::BloomB => 2,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Target::Scene => "HDR scene",
+            Target::BloomA => "Bloom A",
+            Target::BloomB => "Bloom B",
+        }
+    }
+}
+
+// One fullscreen pass: a fragment shader run over its output, reading targets
+// earlier passes wrote.
+struct PassDesc {
+    name: &'static str,
+    shader: &'static str,
+    // Bound at 0 and 1; the first gives `texelSize`. The grading table is at 2.
+    inputs: [Target; 2],
+    // None for the chain's output, which only the last pass writes.
+    output: Option<Target>,
+    enabled: fn(&PostSettings) -> bool,
+    // The shader's `params`.
+    params: fn(&PostSettings) -> [f32; 4],
+}
+
+// The chain, in order. Bloom blurs its half resolution threshold back and forth
+// between two targets and leaves the result in the first.
+fn chain() -> Vec<PassDesc> {
+    vec![
+        PassDesc {
+            name: "Bloom threshold",
+            shader: "assets/shaders/post_bloom.frag",
+            inputs: [Target::Scene, Target::Scene],
+            output: Some(Target::BloomA),
+            enabled: |settings| settings.bloom,
+            params: |settings| [settings.bloom_threshold, settings.bloom_knee, 0.0, 0.0],
+        },
+        PassDesc {
+            name: "Bloom blur horizontal",
+            shader: "assets/shaders/post_blur.frag",
+            inputs: [Target::BloomA, Target::BloomA],
+            output: Some(Target::BloomB),
+            enabled: |settings| settings.bloom,
+            params: |_| [1.0, 0.0, 0.0, 0.0],
+        },
+        PassDesc {
+            name: "Bloom blur vertical",
+            shader: "assets/shaders/post_blur.frag",
+            inputs: [Target::BloomB, Target::BloomB],
+            output: Some(Target::BloomA),
+            enabled: |settings| settings.bloom,
+            params: |_| [0.0, 1.0, 0.0, 0.0],
+        },
+        PassDesc {
+            name: "Tonemap",
+            shader: "assets/shaders/post_tonemap.frag",
+            inputs: [Target::Scene, Target::BloomA],
+            output: None,
+            enabled: |_| true,
+            params: |settings| {
+                let bloom = if settings.bloom {
+                    settings.bloom_intensity
+                } else {
+                    0.0
+                };
+                [
+                    settings.exposure,
+                    bloom,
+                    settings.gamma,
+                    settings.grade.strength,
+                ]
+            },
+        },
+    ]
+}
+
+#[derive(Clone, Copy)]
+#[repr(C)]
+struct PostPush {
+    params: [f32; 4],
+    // One texel of the first input, in texture coordinates.
+    texel_size: [f32; 2],
+    // Bit 0 when the output encodes to sRGB by itself.
+    flags: u32,
+}
+
+struct Pass {
+    desc: PassDesc,
+    pipeline: vk::Pipeline,
+    // Rewritten with the graph's images before each use.
+    set: vk::DescriptorSet,
+}
+
+pub struct PostChain {
+    pub settings: PostSettings,
+    set_layout: vk::DescriptorSetLayout,
+    layout: vk::PipelineLayout,
+    descriptor_pool: vk::DescriptorPool,
+    sampler: vk::Sampler,
+    passes: Vec<Pass>,
+    lut: vk::Image,
+    lut_view: vk::ImageView,
+    lut_allocation: Option<Allocation>,
+    // What the table holds, None until it's first uploaded.
+    lut_grade: Option<ColorGrade>,
+    // The last frame's upload, freed once that frame is done.
+    staging: Option<(vk::Buffer, Allocation)>,
+    output_format: vk::Format,
+}
+
+// Whether writes to `format` are encoded to sRGB by the hardware.
+pub fn encodes_srgb(format: vk::Format) -> bool {
+    matches!(
+        format,
+        vk::Format::B8G8R8A8_SRGB | vk::Format::R8G8B8A8_SRGB
+    )
+}
+
+// What the passes draw into: one HDR target, or the output.
+fn pass_layout(format: vk::Format) -> PassLayout {
+    PassLayout {
+        colors: vec![format],
+        depth: None,
+        samples: vk::SampleCountFlags::TYPE_1,
+        resolve: false,
+    }
+}
+
+impl PostChain {
+    pub fn new(
+        device: &Device,
+        allocator: &mut Allocator,
+        compiler: &shaderc::Compiler,
+        cache: &mut RenderGraphCache,
+        output_format: vk::Format,
+    ) -> EngineResult<Self> {
+        unsafe {
+            let bindings: Vec<vk::DescriptorSetLayoutBinding> = (0..3)
+                .map(|binding| {
+                    vk::DescriptorSetLayoutBinding::builder()
+                        .binding(binding)
+                        .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                        .descriptor_count(1)
+                        .stage_flags(vk::ShaderStageFlags::FRAGMENT)
+                        .build()
+                })
+                .collect();
+            let set_layout = device.create_descriptor_set_layout(
+                &vk::DescriptorSetLayoutCreateInfo::builder().bindings(&bindings),
+                None,
+            )?;
+            let push_constant_ranges = [vk::PushConstantRange {
+                stage_flags: vk::ShaderStageFlags::FRAGMENT,
+                offset: 0,
+                size: std::mem::size_of::<PostPush>() as u32,
+            }];
+            let set_layouts = [set_layout];
+            let layout = device.create_pipeline_layout(
+                &vk::PipelineLayoutCreateInfo::builder()
+                    .set_layouts(&set_layouts)
+                    .push_constant_ranges(&push_constant_ranges),
+                None,
+            )?;
+            let pool_sizes = [vk::DescriptorPoolSize {
+                ty: vk::DescriptorType::COMBINED_IMAGE_SAMPLER,
+                descriptor_count: 3 * MAX_PASSES,
+            }];
+            let descriptor_pool = device.create_descriptor_pool(
+                &vk::DescriptorPoolCreateInfo::builder()
+                    .max_sets(MAX_PASSES)
+                    .pool_sizes(&pool_sizes),
+                None,
+            )?;
+            let sampler = device.create_sampler(
+                &vk::SamplerCreateInfo::builder()
+                    .mag_filter(vk::Filter::LINEAR)
+                    .min_filter(vk::Filter::LINEAR)
+                    .mipmap_mode(vk::SamplerMipmapMode::NEAREST)
+                    .address_mode_u(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_v(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .address_mode_w(vk::SamplerAddressMode::CLAMP_TO_EDGE)
+                    .max_lod(vk::LOD_CLAMP_NONE),
+                None,
+            )?;
+            debug::set_name(device, set_layout, "Post");
+            debug::set_name(device, layout, "Post");
+            debug::set_name(device, descriptor_pool, "Post");
+            let (lut, lut_view, lut_allocation) = create_lut(device, allocator)?;
+
+            let descs = chain();
+            debug_assert!(descs.len() <= MAX_PASSES as usize);
+            debug_assert!(descs.iter().rev().skip(1).all(|desc| desc.output.is_some()));
+            let mut chain = PostChain {
+                settings: PostSettings::default(),
+                set_layout,
+                layout,
+                descriptor_pool,
+                sampler,
+                passes: Vec::with_capacity(descs.len()),
+                lut,
+                lut_view,
+                lut_allocation: Some(lut_allocation),
+                lut_grade: None,
+                staging: None,
+                output_format,
+            };
+            for desc in descs {
+                let format = desc.output.map_or(output_format, |_| HDR_FORMAT);
+                let target = cache.pipeline_target(device, &pass_layout(format), desc.name)?;
+                let pipeline = chain.build_pipeline(device, compiler, &desc, &target)?;
+                let set = device.allocate_descriptor_sets(
+                    &vk::DescriptorSetAllocateInfo::builder()
+                        .descriptor_pool(descriptor_pool)
+                        .set_layouts(&set_layouts),
+                )?[0];
+                debug::set_name(device, set, desc.name);
+                chain.passes.push(Pass {
+                    desc,
+                    pipeline,
+                    set,
+                });
+            }
+            Ok(chain)
+        }
+    }
+
+    // Follows the swapchain's format, rebuilding the last pass's pipeline.
+    pub fn set_output_format(
+        &mut self,
+        device: &Device,
+        compiler: &shaderc::Compiler,
+        cache: &mut RenderGraphCache,
+        output_format: vk::Format,
+    ) -> EngineResult<()> {
+        if output_format == self.output_format {
+            return Ok(());
+        }
+        self.output_format = output_format;
+        let last = self.passes.len() - 1;
+        let desc = &self.passes[last].desc;
+        let target = cache.pipeline_target(device, &pass_layout(output_format), desc.name)?;
+        let pipeline = unsafe { self.build_pipeline(device, compiler, desc, &target)? };
+        let pass = &mut self.passes[last];
+        unsafe { device.destroy_pipeline(pass.pipeline, None) };
+        pass.pipeline = pipeline;
+        Ok(())
+    }
+
+    unsafe fn build_pipeline(
+        &self,
+        device: &Device,
+        compiler: &shaderc::Compiler,
+        desc: &PassDesc,
+        target: &PipelineTarget,
+    ) -> EngineResult<vk::Pipeline> {
+        let shaders = [
+            compile_shader(device, compiler, VERTEX_SHADER, shaderc::ShaderKind::Vertex)?,
+            compile_shader(device, compiler, desc.shader, shaderc::ShaderKind::Fragment)?,
+        ];
+        let shader_info = [
+            shader_stage_create_info(vk::ShaderStageFlags::VERTEX, shaders[0]).build(),
+            shader_stage_create_info(vk::ShaderStageFlags::FRAGMENT, shaders[1]).build(),
+        ];
+        let pipeline = build_fullscreen_pipeline(device, target, &shader_info, self.layout)?;
+        for shader in shaders {
+            device.destroy_shader_module(shader, None);
+        }
+        debug::set_name(device, pipeline, desc.name);
+        Ok(pipeline)
+    }
+
+    // The scene target's description for an output of `extent`.
+    pub fn scene_desc(extent: vk::Extent2D) -> ImageDesc {
+        target_desc(Target::Scene, extent)
+    }
+
+    // Uploads the grading table when it changed, ahead of the graph. Must only run
+    // once the GPU has finished the previous frame.
+    pub unsafe fn prepare(
+        &mut self,
+        device: &Device,
+        allocator: &mut Allocator,
+        command_buffer: vk::CommandBuffer,
+    ) -> EngineResult<()> {
+        if let Some((buffer, allocation)) = self.staging.take() {
+            device.destroy_buffer(buffer, None);
+            allocator.free(allocation)?;
+        }
+        let grade = self.settings.grade;
+        if self.lut_grade == Some(grade) {
+            return Ok(());
+        }
+        let shader_read = (
+            vk::PipelineStageFlags::FRAGMENT_SHADER,
+            vk::AccessFlags::SHADER_READ,
+        );
+        let transfer_write = (
+            vk::PipelineStageFlags::TRANSFER,
+            vk::AccessFlags::TRANSFER_WRITE,
+        );
+        let table = grade.bake();
+        let (staging, allocation) = create_buffer(
+            device,
+            allocator,
+            "Colour grading upload",
+            table.len() * 4,
+            vk::BufferUsageFlags::TRANSFER_SRC,
+        )?;
+        let mapped = allocation.mapped_ptr().unwrap().cast::<[u8; 4]>();
+        std::ptr::copy_nonoverlapping(table.as_ptr(), mapped.as_ptr(), table.len());
+        let old_layout = if self.lut_grade.is_some() {
+            vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+        } else {
+            vk::ImageLayout::UNDEFINED
+        };
+        image_barrier(
+            device,
+            command_buffer,
+            self.lut,
+            (old_layout, vk::ImageLayout::TRANSFER_DST_OPTIMAL),
+            shader_read,
+            transfer_write,
+        );
+        let copy = vk::BufferImageCopy::builder()
+            .image_subresource(vk::ImageSubresourceLayers {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                mip_level: 0,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image_extent(vk::Extent3D {
+                width: LUT_SIZE,
+                height: LUT_SIZE,
+                depth: LUT_SIZE,
+            })
+            .build();
+        device.cmd_copy_buffer_to_image(
+            command_buffer,
+            staging,
+            self.lut,
+            vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+            &[copy],
+        );
+        image_barrier(
+            device,
+            command_buffer,
+            self.lut,
+            (
+                vk::ImageLayout::TRANSFER_DST_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+            ),
+            transfer_write,
+            shader_read,
+        );
+        self.staging = Some((staging, allocation));
+        self.lut_grade = Some(grade);
+        Ok(())
+    }
+
+    // Adds the enabled passes to `graph`, from `scene` to `output`.
+    pub fn add_passes<C>(&self, graph: &mut RenderGraph<C>, scene: ImageId, output: ImageId) {
+        let extent = graph.desc(scene).extent;
+        let bloom_a =
+            graph.create_image(Target::BloomA.name(), target_desc(Target::BloomA, extent));
+        let bloom_b =
+            graph.create_image(Target::BloomB.name(), target_desc(Target::BloomB, extent));
+        let lut = graph.import_image(
+            "Colour grading",
+            ImportedImage {
+                image: self.lut,
+                view: self.lut_view,
+                desc: ImageDesc {
+                    format: LUT_FORMAT,
+                    extent: vk::Extent2D {
+                        width: LUT_SIZE,
+                        height: LUT_SIZE,
+                    },
+                    samples: vk::SampleCountFlags::TYPE_1,
+                },
+                layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+                access: NO_ACCESS,
+                final_layout: None,
+            },
+        );
+        // With bloom off nothing writes the bloom targets, so the tonemap pass
+        // reads the scene in their place, at no intensity.
+        let image = |target: Target| match target {
+            Target::Scene => scene,
+            _ if !self.settings.bloom => scene,
+            Target::BloomA => bloom_a,
+            Target::BloomB => bloom_b,
+        };
+
+        for pass in self.passes.iter() {
+            let desc = &pass.desc;
+            if !(desc.enabled)(&self.settings) {
+                continue;
+            }
+            let inputs = [image(desc.inputs[0]), image(desc.inputs[1])];
+            let input = graph.desc(inputs[0]).extent;
+            let push = PostPush {
+                params: (desc.params)(&self.settings),
+                texel_size: [1.0 / input.width as f32, 1.0 / input.height as f32],
+                flags: (desc.output.is_none() && encodes_srgb(self.output_format)) as u32,
+            };
+            let mut builder = graph
+                .pass(desc.name)
+                .color(desc.output.map_or(output, image), Load::DontCare)
+                .sample(inputs[0]);
+            if inputs[1] != inputs[0] {
+                builder = builder.sample(inputs[1]);
+            }
+            let (pipeline, set, layout) = (pass.pipeline, pass.set, self.layout);
+            let (sampler, lut_view) = (self.sampler, self.lut_view);
+            builder.sample(lut).record(move |_, context| unsafe {
+                let device = context.device;
+                let views = [context.view(inputs[0]), context.view(inputs[1]), lut_view];
+                write_set(device, set, sampler, views);
+                device.cmd_bind_pipeline(
+                    context.command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    pipeline,
+                );
+                device.cmd_bind_descriptor_sets(
+                    context.command_buffer,
+                    vk::PipelineBindPoint::GRAPHICS,
+                    layout,
+                    0,
+                    &[set],
+                    &[],
+                );
+                device.cmd_push_constants(
+                    context.command_buffer,
+                    layout,
+                    vk::ShaderStageFlags::FRAGMENT,
+                    0,
+                    as_bytes(&push),
+                );
+                device.cmd_draw(context.command_buffer, 3, 1, 0, 0);
+            });
+        }
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        unsafe {
+            if let Some((buffer, allocation)) = self.staging.take() {
+                device.destroy_buffer(buffer, None);
+                let _ = allocator.free(allocation);
+            }
+            device.destroy_image_view(self.lut_view, None);
+            device.destroy_image(self.lut, None);
+            if let Some(allocation) = self.lut_allocation.take() {
+                let _ = allocator.free(allocation);
+            }
+            for pass in self.passes.drain(..) {
+                device.destroy_pipeline(pass.pipeline, None);
+            }
+            device.destroy_sampler(self.sampler, None);
+            device.destroy_descriptor_pool(self.descriptor_pool, None);
+            device.destroy_pipeline_layout(self.layout, None);
+            device.destroy_descriptor_set_layout(self.set_layout, None);
+        }
+    }
+}
+
+fn target_desc(target: Target, extent: vk::Extent2D) -> ImageDesc {
+    ImageDesc {
+        format: HDR_FORMAT,
+        extent: vk::Extent2D {
+            width: (extent.width / target.divisor()).max(1),
+            height: (extent.height / target.divisor()).max(1),
+        },
+        samples: vk::SampleCountFlags::TYPE_1,
+    }
+}
+
+// The set is only ever bound by its own pass, after this, so it can be rewritten
+// while recording.
+unsafe fn write_set(
+    device: &Device,
+    set: vk::DescriptorSet,
+    sampler: vk::Sampler,
+    views: [vk::ImageView; 3],
+) {
+    let image_infos = views.map(|view| {
+        [vk::DescriptorImageInfo {
+            sampler,
+            image_view: view,
+            image_layout: vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }]
+    });
+    let writes: Vec<vk::WriteDescriptorSet> = image_infos
+        .iter()
+        .enumerate()
+        .map(|(binding, info)| {
+            vk::WriteDescriptorSet::builder()
+                .dst_set(set)
+                .dst_binding(binding as u32)
+                .descriptor_type(vk::DescriptorType::COMBINED_IMAGE_SAMPLER)
+                .image_info(info)
+                .build()
+        })
+        .collect();
+    device.update_descriptor_sets(&writes, &[]);
+}
+
+// The grading table, a 3D image sampled with the chain's other inputs.
+unsafe fn create_lut(
+    device: &Device,
+    allocator: &mut Allocator,
+) -> EngineResult<(vk::Image, vk::ImageView, Allocation)> {
+    let image = device.create_image(
+        &vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_3D)
+            .format(LUT_FORMAT)
+            .extent(vk::Extent3D {
+                width: LUT_SIZE,
+                height: LUT_SIZE,
+                depth: LUT_SIZE,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(vk::SampleCountFlags::TYPE_1)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(vk::ImageUsageFlags::SAMPLED | vk::ImageUsageFlags::TRANSFER_DST)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        None,
+    )?;
+    let requirements = device.get_image_memory_requirements(image);
+    let allocation = allocator.allocate(&AllocationCreateDesc {
+        name: "Colour grading",
+        requirements,
+        location: gpu_allocator::MemoryLocation::GpuOnly,
+        linear: false,
+    })?;
+    device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+    let view = device.create_image_view(
+        &vk::ImageViewCreateInfo::builder()
+            .view_type(vk::ImageViewType::TYPE_3D)
+            .format(LUT_FORMAT)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: vk::ImageAspectFlags::COLOR,
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image(image),
+        None,
+    )?;
+    debug::set_name(device, image, "Colour grading");
+    debug::set_name(device, view, "Colour grading");
+    Ok((image, view, allocation))
+}
@@ -0,0 +1,315 @@
+// Where frame time goes. CPU scopes are timed with `scope` from anywhere, GPU work
+// with a `GpuTimer` writing timestamps into the frame's command buffer. Both end up
+// in one rolling trace that can be saved in Chrome's tracing format and opened in
+// chrome://tracing or Perfetto.
+
+use std::collections::VecDeque;
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard, OnceLock};
+use std::time::{Duration, Instant};
+
+use ash::{vk, Device, Instance};
+
+use crate::debug;
+
+// Oldest events are dropped past this, which is a few seconds' worth.
+const MAX_EVENTS: usize = 20_000;
+// Frame times averaged for the stats.
+const FRAME_WINDOW: usize = 60;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Track {
+    Cpu,
+    Gpu,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TraceEvent {
+    pub name: &'static str,
+    pub track: Track,
+    pub start: Instant,
+    pub duration: Duration,
+}
+
+struct Profiler {
+    epoch: Instant,
+    events: VecDeque<TraceEvent>,
+    frame_times: VecDeque<Duration>,
+    last_frame: Option<Instant>,
+}
+
+fn profiler() -> MutexGuard<'static, Profiler> {
+    static PROFILER: OnceLock<Mutex<Profiler>> = OnceLock::new();
+    PROFILER
+        .get_or_init(|| {
+            Mutex::new(Profiler {
+                epoch: Instant::now(),
+                events: VecDeque::new(),
+                frame_times: VecDeque::new(),
+                last_frame: None,
+            })
+        })
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+pub fn record(event: TraceEvent) {
+    let mut profiler = profiler();
+    if profiler.events.len() == MAX_EVENTS {
+        profiler.events.pop_front();
+    }
+    profiler.events.push_back(event);
+}
+
+// Times everything until the returned guard is dropped.
+#[must_use]
+pub fn scope(name: &'static str) -> Scope {
+    Scope {
+        name,
+        start: Instant::now(),
+    }
+}
+
+pub struct Scope {
+    name: &'static str,
+    start: Instant,
+}
+
+impl Drop for Scope {
+    fn drop(&mut self) {
+        record(TraceEvent {
+            name: self.name,
+            track: Track::Cpu,
+            start: self.start,
+            duration: self.start.elapsed(),
+        });
+    }
+}
+
+// Marks the start of a new frame.
+pub fn end_frame() {
+    let now = Instant::now();
+    let mut profiler = profiler();
+    if let Some(last) = profiler.last_frame.replace(now) {
+        if profiler.frame_times.len() == FRAME_WINDOW {
+            profiler.frame_times.pop_front();
+        }
+        profiler.frame_times.push_back(now - last);
+    }
+}
+
+// Mean over the last few frames, in milliseconds.
+pub fn frame_time_ms() -> f32 {
+    let profiler = profiler();
+    let count = profiler.frame_times.len().max(1) as f32;
+    profiler.frame_times.iter().sum::<Duration>().as_secs_f32() * 1000.0 / count
+}
+
+pub fn write_chrome_trace(path: &Path) -> std::io::Result<()> {
+    let json = {
+        let profiler = profiler();
+        chrome_trace(profiler.epoch, profiler.events.iter())
+    };
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::write(path, json)
+}
+
+// The thread names and then every event, one entry per line.
+fn chrome_trace<'a>(epoch: Instant, events: impl Iterator<Item = &'a TraceEvent>) -> String {
+    let mut entries: Vec<String> = [(1, "CPU"), (2, "GPU")]
+        .into_iter()
+        .map(|(tid, name)| {
+            format!(
+                "{{\"name\":\"thread_name\",\"ph\":\"M\",\"pid\":1,\"tid\":{},\"args\":{{\"name\":\"{}\"}}}}",
+                tid, name
+            )
+        })
+        .collect();
+    entries.extend(events.map(|event| {
+        let start = event.start.saturating_duration_since(epoch);
+        format!(
+            "{{\"name\":\"{}\",\"ph\":\"X\",\"pid\":1,\"tid\":{},\"ts\":{:.3},\"dur\":{:.3}}}",
+            event.name.replace('"', "'"),
+            if event.track == Track::Cpu { 1 } else { 2 },
+            start.as_secs_f64() * 1e6,
+            event.duration.as_secs_f64() * 1e6
+        )
+    }));
+    format!("{{\"traceEvents\":[\n{}\n]}}\n", entries.join(",\n"))
+}
+
+// Timestamps around passes and dispatches recorded into a frame's command buffer.
+// Queries are written into one of several sections in turn and a section is only
+// read back when its turn comes again, by which time its frame has long finished,
+// so reading never stalls.
+pub struct GpuTimer {
+    pool: vk::QueryPool,
+    // Nanoseconds per timestamp tick.
+    period: f64,
+    sections: Vec<Section>,
+    current: usize,
+    // Name and milliseconds of each scope, from the last section read.
+    pub results: Vec<(&'static str, f32)>,
+}
+
+#[derive(Default)]
+struct Section {
+    scopes: Vec<(&'static str, u32)>,
+    // When the frame was recorded, to line the GPU scopes up with the CPU ones.
+    recorded: Option<Instant>,
+    used: u32,
+}
+
+const SECTIONS: usize = 3;
+const QUERIES_PER_SECTION: u32 = 32;
+
+impl GpuTimer {
+    // None when the queue family can't write timestamps.
+    pub unsafe fn new(
+        instance: &Instance,
+        pdevice: vk::PhysicalDevice,
+        device: &Device,
+        queue_family: u32,
+    ) -> Option<Self> {
+        let properties = instance.get_physical_device_properties(pdevice);
+        let families = instance.get_physical_device_queue_family_properties(pdevice);
+        let valid_bits = families.get(queue_family as usize)?.timestamp_valid_bits;
+        if valid_bits == 0 || properties.limits.timestamp_period == 0.0 {
+            return None;
+        }
+        let pool = device
+            .create_query_pool(
+                &vk::QueryPoolCreateInfo::builder()
+                    .query_type(vk::QueryType::TIMESTAMP)
+                    .query_count(QUERIES_PER_SECTION * SECTIONS as u32),
+                None,
+            )
+            .ok()?;
+        debug::set_name(device, pool, "Timestamps");
+        Some(GpuTimer {
+            pool,
+            period: properties.limits.timestamp_period as f64,
+            sections: (0..SECTIONS).map(|_| Section::default()).collect(),
+            current: 0,
+            results: Vec::new(),
+        })
+    }
+
+    // Reads the oldest section back and resets it for this frame. Call first thing
+    // when recording a frame, after the fence of the frame before has signalled.
+    pub unsafe fn begin_frame(&mut self, device: &Device, command_buffer: vk::CommandBuffer) {
+        self.current = (self.current + 1) % SECTIONS;
+        let first = self.first_query();
+        let section = &mut self.sections[self.current];
+        if let Some(recorded) = section.recorded.take() {
+            let mut timestamps = vec![0u64; section.used as usize];
+            let read = device.get_query_pool_results(
+                self.pool,
+                first,
+                section.used,
+                &mut timestamps,
+                vk::QueryResultFlags::TYPE_64,
+            );
+            if read.is_ok() {
+                let origin = timestamps.first().copied().unwrap_or(0);
+                let to_duration =
+                    |ticks: u64| Duration::from_nanos((ticks as f64 * self.period) as u64);
+                self.results.clear();
+                for &(name, query) in section.scopes.iter() {
+                    let (start, end) = (timestamps[query as usize], timestamps[query as usize + 1]);
+                    let duration = to_duration(end.saturating_sub(start));
+                    self.results.push((name, duration.as_secs_f32() * 1000.0));
+                    record(TraceEvent {
+                        name,
+                        track: Track::Gpu,
+                        start: recorded + to_duration(start.saturating_sub(origin)),
+                        duration,
+                    });
+                }
+            }
+        }
+        section.scopes.clear();
+        section.used = 0;
+        section.recorded = Some(Instant::now());
+        device.cmd_reset_query_pool(command_buffer, self.pool, first, QUERIES_PER_SECTION);
+    }
+
+    // Returns the token to hand to `end`, or None once the section is full.
+    pub unsafe fn begin(
+        &mut self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        name: &'static str,
+    ) -> Option<u32> {
+        let first = self.first_query();
+        let section = &mut self.sections[self.current];
+        if section.used + 2 > QUERIES_PER_SECTION {
+            return None;
+        }
+        let query = section.used;
+        section.used += 2;
+        section.scopes.push((name, query));
+        device.cmd_write_timestamp(
+            command_buffer,
+            vk::PipelineStageFlags::TOP_OF_PIPE,
+            self.pool,
+            first + query,
+        );
+        Some(query)
+    }
+
+    pub unsafe fn end(
+        &self,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        token: Option<u32>,
+    ) {
+        if let Some(query) = token {
+            device.cmd_write_timestamp(
+                command_buffer,
+                vk::PipelineStageFlags::BOTTOM_OF_PIPE,
+                self.pool,
+                self.first_query() + query + 1,
+            );
+        }
+    }
+
+    fn first_query(&self) -> u32 {
+        self.current as u32 * QUERIES_PER_SECTION
+    }
+
+    pub fn destroy(&self, device: &Device) {
+        unsafe { device.destroy_query_pool(self.pool, None) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trace_without_events_has_no_trailing_comma() {
+        let json = chrome_trace(Instant::now(), std::iter::empty());
+        assert!(json.starts_with("{\"traceEvents\":[\n{"));
+        assert!(json.ends_with("}\n]}\n"));
+        assert!(!json.contains(",\n]"));
+        assert_eq!(json.matches("thread_name").count(), 2);
+    }
+
+    #[test]
+    fn events_are_separated_by_commas() {
+        let epoch = Instant::now();
+        let events = [Track::Cpu, Track::Gpu].map(|track| TraceEvent {
+            name: "Pass",
+            track,
+            start: epoch + Duration::from_micros(5),
+            duration: Duration::from_micros(2),
+        });
+        let json = chrome_trace(epoch, events.iter());
+        assert_eq!(json.matches("},\n{").count(), 3);
+        assert!(!json.contains(",\n]"));
+        assert!(json.contains("\"tid\":2,\"ts\":5.000,\"dur\":2.000"));
+    }
+}
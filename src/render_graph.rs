@@ -0,0 +1,1866 @@
+// A frame's GPU work as a graph of passes that declare the images and buffers they
+// read and write. Passes run in the order they were added; the graph works out the
+// rest from their declarations: which passes contribute nothing to the frame's
+// outputs and can be skipped, the barriers and layout transitions between the ones
+// that run, how to begin their attachments, and images for the resources that only
+// live within the frame. Attachments are either begun with dynamic rendering and
+// the barriers recorded with synchronization2, or on devices without those, with
+// render pass and framebuffer objects. Those and the images come from a cache kept
+// across frames, and resources whose lifetimes don't overlap share an image.
+// `CompiledGraph::dot` describes the graph for graphviz.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use ash::{vk, Device};
+use gpu_allocator::vulkan::*;
+
+use crate::compute::Access;
+use crate::debug;
+use crate::error::EngineResult;
+use crate::pipeline::PipelineTarget;
+use crate::profiler::GpuTimer;
+
+// Frames a cached image may go unused before it's freed.
+const KEEP_FRAMES: u64 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageId(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BufferId(usize);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ImageDesc {
+    pub format: vk::Format,
+    pub extent: vk::Extent2D,
+    pub samples: vk::SampleCountFlags,
+}
+
+// An image from outside the graph, like a swapchain image, with the state it's in
+// before the frame.
+#[derive(Clone, Copy, Debug)]
+pub struct ImportedImage {
+    pub image: vk::Image,
+    pub view: vk::ImageView,
+    pub desc: ImageDesc,
+    pub layout: vk::ImageLayout,
+    // What the first use has to wait for.
+    pub access: Access,
+    // Where the frame leaves it. None keeps whatever its last use needed.
+    pub final_layout: Option<vk::ImageLayout>,
+}
+
+// What happens to an attachment's contents as a pass starts. For depth, the clear
+// value is the first component.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Load {
+    Clear([f32; 4]),
+    Keep,
+    DontCare,
+}
+
+impl Load {
+    fn op(self) -> vk::AttachmentLoadOp {
+        match self {
+            Load::Clear(_) => vk::AttachmentLoadOp::CLEAR,
+            Load::Keep => vk::AttachmentLoadOp::LOAD,
+            Load::DontCare => vk::AttachmentLoadOp::DONT_CARE,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum ImageUse {
+    Color(Load),
+    // Written with the resolved samples of the colour attachment it belongs to.
+    Resolve,
+    Depth(Load),
+    // Read in fragment shaders.
+    Sampled,
+}
+
+impl ImageUse {
+    fn layout(self) -> vk::ImageLayout {
+        match self {
+            ImageUse::Color(_) | ImageUse::Resolve => vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+            ImageUse::Depth(_) => vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL,
+            ImageUse::Sampled => vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL,
+        }
+    }
+
+    fn access(self) -> Access {
+        match self {
+            ImageUse::Color(load) => (
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                if load == Load::Keep {
+                    vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                } else {
+                    vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+                },
+            ),
+            ImageUse::Resolve => (
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+            ),
+            ImageUse::Depth(_) => (
+                vk::PipelineStageFlags::EARLY_FRAGMENT_TESTS
+                    | vk::PipelineStageFlags::LATE_FRAGMENT_TESTS,
+                vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_READ
+                    | vk::AccessFlags::DEPTH_STENCIL_ATTACHMENT_WRITE,
+            ),
+            ImageUse::Sampled => (
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_READ,
+            ),
+        }
+    }
+
+    fn usage(self) -> vk::ImageUsageFlags {
+        match self {
+            ImageUse::Color(_) | ImageUse::Resolve => vk::ImageUsageFlags::COLOR_ATTACHMENT,
+            ImageUse::Depth(_) => vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT,
+            ImageUse::Sampled => vk::ImageUsageFlags::SAMPLED,
+        }
+    }
+
+    // Whether the pass depends on what was there before.
+    fn reads(self) -> bool {
+        match self {
+            ImageUse::Color(load) | ImageUse::Depth(load) => load == Load::Keep,
+            ImageUse::Resolve => false,
+            ImageUse::Sampled => true,
+        }
+    }
+
+    fn writes(self) -> bool {
+        self != ImageUse::Sampled
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            ImageUse::Color(_) => "colour",
+            ImageUse::Resolve => "resolve",
+            ImageUse::Depth(_) => "depth",
+            ImageUse::Sampled => "sampled",
+        }
+    }
+}
+
+// The attachments a pass draws into, which the pipelines it binds are built for.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct PassLayout {
+    pub colors: Vec<vk::Format>,
+    pub depth: Option<vk::Format>,
+    pub samples: vk::SampleCountFlags,
+    // Every colour attachment resolves into a single sampled one.
+    pub resolve: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct AttachmentKey {
+    format: vk::Format,
+    samples: vk::SampleCountFlags,
+    load: vk::AttachmentLoadOp,
+    store: vk::AttachmentStoreOp,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct RenderPassKey {
+    colors: Vec<AttachmentKey>,
+    // One per colour attachment, None for those not resolved.
+    resolves: Vec<Option<AttachmentKey>>,
+    depth: Option<AttachmentKey>,
+}
+
+impl RenderPassKey {
+    // Any render pass with these formats and sample counts is compatible with the
+    // ones the graph begins, whatever their load and store ops.
+    fn compatible(layout: &PassLayout) -> Self {
+        let attachment = |format, samples| AttachmentKey {
+            format,
+            samples,
+            load: vk::AttachmentLoadOp::DONT_CARE,
+            store: vk::AttachmentStoreOp::STORE,
+        };
+        RenderPassKey {
+            colors: layout
+                .colors
+                .iter()
+                .map(|&format| attachment(format, layout.samples))
+                .collect(),
+            resolves: layout
+                .colors
+                .iter()
+                .map(|&format| {
+                    layout
+                        .resolve
+                        .then(|| attachment(format, vk::SampleCountFlags::TYPE_1))
+                })
+                .collect(),
+            depth: layout
+                .depth
+                .map(|format| attachment(format, layout.samples)),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+struct FramebufferKey {
+    render_pass: vk::RenderPass,
+    views: Vec<vk::ImageView>,
+    extent: vk::Extent2D,
+}
+
+// What a pass's recording gets to work with.
+pub struct PassContext<'a> {
+    pub device: &'a Device,
+    pub command_buffer: vk::CommandBuffer,
+    // Of the attachments, and zero for passes without any.
+    pub extent: vk::Extent2D,
+    views: &'a [vk::ImageView],
+}
+
+impl PassContext<'_> {
+    pub fn view(&self, image: ImageId) -> vk::ImageView {
+        self.views[image.0]
+    }
+}
+
+type Record<C> = Box<dyn FnOnce(&C, &PassContext)>;
+
+struct ImageResource {
+    name: &'static str,
+    desc: ImageDesc,
+    imported: Option<ImportedImage>,
+}
+
+// Buffers always come from outside the graph, so passes writing them are never
+// culled.
+struct BufferResource {
+    name: &'static str,
+    // The access after the frame, like the CPU reading results back.
+    after: Option<Access>,
+}
+
+struct Pass<C> {
+    name: &'static str,
+    // Each with the image it resolves into, if any.
+    colors: Vec<(ImageId, Load, Option<ImageId>)>,
+    depth: Option<(ImageId, Load)>,
+    sampled: Vec<ImageId>,
+    buffers: Vec<(BufferId, Access, bool)>,
+    record: Option<Record<C>>,
+}
+
+impl<C> Pass<C> {
+    fn image_uses(&self) -> Vec<(ImageId, ImageUse)> {
+        let mut uses = Vec::new();
+        for &(image, load, resolve) in self.colors.iter() {
+            uses.push((image, ImageUse::Color(load)));
+            if let Some(resolve) = resolve {
+                uses.push((resolve, ImageUse::Resolve));
+            }
+        }
+        if let Some((image, load)) = self.depth {
+            uses.push((image, ImageUse::Depth(load)));
+        }
+        for &image in self.sampled.iter() {
+            uses.push((image, ImageUse::Sampled));
+        }
+        uses
+    }
+
+    fn is_raster(&self) -> bool {
+        !self.colors.is_empty() || self.depth.is_some()
+    }
+}
+
+pub struct RenderGraph<C> {
+    images: Vec<ImageResource>,
+    buffers: Vec<BufferResource>,
+    passes: Vec<Pass<C>>,
+}
+
+// Declares what a pass uses, then adds it with `record`.
+pub struct PassBuilder<'g, C> {
+    graph: &'g mut RenderGraph<C>,
+    pass: Pass<C>,
+}
+
+impl<C> PassBuilder<'_, C> {
+    pub fn color(mut self, image: ImageId, load: Load) -> Self {
+        self.pass.colors.push((image, load, None));
+        self
+    }
+
+    // Resolves the last colour attachment into `image` as the pass ends.
+    pub fn resolve(mut self, image: ImageId) -> Self {
+        let color = self
+            .pass
+            .colors
+            .last_mut()
+            .expect("resolve without a colour");
+        color.2 = Some(image);
+        self
+    }
+
+    pub fn depth(mut self, image: ImageId, load: Load) -> Self {
+        self.pass.depth = Some((image, load));
+        self
+    }
+
+    // Read in the pass's fragment shaders.
+    pub fn sample(mut self, image: ImageId) -> Self {
+        self.pass.sampled.push(image);
+        self
+    }
+
+    pub fn read_buffer(mut self, buffer: BufferId, access: Access) -> Self {
+        self.pass.buffers.push((buffer, access, false));
+        self
+    }
+
+    pub fn write_buffer(mut self, buffer: BufferId, access: Access) -> Self {
+        self.pass.buffers.push((buffer, access, true));
+        self
+    }
+
+    // Passes with attachments are recorded inside a render pass over all of them,
+    // with the viewport and scissor covering them.
+    pub fn record(mut self, record: impl FnOnce(&C, &PassContext) + 'static) {
+        self.pass.record = Some(Box::new(record));
+        self.graph.passes.push(self.pass);
+    }
+}
+
+impl<C> Default for RenderGraph<C> {
+    fn default() -> Self {
+        RenderGraph {
+            images: Vec::new(),
+            buffers: Vec::new(),
+            passes: Vec::new(),
+        }
+    }
+}
+
+impl<C> RenderGraph<C> {
+    // An image that only lives within the frame. Its contents are undefined until
+    // a pass writes them.
+    pub fn create_image(&mut self, name: &'static str, desc: ImageDesc) -> ImageId {
+        self.images.push(ImageResource {
+            name,
+            desc,
+            imported: None,
+        });
+        ImageId(self.images.len() - 1)
+    }
+
+    pub fn import_image(&mut self, name: &'static str, image: ImportedImage) -> ImageId {
+        self.images.push(ImageResource {
+            name,
+            desc: image.desc,
+            imported: Some(image),
+        });
+        ImageId(self.images.len() - 1)
+    }
+
+    // `after` is the access that follows the frame, if anything reads the buffer
+    // outside the graph.
+    pub fn import_buffer(&mut self, name: &'static str, after: Option<Access>) -> BufferId {
+        self.buffers.push(BufferResource { name, after });
+        BufferId(self.buffers.len() - 1)
+    }
+
+    pub fn desc(&self, image: ImageId) -> ImageDesc {
+        self.images[image.0].desc
+    }
+
+    pub fn pass(&mut self, name: &'static str) -> PassBuilder<'_, C> {
+        PassBuilder {
+            graph: self,
+            pass: Pass {
+                name,
+                colors: Vec::new(),
+                depth: None,
+                sampled: Vec::new(),
+                buffers: Vec::new(),
+                record: None,
+            },
+        }
+    }
+
+    // Walks back from the passes with effects outside the graph, keeping those
+    // whose writes something later reads. A write that doesn't read the image
+    // first ends the need for whatever wrote it before.
+    fn live_passes(&self) -> Vec<bool> {
+        let mut live = vec![false; self.passes.len()];
+        let mut needed = vec![false; self.images.len()];
+        for (index, pass) in self.passes.iter().enumerate().rev() {
+            let uses = pass.image_uses();
+            let writes_output = uses.iter().any(|&(image, usage)| {
+                usage.writes() && (needed[image.0] || self.images[image.0].imported.is_some())
+            }) || pass.buffers.iter().any(|&(_, _, write)| write);
+            if !writes_output {
+                continue;
+            }
+            live[index] = true;
+            for &(image, usage) in uses.iter() {
+                if usage.writes() && !usage.reads() {
+                    needed[image.0] = false;
+                }
+            }
+            for &(image, usage) in uses.iter() {
+                if usage.reads() {
+                    needed[image.0] = true;
+                }
+            }
+        }
+        live
+    }
+
+    // Whether what `pass` leaves in `image` is used after it: by a later pass
+    // reading it before anything overwrites it, or outside the graph.
+    fn used_after(&self, live: &[bool], pass: usize, image: ImageId) -> bool {
+        if self.images[image.0].imported.is_some() {
+            return true;
+        }
+        for (index, later) in self.passes.iter().enumerate().skip(pass + 1) {
+            if !live[index] {
+                continue;
+            }
+            for (id, usage) in later.image_uses() {
+                if id == image {
+                    if usage.reads() {
+                        return true;
+                    }
+                    if usage.writes() {
+                        return false;
+                    }
+                }
+            }
+        }
+        false
+    }
+
+    // What `pass` does with the contents it leaves in an attachment.
+    fn store_op(&self, live: &[bool], pass: usize, image: ImageId) -> vk::AttachmentStoreOp {
+        if self.used_after(live, pass, image) {
+            vk::AttachmentStoreOp::STORE
+        } else {
+            vk::AttachmentStoreOp::DONT_CARE
+        }
+    }
+
+    // Culls, places the frame's images and works out every barrier. Must only run
+    // once the GPU has finished the previous frame, since cached images it no
+    // longer needs are freed here.
+    pub fn compile(
+        self,
+        device: &Device,
+        allocator: &mut Allocator,
+        cache: &mut RenderGraphCache,
+    ) -> EngineResult<CompiledGraph<C>> {
+        cache.begin_frame(device, allocator);
+        let mut compiled = self.plan(cache, |name, desc, usage| unsafe {
+            create_image(device, allocator, name, desc, usage)
+        })?;
+        for step in compiled.steps.iter_mut() {
+            step.render = compiled.graph.render_info(
+                device,
+                cache,
+                &compiled.live,
+                step.pass,
+                &compiled.views,
+            )?;
+        }
+        Ok(compiled)
+    }
+
+    // Everything `compile` works out short of beginning the attachments, with
+    // `create` making the cached images there aren't free ones for.
+    fn plan(
+        mut self,
+        cache: &mut RenderGraphCache,
+        mut create: impl FnMut(&str, ImageDesc, vk::ImageUsageFlags) -> EngineResult<CachedImage>,
+    ) -> EngineResult<CompiledGraph<C>> {
+        let live = self.live_passes();
+
+        // Lifetimes and usage of the images only this frame has.
+        let mut first = vec![usize::MAX; self.images.len()];
+        let mut last = vec![0; self.images.len()];
+        let mut usage = vec![vk::ImageUsageFlags::empty(); self.images.len()];
+        for (index, pass) in self.passes.iter().enumerate() {
+            if !live[index] {
+                continue;
+            }
+            for (image, image_use) in pass.image_uses() {
+                first[image.0] = first[image.0].min(index);
+                last[image.0] = index;
+                usage[image.0] |= image_use.usage();
+            }
+        }
+        let mut slots = vec![None; self.images.len()];
+        for index in 0..self.passes.len() {
+            for (id, image) in self.images.iter().enumerate() {
+                if image.imported.is_none() && first[id] == index {
+                    slots[id] =
+                        Some(cache.acquire(image.name, image.desc, usage[id], &mut create)?);
+                }
+            }
+            for (id, slot) in slots.iter().enumerate() {
+                if let Some(slot) = slot.filter(|_| last[id] == index) {
+                    cache.release(slot);
+                }
+            }
+        }
+        let physical: Vec<(vk::Image, vk::ImageView)> = self
+            .images
+            .iter()
+            .zip(slots.iter())
+            .map(|(image, slot)| match (image.imported, slot) {
+                (Some(imported), _) => (imported.image, imported.view),
+                (None, Some(slot)) => (cache.images[*slot].image, cache.images[*slot].view),
+                (None, None) => (vk::Image::null(), vk::ImageView::null()),
+            })
+            .collect();
+        let views: Vec<vk::ImageView> = physical.iter().map(|&(_, view)| view).collect();
+
+        // What each image and buffer was last used for, tracked per image rather
+        // than per resource so resources sharing one wait on each other.
+        let mut image_states: HashMap<vk::Image, State> = HashMap::new();
+        for (image, &(handle, _)) in self.images.iter().zip(physical.iter()) {
+            if let Some(imported) = image.imported {
+                image_states.insert(handle, State::new(imported.layout, imported.access));
+            }
+        }
+        let mut buffer_states: Vec<State> = self
+            .buffers
+            .iter()
+            .map(|_| State::new(vk::ImageLayout::UNDEFINED, NO_ACCESS))
+            .collect();
+
+        let mut steps = Vec::new();
+        for (index, pass) in self.passes.iter_mut().enumerate() {
+            if !live[index] {
+                continue;
+            }
+            let mut barrier = Barrier::default();
+            for (image, image_use) in pass.image_uses() {
+                let (handle, _) = physical[image.0];
+                let state = image_states
+                    .entry(handle)
+                    .or_insert_with(|| State::new(vk::ImageLayout::UNDEFINED, NO_ACCESS));
+                // A resource starting out on a shared image has nothing to keep.
+                if first[image.0] == index && self.images[image.0].imported.is_none() {
+                    state.layout = vk::ImageLayout::UNDEFINED;
+                }
+                barrier.image(
+                    state,
+                    handle,
+                    aspect(self.images[image.0].desc.format),
+                    image_use.layout(),
+                    image_use.access(),
+                    image_use.writes(),
+                );
+            }
+            for &(buffer, access, write) in pass.buffers.iter() {
+                barrier.memory(&mut buffer_states[buffer.0], access, write);
+            }
+            steps.push(Step {
+                pass: index,
+                barrier,
+                render: None,
+                record: pass.record.take(),
+            });
+        }
+
+        let mut finish = Barrier::default();
+        for (image, &(handle, _)) in self.images.iter().zip(physical.iter()) {
+            let Some(final_layout) = image.imported.and_then(|imported| imported.final_layout)
+            else {
+                continue;
+            };
+            if let Some(state) = image_states.get_mut(&handle) {
+                if state.layout != final_layout {
+                    finish.image(
+                        state,
+                        handle,
+                        aspect(image.desc.format),
+                        final_layout,
+                        NO_ACCESS,
+                        false,
+                    );
+                }
+            }
+        }
+        for (buffer, state) in self.buffers.iter().zip(buffer_states.iter_mut()) {
+            if let Some(after) = buffer.after {
+                finish.memory(state, after, false);
+            }
+        }
+
+        Ok(CompiledGraph {
+            graph: self,
+            live,
+            slots,
+            views,
+            steps,
+            finish,
+            dynamic_rendering: cache.dynamic_rendering,
+        })
+    }
+
+    fn render_info(
+        &self,
+        device: &Device,
+        cache: &mut RenderGraphCache,
+        live: &[bool],
+        index: usize,
+        views: &[vk::ImageView],
+    ) -> EngineResult<Option<RenderInfo>> {
+        let pass = &self.passes[index];
+        if !pass.is_raster() {
+            return Ok(None);
+        }
+        let attachment = |image: ImageId, load: vk::AttachmentLoadOp| {
+            let desc = self.images[image.0].desc;
+            AttachmentKey {
+                format: desc.format,
+                samples: desc.samples,
+                load,
+                store: self.store_op(live, index, image),
+            }
+        };
+        let key = RenderPassKey {
+            colors: pass
+                .colors
+                .iter()
+                .map(|&(image, load, _)| attachment(image, load.op()))
+                .collect(),
+            resolves: pass
+                .colors
+                .iter()
+                .map(|&(_, _, resolve)| {
+                    resolve.map(|image| attachment(image, vk::AttachmentLoadOp::DONT_CARE))
+                })
+                .collect(),
+            depth: pass.depth.map(|(image, load)| attachment(image, load.op())),
+        };
+
+        // Attachments in the order `create_render_pass` puts them.
+        let mut images: Vec<ImageId> = pass.colors.iter().map(|color| color.0).collect();
+        images.extend(pass.colors.iter().filter_map(|color| color.2));
+        images.extend(pass.depth.map(|depth| depth.0));
+        let mut clear_values: Vec<vk::ClearValue> = pass
+            .colors
+            .iter()
+            .map(|&(_, load, _)| vk::ClearValue {
+                color: vk::ClearColorValue {
+                    float32: clear_color(load),
+                },
+            })
+            .collect();
+        clear_values.extend(pass.colors.iter().filter_map(|color| color.2).map(|_| {
+            vk::ClearValue {
+                color: vk::ClearColorValue::default(),
+            }
+        }));
+        clear_values.extend(pass.depth.map(|(_, load)| vk::ClearValue {
+            depth_stencil: vk::ClearDepthStencilValue {
+                depth: clear_color(load)[0],
+                stencil: 0,
+            },
+        }));
+        let extent = self.images[images[0].0].desc.extent;
+        debug_assert!(images
+            .iter()
+            .all(|image| self.images[image.0].desc.extent == extent));
+
+        if cache.dynamic_rendering {
+            let attachment_info = |image: ImageId, image_use: ImageUse, key: &AttachmentKey| {
+                vk::RenderingAttachmentInfo::builder()
+                    .image_view(views[image.0])
+                    .image_layout(image_use.layout())
+                    .load_op(key.load)
+                    .store_op(key.store)
+            };
+            let colors = pass
+                .colors
+                .iter()
+                .zip(key.colors.iter())
+                .zip(clear_values.iter())
+                .map(|((&(image, load, resolve), key), &clear_value)| {
+                    let info =
+                        attachment_info(image, ImageUse::Color(load), key).clear_value(clear_value);
+                    match resolve {
+                        Some(resolve) => info
+                            .resolve_mode(vk::ResolveModeFlags::AVERAGE)
+                            .resolve_image_view(views[resolve.0])
+                            .resolve_image_layout(ImageUse::Resolve.layout()),
+                        None => info,
+                    }
+                    .build()
+                })
+                .collect();
+            let depth = pass.depth.zip(key.depth).map(|((image, load), key)| {
+                attachment_info(image, ImageUse::Depth(load), &key)
+                    .clear_value(clear_values[clear_values.len() - 1])
+                    .build()
+            });
+            return Ok(Some(RenderInfo {
+                extent,
+                begin: Begin::Rendering { colors, depth },
+            }));
+        }
+
+        let render_pass = cache.render_pass(device, &key, pass.name)?;
+        let framebuffer = cache.framebuffer(
+            device,
+            FramebufferKey {
+                render_pass,
+                views: images.iter().map(|image| views[image.0]).collect(),
+                extent,
+            },
+            pass.name,
+        )?;
+        Ok(Some(RenderInfo {
+            extent,
+            begin: Begin::RenderPass {
+                render_pass,
+                framebuffer,
+                clear_values,
+            },
+        }))
+    }
+}
+
+fn clear_color(load: Load) -> [f32; 4] {
+    match load {
+        Load::Clear(value) => value,
+        Load::Keep | Load::DontCare => [0.0; 4],
+    }
+}
+
+fn aspect(format: vk::Format) -> vk::ImageAspectFlags {
+    match format {
+        vk::Format::D16_UNORM | vk::Format::D32_SFLOAT | vk::Format::X8_D24_UNORM_PACK32 => {
+            vk::ImageAspectFlags::DEPTH
+        }
+        vk::Format::D16_UNORM_S8_UINT
+        | vk::Format::D24_UNORM_S8_UINT
+        | vk::Format::D32_SFLOAT_S8_UINT => {
+            vk::ImageAspectFlags::DEPTH | vk::ImageAspectFlags::STENCIL
+        }
+        _ => vk::ImageAspectFlags::COLOR,
+    }
+}
+
+// For imported images and buffers with nothing to wait for.
+pub const NO_ACCESS: Access = (vk::PipelineStageFlags::empty(), vk::AccessFlags::empty());
+
+// The last use of an image or buffer. Reads since the last write are gathered up so
+// the next write waits for all of them.
+struct State {
+    layout: vk::ImageLayout,
+    access: Access,
+    written: bool,
+}
+
+impl State {
+    fn new(layout: vk::ImageLayout, access: Access) -> Self {
+        State {
+            layout,
+            access,
+            written: !access.1.is_empty(),
+        }
+    }
+
+    // What the next use has to wait for: the stages, and any writes to make
+    // available.
+    fn available(&self) -> Access {
+        if self.written {
+            self.access
+        } else {
+            (self.access.0, vk::AccessFlags::empty())
+        }
+    }
+}
+
+// An image's layout transition and the accesses on either side of it.
+struct ImageTransition {
+    image: vk::Image,
+    aspect: vk::ImageAspectFlags,
+    layouts: (vk::ImageLayout, vk::ImageLayout),
+    src: Access,
+    dst: Access,
+}
+
+// Everything a pass waits for, recorded as one pipeline barrier.
+#[derive(Default)]
+struct Barrier {
+    // Buffers are synchronised with a global memory barrier.
+    memory: Option<(Access, Access)>,
+    images: Vec<ImageTransition>,
+}
+
+impl Barrier {
+    fn image(
+        &mut self,
+        state: &mut State,
+        image: vk::Image,
+        aspect: vk::ImageAspectFlags,
+        layout: vk::ImageLayout,
+        access: Access,
+        write: bool,
+    ) {
+        if state.layout == layout && !state.written && !write {
+            state.access.0 |= access.0;
+            state.access.1 |= access.1;
+            return;
+        }
+        self.images.push(ImageTransition {
+            image,
+            aspect,
+            layouts: (state.layout, layout),
+            src: state.available(),
+            dst: access,
+        });
+        *state = State {
+            layout,
+            access,
+            written: write,
+        };
+    }
+
+    fn memory(&mut self, state: &mut State, access: Access, write: bool) {
+        // Nothing in the frame has used it yet.
+        if state.access.0.is_empty() && !state.written {
+            *state = State {
+                layout: vk::ImageLayout::UNDEFINED,
+                access,
+                written: write,
+            };
+            return;
+        }
+        if !state.written && !write {
+            state.access.0 |= access.0;
+            state.access.1 |= access.1;
+            return;
+        }
+        let (src, dst) = self.memory.get_or_insert((NO_ACCESS, NO_ACCESS));
+        let available = state.available();
+        src.0 |= available.0;
+        src.1 |= available.1;
+        dst.0 |= access.0;
+        dst.1 |= access.1;
+        *state = State {
+            layout: vk::ImageLayout::UNDEFINED,
+            access,
+            written: write,
+        };
+    }
+
+    fn is_empty(&self) -> bool {
+        self.memory.is_none() && self.images.is_empty()
+    }
+
+    unsafe fn record(&self, device: &Device, command_buffer: vk::CommandBuffer, sync2: bool) {
+        if self.is_empty() {
+            return;
+        }
+        if sync2 {
+            self.record2(device, command_buffer);
+            return;
+        }
+        let mut src_stage = vk::PipelineStageFlags::empty();
+        let mut dst_stage = vk::PipelineStageFlags::empty();
+        for (src, dst) in self.memory.into_iter().chain(
+            self.images
+                .iter()
+                .map(|transition| (transition.src, transition.dst)),
+        ) {
+            src_stage |= src.0;
+            dst_stage |= dst.0;
+        }
+        let or = |stage: vk::PipelineStageFlags, default| {
+            if stage.is_empty() {
+                default
+            } else {
+                stage
+            }
+        };
+        let memory: Vec<vk::MemoryBarrier> = self
+            .memory
+            .iter()
+            .map(|&(src, dst)| {
+                vk::MemoryBarrier::builder()
+                    .src_access_mask(src.1)
+                    .dst_access_mask(dst.1)
+                    .build()
+            })
+            .collect();
+        let images: Vec<vk::ImageMemoryBarrier> = self
+            .images
+            .iter()
+            .map(|transition| {
+                vk::ImageMemoryBarrier::builder()
+                    .src_access_mask(transition.src.1)
+                    .dst_access_mask(transition.dst.1)
+                    .old_layout(transition.layouts.0)
+                    .new_layout(transition.layouts.1)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(transition.image)
+                    .subresource_range(subresource_range(transition.aspect))
+                    .build()
+            })
+            .collect();
+        device.cmd_pipeline_barrier(
+            command_buffer,
+            or(src_stage, vk::PipelineStageFlags::TOP_OF_PIPE),
+            or(dst_stage, vk::PipelineStageFlags::BOTTOM_OF_PIPE),
+            vk::DependencyFlags::empty(),
+            &memory,
+            &[],
+            &images,
+        );
+    }
+
+    // With synchronization2 every barrier carries its own stages, so images only
+    // wait for what touched them.
+    unsafe fn record2(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        let memory: Vec<vk::MemoryBarrier2> = self
+            .memory
+            .iter()
+            .map(|&(src, dst)| {
+                vk::MemoryBarrier2::builder()
+                    .src_stage_mask(stage2(src.0))
+                    .src_access_mask(access2(src.1))
+                    .dst_stage_mask(stage2(dst.0))
+                    .dst_access_mask(access2(dst.1))
+                    .build()
+            })
+            .collect();
+        let images: Vec<vk::ImageMemoryBarrier2> = self
+            .images
+            .iter()
+            .map(|transition| {
+                vk::ImageMemoryBarrier2::builder()
+                    .src_stage_mask(stage2(transition.src.0))
+                    .src_access_mask(access2(transition.src.1))
+                    .dst_stage_mask(stage2(transition.dst.0))
+                    .dst_access_mask(access2(transition.dst.1))
+                    .old_layout(transition.layouts.0)
+                    .new_layout(transition.layouts.1)
+                    .src_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .dst_queue_family_index(vk::QUEUE_FAMILY_IGNORED)
+                    .image(transition.image)
+                    .subresource_range(subresource_range(transition.aspect))
+                    .build()
+            })
+            .collect();
+        device.cmd_pipeline_barrier2(
+            command_buffer,
+            &vk::DependencyInfo::builder()
+                .memory_barriers(&memory)
+                .image_memory_barriers(&images),
+        );
+    }
+}
+
+// The synchronization2 flags start with the original ones, bit for bit. An empty
+// mask becomes NONE, which is what an empty mask means there.
+fn stage2(stage: vk::PipelineStageFlags) -> vk::PipelineStageFlags2 {
+    vk::PipelineStageFlags2::from_raw(stage.as_raw() as u64)
+}
+
+fn access2(access: vk::AccessFlags) -> vk::AccessFlags2 {
+    vk::AccessFlags2::from_raw(access.as_raw() as u64)
+}
+
+fn subresource_range(aspect: vk::ImageAspectFlags) -> vk::ImageSubresourceRange {
+    vk::ImageSubresourceRange {
+        aspect_mask: aspect,
+        base_mip_level: 0,
+        level_count: 1,
+        base_array_layer: 0,
+        layer_count: 1,
+    }
+}
+
+struct RenderInfo {
+    extent: vk::Extent2D,
+    begin: Begin,
+}
+
+// How a raster pass starts drawing into its attachments.
+enum Begin {
+    RenderPass {
+        render_pass: vk::RenderPass,
+        framebuffer: vk::Framebuffer,
+        clear_values: Vec<vk::ClearValue>,
+    },
+    // Dynamic rendering, which takes the attachments themselves.
+    Rendering {
+        colors: Vec<vk::RenderingAttachmentInfo>,
+        depth: Option<vk::RenderingAttachmentInfo>,
+    },
+}
+
+impl RenderInfo {
+    unsafe fn begin(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        let area = vk::Rect2D {
+            offset: vk::Offset2D { x: 0, y: 0 },
+            extent: self.extent,
+        };
+        match &self.begin {
+            Begin::RenderPass {
+                render_pass,
+                framebuffer,
+                clear_values,
+            } => device.cmd_begin_render_pass(
+                command_buffer,
+                &vk::RenderPassBeginInfo::builder()
+                    .render_pass(*render_pass)
+                    .framebuffer(*framebuffer)
+                    .render_area(area)
+                    .clear_values(clear_values),
+                vk::SubpassContents::INLINE,
+            ),
+            Begin::Rendering { colors, depth } => {
+                let mut info = vk::RenderingInfo::builder()
+                    .render_area(area)
+                    .layer_count(1)
+                    .color_attachments(colors);
+                if let Some(depth) = depth.as_ref() {
+                    info = info.depth_attachment(depth);
+                }
+                device.cmd_begin_rendering(command_buffer, &info);
+            }
+        }
+        device.cmd_set_viewport(
+            command_buffer,
+            0,
+            &[vk::Viewport {
+                x: 0.0,
+                y: 0.0,
+                width: self.extent.width as f32,
+                height: self.extent.height as f32,
+                min_depth: 0.0,
+                max_depth: 1.0,
+            }],
+        );
+        device.cmd_set_scissor(command_buffer, 0, &[area]);
+    }
+
+    unsafe fn end(&self, device: &Device, command_buffer: vk::CommandBuffer) {
+        match self.begin {
+            Begin::RenderPass { .. } => device.cmd_end_render_pass(command_buffer),
+            Begin::Rendering { .. } => device.cmd_end_rendering(command_buffer),
+        }
+    }
+}
+
+struct Step<C> {
+    pass: usize,
+    barrier: Barrier,
+    render: Option<RenderInfo>,
+    record: Option<Record<C>>,
+}
+
+// A graph ready to record, with the images it uses in place.
+pub struct CompiledGraph<C> {
+    graph: RenderGraph<C>,
+    live: Vec<bool>,
+    // Cached image each resource was given, None for imported and unused ones.
+    slots: Vec<Option<usize>>,
+    views: Vec<vk::ImageView>,
+    steps: Vec<Step<C>>,
+    // Into the layouts and accesses that follow the frame.
+    finish: Barrier,
+    // Begins attachments with dynamic rendering and records synchronization2
+    // barriers.
+    dynamic_rendering: bool,
+}
+
+impl<C> CompiledGraph<C> {
+    // Records the passes that weren't culled, each between its barriers, a debug
+    // label and a GPU timer scope.
+    pub unsafe fn execute(
+        self,
+        context: &C,
+        device: &Device,
+        command_buffer: vk::CommandBuffer,
+        mut timer: Option<&mut GpuTimer>,
+    ) {
+        for step in self.steps {
+            let name = self.graph.passes[step.pass].name;
+            step.barrier
+                .record(device, command_buffer, self.dynamic_rendering);
+            let color = if step.render.is_some() {
+                debug::PASS_LABEL
+            } else {
+                debug::COMPUTE_LABEL
+            };
+            debug::begin_label(command_buffer, name, color);
+            let token = timer
+                .as_mut()
+                .and_then(|timer| timer.begin(device, command_buffer, name));
+            let extent = step
+                .render
+                .as_ref()
+                .map_or(vk::Extent2D::default(), |render| render.extent);
+            if let Some(render) = step.render.as_ref() {
+                render.begin(device, command_buffer);
+            }
+            if let Some(record) = step.record {
+                record(
+                    context,
+                    &PassContext {
+                        device,
+                        command_buffer,
+                        extent,
+                        views: &self.views,
+                    },
+                );
+            }
+            if let Some(render) = step.render.as_ref() {
+                render.end(device, command_buffer);
+            }
+            if let Some(timer) = timer.as_deref() {
+                timer.end(device, command_buffer, token);
+            }
+            debug::end_label(command_buffer);
+        }
+        self.finish
+            .record(device, command_buffer, self.dynamic_rendering);
+    }
+
+    // The graph in graphviz's dot language: passes as boxes in order, culled ones
+    // dashed, and images and buffers between them. Images the frame created show
+    // which cached image they were given.
+    pub fn dot(&self) -> String {
+        let graph = &self.graph;
+        let mut dot = String::from("digraph \"render graph\" {\n\trankdir=LR;\n");
+        dot.push_str("\tnode [fontname=\"sans-serif\", fontsize=10];\n");
+        dot.push_str("\tedge [fontname=\"sans-serif\", fontsize=8];\n");
+        for (index, pass) in graph.passes.iter().enumerate() {
+            let style = if self.live[index] {
+                "style=filled, fillcolor=\"#cfe0f5\""
+            } else {
+                "style=dashed, fontcolor=gray50, color=gray50"
+            };
+            let _ = writeln!(
+                dot,
+                "\tp{} [label=\"{}. {}\", shape=box, {}];",
+                index, index, pass.name, style
+            );
+        }
+        for (index, image) in graph.images.iter().enumerate() {
+            let desc = image.desc;
+            let origin = match (image.imported.is_some(), self.slots[index]) {
+                (true, _) => "imported".to_string(),
+                (false, Some(slot)) => format!("image {}", slot),
+                (false, None) => "unused".to_string(),
+            };
+            let samples = if desc.samples == vk::SampleCountFlags::TYPE_1 {
+                String::new()
+            } else {
+                format!(" {}x", desc.samples.as_raw())
+            };
+            let _ = writeln!(
+                dot,
+                "\ti{} [label=\"{}\\n{}x{} {:?}{}\\n{}\", shape=ellipse{}];",
+                index,
+                image.name,
+                desc.extent.width,
+                desc.extent.height,
+                desc.format,
+                samples,
+                origin,
+                if image.imported.is_some() {
+                    ", style=bold"
+                } else {
+                    ""
+                }
+            );
+        }
+        for (index, buffer) in graph.buffers.iter().enumerate() {
+            let _ = writeln!(
+                dot,
+                "\tb{} [label=\"{}\", shape=cylinder, style=bold];",
+                index, buffer.name
+            );
+        }
+        for (index, pass) in graph.passes.iter().enumerate() {
+            for (image, usage) in pass.image_uses() {
+                if usage.reads() {
+                    let _ = writeln!(
+                        dot,
+                        "\ti{} -> p{} [label=\"{}\"];",
+                        image.0,
+                        index,
+                        usage.label()
+                    );
+                }
+                if usage.writes() {
+                    let _ = writeln!(
+                        dot,
+                        "\tp{} -> i{} [label=\"{}\"];",
+                        index,
+                        image.0,
+                        usage.label()
+                    );
+                }
+            }
+            for &(buffer, _, write) in pass.buffers.iter() {
+                if write {
+                    let _ = writeln!(dot, "\tp{} -> b{};", index, buffer.0);
+                } else {
+                    let _ = writeln!(dot, "\tb{} -> p{};", buffer.0, index);
+                }
+            }
+        }
+        dot.push_str("}\n");
+        dot
+    }
+}
+
+struct CachedImage {
+    desc: ImageDesc,
+    usage: vk::ImageUsageFlags,
+    image: vk::Image,
+    view: vk::ImageView,
+    allocation: Option<Allocation>,
+    last_used: u64,
+    // Given to a resource still alive at the point being placed.
+    taken: bool,
+}
+
+// What the graph keeps from frame to frame: images for the resources frames
+// create, and without dynamic rendering, render passes and framebuffers for the
+// attachments they use.
+pub struct RenderGraphCache {
+    dynamic_rendering: bool,
+    images: Vec<CachedImage>,
+    render_passes: HashMap<RenderPassKey, vk::RenderPass>,
+    framebuffers: HashMap<FramebufferKey, vk::Framebuffer>,
+    frame: u64,
+}
+
+impl RenderGraphCache {
+    // Dynamic rendering needs the device's dynamicRendering and synchronization2
+    // features enabled.
+    pub fn new(dynamic_rendering: bool) -> Self {
+        RenderGraphCache {
+            dynamic_rendering,
+            images: Vec::new(),
+            render_passes: HashMap::new(),
+            framebuffers: HashMap::new(),
+            frame: 0,
+        }
+    }
+
+    // What pipelines for passes drawing into `layout` are built for. `name` labels
+    // the compatible render pass, if one gets created.
+    pub fn pipeline_target(
+        &mut self,
+        device: &Device,
+        layout: &PassLayout,
+        name: &str,
+    ) -> EngineResult<PipelineTarget> {
+        if self.dynamic_rendering {
+            return Ok(PipelineTarget::Formats {
+                colors: layout.colors.clone(),
+                depth: layout.depth,
+            });
+        }
+        let render_pass = self.render_pass(device, &RenderPassKey::compatible(layout), name)?;
+        Ok(PipelineTarget::RenderPass(render_pass))
+    }
+
+    // Frees the images no frame has used for a while, and the framebuffers using
+    // them.
+    fn begin_frame(&mut self, device: &Device, allocator: &mut Allocator) {
+        self.frame += 1;
+        let frame = self.frame;
+        let (stale, kept): (Vec<CachedImage>, Vec<CachedImage>) = std::mem::take(&mut self.images)
+            .into_iter()
+            .partition(|image| image.last_used + KEEP_FRAMES < frame);
+        self.images = kept;
+        for mut image in stale {
+            self.framebuffers.retain(|key, &mut framebuffer| {
+                let uses = key.views.contains(&image.view);
+                if uses {
+                    unsafe { device.destroy_framebuffer(framebuffer, None) };
+                }
+                !uses
+            });
+            destroy_image(device, allocator, &mut image);
+        }
+    }
+
+    fn acquire(
+        &mut self,
+        name: &str,
+        desc: ImageDesc,
+        usage: vk::ImageUsageFlags,
+        create: impl FnOnce(&str, ImageDesc, vk::ImageUsageFlags) -> EngineResult<CachedImage>,
+    ) -> EngineResult<usize> {
+        let frame = self.frame;
+        let free = self
+            .images
+            .iter()
+            .position(|image| !image.taken && image.desc == desc && image.usage == usage);
+        let slot = match free {
+            Some(slot) => slot,
+            None => {
+                let image = create(name, desc, usage)?;
+                self.images.push(image);
+                self.images.len() - 1
+            }
+        };
+        let image = &mut self.images[slot];
+        image.taken = true;
+        image.last_used = frame;
+        Ok(slot)
+    }
+
+    fn release(&mut self, slot: usize) {
+        self.images[slot].taken = false;
+    }
+
+    // Passes with the same key share a render pass, named after the first of them.
+    fn render_pass(
+        &mut self,
+        device: &Device,
+        key: &RenderPassKey,
+        name: &str,
+    ) -> EngineResult<vk::RenderPass> {
+        if let Some(&render_pass) = self.render_passes.get(key) {
+            return Ok(render_pass);
+        }
+        let render_pass = unsafe { create_render_pass(device, key)? };
+        debug::set_name(device, render_pass, name);
+        self.render_passes.insert(key.clone(), render_pass);
+        Ok(render_pass)
+    }
+
+    fn framebuffer(
+        &mut self,
+        device: &Device,
+        key: FramebufferKey,
+        name: &str,
+    ) -> EngineResult<vk::Framebuffer> {
+        if let Some(&framebuffer) = self.framebuffers.get(&key) {
+            return Ok(framebuffer);
+        }
+        let framebuffer = unsafe {
+            device.create_framebuffer(
+                &vk::FramebufferCreateInfo::builder()
+                    .render_pass(key.render_pass)
+                    .attachments(&key.views)
+                    .width(key.extent.width)
+                    .height(key.extent.height)
+                    .layers(1),
+                None,
+            )?
+        };
+        debug::set_name(device, framebuffer, name);
+        self.framebuffers.insert(key, framebuffer);
+        Ok(framebuffer)
+    }
+
+    // For when imported views go away, as with the swapchain's, since a new view
+    // could get the handle of an old one.
+    pub fn release_framebuffers(&mut self, device: &Device) {
+        for (_, framebuffer) in self.framebuffers.drain() {
+            unsafe { device.destroy_framebuffer(framebuffer, None) };
+        }
+    }
+
+    pub fn destroy(&mut self, device: &Device, allocator: &mut Allocator) {
+        self.release_framebuffers(device);
+        for (_, render_pass) in self.render_passes.drain() {
+            unsafe { device.destroy_render_pass(render_pass, None) };
+        }
+        for mut image in self.images.drain(..) {
+            destroy_image(device, allocator, &mut image);
+        }
+    }
+}
+
+// Attachments go colours first, then the resolve targets, then depth. Each stays in
+// its attachment layout throughout; the graph's barriers move images in and out.
+unsafe fn create_render_pass(device: &Device, key: &RenderPassKey) -> EngineResult<vk::RenderPass> {
+    let description = |attachment: &AttachmentKey, layout| vk::AttachmentDescription {
+        format: attachment.format,
+        samples: attachment.samples,
+        load_op: attachment.load,
+        store_op: attachment.store,
+        stencil_load_op: vk::AttachmentLoadOp::DONT_CARE,
+        stencil_store_op: vk::AttachmentStoreOp::DONT_CARE,
+        initial_layout: layout,
+        final_layout: layout,
+        ..Default::default()
+    };
+    let color_layout = vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL;
+    let depth_layout = vk::ImageLayout::DEPTH_STENCIL_ATTACHMENT_OPTIMAL;
+    let mut attachments: Vec<vk::AttachmentDescription> = key
+        .colors
+        .iter()
+        .map(|color| description(color, color_layout))
+        .collect();
+    let color_refs: Vec<vk::AttachmentReference> = (0..key.colors.len())
+        .map(|index| vk::AttachmentReference {
+            attachment: index as u32,
+            layout: color_layout,
+        })
+        .collect();
+    let mut resolve_refs = Vec::new();
+    for resolve in key.resolves.iter() {
+        let attachment = match resolve {
+            Some(resolve) => {
+                attachments.push(description(resolve, color_layout));
+                attachments.len() as u32 - 1
+            }
+            None => vk::ATTACHMENT_UNUSED,
+        };
+        resolve_refs.push(vk::AttachmentReference {
+            attachment,
+            layout: color_layout,
+        });
+    }
+    let depth_ref = key.depth.map(|depth| {
+        attachments.push(description(&depth, depth_layout));
+        vk::AttachmentReference {
+            attachment: attachments.len() as u32 - 1,
+            layout: depth_layout,
+        }
+    });
+
+    let mut subpass = vk::SubpassDescription::builder()
+        .pipeline_bind_point(vk::PipelineBindPoint::GRAPHICS)
+        .color_attachments(&color_refs);
+    if key.resolves.iter().any(Option::is_some) {
+        subpass = subpass.resolve_attachments(&resolve_refs);
+    }
+    if let Some(depth_ref) = depth_ref.as_ref() {
+        subpass = subpass.depth_stencil_attachment(depth_ref);
+    }
+    let subpasses = [subpass.build()];
+    let render_pass = device.create_render_pass(
+        &vk::RenderPassCreateInfo::builder()
+            .attachments(&attachments)
+            .subpasses(&subpasses),
+        None,
+    )?;
+    Ok(render_pass)
+}
+
+unsafe fn create_image(
+    device: &Device,
+    allocator: &mut Allocator,
+    name: &str,
+    desc: ImageDesc,
+    usage: vk::ImageUsageFlags,
+) -> EngineResult<CachedImage> {
+    // Images only ever drawn into within a pass can stay in tile memory where the
+    // device allows.
+    let attachment_only = (vk::ImageUsageFlags::COLOR_ATTACHMENT
+        | vk::ImageUsageFlags::DEPTH_STENCIL_ATTACHMENT)
+        .contains(usage);
+    let image_usage = if attachment_only {
+        usage | vk::ImageUsageFlags::TRANSIENT_ATTACHMENT
+    } else {
+        usage
+    };
+    let image = device.create_image(
+        &vk::ImageCreateInfo::builder()
+            .image_type(vk::ImageType::TYPE_2D)
+            .format(desc.format)
+            .extent(vk::Extent3D {
+                width: desc.extent.width,
+                height: desc.extent.height,
+                depth: 1,
+            })
+            .mip_levels(1)
+            .array_layers(1)
+            .samples(desc.samples)
+            .tiling(vk::ImageTiling::OPTIMAL)
+            .usage(image_usage)
+            .sharing_mode(vk::SharingMode::EXCLUSIVE),
+        None,
+    )?;
+    let requirements = device.get_image_memory_requirements(image);
+    let allocation = allocator.allocate(&AllocationCreateDesc {
+        name,
+        requirements,
+        location: gpu_allocator::MemoryLocation::GpuOnly,
+        linear: false,
+    })?;
+    device.bind_image_memory(image, allocation.memory(), allocation.offset())?;
+    let view = device.create_image_view(
+        &vk::ImageViewCreateInfo::builder()
+            .view_type(vk::ImageViewType::TYPE_2D)
+            .format(desc.format)
+            .subresource_range(vk::ImageSubresourceRange {
+                aspect_mask: aspect(desc.format),
+                base_mip_level: 0,
+                level_count: 1,
+                base_array_layer: 0,
+                layer_count: 1,
+            })
+            .image(image),
+        None,
+    )?;
+    debug::set_name(device, image, name);
+    debug::set_name(device, view, name);
+    Ok(CachedImage {
+        desc,
+        usage,
+        image,
+        view,
+        allocation: Some(allocation),
+        last_used: 0,
+        taken: false,
+    })
+}
+
+fn destroy_image(device: &Device, allocator: &mut Allocator, image: &mut CachedImage) {
+    unsafe {
+        device.destroy_image_view(image.view, None);
+        device.destroy_image(image.image, None);
+    }
+    if let Some(allocation) = image.allocation.take() {
+        let _ = allocator.free(allocation);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ash::vk::Handle;
+
+    // Passes record nothing, so the context can be anything.
+    type Graph = RenderGraph<()>;
+
+    const COLOR: vk::Format = vk::Format::R16G16B16A16_SFLOAT;
+    const DEPTH: vk::Format = vk::Format::D32_SFLOAT;
+    const CLEAR: Load = Load::Clear([0.0; 4]);
+
+    fn desc(format: vk::Format) -> ImageDesc {
+        ImageDesc {
+            format,
+            extent: vk::Extent2D {
+                width: 320,
+                height: 200,
+            },
+            samples: vk::SampleCountFlags::TYPE_1,
+        }
+    }
+
+    fn swapchain(graph: &mut Graph) -> ImageId {
+        graph.import_image(
+            "swapchain",
+            ImportedImage {
+                image: vk::Image::from_raw(0x1000),
+                view: vk::ImageView::from_raw(0x1000),
+                desc: desc(vk::Format::B8G8R8A8_SRGB),
+                layout: vk::ImageLayout::UNDEFINED,
+                access: NO_ACCESS,
+                final_layout: Some(vk::ImageLayout::PRESENT_SRC_KHR),
+            },
+        )
+    }
+
+    // Compiles without a device, handing out made up handles for new images.
+    fn plan(graph: Graph, cache: &mut RenderGraphCache) -> CompiledGraph<()> {
+        let mut next = 0;
+        graph
+            .plan(cache, |_, desc, usage| {
+                next += 1;
+                Ok(CachedImage {
+                    desc,
+                    usage,
+                    image: vk::Image::from_raw(next),
+                    view: vk::ImageView::from_raw(next),
+                    allocation: None,
+                    last_used: 0,
+                    taken: false,
+                })
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn passes_nothing_reads_are_culled_with_what_feeds_them() {
+        let mut graph = Graph::default();
+        let output = swapchain(&mut graph);
+        let shadow = graph.create_image("shadow", desc(DEPTH));
+        let feed = graph.create_image("feed", desc(COLOR));
+        let debug = graph.create_image("debug", desc(COLOR));
+        let scene = graph.create_image("scene", desc(COLOR));
+        let readback = graph.import_buffer("readback", None);
+
+        graph.pass("shadow").depth(shadow, CLEAR).record(|_, _| {});
+        graph.pass("feed").color(feed, CLEAR).record(|_, _| {});
+        graph
+            .pass("debug")
+            .sample(feed)
+            .color(debug, CLEAR)
+            .record(|_, _| {});
+        // Cleared again before anything reads it, so this one is wasted.
+        graph
+            .pass("overwritten")
+            .color(scene, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("scene")
+            .sample(shadow)
+            .color(scene, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("present")
+            .sample(scene)
+            .color(output, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("readback")
+            .write_buffer(
+                readback,
+                (
+                    vk::PipelineStageFlags::TRANSFER,
+                    vk::AccessFlags::TRANSFER_WRITE,
+                ),
+            )
+            .record(|_, _| {});
+
+        assert_eq!(
+            graph.live_passes(),
+            [true, false, false, false, true, true, true]
+        );
+    }
+
+    #[test]
+    fn attachments_are_stored_only_when_used_after() {
+        let mut graph = Graph::default();
+        let output = swapchain(&mut graph);
+        let scene = graph.create_image("scene", desc(COLOR));
+        let depth = graph.create_image("depth", desc(DEPTH));
+        let debug = graph.create_image("debug", desc(COLOR));
+        let overlay = graph.create_image("overlay", desc(COLOR));
+
+        graph
+            .pass("scene")
+            .color(scene, CLEAR)
+            .depth(depth, CLEAR)
+            .record(|_, _| {});
+        // Culled, so its read of the depth doesn't count.
+        graph
+            .pass("debug")
+            .sample(depth)
+            .color(debug, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("overlay")
+            .color(overlay, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("more overlay")
+            .color(overlay, Load::Keep)
+            .record(|_, _| {});
+        graph
+            .pass("present")
+            .sample(scene)
+            .sample(overlay)
+            .color(output, Load::DontCare)
+            .record(|_, _| {});
+
+        let live = graph.live_passes();
+        assert_eq!(live, [true, false, true, true, true]);
+        let store = |pass, image| graph.store_op(&live, pass, image);
+        assert_eq!(store(0, scene), vk::AttachmentStoreOp::STORE);
+        assert_eq!(store(0, depth), vk::AttachmentStoreOp::DONT_CARE);
+        assert_eq!(store(2, overlay), vk::AttachmentStoreOp::STORE);
+        assert_eq!(store(3, overlay), vk::AttachmentStoreOp::STORE);
+        assert_eq!(store(4, output), vk::AttachmentStoreOp::STORE);
+    }
+
+    #[test]
+    fn images_share_an_allocation_only_when_their_lifetimes_do_not_overlap() {
+        let mut graph = Graph::default();
+        let output = swapchain(&mut graph);
+        let a = graph.create_image("a", desc(COLOR));
+        let b = graph.create_image("b", desc(COLOR));
+        let c = graph.create_image("c", desc(COLOR));
+        // Same lifetime as `c` would share with, but a different description.
+        let depth = graph.create_image("depth", desc(DEPTH));
+
+        graph.pass("a").color(a, CLEAR).record(|_, _| {});
+        graph.pass("b").sample(a).color(b, CLEAR).record(|_, _| {});
+        graph
+            .pass("c")
+            .sample(b)
+            .color(c, CLEAR)
+            .depth(depth, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("present")
+            .sample(c)
+            .color(output, CLEAR)
+            .record(|_, _| {});
+
+        let mut cache = RenderGraphCache::new(true);
+        let compiled = plan(graph, &mut cache);
+        let slot = |image: ImageId| compiled.slots[image.0].unwrap();
+        // `a` ends where `b` starts, and `b` where `c` does.
+        assert_ne!(slot(a), slot(b));
+        assert_ne!(slot(b), slot(c));
+        assert_eq!(slot(a), slot(c));
+        assert_ne!(slot(depth), slot(a));
+        assert_ne!(slot(depth), slot(b));
+        assert_eq!(compiled.slots[output.0], None);
+        assert_eq!(cache.images.len(), 3);
+        assert!(cache.images.iter().all(|image| !image.taken));
+    }
+
+    #[test]
+    fn sampling_after_a_write_transitions_and_waits_for_the_write() {
+        let mut graph = Graph::default();
+        let output = swapchain(&mut graph);
+        let scene = graph.create_image("scene", desc(COLOR));
+        graph.pass("scene").color(scene, CLEAR).record(|_, _| {});
+        graph
+            .pass("post")
+            .sample(scene)
+            .color(output, CLEAR)
+            .record(|_, _| {});
+        graph
+            .pass("overlay")
+            .sample(scene)
+            .color(output, Load::Keep)
+            .record(|_, _| {});
+
+        let mut cache = RenderGraphCache::new(true);
+        let compiled = plan(graph, &mut cache);
+        let scene = cache.images[compiled.slots[scene.0].unwrap()].image;
+        let transitions = |step: usize| -> Vec<&ImageTransition> {
+            compiled.steps[step]
+                .barrier
+                .images
+                .iter()
+                .filter(|transition| transition.image == scene)
+                .collect()
+        };
+        let attachment = (
+            vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+            vk::AccessFlags::COLOR_ATTACHMENT_WRITE,
+        );
+
+        let written = transitions(0);
+        assert_eq!(written.len(), 1);
+        assert_eq!(
+            written[0].layouts,
+            (
+                vk::ImageLayout::UNDEFINED,
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL
+            )
+        );
+        assert_eq!(written[0].src, NO_ACCESS);
+        assert_eq!(written[0].dst, attachment);
+
+        let sampled = transitions(1);
+        assert_eq!(sampled.len(), 1);
+        assert_eq!(
+            sampled[0].layouts,
+            (
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::SHADER_READ_ONLY_OPTIMAL
+            )
+        );
+        assert_eq!(sampled[0].src, attachment);
+        assert_eq!(
+            sampled[0].dst,
+            (
+                vk::PipelineStageFlags::FRAGMENT_SHADER,
+                vk::AccessFlags::SHADER_READ
+            )
+        );
+        assert_eq!(sampled[0].aspect, vk::ImageAspectFlags::COLOR);
+
+        // A second read in the same layout needs no barrier of its own.
+        assert!(transitions(2).is_empty());
+
+        // The swapchain image ends up ready to present.
+        let finish = &compiled.finish.images;
+        assert_eq!(finish.len(), 1);
+        assert_eq!(
+            finish[0].layouts,
+            (
+                vk::ImageLayout::COLOR_ATTACHMENT_OPTIMAL,
+                vk::ImageLayout::PRESENT_SRC_KHR
+            )
+        );
+        assert_eq!(
+            finish[0].src,
+            (
+                vk::PipelineStageFlags::COLOR_ATTACHMENT_OUTPUT,
+                vk::AccessFlags::COLOR_ATTACHMENT_READ | vk::AccessFlags::COLOR_ATTACHMENT_WRITE
+            )
+        );
+    }
+
+    #[test]
+    fn dot_shows_every_pass_and_edge() {
+        let mut graph = Graph::default();
+        let output = swapchain(&mut graph);
+        let scene = graph.create_image("scene", desc(COLOR));
+        let debug = graph.create_image("debug", desc(COLOR));
+        let counts = graph.import_buffer("counts", None);
+        let access = (
+            vk::PipelineStageFlags::COMPUTE_SHADER,
+            vk::AccessFlags::SHADER_READ | vk::AccessFlags::SHADER_WRITE,
+        );
+        graph
+            .pass("count")
+            .write_buffer(counts, access)
+            .record(|_, _| {});
+        graph
+            .pass("scene")
+            .read_buffer(counts, access)
+            .color(scene, CLEAR)
+            .record(|_, _| {});
+        graph.pass("debug").color(debug, CLEAR).record(|_, _| {});
+        graph
+            .pass("present")
+            .sample(scene)
+            .color(output, Load::Keep)
+            .record(|_, _| {});
+
+        let mut cache = RenderGraphCache::new(false);
+        let dot = plan(graph, &mut cache).dot();
+        for line in [
+            "p0 [label=\"0. count\", shape=box, style=filled",
+            "p1 [label=\"1. scene\", shape=box, style=filled",
+            "p2 [label=\"2. debug\", shape=box, style=dashed",
+            "p3 [label=\"3. present\", shape=box, style=filled",
+            "i0 [label=\"swapchain\\n320x200 B8G8R8A8_SRGB\\nimported\"",
+            "i1 [label=\"scene\\n320x200 R16G16B16A16_SFLOAT\\nimage 0\"",
+            "i2 [label=\"debug\\n320x200 R16G16B16A16_SFLOAT\\nunused\"",
+            "b0 [label=\"counts\", shape=cylinder",
+            "p0 -> b0;",
+            "b0 -> p1;",
+            "p1 -> i1 [label=\"colour\"];",
+            "p2 -> i2 [label=\"colour\"];",
+            "i1 -> p3 [label=\"sampled\"];",
+            "i0 -> p3 [label=\"colour\"];",
+            "p3 -> i0 [label=\"colour\"];",
+        ] {
+            assert!(dot.contains(line), "{} missing from\n{}", line, dot);
+        }
+        assert_eq!(dot.matches(" -> ").count(), 7);
+        assert!(dot.starts_with("digraph") && dot.ends_with("}\n"));
+    }
+}
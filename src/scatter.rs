@@ -0,0 +1,156 @@
+// Turns the simulation state into instances to draw: trees wherever producers are
+// doing well, and a herd for every animal species in every region, one instance per
+// animal. Placement only depends on its inputs, so nothing flickers between calls.
+
+use std::f32::consts::{FRAC_PI_2, TAU};
+
+use cgmath::{Matrix4, Rad, Vector3, Vector4};
+
+use crate::ecosystem::{Ecosystem, Trophic};
+use crate::mesh::InstanceData;
+use crate::noise::splitmix64;
+use crate::simulation::{REGIONS_X, REGIONS_Z};
+use crate::terrain::Terrain;
+
+// Distance between candidate tree positions.
+const TREE_SPACING: f32 = 3.0;
+// Chance of a tree at a candidate position when the producers are at capacity.
+const TREE_DENSITY: f32 = 0.6;
+const MAX_HERD: usize = 400;
+const HERD_RADIUS: f32 = 12.0;
+
+pub fn vegetation(terrain: &Terrain, ecosystem: &Ecosystem) -> Vec<InstanceData> {
+    let cover = producer_cover(ecosystem);
+    let field = &terrain.heightfield;
+    let extent = field.extent();
+    let steps = (extent / TREE_SPACING) as usize;
+    let mut seed = terrain.settings.seed;
+    let mut trees = Vec::new();
+
+    for j in 0..steps {
+        for i in 0..steps {
+            // Every candidate draws the same numbers whether or not it is used, so a
+            // tree never moves because a neighbour appeared or disappeared.
+            let (jitter_x, jitter_z) = (random(&mut seed), random(&mut seed));
+            let (chance, yaw, size) = (random(&mut seed), random(&mut seed), random(&mut seed));
+
+            let x = (i as f32 + jitter_x) * TREE_SPACING;
+            let z = (j as f32 + jitter_z) * TREE_SPACING;
+            let y = field.sample(x, z);
+            let height = y / terrain.settings.height_scale;
+            let nearest = |v: f32| (v / field.cell_size).round() as usize;
+            let slope = field.normal(nearest(x), nearest(z)).y;
+            if !(0.08..0.7).contains(&height) || slope < 0.85 {
+                continue;
+            }
+            if chance >= cover[region_at(extent, x, z)] * TREE_DENSITY {
+                continue;
+            }
+
+            let transform = Matrix4::from_translation(Vector3::new(x, y - 0.2, z))
+                * Matrix4::from_angle_y(Rad(yaw * TAU))
+                * Matrix4::from_scale(0.7 + 0.6 * size);
+            trees.push(InstanceData {
+                transform,
+                tint: Vector4::new(0.85 + 0.3 * size, 1.0, 1.15 - 0.3 * size, 1.0),
+            });
+        }
+    }
+    trees
+}
+
+// `time` is in seconds and moves the herds around their regions.
+pub fn fauna(terrain: &Terrain, ecosystem: &Ecosystem, time: f32) -> Vec<InstanceData> {
+    let field = &terrain.heightfield;
+    let region_width = field.extent() / REGIONS_X as f32;
+    let region_depth = field.extent() / REGIONS_Z as f32;
+    let roam = 0.3 * region_width.min(region_depth);
+    let mut animals = Vec::new();
+
+    for (index, region) in ecosystem.regions.iter().enumerate() {
+        let center_x = ((index % REGIONS_X) as f32 + 0.5) * region_width;
+        let center_z = ((index / REGIONS_X) as f32 + 0.5) * region_depth;
+
+        for (id, species) in ecosystem.species.iter().enumerate() {
+            let scale = match species.trophic {
+                Trophic::Producer => continue,
+                Trophic::Herbivore => 0.5,
+                Trophic::Predator => 0.7,
+            };
+            let count = (region.populations[id] as usize).min(MAX_HERD);
+            let mut seed = name_hash(&species.name) ^ index as u64;
+            let tint = Vector4::new(
+                0.5 + 0.5 * random(&mut seed),
+                0.5 + 0.5 * random(&mut seed),
+                0.5 + 0.5 * random(&mut seed),
+                1.0,
+            );
+
+            // Each herd walks a slow circle around the middle of its region.
+            let phase = random(&mut seed) * TAU;
+            let speed = 0.02 + 0.03 * random(&mut seed);
+            let angle = phase + time * speed;
+            let herd_x = center_x + roam * angle.cos();
+            let herd_z = center_z + roam * angle.sin();
+            let facing = Matrix4::from_angle_y(Rad(-angle - FRAC_PI_2));
+
+            for _ in 0..count {
+                let distance = HERD_RADIUS * random(&mut seed).sqrt();
+                let direction = random(&mut seed) * TAU;
+                let x = herd_x + distance * direction.cos();
+                let z = herd_z + distance * direction.sin();
+                let y = field.sample(x, z) + scale;
+                animals.push(InstanceData {
+                    transform: Matrix4::from_translation(Vector3::new(x, y, z))
+                        * facing
+                        * Matrix4::from_scale(scale),
+                    tint,
+                });
+            }
+        }
+    }
+    animals
+}
+
+// How close each region's producers are to their carrying capacity, from 0 to 1.
+fn producer_cover(ecosystem: &Ecosystem) -> Vec<f32> {
+    ecosystem
+        .regions
+        .iter()
+        .map(|region| {
+            let fill: Vec<f64> = ecosystem
+                .species
+                .iter()
+                .enumerate()
+                .filter(|(_, species)| species.trophic == Trophic::Producer)
+                .map(|(id, species)| {
+                    let capacity = species.carrying_capacity * region.capacity_scale[id];
+                    if capacity > 0.0 {
+                        (region.populations[id] / capacity).min(1.0)
+                    } else {
+                        0.0
+                    }
+                })
+                .collect();
+            (fill.iter().sum::<f64>() / fill.len().max(1) as f64) as f32
+        })
+        .collect()
+}
+
+fn region_at(extent: f32, x: f32, z: f32) -> usize {
+    let rx = ((x / extent * REGIONS_X as f32) as usize).min(REGIONS_X - 1);
+    let rz = ((z / extent * REGIONS_Z as f32) as usize).min(REGIONS_Z - 1);
+    rz * REGIONS_X + rx
+}
+
+// Uniform in [0, 1).
+fn random(seed: &mut u64) -> f32 {
+    (splitmix64(seed) >> 40) as f32 / (1u64 << 24) as f32
+}
+
+// FNV-1a, so every species keeps its look and its herds' paths across runs.
+fn name_hash(name: &str) -> u64 {
+    name.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
@@ -0,0 +1,210 @@
+use crate::definitions::Definitions;
+use crate::ecosystem::{Ecosystem, RateModifier};
+use crate::environment::{Diffuser, Environment, EnvironmentSettings, Layer};
+use crate::profiler;
+
+// Simulated seconds per tick. Everything in the simulation advances in steps of
+// exactly this size, however fast frames are being drawn.
+pub const TICK: f64 = 0.1;
+// Caps the catch-up after a long stall so the simulation can't spiral.
+pub const MAX_TICKS_PER_FRAME: u32 = 10;
+
+pub const REGIONS_X: usize = 4;
+pub const REGIONS_Z: usize = 4;
+
+pub struct Simulation {
+    pub ecosystem: Ecosystem,
+    pub environment: Environment,
+    pub definitions: Definitions,
+    // Normalized terrain height of each region, used to pick its biome.
+    pub region_heights: Vec<f32>,
+    pub paused: bool,
+    accumulator: f64,
+}
+
+impl Simulation {
+    pub fn new(
+        definitions: Definitions,
+        region_heights: Vec<f32>,
+        environment: EnvironmentSettings,
+    ) -> Self {
+        assert_eq!(region_heights.len(), REGIONS_X * REGIONS_Z);
+        let species = definitions.species();
+        let regions = Ecosystem::grid_regions(REGIONS_X, REGIONS_Z, &vec![0.0; species.len()]);
+        let mut simulation = Simulation {
+            ecosystem: Ecosystem::new(species, regions),
+            environment: Environment::new(environment),
+            definitions,
+            region_heights,
+            paused: false,
+            accumulator: 0.0,
+        };
+        simulation.apply_biomes();
+
+        for region in simulation.ecosystem.regions.iter_mut() {
+            for (id, species) in simulation.ecosystem.species.iter().enumerate() {
+                region.populations[id] =
+                    species.carrying_capacity * region.capacity_scale[id] * 0.25;
+            }
+        }
+        simulation
+    }
+
+    // Picks up edited definitions without resetting the populations.
+    pub fn reload(&mut self, definitions: Definitions) {
+        self.ecosystem.replace_species(definitions.species());
+        self.definitions = definitions;
+        self.apply_biomes();
+    }
+
+    fn apply_biomes(&mut self) {
+        let definitions = &self.definitions;
+        for (region, height) in self
+            .ecosystem
+            .regions
+            .iter_mut()
+            .zip(self.region_heights.iter())
+        {
+            let biome = definitions.biome_for_height(*height);
+            for (id, species) in self.ecosystem.species.iter().enumerate() {
+                region.capacity_scale[id] = biome
+                    .and_then(|biome| biome.capacity.get(&species.name))
+                    .copied()
+                    .unwrap_or(1.0);
+            }
+        }
+    }
+
+    // Feeds real elapsed time in and runs however many whole ticks it adds up to.
+    pub fn advance(&mut self, elapsed: f64, diffuser: &mut dyn Diffuser) -> u32 {
+        if self.paused {
+            return 0;
+        }
+        self.accumulator += elapsed;
+        let mut ticks = 0;
+        while self.accumulator >= TICK && ticks < MAX_TICKS_PER_FRAME {
+            self.tick(diffuser);
+            self.accumulator -= TICK;
+            ticks += 1;
+        }
+        // Past the cap the whole ticks still due are dropped, but not the fraction
+        // of one, so when ticks land doesn't depend on how frames are paced.
+        if self.accumulator >= TICK {
+            self.accumulator %= TICK;
+        }
+        ticks
+    }
+
+    pub fn tick(&mut self, diffuser: &mut dyn Diffuser) {
+        {
+            let _scope = profiler::scope("Environment");
+            self.environment.step(TICK as f32, diffuser);
+        }
+        self.apply_environment();
+        let _scope = profiler::scope("Ecosystem");
+        self.ecosystem.step(TICK);
+    }
+
+    // Turns the environment averaged over each region into birth/death modifiers
+    // for the species living there.
+    fn apply_environment(&mut self) {
+        let env = &self.environment;
+        let (width, height) = (env.settings.width, env.settings.height);
+        let rest = env.settings.temperature.rest;
+        for rz in 0..REGIONS_Z {
+            for rx in 0..REGIONS_X {
+                let min = (rx * width / REGIONS_X, rz * height / REGIONS_Z);
+                let max = ((rx + 1) * width / REGIONS_X, (rz + 1) * height / REGIONS_Z);
+                let soil = env.mean(Layer::Soil, min, max) as f64;
+                let water = env.mean(Layer::Water, min, max) as f64;
+                let air = env.mean(Layer::Air, min, max) as f64;
+                let heat = (env.mean(Layer::Temperature, min, max) - rest).abs() as f64;
+
+                let region = rz * REGIONS_X + rx;
+                for (id, species) in self.ecosystem.species.iter().enumerate() {
+                    let s = species.sensitivity;
+                    let stress =
+                        s.soil * soil + s.water * water + s.air * air + s.temperature * heat;
+                    self.ecosystem.modifiers[region][id] = RateModifier::from_stress(stress);
+                }
+            }
+        }
+    }
+
+    pub fn summary(&self) -> String {
+        let eco = &self.ecosystem;
+        let env = &self.environment;
+        let whole_map = ((0, 0), (env.settings.width, env.settings.height));
+        let mut parts: Vec<String> = eco
+            .species
+            .iter()
+            .enumerate()
+            .map(|(id, s)| format!("{} {:.0}", s.name, eco.total_population(id)))
+            .collect();
+        parts.push(format!(
+            "air {:.2}",
+            env.mean(Layer::Air, whole_map.0, whole_map.1)
+        ));
+        parts.join(", ")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::environment::{CpuDiffuser, Emission, PollutionSource};
+
+    fn simulation() -> Simulation {
+        let definitions = Definitions::load().unwrap();
+        let heights = vec![0.3; REGIONS_X * REGIONS_Z];
+        Simulation::new(definitions, heights, EnvironmentSettings::default())
+    }
+
+    #[test]
+    fn a_capped_frame_keeps_the_fraction_of_a_tick() {
+        let mut simulation = simulation();
+        let mut diffuser = CpuDiffuser::default();
+        let ticks = simulation.advance(10.5 * TICK, &mut diffuser);
+        assert_eq!(ticks, MAX_TICKS_PER_FRAME);
+        assert!((simulation.accumulator - 0.5 * TICK).abs() < 1e-9);
+
+        // A long stall drops the backlog but still keeps the fraction.
+        let ticks = simulation.advance(100.25 * TICK, &mut diffuser);
+        assert_eq!(ticks, MAX_TICKS_PER_FRAME);
+        assert!((simulation.accumulator - 0.75 * TICK).abs() < 1e-9);
+    }
+
+    #[test]
+    fn pollution_stresses_only_the_region_it_is_in() {
+        let mut simulation = simulation();
+        let settings = simulation.environment.settings;
+        // The middle of the first region, well inside its cells.
+        let center = |cells: usize| (cells / REGIONS_X / 2) as f32 * settings.cell_size;
+        simulation.environment.add_source(PollutionSource {
+            x: center(settings.width),
+            z: center(settings.height),
+            radius: 2.0 * settings.cell_size,
+            emission: Emission {
+                soil: 50.0,
+                ..Default::default()
+            },
+            remaining: None,
+        });
+        simulation.tick(&mut CpuDiffuser::default());
+
+        let sensitive: Vec<usize> = (0..simulation.ecosystem.species.len())
+            .filter(|&id| simulation.ecosystem.species[id].sensitivity.soil > 0.0)
+            .collect();
+        assert!(!sensitive.is_empty());
+        for (region, modifiers) in simulation.ecosystem.modifiers.iter().enumerate() {
+            for &id in sensitive.iter() {
+                if region == 0 {
+                    assert!(modifiers[id].birth < 1.0);
+                    assert!(modifiers[id].death > 1.0);
+                } else {
+                    assert_eq!(modifiers[id], RateModifier::default());
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,214 @@
+// Textured and flat coloured rectangles in screen space, for HUD icons, bars,
+// panels and maps. Sprites come out of atlases registered up front; over a frame
+// they are queued with a layer, then sorted and batched into as few overlay meshes
+// as the textures allow. Positions are in points, which the overlay's orthographic
+// projection maps onto the window.
+
+use egui::epaint::{ClippedPrimitive, ColorImage, ImageDelta, Mesh, Primitive, TextureId};
+use egui::{pos2, vec2, Color32, Rect, TextureOptions, Vec2};
+
+use crate::overlay::{user_texture_id, OverlayFrame};
+
+// A rectangle of an atlas.
+#[derive(Clone, Copy, Debug)]
+pub struct Sprite {
+    texture: TextureId,
+    uv: Rect,
+    // In texels.
+    pub size: Vec2,
+}
+
+// An image registered with the batch, to cut sprites out of.
+#[derive(Clone, Copy, Debug)]
+pub struct Atlas {
+    texture: TextureId,
+    size: [usize; 2],
+}
+
+impl Atlas {
+    pub fn sprite(&self, min: [usize; 2], size: [usize; 2]) -> Sprite {
+        let scale = vec2(1.0 / self.size[0] as f32, 1.0 / self.size[1] as f32);
+        let min = vec2(min[0] as f32, min[1] as f32) * scale;
+        let extent = vec2(size[0] as f32, size[1] as f32);
+        Sprite {
+            texture: self.texture,
+            uv: Rect::from_min_size(min.to_pos2(), extent * scale),
+            size: extent,
+        }
+    }
+}
+
+struct Quad {
+    layer: i32,
+    texture: TextureId,
+    rect: Rect,
+    uv: Rect,
+    color: Color32,
+}
+
+pub struct SpriteBatch {
+    // A white texel for flat colours.
+    white: Sprite,
+    uploads: Vec<(TextureId, ImageDelta)>,
+    quads: Vec<Quad>,
+}
+
+impl SpriteBatch {
+    pub fn new() -> Self {
+        let mut batch = SpriteBatch {
+            white: Sprite {
+                texture: TextureId::default(),
+                uv: Rect::NOTHING,
+                size: Vec2::ZERO,
+            },
+            uploads: Vec::new(),
+            quads: Vec::new(),
+        };
+        // Sampled at the middle of a 3x3 block so filtering only ever sees white.
+        let white = batch.add_atlas(
+            ColorImage::new([3, 3], Color32::WHITE),
+            TextureOptions::NEAREST,
+        );
+        batch.white = white.sprite([1, 1], [1, 1]);
+        batch
+    }
+
+    // Premultiplied alpha, as egui keeps it. Uploaded with the next frame.
+    pub fn add_atlas(&mut self, image: ColorImage, options: TextureOptions) -> Atlas {
+        let atlas = Atlas {
+            texture: user_texture_id(),
+            size: image.size,
+        };
+        self.uploads
+            .push((atlas.texture, ImageDelta::full(image, options)));
+        atlas
+    }
+
+    // Lower layers are drawn first; within a layer, in the order queued.
+    pub fn sprite(&mut self, sprite: &Sprite, rect: Rect, color: Color32, layer: i32) {
+        self.quads.push(Quad {
+            layer,
+            texture: sprite.texture,
+            rect,
+            uv: sprite.uv,
+            color,
+        });
+    }
+
+    pub fn rect(&mut self, rect: Rect, color: Color32, layer: i32) {
+        let white = self.white;
+        self.sprite(&white, rect, color, layer);
+    }
+
+    // Stretches `sprite` over `rect` keeping its corners as they are: the `border`
+    // texels along each edge are drawn one point per texel, the edges stretch
+    // along their length and the middle both ways.
+    pub fn nine_slice(
+        &mut self,
+        sprite: &Sprite,
+        border: f32,
+        rect: Rect,
+        color: Color32,
+        layer: i32,
+    ) {
+        let border = border.min(sprite.size.x * 0.5).min(sprite.size.y * 0.5);
+        // Small rectangles squash the corners rather than overlap them.
+        let inset = border.min(rect.width() * 0.5).min(rect.height() * 0.5);
+        let uv_border = vec2(
+            border * sprite.uv.width() / sprite.size.x,
+            border * sprite.uv.height() / sprite.size.y,
+        );
+        let xs = [
+            rect.left(),
+            rect.left() + inset,
+            rect.right() - inset,
+            rect.right(),
+        ];
+        let ys = [
+            rect.top(),
+            rect.top() + inset,
+            rect.bottom() - inset,
+            rect.bottom(),
+        ];
+        let us = [
+            sprite.uv.left(),
+            sprite.uv.left() + uv_border.x,
+            sprite.uv.right() - uv_border.x,
+            sprite.uv.right(),
+        ];
+        let vs = [
+            sprite.uv.top(),
+            sprite.uv.top() + uv_border.y,
+            sprite.uv.bottom() - uv_border.y,
+            sprite.uv.bottom(),
+        ];
+        for row in 0..3 {
+            for column in 0..3 {
+                let slice = Rect::from_min_max(
+                    pos2(xs[column], ys[row]),
+                    pos2(xs[column + 1], ys[row + 1]),
+                );
+                if slice.width() <= 0.0 || slice.height() <= 0.0 {
+                    continue;
+                }
+                self.quads.push(Quad {
+                    layer,
+                    texture: sprite.texture,
+                    rect: slice,
+                    uv: Rect::from_min_max(
+                        pos2(us[column], vs[row]),
+                        pos2(us[column + 1], vs[row + 1]),
+                    ),
+                    color,
+                });
+            }
+        }
+    }
+
+    // A bar filled from the left to `fraction` of its width, over a background.
+    pub fn bar(
+        &mut self,
+        rect: Rect,
+        fraction: f32,
+        fill: Color32,
+        background: Color32,
+        layer: i32,
+    ) {
+        self.rect(rect, background, layer);
+        let width = rect.width() * fraction.clamp(0.0, 1.0);
+        if width > 0.0 {
+            let filled = Rect::from_min_size(rect.min, vec2(width, rect.height()));
+            self.rect(filled, fill, layer);
+        }
+    }
+
+    // Puts everything queued this frame under the rest of `frame`, one mesh per run
+    // of quads sharing a texture, along with any atlases still to upload.
+    pub fn finish(&mut self, frame: &mut OverlayFrame) {
+        frame.textures.set.append(&mut self.uploads);
+        let mut quads = std::mem::take(&mut self.quads);
+        quads.sort_by_key(|quad| quad.layer);
+
+        let mut primitives: Vec<ClippedPrimitive> = Vec::new();
+        let mut mesh: Option<Mesh> = None;
+        for quad in quads {
+            if mesh
+                .as_ref()
+                .is_some_and(|mesh| mesh.texture_id != quad.texture)
+            {
+                primitives.extend(mesh.take().map(primitive));
+            }
+            mesh.get_or_insert_with(|| Mesh::with_texture(quad.texture))
+                .add_rect_with_uv(quad.rect, quad.uv, quad.color);
+        }
+        primitives.extend(mesh.map(primitive));
+        frame.primitives.splice(0..0, primitives);
+    }
+}
+
+fn primitive(mesh: Mesh) -> ClippedPrimitive {
+    ClippedPrimitive {
+        clip_rect: Rect::EVERYTHING,
+        primitive: Primitive::Mesh(mesh),
+    }
+}
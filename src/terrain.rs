@@ -0,0 +1,432 @@
+use std::collections::{HashMap, HashSet};
+
+use cgmath::{InnerSpace, Vector3, Vector4};
+
+use crate::mesh::Vertex;
+use crate::noise::Noise;
+
+// Cells along one side of a chunk at full detail. Every LOD step must divide it.
+pub const CHUNK_CELLS: usize = 32;
+pub const LOD_LEVELS: u32 = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ChunkCoord {
+    pub x: i32,
+    pub z: i32,
+}
+
+pub struct TerrainSettings {
+    pub seed: u64,
+    pub chunks: usize,
+    pub cell_size: f32,
+    pub height_scale: f32,
+    pub noise_scale: f32,
+    pub octaves: u32,
+    pub view_distance: f32,
+    pub lod_distance: f32,
+    pub chunks_per_frame: usize,
+}
+
+impl Default for TerrainSettings {
+    fn default() -> Self {
+        TerrainSettings {
+            seed: 0xEC0C1DE,
+            chunks: 16,
+            cell_size: 1.0,
+            height_scale: 48.0,
+            noise_scale: 1.0 / 128.0,
+            octaves: 6,
+            view_distance: 224.0,
+            lod_distance: 64.0,
+            chunks_per_frame: 4,
+        }
+    }
+}
+
+pub struct Heightfield {
+    pub size: usize,
+    pub cell_size: f32,
+    pub heights: Vec<f32>,
+    // Painted colour per sample in rgb, blended over the generated colour by alpha.
+    pub tint: Vec<Vector4<f32>>,
+}
+
+impl Heightfield {
+    pub fn generate(settings: &TerrainSettings) -> Self {
+        let noise = Noise::new(settings.seed);
+        let size = settings.chunks * CHUNK_CELLS + 1;
+        let mut heights = Vec::with_capacity(size * size);
+        for z in 0..size {
+            for x in 0..size {
+                let n = noise.fbm(
+                    x as f32 * settings.noise_scale,
+                    z as f32 * settings.noise_scale,
+                    settings.octaves,
+                    2.0,
+                    0.5,
+                );
+                let h = (n * 0.5 + 0.5).clamp(0.0, 1.0);
+                heights.push(h.powf(1.6) * settings.height_scale);
+            }
+        }
+        Heightfield {
+            size,
+            cell_size: settings.cell_size,
+            heights,
+            tint: vec![Vector4::new(0.0, 0.0, 0.0, 0.0); size * size],
+        }
+    }
+
+    pub fn index(&self, x: usize, z: usize) -> usize {
+        z.min(self.size - 1) * self.size + x.min(self.size - 1)
+    }
+
+    pub fn height(&self, x: usize, z: usize) -> f32 {
+        self.heights[self.index(x, z)]
+    }
+
+    // Bilinearly interpolated height at a world position, clamped to the map.
+    pub fn sample(&self, wx: f32, wz: f32) -> f32 {
+        let max = (self.size - 1) as f32;
+        let fx = (wx / self.cell_size).clamp(0.0, max);
+        let fz = (wz / self.cell_size).clamp(0.0, max);
+        let x = fx.floor() as usize;
+        let z = fz.floor() as usize;
+        let tx = fx - x as f32;
+        let tz = fz - z as f32;
+        let h0 = self.height(x, z) * (1.0 - tx) + self.height(x + 1, z) * tx;
+        let h1 = self.height(x, z + 1) * (1.0 - tx) + self.height(x + 1, z + 1) * tx;
+        h0 * (1.0 - tz) + h1 * tz
+    }
+
+    pub fn normal(&self, x: usize, z: usize) -> Vector3<f32> {
+        let left = self.height(x.saturating_sub(1), z);
+        let right = self.height(x + 1, z);
+        let down = self.height(x, z.saturating_sub(1));
+        let up = self.height(x, z + 1);
+        Vector3::new(left - right, 2.0 * self.cell_size, down - up).normalize()
+    }
+
+    pub fn extent(&self) -> f32 {
+        (self.size - 1) as f32 * self.cell_size
+    }
+}
+
+pub struct ChunkMesh {
+    pub coord: ChunkCoord,
+    pub vertices: Vec<Vertex>,
+}
+
+#[derive(Default)]
+pub struct StreamUpdate {
+    pub load: Vec<ChunkMesh>,
+    pub unload: Vec<ChunkCoord>,
+}
+
+pub struct Terrain {
+    pub settings: TerrainSettings,
+    pub heightfield: Heightfield,
+    loaded: HashMap<ChunkCoord, u32>,
+    dirty: HashSet<ChunkCoord>,
+    revision: u64,
+}
+
+impl Terrain {
+    pub fn new(settings: TerrainSettings) -> Self {
+        let heightfield = Heightfield::generate(&settings);
+        Terrain {
+            settings,
+            heightfield,
+            loaded: HashMap::new(),
+            dirty: HashSet::new(),
+            revision: 0,
+        }
+    }
+
+    // Throws away every edit and goes back to the generated heightfield.
+    pub fn regenerate(&mut self) {
+        self.heightfield = Heightfield::generate(&self.settings);
+        self.dirty.extend(self.loaded.keys());
+        self.revision += 1;
+    }
+
+    // Flags every chunk touching the sample rectangle for a rebuild. The rectangle is
+    // grown by one sample since normals along its border depend on the changed heights.
+    pub fn mark_dirty(&mut self, min: (usize, usize), max: (usize, usize)) {
+        let last = self.settings.chunks as i32 - 1;
+        let to_chunk =
+            |sample: usize, grow: i32| ((sample as i32 + grow) / CHUNK_CELLS as i32).clamp(0, last);
+        // A sample on a chunk border is shared with the chunk before it.
+        let first = |sample: usize| to_chunk(sample, -2);
+        for z in first(min.1)..=to_chunk(max.1, 1) {
+            for x in first(min.0)..=to_chunk(max.0, 1) {
+                self.dirty.insert(ChunkCoord { x, z });
+            }
+        }
+        self.revision += 1;
+    }
+
+    // Bumped by every change to the heightfield, so anything placed on the terrain
+    // can tell when it needs to be placed again.
+    pub fn revision(&self) -> u64 {
+        self.revision
+    }
+
+    // Marches along a ray until it passes below the heightfield.
+    pub fn raycast(
+        &self,
+        origin: Vector3<f32>,
+        direction: Vector3<f32>,
+        max_distance: f32,
+    ) -> Option<Vector3<f32>> {
+        let step = self.settings.cell_size * 0.5;
+        let direction = direction.normalize();
+        let extent = self.heightfield.extent();
+        let mut t = 0.0;
+        while t < max_distance {
+            let p = origin + direction * t;
+            let inside = p.x >= 0.0 && p.z >= 0.0 && p.x <= extent && p.z <= extent;
+            if inside && p.y <= self.heightfield.sample(p.x, p.z) {
+                return Some(p);
+            }
+            t += step;
+        }
+        None
+    }
+
+    pub fn chunk_world_size(&self) -> f32 {
+        CHUNK_CELLS as f32 * self.settings.cell_size
+    }
+
+    pub fn chunk_center(&self, coord: ChunkCoord) -> Vector3<f32> {
+        let size = self.chunk_world_size();
+        let x = (coord.x as f32 + 0.5) * size;
+        let z = (coord.z as f32 + 0.5) * size;
+        Vector3::new(x, self.heightfield.sample(x, z), z)
+    }
+
+    // The LOD a chunk should be drawn at from `camera`, or None if it is out of range.
+    pub fn desired_lod(&self, coord: ChunkCoord, camera: Vector3<f32>) -> Option<u32> {
+        let center = self.chunk_center(coord);
+        let dx = center.x - camera.x;
+        let dz = center.z - camera.z;
+        let distance = (dx * dx + dz * dz).sqrt();
+        if distance > self.settings.view_distance + self.chunk_world_size() {
+            return None;
+        }
+        let lod = (distance / self.settings.lod_distance) as u32;
+        Some(lod.min(LOD_LEVELS - 1))
+    }
+
+    // Works out which chunks need (re)building or dropping for the current camera
+    // position. At most `chunks_per_frame` meshes are built per call, nearest first.
+    pub fn stream(&mut self, camera: Vector3<f32>) -> StreamUpdate {
+        let mut update = StreamUpdate::default();
+        let mut pending = Vec::new();
+
+        let chunks = self.settings.chunks as i32;
+        for z in 0..chunks {
+            for x in 0..chunks {
+                let coord = ChunkCoord { x, z };
+                match (self.desired_lod(coord, camera), self.loaded.get(&coord)) {
+                    (None, Some(_)) => update.unload.push(coord),
+                    (Some(lod), loaded) if loaded != Some(&lod) => pending.push((coord, lod)),
+                    _ => {}
+                }
+            }
+        }
+
+        for coord in update.unload.iter() {
+            self.loaded.remove(coord);
+            self.dirty.remove(coord);
+        }
+
+        let distance = |coord: &ChunkCoord| (self.chunk_center(*coord) - camera).magnitude2();
+        let by_distance = |a: &(ChunkCoord, u32), b: &(ChunkCoord, u32)| {
+            distance(&a.0).partial_cmp(&distance(&b.0)).unwrap()
+        };
+        pending.sort_by(by_distance);
+
+        // Edited chunks keep their LOD and go to the front of the queue.
+        let mut rebuild: Vec<(ChunkCoord, u32)> = self
+            .dirty
+            .iter()
+            .filter(|coord| !pending.iter().any(|(p, _)| p == *coord))
+            .filter_map(|coord| self.loaded.get(coord).map(|lod| (*coord, *lod)))
+            .collect();
+        rebuild.sort_by(by_distance);
+        rebuild.append(&mut pending);
+        let pending = rebuild;
+
+        for (coord, lod) in pending.into_iter().take(self.settings.chunks_per_frame) {
+            update.load.push(ChunkMesh {
+                coord,
+                vertices: self.build_chunk(coord, lod),
+            });
+            self.loaded.insert(coord, lod);
+            self.dirty.remove(&coord);
+        }
+        update
+    }
+
+    pub fn build_chunk(&self, coord: ChunkCoord, lod: u32) -> Vec<Vertex> {
+        let step = 1usize << lod;
+        let n = CHUNK_CELLS / step;
+        let x0 = coord.x as usize * CHUNK_CELLS;
+        let z0 = coord.z as usize * CHUNK_CELLS;
+
+        let mut grid = Vec::with_capacity((n + 1) * (n + 1));
+        for j in 0..=n {
+            for i in 0..=n {
+                grid.push(self.vertex(x0 + i * step, z0 + j * step));
+            }
+        }
+        let at = |i: usize, j: usize| grid[j * (n + 1) + i];
+
+        let mut vertices = Vec::with_capacity(n * n * 6 + n * 24);
+        for j in 0..n {
+            for i in 0..n {
+                let (a, b, c, d) = (at(i, j), at(i + 1, j), at(i, j + 1), at(i + 1, j + 1));
+                vertices.extend_from_slice(&[a, c, b, b, c, d]);
+            }
+        }
+
+        // Skirts hang below every chunk edge so that neighbours at a different LOD
+        // never leave a visible crack between them.
+        let skirt = self.settings.cell_size * step as f32 * 2.0 + 1.0;
+        for k in 0..n {
+            let edges = [
+                (at(k, 0), at(k + 1, 0)),
+                (at(k + 1, n), at(k, n)),
+                (at(0, k + 1), at(0, k)),
+                (at(n, k), at(n, k + 1)),
+            ];
+            for (top_a, top_b) in edges {
+                let mut bottom_a = top_a;
+                let mut bottom_b = top_b;
+                bottom_a.position.y -= skirt;
+                bottom_b.position.y -= skirt;
+                vertices.extend_from_slice(&[top_a, bottom_a, top_b, top_b, bottom_a, bottom_b]);
+            }
+        }
+        vertices
+    }
+
+    fn vertex(&self, x: usize, z: usize) -> Vertex {
+        let height = self.heightfield.height(x, z);
+        let normal = self.heightfield.normal(x, z);
+        let tint = self.heightfield.tint[self.heightfield.index(x, z)];
+        let base = terrain_color(height / self.settings.height_scale, normal);
+        Vertex {
+            position: Vector3::new(
+                x as f32 * self.settings.cell_size,
+                height,
+                z as f32 * self.settings.cell_size,
+            ),
+            color: shade(base * (1.0 - tint.w) + tint.truncate() * tint.w, normal),
+        }
+    }
+}
+
+fn terrain_color(height: f32, normal: Vector3<f32>) -> Vector3<f32> {
+    let sand = Vector3::new(0.76, 0.70, 0.50);
+    let grass = Vector3::new(0.25, 0.50, 0.18);
+    let rock = Vector3::new(0.42, 0.40, 0.38);
+    let snow = Vector3::new(0.92, 0.93, 0.95);
+
+    if normal.y < 0.75 {
+        rock
+    } else if height < 0.08 {
+        sand
+    } else if height < 0.55 {
+        grass
+    } else if height < 0.75 {
+        rock
+    } else {
+        snow
+    }
+}
+
+fn shade(color: Vector3<f32>, normal: Vector3<f32>) -> Vector3<f32> {
+    let sun = Vector3::new(0.4f32, 0.8, 0.3).normalize();
+    color * (0.3 + 0.7 * normal.dot(sun).max(0.0))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn small_terrain() -> Terrain {
+        Terrain::new(TerrainSettings {
+            chunks: 4,
+            view_distance: 96.0,
+            lod_distance: 32.0,
+            chunks_per_frame: 3,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn generation_is_deterministic() {
+        let a = small_terrain();
+        let b = small_terrain();
+        assert_eq!(a.heightfield.heights, b.heightfield.heights);
+    }
+
+    #[test]
+    fn lod_grows_with_distance() {
+        let terrain = small_terrain();
+        let coord = ChunkCoord { x: 0, z: 0 };
+        let center = terrain.chunk_center(coord);
+        let along = |distance: f32| center + Vector3::new(distance, 0.0, 0.0);
+
+        assert_eq!(terrain.desired_lod(coord, center), Some(0));
+        assert_eq!(terrain.desired_lod(coord, along(40.0)), Some(1));
+        assert_eq!(terrain.desired_lod(coord, along(70.0)), Some(2));
+        // Past the last LOD's distance but still in view.
+        assert_eq!(
+            terrain.desired_lod(coord, along(120.0)),
+            Some(LOD_LEVELS - 1)
+        );
+        assert_eq!(terrain.desired_lod(coord, along(200.0)), None);
+    }
+
+    #[test]
+    fn chunks_have_a_skirt_along_every_edge() {
+        let terrain = small_terrain();
+        let coord = ChunkCoord { x: 1, z: 2 };
+        let size = terrain.chunk_world_size();
+        let (x0, z0) = (coord.x as f32 * size, coord.z as f32 * size);
+        for lod in 0..LOD_LEVELS {
+            let step = 1usize << lod;
+            let n = CHUNK_CELLS / step;
+            let vertices = terrain.build_chunk(coord, lod);
+            assert_eq!(vertices.len(), n * n * 6 + n * 24);
+
+            let skirt = terrain.settings.cell_size * step as f32 * 2.0 + 1.0;
+            for quad in vertices[n * n * 6..].chunks(6) {
+                let (top, bottom) = (quad[0].position, quad[1].position);
+                assert_eq!((top.x, top.z), (bottom.x, bottom.z));
+                assert_eq!(bottom.y, top.y - skirt);
+                let on_edge =
+                    top.x == x0 || top.x == x0 + size || top.z == z0 || top.z == z0 + size;
+                assert!(on_edge);
+            }
+        }
+    }
+
+    #[test]
+    fn streaming_loads_the_nearest_chunks_first() {
+        let mut terrain = small_terrain();
+        let camera = terrain.chunk_center(ChunkCoord { x: 0, z: 0 });
+        let update = terrain.stream(camera);
+        assert!(update.unload.is_empty());
+        assert_eq!(update.load.len(), 3);
+        assert_eq!(update.load[0].coord, ChunkCoord { x: 0, z: 0 });
+
+        // Moving far away drops what was loaded.
+        let update = terrain.stream(camera + Vector3::new(1000.0, 0.0, 1000.0));
+        assert_eq!(update.unload.len(), 3);
+        assert!(update.load.is_empty());
+    }
+}
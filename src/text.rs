@@ -0,0 +1,442 @@
+// Text in screen space, for the HUD, menus and debug output. Fonts are TrueType or
+// OpenType files; glyphs are rasterized as they are first needed into an atlas that
+// the overlay draws from, and text is queued up over the frame and turned into one
+// mesh at the end of it.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use ab_glyph::{point, Font as _, FontVec, GlyphId, PxScale, ScaleFont};
+use anyhow::Context;
+use egui::epaint::{ClippedPrimitive, ColorImage, ImageDelta, Mesh, Primitive, TextureId};
+use egui::{pos2, vec2, Color32, Pos2, Rect, TextureOptions, Vec2};
+
+use crate::overlay::{user_texture_id, OverlayFrame};
+
+pub const FONT_FILE: &str = "assets/fonts/DejaVuSans.ttf";
+
+const ATLAS_SIZE: usize = 1024;
+// Empty pixels around each glyph, so filtering doesn't pick up its neighbours.
+const PADDING: usize = 1;
+
+#[derive(Clone, Copy, Debug)]
+pub struct TextStyle {
+    // Line height is derived from this, in points.
+    pub size: f32,
+    pub color: Color32,
+    // Lines longer than this are broken between words.
+    pub wrap_width: Option<f32>,
+}
+
+impl Default for TextStyle {
+    fn default() -> Self {
+        TextStyle {
+            size: 16.0,
+            color: Color32::WHITE,
+            wrap_width: None,
+        }
+    }
+}
+
+// A glyph placed relative to the top left of its text, with `y` on the baseline.
+#[derive(Clone, Copy, Debug)]
+struct PlacedGlyph {
+    id: GlyphId,
+    x: f32,
+    y: f32,
+}
+
+struct Layout {
+    glyphs: Vec<PlacedGlyph>,
+    size: Vec2,
+}
+
+struct Run {
+    position: Pos2,
+    style: TextStyle,
+    layout: Layout,
+}
+
+#[derive(Clone, Copy)]
+struct AtlasGlyph {
+    // Pixel rectangle in the atlas, empty for glyphs with no outline.
+    min: [usize; 2],
+    size: [usize; 2],
+    // From the pen position on the baseline to the top left of the bitmap.
+    offset: Vec2,
+}
+
+// Glyph bitmaps packed into rows, with whatever changed since the last upload.
+struct Atlas {
+    coverage: Vec<u8>,
+    glyphs: HashMap<(GlyphId, u32), AtlasGlyph>,
+    cursor: [usize; 2],
+    row_height: usize,
+    // Inclusive minimum and exclusive maximum, in pixels.
+    dirty: Option<([usize; 2], [usize; 2])>,
+    // Set when the texture has to be sent whole: at first and after a reset.
+    uploaded: bool,
+}
+
+impl Atlas {
+    fn new() -> Self {
+        Atlas {
+            coverage: vec![0; ATLAS_SIZE * ATLAS_SIZE],
+            glyphs: HashMap::new(),
+            cursor: [0, 0],
+            row_height: 0,
+            dirty: None,
+            uploaded: false,
+        }
+    }
+
+    fn reset(&mut self) {
+        *self = Atlas::new();
+    }
+
+    // Finds room for a bitmap, or None once the atlas is full.
+    fn allocate(&mut self, width: usize, height: usize) -> Option<[usize; 2]> {
+        let (width, height) = (width + PADDING, height + PADDING);
+        if self.cursor[0] + width > ATLAS_SIZE {
+            self.cursor = [0, self.cursor[1] + self.row_height];
+            self.row_height = 0;
+        }
+        if width > ATLAS_SIZE || self.cursor[1] + height > ATLAS_SIZE {
+            return None;
+        }
+        let min = self.cursor;
+        self.cursor[0] += width;
+        self.row_height = self.row_height.max(height);
+        Some(min)
+    }
+
+    fn mark_dirty(&mut self, min: [usize; 2], size: [usize; 2]) {
+        let max = [min[0] + size[0], min[1] + size[1]];
+        self.dirty = Some(match self.dirty {
+            Some((old_min, old_max)) => (
+                [old_min[0].min(min[0]), old_min[1].min(min[1])],
+                [old_max[0].max(max[0]), old_max[1].max(max[1])],
+            ),
+            None => (min, max),
+        });
+    }
+
+    // The texture changes to send to the overlay since the last call.
+    fn take_delta(&mut self) -> Option<ImageDelta> {
+        let texels = |min: [usize; 2], max: [usize; 2]| {
+            let pixels = (min[1]..max[1])
+                .flat_map(|y| {
+                    self.coverage[y * ATLAS_SIZE + min[0]..y * ATLAS_SIZE + max[0]]
+                        .iter()
+                        .map(|&a| Color32::from_rgba_premultiplied(a, a, a, a))
+                })
+                .collect();
+            ColorImage {
+                size: [max[0] - min[0], max[1] - min[1]],
+                pixels,
+            }
+        };
+        if !self.uploaded {
+            self.uploaded = true;
+            self.dirty = None;
+            let image = texels([0, 0], [ATLAS_SIZE, ATLAS_SIZE]);
+            return Some(ImageDelta::full(image, TextureOptions::LINEAR));
+        }
+        let (min, max) = self.dirty.take()?;
+        Some(ImageDelta::partial(
+            min,
+            texels(min, max),
+            TextureOptions::LINEAR,
+        ))
+    }
+}
+
+pub struct TextBatch {
+    font: FontVec,
+    atlas: Atlas,
+    // The overlay texture the atlas lives in.
+    texture: TextureId,
+    runs: Vec<Run>,
+}
+
+impl TextBatch {
+    // Loads a TrueType or OpenType font.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let data = std::fs::read(path).with_context(|| format!("Reading {}", path.display()))?;
+        let font = FontVec::try_from_vec(data)
+            .with_context(|| format!("{} is not a font", path.display()))?;
+        Ok(TextBatch {
+            font,
+            atlas: Atlas::new(),
+            texture: user_texture_id(),
+            runs: Vec::new(),
+        })
+    }
+
+    // Queues `text` with its top left corner at `position`, in points. Returns the
+    // rectangle it covers.
+    pub fn draw(&mut self, position: Pos2, text: &str, style: TextStyle) -> Rect {
+        let layout = self.layout(text, &style);
+        let rect = Rect::from_min_size(position, layout.size);
+        self.runs.push(Run {
+            position,
+            style,
+            layout,
+        });
+        rect
+    }
+
+    // The size `text` would be drawn at.
+    pub fn measure(&self, text: &str, style: &TextStyle) -> Vec2 {
+        self.layout(text, style).size
+    }
+
+    // Lays the text out in lines, applying kerning between neighbouring glyphs and
+    // breaking at the last space before `wrap_width` where there is one.
+    fn layout(&self, text: &str, style: &TextStyle) -> Layout {
+        let font = self.font.as_scaled(PxScale::from(style.size));
+        let line_height = font.height() + font.line_gap();
+        let mut glyphs: Vec<PlacedGlyph> = Vec::new();
+        let (mut x, mut y) = (0.0f32, font.ascent());
+        let mut width = 0.0f32;
+        let mut line_start = 0;
+        // First glyph after the last space on the line, and where it starts.
+        let mut break_at: Option<(usize, f32)> = None;
+        let mut previous: Option<GlyphId> = None;
+
+        for c in text.chars() {
+            if c == '\n' {
+                width = width.max(x);
+                x = 0.0;
+                y += line_height;
+                line_start = glyphs.len();
+                break_at = None;
+                previous = None;
+                continue;
+            }
+            let id = font.glyph_id(c);
+            if let Some(previous) = previous {
+                x += font.kern(previous, id);
+            }
+            previous = Some(id);
+            let advance = font.h_advance(id);
+            if c.is_whitespace() {
+                x += advance;
+                break_at = Some((glyphs.len(), x));
+                continue;
+            }
+            if let (Some(wrap), Some((index, break_x))) = (style.wrap_width, break_at) {
+                if x + advance > wrap && index > line_start {
+                    width = width.max(break_x);
+                    for glyph in glyphs[index..].iter_mut() {
+                        glyph.x -= break_x;
+                        glyph.y += line_height;
+                    }
+                    x -= break_x;
+                    y += line_height;
+                    line_start = index;
+                    break_at = None;
+                }
+            }
+            glyphs.push(PlacedGlyph { id, x, y });
+            x += advance;
+        }
+        width = width.max(x);
+        Layout {
+            glyphs,
+            size: vec2(width, y - font.ascent() + line_height),
+        }
+    }
+
+    // Turns everything queued this frame into a mesh under the rest of `frame`,
+    // rasterizing new glyphs at the frame's scale.
+    pub fn finish(&mut self, frame: &mut OverlayFrame) {
+        let runs = std::mem::take(&mut self.runs);
+        let pixels_per_point = frame.pixels_per_point.max(f32::EPSILON);
+        let mut mesh = self.build_mesh(&runs, pixels_per_point);
+        if mesh.is_none() {
+            // Out of room: start over with only what this frame needs.
+            self.atlas.reset();
+            mesh = self.build_mesh(&runs, pixels_per_point);
+        }
+        if let Some(delta) = self.atlas.take_delta() {
+            frame.textures.set.push((self.texture, delta));
+        }
+        if let Some(mesh) = mesh.filter(|mesh| !mesh.is_empty()) {
+            let primitive = ClippedPrimitive {
+                clip_rect: Rect::EVERYTHING,
+                primitive: Primitive::Mesh(mesh),
+            };
+            frame.primitives.insert(0, primitive);
+        }
+    }
+
+    // None when the atlas ran out of room.
+    fn build_mesh(&mut self, runs: &[Run], pixels_per_point: f32) -> Option<Mesh> {
+        let mut mesh = Mesh::with_texture(self.texture);
+        let uv_scale = 1.0 / ATLAS_SIZE as f32;
+        for run in runs {
+            let pixel_size = (run.style.size * pixels_per_point).round().max(1.0);
+            for glyph in run.layout.glyphs.iter() {
+                let cached = self.rasterize(glyph.id, pixel_size)?;
+                if cached.size[0] == 0 {
+                    continue;
+                }
+                // Pen positions land on whole pixels so glyphs stay sharp.
+                let pen =
+                    ((run.position.to_vec2() + vec2(glyph.x, glyph.y)) * pixels_per_point).round();
+                let min = (pen + cached.offset) / pixels_per_point;
+                let size = vec2(cached.size[0] as f32, cached.size[1] as f32) / pixels_per_point;
+                let uv_min = pos2(cached.min[0] as f32, cached.min[1] as f32) * uv_scale;
+                let uv_size = vec2(cached.size[0] as f32, cached.size[1] as f32) * uv_scale;
+                mesh.add_rect_with_uv(
+                    Rect::from_min_size(min.to_pos2(), size),
+                    Rect::from_min_size(uv_min, uv_size),
+                    run.style.color,
+                );
+            }
+        }
+        Some(mesh)
+    }
+
+    fn rasterize(&mut self, id: GlyphId, pixel_size: f32) -> Option<AtlasGlyph> {
+        let key = (id, pixel_size as u32);
+        if let Some(glyph) = self.atlas.glyphs.get(&key) {
+            return Some(*glyph);
+        }
+        let empty = AtlasGlyph {
+            min: [0, 0],
+            size: [0, 0],
+            offset: Vec2::ZERO,
+        };
+        let glyph = match self
+            .font
+            .outline_glyph(id.with_scale_and_position(pixel_size, point(0.0, 0.0)))
+        {
+            Some(outline) => {
+                let bounds = outline.px_bounds();
+                let size = [bounds.width() as usize, bounds.height() as usize];
+                let min = self.atlas.allocate(size[0], size[1])?;
+                outline.draw(|x, y, coverage| {
+                    let (x, y) = (min[0] + x as usize, min[1] + y as usize);
+                    if x < ATLAS_SIZE && y < ATLAS_SIZE {
+                        self.atlas.coverage[y * ATLAS_SIZE + x] = (coverage * 255.0) as u8;
+                    }
+                });
+                self.atlas.mark_dirty(min, size);
+                AtlasGlyph {
+                    min,
+                    size,
+                    offset: vec2(bounds.min.x, bounds.min.y),
+                }
+            }
+            None => empty,
+        };
+        self.atlas.glyphs.insert(key, glyph);
+        Some(glyph)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch() -> TextBatch {
+        TextBatch::load(Path::new(FONT_FILE)).unwrap()
+    }
+
+    fn style(wrap_width: Option<f32>) -> TextStyle {
+        TextStyle {
+            wrap_width,
+            ..TextStyle::default()
+        }
+    }
+
+    fn line_height(batch: &TextBatch) -> f32 {
+        let font = batch
+            .font
+            .as_scaled(PxScale::from(TextStyle::default().size));
+        font.height() + font.line_gap()
+    }
+
+    fn advance(batch: &TextBatch, c: char) -> f32 {
+        let font = batch
+            .font
+            .as_scaled(PxScale::from(TextStyle::default().size));
+        font.h_advance(font.glyph_id(c))
+    }
+
+    #[test]
+    fn wrapping_breaks_at_the_last_space_that_fits() {
+        let batch = batch();
+        let line_height = line_height(&batch);
+        let wrap = batch.measure("one two", &style(None)).x + 1.0;
+        let layout = batch.layout("one two three", &style(Some(wrap)));
+
+        // "one" and "two" stay on the first line, "three" starts the second.
+        let lines: Vec<f32> = layout.glyphs.iter().map(|glyph| glyph.y).collect();
+        assert!(lines[..6].iter().all(|&y| y == lines[0]));
+        assert!(lines[6..].iter().all(|&y| y == lines[0] + line_height));
+        assert_eq!(layout.glyphs[6].x, 0.0);
+        assert_eq!(layout.size.y, 2.0 * line_height);
+        assert!(layout.size.x <= wrap + advance(&batch, ' '));
+    }
+
+    #[test]
+    fn a_word_wider_than_the_wrap_width_is_not_split() {
+        let batch = batch();
+        let line_height = line_height(&batch);
+        let word = "unbreakable";
+        let alone = batch.layout(word, &style(Some(10.0)));
+        assert_eq!(alone.size.y, line_height);
+        assert_eq!(alone.size.x, batch.measure(word, &style(None)).x);
+
+        // After a space it moves to its own line, still whole.
+        let after = batch.layout("a unbreakable", &style(Some(10.0)));
+        assert_eq!(after.size.y, 2.0 * line_height);
+        let y = after.glyphs[1].y;
+        assert!(after.glyphs[1..].iter().all(|glyph| glyph.y == y));
+        assert_eq!(after.glyphs[1].x, 0.0);
+    }
+
+    #[test]
+    fn a_newline_resets_x_and_kerning() {
+        let batch = batch();
+        let line_height = line_height(&batch);
+        let layout = batch.layout("AV\nVA", &style(None));
+        assert_eq!(layout.glyphs.len(), 4);
+        assert_eq!(layout.glyphs[2].x, 0.0);
+        assert_eq!(layout.glyphs[2].y, layout.glyphs[0].y + line_height);
+        assert_eq!(layout.size.y, 2.0 * line_height);
+
+        // No kerning is carried from the "V" ending one line to the "A" starting the next.
+        let split = batch.layout("A\nV", &style(None));
+        assert_eq!(split.glyphs[1].x, 0.0);
+        assert_eq!(split.size.x, advance(&batch, 'A').max(advance(&batch, 'V')));
+    }
+
+    #[test]
+    fn kerned_pairs_are_closer_than_their_advances() {
+        let batch = batch();
+        let layout = batch.layout("AV", &style(None));
+        let sum = advance(&batch, 'A') + advance(&batch, 'V');
+        assert!(layout.size.x < sum, "{} >= {}", layout.size.x, sum);
+        assert!(layout.glyphs[1].x < advance(&batch, 'A'));
+    }
+
+    #[test]
+    fn measure_matches_draw() {
+        let mut batch = batch();
+        let position = pos2(12.0, 34.0);
+        for (text, style) in [
+            ("Population: 1024", style(None)),
+            ("wrapped across a few short lines", style(Some(60.0))),
+            ("two\nlines", style(None)),
+            ("", style(None)),
+        ] {
+            let size = batch.measure(text, &style);
+            let rect = batch.draw(position, text, style);
+            assert_eq!(rect, Rect::from_min_size(position, size), "{:?}", text);
+        }
+    }
+}
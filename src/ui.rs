@@ -0,0 +1,208 @@
+// The egui side of the developer UI: turns winit's window events into egui input
+// and runs a frame of UI into something the overlay renderer can draw.
+
+use std::time::Instant;
+
+use egui::{pos2, vec2, Event, Key, Modifiers, PointerButton, Pos2, RawInput, Rect};
+use winit::dpi::PhysicalSize;
+use winit::event::{
+    ElementState, ModifiersState, MouseButton, MouseScrollDelta, VirtualKeyCode, WindowEvent,
+};
+
+use crate::overlay::OverlayFrame;
+
+// Points scrolled per line of a mouse wheel.
+const LINE_HEIGHT: f32 = 24.0;
+
+pub struct Ui {
+    pub ctx: egui::Context,
+    // Hidden, the UI neither draws nor takes input.
+    pub visible: bool,
+    input: RawInput,
+    pointer: Pos2,
+    modifiers: Modifiers,
+    pixels_per_point: f32,
+    start: Instant,
+}
+
+impl Ui {
+    pub fn new(scale_factor: f64) -> Self {
+        Ui {
+            ctx: egui::Context::default(),
+            visible: false,
+            input: RawInput::default(),
+            pointer: Pos2::ZERO,
+            modifiers: Modifiers::default(),
+            pixels_per_point: scale_factor as f32,
+            start: Instant::now(),
+        }
+    }
+
+    // Queues the event for the next frame. Returns true when the UI is using it, in
+    // which case the game should leave it alone.
+    pub fn on_event(&mut self, event: &WindowEvent) -> bool {
+        if !self.visible {
+            return false;
+        }
+        let wants_keyboard = self.ctx.wants_keyboard_input();
+        let wants_pointer = self.ctx.wants_pointer_input();
+        match event {
+            WindowEvent::CursorMoved { position, .. } => {
+                self.pointer = pos2(
+                    position.x as f32 / self.pixels_per_point,
+                    position.y as f32 / self.pixels_per_point,
+                );
+                self.input.events.push(Event::PointerMoved(self.pointer));
+                false
+            }
+            WindowEvent::CursorLeft { .. } => {
+                self.input.events.push(Event::PointerGone);
+                false
+            }
+            WindowEvent::MouseInput { state, button, .. } => {
+                let button = match button {
+                    MouseButton::Left => PointerButton::Primary,
+                    MouseButton::Right => PointerButton::Secondary,
+                    MouseButton::Middle => PointerButton::Middle,
+                    MouseButton::Other(_) => return false,
+                };
+                self.input.events.push(Event::PointerButton {
+                    pos: self.pointer,
+                    button,
+                    pressed: *state == ElementState::Pressed,
+                    modifiers: self.modifiers,
+                });
+                wants_pointer
+            }
+            WindowEvent::MouseWheel { delta, .. } => {
+                let delta = match delta {
+                    MouseScrollDelta::LineDelta(x, y) => vec2(*x, *y) * LINE_HEIGHT,
+                    MouseScrollDelta::PixelDelta(offset) => {
+                        vec2(offset.x as f32, offset.y as f32) / self.pixels_per_point
+                    }
+                };
+                self.input.events.push(Event::Scroll(delta));
+                wants_pointer
+            }
+            WindowEvent::ModifiersChanged(state) => {
+                self.modifiers = modifiers(*state);
+                false
+            }
+            WindowEvent::ReceivedCharacter(c) => {
+                if !c.is_control() {
+                    self.input.events.push(Event::Text(c.to_string()));
+                }
+                wants_keyboard
+            }
+            WindowEvent::KeyboardInput { input, .. } => {
+                if let Some(key) = input.virtual_keycode.and_then(key) {
+                    self.input.events.push(Event::Key {
+                        key,
+                        physical_key: None,
+                        pressed: input.state == ElementState::Pressed,
+                        repeat: false,
+                        modifiers: self.modifiers,
+                    });
+                }
+                // Releases always go through, or the game would think keys pressed
+                // before the UI took focus were still held.
+                wants_keyboard && input.state == ElementState::Pressed
+            }
+            WindowEvent::Focused(focused) => {
+                self.input.events.push(Event::WindowFocused(*focused));
+                false
+            }
+            _ => false,
+        }
+    }
+
+    // Runs `build` over a window of `size` physical pixels. Hidden, the frame is
+    // empty apart from texture changes still owed to the renderer.
+    pub fn run(
+        &mut self,
+        size: PhysicalSize<u32>,
+        scale_factor: f64,
+        build: impl FnOnce(&egui::Context),
+    ) -> OverlayFrame {
+        self.pixels_per_point = scale_factor as f32;
+        let mut input = std::mem::take(&mut self.input);
+        input.screen_rect = Some(Rect::from_min_size(
+            Pos2::ZERO,
+            vec2(size.width as f32, size.height as f32) / self.pixels_per_point,
+        ));
+        input.time = Some(self.start.elapsed().as_secs_f64());
+        input.modifiers = self.modifiers;
+        input
+            .viewports
+            .entry(input.viewport_id)
+            .or_default()
+            .native_pixels_per_point = Some(self.pixels_per_point);
+
+        let visible = self.visible;
+        let output = self.ctx.run(input, |ctx| {
+            if visible {
+                build(ctx)
+            }
+        });
+        OverlayFrame {
+            primitives: self.ctx.tessellate(output.shapes, output.pixels_per_point),
+            textures: output.textures_delta,
+            pixels_per_point: output.pixels_per_point,
+        }
+    }
+}
+
+fn modifiers(state: ModifiersState) -> Modifiers {
+    Modifiers {
+        alt: state.alt(),
+        ctrl: state.ctrl(),
+        shift: state.shift(),
+        mac_cmd: cfg!(target_os = "macos") && state.logo(),
+        command: if cfg!(target_os = "macos") {
+            state.logo()
+        } else {
+            state.ctrl()
+        },
+    }
+}
+
+// The keys egui uses for navigation and text editing.
+fn key(key: VirtualKeyCode) -> Option<Key> {
+    Some(match key {
+        VirtualKeyCode::Down => Key::ArrowDown,
+        VirtualKeyCode::Left => Key::ArrowLeft,
+        VirtualKeyCode::Right => Key::ArrowRight,
+        VirtualKeyCode::Up => Key::ArrowUp,
+        VirtualKeyCode::Escape => Key::Escape,
+        VirtualKeyCode::Tab => Key::Tab,
+        VirtualKeyCode::Back => Key::Backspace,
+        VirtualKeyCode::Return | VirtualKeyCode::NumpadEnter => Key::Enter,
+        VirtualKeyCode::Space => Key::Space,
+        VirtualKeyCode::Insert => Key::Insert,
+        VirtualKeyCode::Delete => Key::Delete,
+        VirtualKeyCode::Home => Key::Home,
+        VirtualKeyCode::End => Key::End,
+        VirtualKeyCode::PageUp => Key::PageUp,
+        VirtualKeyCode::PageDown => Key::PageDown,
+        VirtualKeyCode::Minus | VirtualKeyCode::NumpadSubtract => Key::Minus,
+        VirtualKeyCode::Equals => Key::Equals,
+        VirtualKeyCode::Plus | VirtualKeyCode::NumpadAdd => Key::Plus,
+        VirtualKeyCode::Key0 | VirtualKeyCode::Numpad0 => Key::Num0,
+        VirtualKeyCode::Key1 | VirtualKeyCode::Numpad1 => Key::Num1,
+        VirtualKeyCode::Key2 | VirtualKeyCode::Numpad2 => Key::Num2,
+        VirtualKeyCode::Key3 | VirtualKeyCode::Numpad3 => Key::Num3,
+        VirtualKeyCode::Key4 | VirtualKeyCode::Numpad4 => Key::Num4,
+        VirtualKeyCode::Key5 | VirtualKeyCode::Numpad5 => Key::Num5,
+        VirtualKeyCode::Key6 | VirtualKeyCode::Numpad6 => Key::Num6,
+        VirtualKeyCode::Key7 | VirtualKeyCode::Numpad7 => Key::Num7,
+        VirtualKeyCode::Key8 | VirtualKeyCode::Numpad8 => Key::Num8,
+        VirtualKeyCode::Key9 | VirtualKeyCode::Numpad9 => Key::Num9,
+        VirtualKeyCode::A => Key::A,
+        VirtualKeyCode::C => Key::C,
+        VirtualKeyCode::V => Key::V,
+        VirtualKeyCode::X => Key::X,
+        VirtualKeyCode::Y => Key::Y,
+        VirtualKeyCode::Z => Key::Z,
+        _ => return None,
+    })
+}